@@ -0,0 +1,144 @@
+use std::fs::File;
+use std::io::Read;
+use std::str::FromStr;
+
+use rstracer::scene::material::Color;
+
+// Render settings that can be kept in a file, with one `key = value` pair per line. Lines
+// starting with '#' are ignored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RenderConfig {
+    pub size: u32,
+    pub depth: usize,
+    pub samples: usize,
+    pub aa: usize,
+    pub seed: u32,
+    // The color of rays that miss the scene, or None to keep the background of the scene
+    pub background: Option<Color>,
+    pub output: String
+}
+
+impl RenderConfig {
+    pub fn new() -> RenderConfig {
+        RenderConfig {
+            size: 100,
+            depth: 10,
+            samples: 10,
+            aa: 1,
+            seed: 0,
+            background: None,
+            output: "img".to_string()
+        }
+    }
+
+    pub fn load(path: &str) -> Result<RenderConfig, String> {
+        let mut contents = String::new();
+        match File::open(path) {
+            Ok(mut f) => match f.read_to_string(&mut contents) {
+                Ok(_) => RenderConfig::parse(contents.as_slice()),
+                Err(e) => Err(format!("file error: {}, path: {}", e, path))
+            },
+            Err(e) => Err(format!("file error: {}, path: {}", e, path))
+        }
+    }
+
+    pub fn parse(contents: &str) -> Result<RenderConfig, String> {
+        let mut config = RenderConfig::new();
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.len() == 0 || line.starts_with("#") {
+                continue;
+            }
+
+            let (key, value) = match line.find('=') {
+                Some(i) => (line[.. i].trim(), line[i + 1 ..].trim()),
+                None => return Err(format!("Expected 'key = value', found: '{}'", line))
+            };
+
+            match key {
+                "size" => config.size = try!(parse_value(key, value)),
+                "depth" => config.depth = try!(parse_value(key, value)),
+                "samples" => config.samples = try!(parse_value(key, value)),
+                "aa" => config.aa = try!(parse_value(key, value)),
+                "seed" => config.seed = try!(parse_value(key, value)),
+                "background" => config.background = Some(try!(parse_color(value))),
+                "output" => config.output = value.to_string(),
+                _ => return Err(format!("Unknown config key: '{}'", key))
+            }
+        }
+        Ok(config)
+    }
+}
+
+fn parse_value<T: FromStr>(key: &str, value: &str) -> Result<T, String> {
+    match value.parse() {
+        Ok(v) => Ok(v),
+        Err(_) => Err(format!("Invalid value for '{}': '{}'", key, value))
+    }
+}
+
+pub fn parse_color(value: &str) -> Result<Color, String> {
+    let channels: Vec<&str> = value.split(',').map(|c| c.trim()).collect();
+    if channels.len() != 3 {
+        return Err(format!("Expected a color as 'r, g, b', found: '{}'", value));
+    }
+    Ok(Color::init(
+        try!(parse_value("r", channels[0])),
+        try!(parse_value("g", channels[1])),
+        try!(parse_value("b", channels[2]))
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use rstracer::scene::material::Color;
+    use config::{RenderConfig, parse_color};
+
+    #[test]
+    fn empty_config_has_defaults() {
+        let config = RenderConfig::parse("").unwrap();
+        assert_eq!(RenderConfig::new(), config);
+        assert_eq!(100, config.size);
+        assert_eq!("img", config.output.as_slice());
+    }
+
+    #[test]
+    fn can_override_defaults() {
+        let config = RenderConfig::parse("
+            # A comment
+            size = 500
+            depth=4
+            samples = 100
+            aa = 2
+            seed = 42
+            background = 0.5, 0, 1
+            output = render
+        ").unwrap();
+
+        assert_eq!(500, config.size);
+        assert_eq!(4, config.depth);
+        assert_eq!(100, config.samples);
+        assert_eq!(2, config.aa);
+        assert_eq!(42, config.seed);
+        assert_eq!(Some(Color::init(0.5, 0.0, 1.0)), config.background);
+        assert_eq!("render", config.output.as_slice());
+    }
+
+    #[test]
+    fn rejects_invalid_lines() {
+        assert!(RenderConfig::parse("size 500").is_err());
+        assert!(RenderConfig::parse("size = big").is_err());
+        assert!(RenderConfig::parse("colour = 1, 1, 1").is_err());
+        assert!(RenderConfig::parse("background = 1, 1").is_err());
+    }
+
+    #[test]
+    fn can_parse_color() {
+        assert_eq!(Ok(Color::init(0.5, 0.7, 1.0)), parse_color("0.5,0.7,1"));
+        assert_eq!(Ok(Color::init(0.5, 0.7, 1.0)), parse_color("0.5, 0.7, 1"));
+        assert!(parse_color("0.5,0.7").is_err());
+        assert!(parse_color("blue").is_err());
+        assert!(parse_color("0.5,blue,0.7").is_err());
+    }
+}
@@ -4,35 +4,560 @@
 extern crate bmp;
 extern crate rand;
 
+use std::cmp;
+use std::f32::consts;
+use std::hash::{hash, SipHasher};
+use std::io::{self, Write};
 use std::num::Float;
+use std::sync::Mutex;
+use std::sync::mpsc::channel;
+use std::thread;
 
-use bmp::Image;
+use bmp::{Image, Pixel};
+use rand::{thread_rng, Open01, Rng, SeedableRng, XorShiftRng};
 
 use vec::Vec3;
 use ray::Ray;
-use scene::{IntersectableScene, Light};
+use scene::{IntersectableScene, Background, Camera, Light};
+use scene::SceneIntersection;
 use scene::SceneIntersection::{Intersected, Missed};
-use scene::material::Color;
+use scene::material::{self, Color, ColorBuf};
+use scene::stats::{RenderStats, Stat};
 use scene::intersection::Intersection;
 
 pub mod vec;
+pub mod mat;
 pub mod ray;
 pub mod scene;
+pub mod output;
 
 static SCALE: f32 = 10000.0;
+static TILE_SIZE: u32 = 16;
+static PREVIEW_SIZE: u32 = 32;
+static MIDDLE_GRAY: f32 = 0.18;
+static LUMINANCE_DELTA: f32 = 0.0001;
+// Stands out from any lit surface, so pixels that are NaN or infinite are easy to find. It's
+// written after exposure and tone mapping, which would otherwise change it.
+static NAN_PIXEL: Pixel = Pixel { r: 255, g: 0, b: 255 };
+// Fills out the seeds of the per sample generators, which can't be all zeros
+static SEED: [u32; 3] = [0x193a6754, 0xa8a7d469, 0x97830e05];
+// Shadow samples are drawn in chunks of this many, each chunk from its own seeded generator
+static SHADOW_CHUNK: usize = 8;
+
+// How the primary ray samples of a pixel are combined into its color
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SampleCombiner {
+    Mean,
+    // The mean of all samples except the brightest, which rejects single bright outliers
+    TrimmedMean,
+    // The median of each channel on its own
+    Median
+}
+
+impl SampleCombiner {
+    pub fn combine(&self, samples: &[ColorBuf]) -> ColorBuf {
+        match *self {
+            SampleCombiner::Mean => SampleCombiner::mean(samples.iter()),
+            SampleCombiner::TrimmedMean if samples.len() > 1 => {
+                let mut brightest = 0;
+                for (i, sample) in samples.iter().enumerate() {
+                    if sample.luminance() > samples[brightest].luminance() {
+                        brightest = i;
+                    }
+                }
+                SampleCombiner::mean(samples.iter().enumerate()
+                    .filter(|&(i, _)| i != brightest).map(|(_, sample)| sample))
+            },
+            SampleCombiner::TrimmedMean => SampleCombiner::mean(samples.iter()),
+            SampleCombiner::Median => ColorBuf::init(
+                SampleCombiner::median(samples.iter().map(|c| c.r).collect()),
+                SampleCombiner::median(samples.iter().map(|c| c.g).collect()),
+                SampleCombiner::median(samples.iter().map(|c| c.b).collect()))
+        }
+    }
+
+    fn mean<'b, I: Iterator<Item=&'b ColorBuf>>(samples: I) -> ColorBuf {
+        let (mut sum, mut n) = (ColorBuf::new(), 0);
+        for color in samples {
+            sum.add_assign(*color);
+            n += 1;
+        }
+        match n {
+            0 => ColorBuf::new(),
+            _ => sum.mult(1.0 / n as f32)
+        }
+    }
+
+    fn median(mut values: Vec<f32>) -> f32 {
+        if values.len() == 0 {
+            return 0.0;
+        }
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(cmp::Ordering::Equal));
+        let mid = values.len() / 2;
+        match values.len() % 2 == 0 {
+            true => (values[mid - 1] + values[mid]) * 0.5,
+            false => values[mid]
+        }
+    }
+}
+
+// Shades the hits of rays with the lights of a scene. It only holds the sampling settings, so it
+// can be used on its own, without tracing a whole image with a RayTracer.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Shader {
+    // The number of samples of each area light
+    pub num_samples: usize,
+    // The number of threads the shadow samples of each light are split between
+    pub threads: usize,
+    // The number of times rays are reflected or refracted, plus one for the first hit
+    pub depth: usize,
+    // What rays that miss the scene see, instead of the background of the scene
+    pub background: Option<Background>,
+    // The number of rays that test how open the surface around each hit is, which scales the
+    // ambient light. None are cast by default, leaving the ambient light flat.
+    pub ao_samples: usize,
+    // How close a surface has to be to the hit to occlude it
+    pub ao_distance: f32
+}
+
+impl Shader {
+    pub fn new() -> Shader {
+        Shader {
+            num_samples: 1,
+            threads: 1,
+            depth: 1,
+            background: None,
+            ao_samples: 0,
+            ao_distance: 1.0
+        }
+    }
+
+    pub fn init(depth: usize, num_samples: usize) -> Shader {
+        let mut shader = Shader::new();
+        shader.depth = depth;
+        shader.num_samples = num_samples;
+        shader
+    }
+
+    // Shades a hit with every light of the scene, following reflected and refracted rays up to
+    // the depth of the shader. Every sample is drawn from `rng`.
+    pub fn shade<'a, R: Rng>(&self, scene: &'a Box<IntersectableScene + Sync + 'a>,
+                             intersection: &Intersection, rng: &mut R) -> ColorBuf {
+        self.shade_intersection(scene, intersection, self.depth, rng)
+    }
+
+    fn shade_intersection<'a, R: Rng>(&self, scene: &'a Box<IntersectableScene + Sync + 'a>,
+                                      intersection: &Intersection, depth: usize,
+                                      rng: &mut R) -> ColorBuf {
+        if depth <= 0 {
+            return ColorBuf::new();
+        }
+
+        let material = intersection.material();
+        let kt: f32 = material.transparency;
+        let ks: Color = material.specular;
+        let ka: Color = material.ambient;
+        let cd: Color = intersection.color();
+        let normal: Vec3 = intersection.surface_normal();
+
+        let ambient_light: Color = match self.ao_samples > 0 {
+            true => Shader::ambient_lightning(kt, ka, cd).mult(Shader::ambient_occlusion(scene,
+                intersection, self.ao_samples, self.ao_distance, rng)),
+            false => Shader::ambient_lightning(kt, ka, cd)
+        };
+
+        let mut direct_light: ColorBuf = ColorBuf::new();
+        for light in scene.get_lights().iter().filter(|light| light.is_enabled()) {
+            let fattj = Shader::calculate_fattj(light, intersection.point(), rng);
+            if fattj > 0.0 {
+                let n = match light {
+                    // At least one sample, to not leave the scene unlit or divide by zero
+                    &Light::Area(_) | &Light::Triangle(_) => cmp::max(self.num_samples, 1),
+                    _ => 1
+                };
+
+                let shadow_scalar = Shader::shadow_scalar(scene, light, intersection, normal, n,
+                    depth, self.threads, rng);
+                direct_light.add_assign(Shader::direct_lightning(light, intersection, normal,
+                    shadow_scalar, fattj, n, rng));
+            }
+        }
+
+        // Transparent surfaces reflect part of the light they let through, more so at grazing
+        // angles, where glass becomes a mirror
+        let fresnel = match kt > 0.0 {
+            true => intersection.fresnel_from(normal),
+            false => 0.0
+        };
+        let kr: Color = ks + Color::init(kt * fresnel, kt * fresnel, kt * fresnel);
+
+        let reflective_light = if kr.scalar() > 0.0 {
+            // Only the first hit splits into several reflections, so the number of rays grows
+            // with the depth instead of exponentially
+            let n = match depth == self.depth {
+                true => self.num_samples,
+                false => 1
+            };
+            let rays = Shader::reflective_rays(intersection, normal, n, rng);
+            let weight = 1.0 / rays.len() as f32;
+            rays.iter().fold(ColorBuf::new(), |light, ray| {
+                scene.stats().count(Stat::RaysReflect);
+                light + self.trace_ray(scene, ray, depth - 1, rng).mult(weight)
+            }) * kr
+        } else {
+            ColorBuf::new()
+        };
+
+        let refractive_light = if kt > 0.0 {
+            match intersection.refractive_ray_from(normal) {
+                Some(ray) => {
+                    scene.stats().count(Stat::RaysRefract);
+                    self.trace_ray(scene, &ray, depth - 1, rng).mult(kt * (1.0 - fresnel))
+                },
+                None => ColorBuf::new()
+            }
+        } else {
+            ColorBuf::new()
+        };
+
+        // Emissive surfaces glow on their own, whether they are lit or not. Nothing is clamped
+        // here, so light bounced off bright surfaces keeps its intensity.
+        direct_light + reflective_light + refractive_light +
+            ColorBuf::from_color(ambient_light + material.emissive)
+    }
+
+    fn trace_ray<'a, R: Rng>(&self, scene: &'a Box<IntersectableScene + Sync + 'a>, ray: &Ray,
+                             depth: usize, rng: &mut R) -> ColorBuf {
+        self.shade_ray(scene, ray, scene.intersects(ray), depth, rng)
+    }
+
+    // Primary rays are clipped by the near and far planes of the camera
+    fn trace_primary_ray<'a, R: Rng>(&self, scene: &'a Box<IntersectableScene + Sync + 'a>,
+                                     ray: &Ray, (near, far): (f32, f32), rng: &mut R) -> ColorBuf {
+        scene.stats().count(Stat::RaysPrimary);
+        self.shade_ray(scene, ray, scene.intersects_between(ray, near, far), self.depth, rng)
+    }
+
+    fn shade_ray<'a, R: Rng>(&self, scene: &'a Box<IntersectableScene + Sync + 'a>, ray: &Ray,
+                             hit: SceneIntersection<'a>, depth: usize, rng: &mut R) -> ColorBuf {
+        match hit {
+            Intersected(intersection) => {
+                let color = self.shade_intersection(scene, &intersection, depth, rng);
+                scene.attenuate(ray, intersection.distance(), color)
+            },
+            Missed => {
+                let color = ColorBuf::from_color(self.background_color(scene, ray.dir()));
+                scene.attenuate(ray, Float::infinity(), color)
+            }
+        }
+    }
+
+    // The color seen by rays going in the direction `dir` that miss every primitive of `scene`
+    pub fn background_color<'a>(&self, scene: &'a Box<IntersectableScene + Sync + 'a>,
+                                dir: Vec3) -> Color {
+        self.background.unwrap_or(*scene.get_background()).color(dir)
+    }
+
+    fn shadow_scalar<'a, R: Rng>(scene: &'a Box<IntersectableScene + Sync + 'a>,
+                                 light: &Light, intersection: &Intersection, normal: Vec3,
+                                 n: usize, depth: usize, threads: usize,
+                                 rng: &mut R) -> Color {
+        if depth <= 0 || n == 0 {
+            return Color::new();
+        }
+
+        let ori = intersection.point() + normal.mult(intersection.bias());
+        let template = intersection.surface_ray(ori, Vec3::new());
+        let chunks = (n + SHADOW_CHUNK - 1) / SHADOW_CHUNK;
+        let seeds: Vec<u32> = (0 .. chunks).map(|_| rng.gen::<u32>()).collect();
+
+        let shade = Shader::sample_shadows(scene, light, &template, &seeds[..], n, depth, threads);
+        Color::init(shade, shade, shade)
+    }
+
+    // Averages `n` shadow samples, drawn in chunks of `SHADOW_CHUNK` with one seed per chunk.
+    // The chunks are split evenly between `threads` threads, and their sums are added up in
+    // order, so the result doesn't depend on the number of threads.
+    fn sample_shadows<'a>(scene: &'a Box<IntersectableScene + Sync + 'a>, light: &Light,
+                          template: &Ray, seeds: &[u32], n: usize, depth: usize,
+                          threads: usize) -> f32 {
+        let sums: Vec<f32> = match threads > 1 && seeds.len() > 1 {
+            true => {
+                let per_thread = (seeds.len() + threads - 1) / threads;
+                let guards: Vec<_> = seeds.chunks(per_thread).enumerate().map(|(t, seeds)| {
+                    let template = template.clone();
+                    thread::scoped(move || seeds.iter().enumerate().map(|(j, &seed)|
+                        Shader::shadow_chunk(scene, light, &template, t * per_thread + j, seed, n,
+                            depth)).collect::<Vec<f32>>())
+                }).collect();
+                guards.into_iter().flat_map(|guard| guard.join().into_iter()).collect()
+            },
+            false => seeds.iter().enumerate().map(|(k, &seed)|
+                Shader::shadow_chunk(scene, light, template, k, seed, n, depth)).collect()
+        };
+        sums.iter().fold(0.0, |shade, &sum| shade + sum) / n as f32
+    }
+
+    // The sum of the `k`th chunk of the `n` shadow samples, drawn from a generator seeded with
+    // `seed`
+    fn shadow_chunk<'a>(scene: &'a Box<IntersectableScene + Sync + 'a>, light: &Light,
+                        template: &Ray, k: usize, seed: u32, n: usize, depth: usize) -> f32 {
+        let mut rng: XorShiftRng = SeedableRng::from_seed([seed, SEED[0], SEED[1], SEED[2]]);
+        (k * SHADOW_CHUNK .. cmp::min((k + 1) * SHADOW_CHUNK, n)).fold(0.0, |shade, i|
+            shade + Shader::shadow_sample(scene, light, template, i, n, depth, &mut rng))
+    }
+
+    // The `i`th of `n` shadow samples, which picks its point on area lights from the `i`th cell
+    // of their grid, see `AreaLight::sample_stratified_with`
+    fn shadow_sample<'a, R: Rng>(scene: &'a Box<IntersectableScene + Sync + 'a>,
+                                 light: &Light, template: &Ray, i: usize, n: usize, depth: usize,
+                                 rng: &mut R) -> f32 {
+        let ori = template.ori;
+        let target = light.position_stratified_with(i, n, rng);
+
+        let mut shadow = template.clone();
+        shadow.set_dir(match light {
+            &Light::Directional(_) => light.get_dir_with(ori, rng),
+            _ => {
+                let mut dir = target - ori;
+                dir.normalize();
+                dir
+            }
+        });
+
+        scene.stats().count(Stat::RaysShadow);
+        let max_dist = match light {
+            &Light::Directional(_) => Float::infinity(),
+            _ => ori.distance(target)
+        };
+        // Opaque blockers are found without searching for the nearest hit
+        match scene.shadow_hit(&shadow, max_dist) {
+            Intersected(intersection) => {
+                let material = intersection.material();
+                if material.transparency == 0.0 {
+                    0.0 // Hit something before the light
+                } else { // Shape is transparent, continue recursively
+                    material.transparency * Shader::shadow_scalar(scene, light,
+                        &intersection, intersection.surface_normal(), n, depth - 1, 1,
+                        rng).r_val()
+                }
+            },
+            Missed => 1.0 // The point is in direct light
+        }
+    }
+
+    // A cosine weighted direction in the hemisphere around `normal`
+    fn sample_hemisphere<R: Rng>(normal: Vec3, rng: &mut R) -> Vec3 {
+        let Open01(r1) = rng.gen::<Open01<f32>>();
+        let Open01(r2) = rng.gen::<Open01<f32>>();
+        let (tangent, bitangent) = normal.orthonormal_basis();
+        let r = r1.sqrt();
+        let phi = 2.0 * consts::PI * r2;
+        tangent.mult(r * phi.cos()) + bitangent.mult(r * phi.sin()) + normal.mult((1.0 - r1).sqrt())
+    }
+
+    // The fraction of `n` rays over the hemisphere around the hit that do not hit anything
+    // closer than `max_dist`
+    fn ambient_occlusion<'a, R: Rng>(scene: &'a Box<IntersectableScene + Sync + 'a>,
+                                     intersection: &Intersection, n: usize, max_dist: f32,
+                                     rng: &mut R) -> f32 {
+        let normal = intersection.surface_normal();
+        let ori = intersection.point() + normal.mult(intersection.bias());
+        let n = cmp::max(n, 1);
+
+        let mut escaped = 0;
+        for _ in 0 .. n {
+            let ray = intersection.surface_ray(ori, Shader::sample_hemisphere(normal, rng));
+            match scene.intersects(&ray) {
+                Intersected(ref hit) if hit.distance() < max_dist => (),
+                _ => escaped += 1
+            }
+        }
+        escaped as f32 / n as f32
+    }
+
+    // The mirror reflection of a smooth surface, or `n` reflections spread over a cone around the
+    // mirror direction on a rough one. The cone widens with the roughness of the material.
+    fn reflective_rays<R: Rng>(intersection: &Intersection, normal: Vec3, n: usize,
+                               rng: &mut R) -> Vec<Ray> {
+        let mirror = intersection.reflective_ray_from(normal);
+        let roughness = intersection.material().roughness;
+        if roughness <= 0.0 {
+            return vec!(mirror);
+        }
+
+        let cos_max = (roughness * consts::FRAC_PI_2).cos();
+        let (tangent, bitangent) = mirror.dir().orthonormal_basis();
+        (0 .. cmp::max(n, 1)).map(|_| {
+            let Open01(r1) = rng.gen::<Open01<f32>>();
+            let Open01(r2) = rng.gen::<Open01<f32>>();
+            let cos_theta = 1.0 - r1 * (1.0 - cos_max);
+            let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+            let phi = 2.0 * consts::PI * r2;
+            let mut dir = tangent.mult(sin_theta * phi.cos()) +
+                bitangent.mult(sin_theta * phi.sin()) + mirror.dir().mult(cos_theta);
+            // Directions below the surface are mirrored back above it
+            if dir.dot(normal) < 0.0 {
+                dir = dir.reflect(normal);
+            }
+            dir.normalize();
+
+            let mut ray = mirror.clone();
+            ray.set_dir(dir);
+            ray
+        }).collect()
+    }
+
+    fn ambient_lightning(kt: f32, ka: Color, cd: Color) -> Color {
+        (cd * ka).mult(1.0 - kt)
+    }
+
+    fn calculate_fattj<R: Rng>(light: &Light, point: Vec3, rng: &mut R) -> f32 {
+        match light {
+            &Light::Directional(_) => 1.0,
+            _ => {
+                let distance_squared = point.distance_squared(light.position_with(rng));
+                let distance = distance_squared.sqrt();
+                (1.0 as f32).min(1.0 / (0.25 + 0.1 * distance + 0.01 * distance_squared))
+            }
+        }
+    }
+
+    fn diffuse_lightning(kt: f32, cd: Color, normal: Vec3, dj: Vec3) -> Color {
+        let a: f32 = 1.0 - kt;
+        let b: f32 = (0.0 as f32).max(normal.dot(dj));
+        cd.mult(a * b)
+    }
+
+    fn specular_lightning(q: f32, ks: Color, normal: Vec3, dj: Vec3, v: Vec3) -> Color {
+        let rj: Vec3 = dj.invert().reflect(normal);
+        let t: f32 = rj.dot(v).max(0.0);
+        ks.mult(t.powf(q))
+    }
+
+    // A Phong-like highlight around the half vector, with the exponent `q` lowered along the
+    // tangent of `frame` by `anisotropy`, so the highlight stretches along the tangent
+    fn anisotropic_lightning(q: f32, ks: Color, anisotropy: f32, frame: (Vec3, Vec3, Vec3),
+                             dj: Vec3, v: Vec3) -> Color {
+        let (tangent, bitangent, normal) = frame;
+        let mut h: Vec3 = dj + v;
+        h.normalize();
+        let nh: f32 = normal.dot(h);
+        if nh <= 0.0 {
+            return Color::new();
+        }
+
+        let sin2: f32 = 1.0 - nh * nh;
+        if sin2 < 1.0e-6 { // The half vector is the normal, which is the peak of the highlight
+            return ks;
+        }
+        let (ht, hb) = (h.dot(tangent), h.dot(bitangent));
+        let exponent = (q * (1.0 - anisotropy) * ht * ht + q * (1.0 + anisotropy) * hb * hb) / sin2;
+        ks.mult(nh.powf(exponent))
+    }
+
+    // The tangent frame used for anisotropic highlights, following the tangent of the material
+    // when it has one
+    fn anisotropic_frame(intersection: &Intersection, normal: Vec3) -> (Vec3, Vec3, Vec3) {
+        let tangent = intersection.material().tangent;
+        let mut projected = tangent - normal.mult(normal.dot(tangent));
+        match projected.length() > 1.0e-6 {
+            true => {
+                projected.normalize();
+                (projected, normal.cross(projected), normal)
+            },
+            false => {
+                let (tangent, bitangent, _) = intersection.tangent_frame();
+                (tangent, bitangent, normal)
+            }
+        }
+    }
+
+    // Not clamped, so bright lights keep their highlights until the pixel is stored
+    fn direct_lightning<R: Rng>(light: &Light, intersection: &Intersection, normal: Vec3,
+                                sj: Color, fattj: f32, n: usize, rng: &mut R) -> ColorBuf {
+        let point: Vec3 = intersection.point();
+        let material = intersection.material();
+        let kt: f32 = material.transparency;
+        let cd: Color = intersection.color();
+        let ks: Color = material.specular;
+        let q: f32 = material.shininess * 128.0;
+        let frame = match material.anisotropy > 0.0 {
+            true => Some(Shader::anisotropic_frame(intersection, normal)),
+            false => None
+        };
+
+        let falloff = light.falloff(point);
+        let direct_light: ColorBuf = (light.intensity() * sj).mult(fattj * falloff);
+
+        let mut lightning = ColorBuf::new();
+        for i in 0 .. n {
+            let dir = light.get_dir_stratified_with(point, i, n, rng);
+            let diffuse_light: Color = Shader::diffuse_lightning(kt, cd, normal, dir);
+
+            let v: Vec3 = intersection.direction().invert();
+            let specular_light: Color = match frame {
+                Some(frame) => Shader::anisotropic_lightning(q, ks, material.anisotropy, frame,
+                    dir, v),
+                None => Shader::specular_lightning(q, ks, normal, dir, v)
+            };
+
+            let sample = direct_light * (diffuse_light + specular_light);
+            lightning.add_assign(sample.mult(1.0 / n as f32));
+        }
+
+        lightning
+    }
+}
+
+// How the colors of the pixels, which can be brighter than 1.0, are brought into range when they
+// are stored
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ToneMapping {
+    // Every channel above 1.0 is cut off at 1.0
+    Clamp,
+    // Every channel `c` becomes `c / (1 + c)`, which keeps detail in the highlights
+    Reinhard
+}
 
 pub struct RayTracer<'a> {
     width: u32,
     height: u32,
     num_samples: usize,
     depth: usize,
+    sample_threads: usize,
+    aa_samples: usize,
+    background_aa: bool,
+    aspect_fit: bool,
+    error_diffusion: bool,
+    nan_debug: bool,
+    sample_combiner: SampleCombiner,
+    tone_mapping: ToneMapping,
     center: Vec3,
     camera_pos: Vec3,
     parallel_up: Vec3,
     parallel_right: Vec3,
     vertical_fov: f32,
     horizontal_fov: f32,
-    scene: Option<Box<IntersectableScene<'a> + 'a>>
+    camera_aspect: f32,
+    focal_dist: f32,
+    near: f32,
+    far: f32,
+    aperture: f32,
+    gamma: f32,
+    background: Option<Background>,
+    ao_samples: usize,
+    ao_distance: f32,
+    // Each tile samples with a generator seeded from this and the position of the tile
+    seed: u32,
+    // Replaces the camera of the scene when set
+    camera: Option<Camera>,
+    exposure: f32,
+    // The colors of the last frame from `trace_rays_cached` before exposure, along with the key
+    // of the settings it was traced with
+    cache: Mutex<Option<(u64, Vec<ColorBuf>)>>,
+    // Only read while tracing, so the tiles of `trace_rays_parallel` can share it
+    scene: Option<Box<IntersectableScene + Sync + 'a>>
 }
 
 impl<'a> RayTracer<'a> {
@@ -42,12 +567,33 @@ impl<'a> RayTracer<'a> {
             height: 0,
             num_samples: 1,
             depth: 0,
+            sample_threads: 1,
+            aa_samples: 1,
+            background_aa: true,
+            aspect_fit: false,
+            error_diffusion: false,
+            nan_debug: false,
+            sample_combiner: SampleCombiner::Mean,
+            tone_mapping: ToneMapping::Clamp,
             center: Vec3::new(),
             camera_pos: Vec3::new(),
             parallel_up: Vec3::new(),
             parallel_right: Vec3::new(),
             vertical_fov: 0.0,
             horizontal_fov: 0.0,
+            camera_aspect: 1.0,
+            focal_dist: 0.0,
+            near: 0.0,
+            far: Float::infinity(),
+            aperture: 0.0,
+            gamma: 1.0,
+            background: None,
+            ao_samples: 0,
+            ao_distance: 1.0,
+            seed: 0,
+            camera: None,
+            exposure: 1.0,
+            cache: Mutex::new(None),
             scene: None
         }
     }
@@ -61,16 +607,117 @@ impl<'a> RayTracer<'a> {
         raytracer
     }
 
-    pub fn set_scene(&mut self, scene: Box<IntersectableScene<'a> + 'a>) {
+    // Splits the light samples of each shadow test between `threads` threads, which pays off for
+    // a few pixels with many area light samples, rather than for whole images
+    pub fn set_sample_threads(&mut self, threads: usize) {
+        self.sample_threads = cmp::max(threads, 1);
+    }
+
+    // Each pixel averages an `aa_samples` by `aa_samples` grid of primary rays
+    pub fn set_aa_samples(&mut self, aa_samples: usize) {
+        self.aa_samples = cmp::max(aa_samples, 1);
+    }
+
+    // Without background AA, pixels whose first primary ray misses the scene get the background
+    // color from that ray alone. This saves rays when the background is cheap, but edges of
+    // objects that don't cover the first ray of a pixel are not anti-aliased.
+    pub fn set_background_aa(&mut self, background_aa: bool) {
+        self.background_aa = background_aa;
+    }
+
+    // With aspect fit, an image with another aspect than the camera is letterboxed instead of
+    // stretched. The vertical field of view of the camera is kept, and the bands outside of the
+    // view of the camera are left as background.
+    pub fn set_aspect_fit(&mut self, aspect_fit: bool) {
+        self.aspect_fit = aspect_fit;
+        if self.scene.is_some() || self.camera.is_some() {
+            self.setup_camera();
+        }
+    }
+
+    // Quantizes the final image with Floyd-Steinberg error diffusion instead of truncating each
+    // pixel, which avoids banding in smooth gradients
+    pub fn set_error_diffusion(&mut self, error_diffusion: bool) {
+        self.error_diffusion = error_diffusion;
+    }
+
+    // Pixels that come out NaN or infinite are painted magenta, and their positions are written
+    // to stderr. Otherwise they would look like any other black or white pixel.
+    pub fn set_nan_debug(&mut self, nan_debug: bool) {
+        self.nan_debug = nan_debug;
+    }
+
+    // Combining the anti-aliasing samples of a pixel with something other than their mean can
+    // keep single bright samples from showing up as fireflies
+    pub fn set_sample_combiner(&mut self, sample_combiner: SampleCombiner) {
+        self.sample_combiner = sample_combiner;
+    }
+
+    // The colors are traced without clamping, and mapped into range after the exposure is applied
+    pub fn set_tone_mapping(&mut self, tone_mapping: ToneMapping) {
+        self.tone_mapping = tone_mapping;
+    }
+
+    // With an aperture larger than 0, the primary rays start on a disk of that radius around the
+    // camera and meet at the focal distance of the camera, so everything else is out of focus
+    pub fn set_aperture(&mut self, aperture: f32) {
+        self.aperture = aperture.max(0.0);
+    }
+
+    // The colors are traced in linear space, and raised to 1 / gamma when they are stored, so that
+    // they look right on screens. A gamma of 1 stores the linear colors.
+    pub fn set_gamma(&mut self, gamma: f32) {
+        self.gamma = match gamma > 0.0 {
+            true => gamma,
+            false => 1.0
+        };
+    }
+
+    // The color of every ray that misses the scene, whether it is a primary ray or a reflected or
+    // refracted one. It replaces the background of the scene.
+    pub fn set_background(&mut self, background: Color) {
+        self.background = Some(Background::Solid(background));
+    }
+
+    // Darkens the ambient light of hits by the fraction of `num_samples` rays over the hemisphere
+    // around them that hit something closer than `max_dist`. No rays are cast when it is 0.
+    pub fn set_ambient_occlusion(&mut self, num_samples: usize, max_dist: f32) {
+        self.ao_samples = num_samples;
+        self.ao_distance = max_dist;
+    }
+
+    // Renders with the same seed are identical, no matter how many threads they are traced on
+    pub fn set_seed(&mut self, seed: u32) {
+        self.seed = seed;
+    }
+
+    // The frame cached by `trace_rays_cached` belongs to the old scene, so it is dropped
+    pub fn set_scene(&mut self, scene: Box<IntersectableScene + Sync + 'a>) {
         self.scene = Some(scene);
+        *self.cache.lock().unwrap() = None;
         self.setup_camera();
     }
 
+    // Renders from `camera` instead of the camera of the scene, which is left as it is
+    pub fn set_camera(&mut self, camera: Camera) {
+        self.camera = Some(camera);
+        self.setup_camera();
+    }
+
+    pub fn exposure(&self) -> f32 {
+        self.exposure
+    }
+
+    pub fn set_exposure(&mut self, exposure: f32) {
+        self.exposure = exposure;
+    }
+
     fn setup_camera(&mut self) {
-        let cam = match self.scene {
-            Some(ref scene) => scene.get_camera(),
-            None => panic!("RayTracer has not been assigned any Scene")
-        };
+        let cam = match (self.camera, &self.scene) {
+            (Some(camera), _) => camera,
+            (None, &Some(ref scene)) => *scene.get_camera(),
+            (None, &None) => panic!("RayTracer has not been assigned any Scene")
+        }.transformed();
 
         self.parallel_right = cam.view_dir.cross(cam.ortho_up);
         self.parallel_up = self.parallel_right.cross(cam.view_dir);
@@ -78,8 +725,15 @@ impl<'a> RayTracer<'a> {
         self.parallel_up.normalize();
 
         self.vertical_fov = cam.vertical_fov;
-        self.horizontal_fov = cam.vertical_fov * (self.width as f32 / self.height as f32);
+        self.camera_aspect = cam.aspect;
+        self.horizontal_fov = match self.aspect_fit && cam.aspect > 0.0 {
+            true => cam.vertical_fov * cam.aspect,
+            false => cam.vertical_fov * (self.width as f32 / self.height as f32)
+        };
         self.camera_pos = cam.pos;
+        self.focal_dist = cam.focal_dist;
+        self.near = cam.near;
+        self.far = cam.far;
         self.center = cam.pos + cam.view_dir.mult(SCALE);
     }
 
@@ -94,183 +748,525 @@ impl<'a> RayTracer<'a> {
     }
 
     fn compute_ray(&self, x: f32, y: f32) -> Ray {
-        let (x, y) = (x * (1.0 / self.width as f32), y * (1.0 / self.height as f32));
+        self.compute_ray_with(x, y, &mut thread_rng())
+    }
+
+    // The part of the image the camera sees, as (x, y, width, height) in pixels. That is the whole
+    // image, unless aspect fit leaves bands on two of its sides.
+    fn frame(&self) -> (f32, f32, f32, f32) {
+        let (w, h) = (self.width as f32, self.height as f32);
+        if !self.aspect_fit || self.camera_aspect <= 0.0 {
+            return (0.0, 0.0, w, h);
+        }
+        let (fit_w, fit_h) = ((h * self.camera_aspect).min(w), (w / self.camera_aspect).min(h));
+        ((w - fit_w) * 0.5, (h - fit_h) * 0.5, fit_w, fit_h)
+    }
+
+    fn compute_ray_with<R: Rng>(&self, x: f32, y: f32, rng: &mut R) -> Ray {
+        let (x0, y0, w, h) = self.frame();
+        let (x, y) = ((x - x0) * (1.0 / w), (y - y0) * (1.0 / h));
         let dx = self.horizontal_plane().mult(2.0 * x - 1.0);
         let dy = self.vertical_plane().mult(2.0 * y - 1.0);
         let mut dir = self.center + dx + dy;
         dir.normalize();
-        Ray::init(self.camera_pos, dir)
+        match self.aperture > 0.0 && self.focal_dist > 0.0 {
+            true => {
+                let focus = self.camera_pos + dir.mult(self.focal_dist);
+                let (u, v) = RayTracer::sample_disk(rng, self.aperture);
+                let ori = self.camera_pos + self.parallel_right.mult(u) + self.parallel_up.mult(v);
+                let mut dir = focus - ori;
+                dir.normalize();
+                Ray::init(ori, dir)
+            },
+            false => Ray::init(self.camera_pos, dir)
+        }
     }
 
-    fn shadow_scalar<'b>(scene: &'a Box<IntersectableScene<'a> + 'a>, light: &Light,
-                         intersection: &Intersection, n: usize, depth: usize) -> Color {
-        if depth <= 0 {
-            return Color::new();
+    // A uniformly distributed point on a disk with the given radius
+    fn sample_disk<R: Rng>(rng: &mut R, radius: f32) -> (f32, f32) {
+        let Open01(r1) = rng.gen::<Open01<f32>>();
+        let Open01(r2) = rng.gen::<Open01<f32>>();
+        let r = radius * r1.sqrt();
+        let phi = 2.0 * consts::PI * r2;
+        (r * phi.cos(), r * phi.sin())
+    }
+
+    // Shades the hits of the rays with the sampling settings of the RayTracer
+    pub fn shader(&self) -> Shader {
+        Shader {
+            num_samples: self.num_samples,
+            threads: self.sample_threads,
+            depth: self.depth,
+            background: self.background,
+            ao_samples: self.ao_samples,
+            ao_distance: self.ao_distance
         }
+    }
 
-        let ori = intersection.point() + intersection.surface_normal().mult(0.0001);
+    // The ray through the pixel at (x, y) in the image, where y goes from the top of the image
+    // down, like in `trace_rays`
+    pub fn primary_ray(&self, x: f32, y: f32) -> Ray {
+        self.pixel_ray(x, y, &mut thread_rng())
+    }
 
-        let mut shade: f32 = 0.0;
-        for _ in 0 .. n {
-            let dir = light.get_dir(ori);
-            let shadow = Ray::init(ori, dir);
-            shade += match scene.intersects(&shadow) {
-                Intersected(intersection) => {
-                    let material = intersection.material();
-                    if material.transparency == 0.0 {
-                        match light {
-                            &Light::Directional(_) => 0.0, // Hit something before directional light
-                            _ => if ori.distance(intersection.point()) > ori.distance(light.position()) {
-                                1.0 // Intersects with object behind the light source
-                            } else {
-                                0.0
-                            }
+    // Like `primary_ray`, with the samples of the lens taken from `rng`. The rows of the camera
+    // go from the bottom up, so they are flipped.
+    fn pixel_ray<R: Rng>(&self, x: f32, y: f32, rng: &mut R) -> Ray {
+        self.compute_ray_with(x, self.height as f32 - y - 1.0, rng)
+    }
+
+    // Quantizes the rows of colors to 8 bits per channel, passing the rounding error of each pixel
+    // on to its neighbours to the right and below
+    fn diffuse_errors(colors: &[Color], width: u32, height: u32) -> Vec<Pixel> {
+        let (w, h) = (width as i32, height as i32);
+        let spread: [(i32, i32, f32); 4] = [(1, 0, 7.0), (-1, 1, 3.0), (0, 1, 5.0), (1, 1, 1.0)];
+        let mut channels: Vec<[f32; 3]> = colors.iter().map(|color|
+            [color.r_val() * 255.0, color.g_val() * 255.0, color.b_val() * 255.0]).collect();
+
+        let mut pixels = Vec::with_capacity(colors.len());
+        for y in 0 .. h {
+            for x in 0 .. w {
+                let mut quantized = [0u8; 3];
+                for c in 0 .. 3 {
+                    let value = channels[(y * w + x) as usize][c];
+                    let q = value.round().max(0.0).min(255.0);
+                    quantized[c] = q as u8;
+
+                    let error = value - q;
+                    for &(dx, dy, weight) in spread.iter() {
+                        let (nx, ny) = (x + dx, y + dy);
+                        if nx >= 0 && nx < w && ny < h {
+                            channels[(ny * w + nx) as usize][c] += error * weight / 16.0;
                         }
-                    } else { // Shape is transparent, continue recursively
-                        material.transparency * RayTracer::shadow_scalar(scene, light,
-                            &intersection, n, depth - 1).r_val()
                     }
-                },
-                Missed => 1.0 // The point is in direct light
+                }
+                pixels.push(Pixel { r: quantized[0], g: quantized[1], b: quantized[2] });
             }
         }
+        pixels
+    }
 
-        shade = shade / n as f32;
+    // Returns the color of the pixel at (x, y) in the image, and the number of primary rays that
+    // were averaged to get it
+    fn sample_pixel<R: Rng>(&self, x: u32, y: u32, rng: &mut R) -> (ColorBuf, usize) {
+        let scene = match self.scene {
+            Some(ref scene) => scene,
+            None => panic!("RayTracer has not been assigned any Scene")
+        };
+        let (px, py) = (x as f32, y as f32);
+        let n = self.aa_samples;
+        let shader = self.shader();
 
-        Color::init(shade, shade, shade)
-    }
+        // Pixels in the letterbox bands are background, without tracing any rays. The bands are
+        // the same at the top and the bottom, so the rows don't need to be flipped.
+        let (x0, y0, w, h) = self.frame();
+        let (cx, cy) = (px + 0.5, py + 0.5);
+        if cx < x0 || cx > x0 + w || cy < y0 || cy > y0 + h {
+            let dir = self.pixel_ray(px, py, rng).dir();
+            return (ColorBuf::from_color(shader.background_color(scene, dir)), 0);
+        }
 
-    fn ambient_lightning(kt: f32, ka: Color, cd: Color) -> Color {
-        (cd * ka).mult(1.0 - kt)
-    }
+        if n > 1 && !self.background_aa {
+            let ray = self.pixel_ray(px, py, rng);
+            scene.stats().count(Stat::RaysPrimary);
+            match scene.intersects_between(&ray, self.near, self.far) {
+                Missed => return (shader.shade_ray(scene, &ray, Missed, self.depth, rng), 1),
+                _ => ()
+            }
+        }
 
-    fn calculate_fattj(light: &Light, point: Vec3) -> f32 {
-        match light {
-            &Light::Directional(_) => 1.0,
-            _ => {
-                let distance = point.distance(light.position());
-                (1.0 as f32).min(1.0 / (0.25 + 0.1 * distance + 0.01 * distance * distance))
+        let mut samples = Vec::with_capacity(n * n);
+        for i in 0 .. n {
+            for j in 0 .. n {
+                // The pixel reaches up the image from the point its first ray goes through
+                let ray = self.pixel_ray(px + j as f32 / n as f32, py - i as f32 / n as f32, rng);
+                samples.push(shader.trace_primary_ray(scene, &ray, (self.near, self.far), rng));
             }
         }
+        (self.sample_combiner.combine(&samples[..]), samples.len())
     }
 
-    fn diffuse_lightning(kt: f32, cd: Color, normal: Vec3, dj: Vec3) -> Color {
-        let a: f32 = 1.0 - kt;
-        let b: f32 = (0.0 as f32).max(normal.dot(dj));
-        cd.mult(a * b)
+    // The color of a single ray through the pixel at (x, y), counting the rows from the top
+    pub fn trace_pixel(&self, x: f32, y: f32) -> Color {
+        match self.scene {
+            Some(ref scene) => {
+                let mut rng = thread_rng();
+                let ray = self.pixel_ray(x, y, &mut rng);
+                self.shader().trace_primary_ray(scene, &ray, (self.near, self.far), &mut rng)
+                    .to_color()
+            },
+            None => panic!("RayTracer has not been assigned any Scene")
+        }
     }
 
-    fn specular_lightning(q: f32, ks: Color, normal: Vec3, dj: Vec3, v: Vec3) -> Color {
-        let mut t: f32 = normal.dot(dj);
-        let mut rj: Vec3 = normal.mult(t).mult(2.0);
-        rj = rj - dj;
-        t = rj.dot(v).max(0.0);
-        ks.mult(t.powf(q))
+    // Traces every `step`th pixel in both directions, giving a cheap low resolution version of
+    // the image without any exposure applied
+    pub fn preview(&self, step: u32) -> Vec<Color> {
+        let step = cmp::max(step, 1);
+        let mut colors = Vec::new();
+        let mut y = 0;
+        while y < self.height {
+            let mut x = 0;
+            while x < self.width {
+                colors.push(self.trace_pixel(x as f32, y as f32));
+                x += step;
+            }
+            y += step;
+        }
+        colors
     }
 
-    fn direct_lightning(light: &Light, intersection: &Intersection , sj: Color,
-                        fattj: f32, n: usize) -> Color {
-        let point: Vec3 = intersection.point();
-        let material = intersection.material();
-        let kt: f32 = material.transparency;
-        let cd: Color = intersection.color();
-        let ks: Color = material.specular;
-        let q: f32 = material.shininess * 128.0;
-
-        let direct_light: Color = (light.intensity() * sj).mult(fattj);
+    // Sets the exposure so that the log-average luminance of a preview maps to middle gray
+    pub fn auto_exposure(&mut self) {
+        let step = cmp::max(self.width, self.height) / PREVIEW_SIZE;
+        let colors = self.preview(step);
+        if colors.len() == 0 {
+            return;
+        }
 
-        let mut lightning = Color::new();
-        for _ in 0 .. n {
-            let n = n as f32;
+        let mut sum: f32 = 0.0;
+        for color in colors.iter() {
+            sum += (LUMINANCE_DELTA + color.luminance()).ln();
+        }
+        let average = (sum / colors.len() as f32).exp();
+        self.set_exposure(MIDDLE_GRAY / average);
+    }
 
-            let dir = light.get_dir(point);
-            let normal: Vec3 = intersection.surface_normal();
-            let diffuse_light: Color = RayTracer::diffuse_lightning(kt, cd, normal, dir);
+    // Renders the image at `factor` times the resolution in each direction, and combines each
+    // `factor` by `factor` block of the large image down to one pixel with the sample combiner
+    pub fn render_supersampled(&self, factor: u32) -> Image {
+        let scene = match self.scene {
+            Some(ref scene) => scene,
+            None => panic!("RayTracer has not been assigned any Scene")
+        };
+        let factor = cmp::max(factor, 1);
+        let f = factor as f32;
+        let large_height = self.height * factor;
+        let shader = self.shader();
+        let mut rng = self.image_rng();
 
-            let v: Vec3 = intersection.direction().invert();
-            let specular_light: Color = RayTracer::specular_lightning(q, ks, normal, dir, v);
+        let mut img = Image::new(self.width, self.height);
+        for y in 0 .. self.height {
+            for x in 0 .. self.width {
+                let mut samples = Vec::with_capacity((factor * factor) as usize);
+                for i in 0 .. factor {
+                    for j in 0 .. factor {
+                        let (lx, ly) = (x * factor + j, y * factor + i);
+                        let ray = self.compute_ray_with(lx as f32 / f,
+                            (large_height - ly - 1) as f32 / f, &mut rng);
+                        samples.push(shader.trace_primary_ray(scene, &ray,
+                            (self.near, self.far), &mut rng));
+                    }
+                }
+                img.set_pixel(x, y, self.to_pixel(self.sample_combiner.combine(&samples[..])));
+            }
+        }
+        img
+    }
 
-            let mut sample = direct_light * (diffuse_light + specular_light);
-            sample = Color::init(sample.r_val() / n, sample.g_val() / n, sample.b_val() / n);
-            lightning = lightning + sample;
+    // Renders only the ambient occlusion as a grayscale image, which is useful for compositing
+    // contact shadows onto photos. Pixels that miss the scene are white.
+    pub fn trace_ao(&self, num_samples: usize, max_dist: f32) -> Image {
+        match self.scene {
+            Some(ref scene) => {
+                let mut img = Image::new(self.width, self.height);
+                let mut rng = self.image_rng();
+                for y in 0 .. self.height {
+                    for x in 0 .. self.width {
+                        let ray = self.pixel_ray(x as f32, y as f32, &mut rng);
+                        let ao = match scene.intersects_between(&ray, self.near, self.far) {
+                            Intersected(intersection) => Shader::ambient_occlusion(scene,
+                                &intersection, num_samples, max_dist, &mut rng),
+                            Missed => 1.0
+                        };
+                        img.set_pixel(x, y, Color::init(ao, ao, ao).as_pixel());
+                    }
+                }
+                img
+            },
+            None => panic!("RayTracer has not been assigned any Scene")
         }
+    }
 
-        lightning
+    // A generator for the samples of the tile at (tile_x, tile_y), which doesn't depend on the
+    // order the tiles are traced in
+    fn tile_rng(tile_x: u32, tile_y: u32, seed: u32) -> XorShiftRng {
+        // Spreads nearby numbers apart, as xorshift generators with similar seeds start out alike
+        let scramble = |v: u32, k: u64| ((v as u64 + 1) * k) as u32;
+        SeedableRng::from_seed([scramble(tile_x, 0x9e3779b1) ^ SEED[0],
+            scramble(tile_y, 0x85ebca6b) ^ SEED[1], scramble(seed, 0xc2b2ae35) ^ SEED[2],
+            SEED[0] ^ SEED[1]])
     }
 
-    fn shade_intersection<'b>(scene: &'a Box<IntersectableScene<'a> + 'a>,
-                              intersection: &Intersection, num_samples: usize, depth: usize) -> Color {
-        if depth <= 0 {
-            return Color::new();
+    // A generator for renders that are traced in one piece instead of in tiles
+    fn image_rng(&self) -> XorShiftRng {
+        RayTracer::tile_rng(0, 0, self.seed)
+    }
+
+    // Traces the pixels of a tile, counting the tiles row by row from the top left
+    fn trace_tile(&self, tile: u32, tiles_x: u32, seed: u32) -> Vec<(u32, u32, ColorBuf)> {
+        let (tile_x, tile_y) = (tile % tiles_x, tile / tiles_x);
+        let (x0, y0) = (tile_x * TILE_SIZE, tile_y * TILE_SIZE);
+        let mut rng = RayTracer::tile_rng(tile_x, tile_y, seed);
+
+        let mut pixels = Vec::with_capacity((TILE_SIZE * TILE_SIZE) as usize);
+        for y in y0 .. cmp::min(y0 + TILE_SIZE, self.height) {
+            for x in x0 .. cmp::min(x0 + TILE_SIZE, self.width) {
+                pixels.push((x, y, self.sample_pixel(x, y, &mut rng).0));
+            }
         }
+        pixels
+    }
 
-        let material = intersection.material();
-        let kt: f32 = material.transparency;
-        let ks: Color = material.specular;
-        let ka: Color = material.ambient;
-        let cd: Color = intersection.color();
+    // Traces the tiles of the image on `threads` threads, which take every `threads`th tile each.
+    // As every tile has its own generator, the image is the same as from `trace_rays`.
+    pub fn trace_rays_parallel(&self, threads: usize) -> Image {
+        self.trace_rays_parallel_with_progress(threads, |_, _| ())
+    }
 
-        let ambient_light: Color = RayTracer::ambient_lightning(kt, ka, cd);
+    // Like `trace_rays_parallel`, but calls `progress` on this thread with the number of finished
+    // tiles and the total number of tiles each time one of the threads is done with a tile
+    pub fn trace_rays_parallel_with_progress<F>(&self, threads: usize, mut progress: F) -> Image
+            where F: FnMut(u32, u32) {
+        if self.scene.is_none() {
+            panic!("RayTracer has not been assigned any Scene");
+        }
 
-        let mut direct_light: Color = Color::new();
-        for light in scene.get_lights().iter() {
-            let fattj = RayTracer::calculate_fattj(light, intersection.point());
-            if fattj > 0.0 {
-                let n = match light {
-                    &Light::Area(_) => num_samples,
-                    _ => 1
-                };
+        let threads = cmp::max(threads, 1) as u32;
+        let tiles_x = (self.width + TILE_SIZE - 1) / TILE_SIZE;
+        let num_tiles = tiles_x * ((self.height + TILE_SIZE - 1) / TILE_SIZE);
+        let (tx, rx) = channel();
+        let guards: Vec<_> = (0 .. threads).map(|i| {
+            let tx = tx.clone();
+            thread::scoped(move || {
+                let mut tile = i;
+                while tile < num_tiles {
+                    tx.send(self.trace_tile(tile, tiles_x, self.seed)).unwrap();
+                    tile += threads;
+                }
+            })
+        }).collect();
+        // Only the threads hold senders now, so a thread that panics doesn't leave this one waiting
+        drop(tx);
 
-                let shadow_scalar = RayTracer::shadow_scalar(scene, light, intersection, n, depth);
-                direct_light = direct_light + RayTracer::direct_lightning(light, intersection,
-                    shadow_scalar, fattj, n);
+        let mut colors: Vec<ColorBuf> = (0 .. self.width * self.height).map(|_| ColorBuf::new())
+            .collect();
+        for done in 0 .. num_tiles {
+            for (x, y, color) in rx.recv().unwrap().into_iter() {
+                colors[(y * self.width + x) as usize] = color;
             }
+            progress(done + 1, num_tiles);
         }
+        drop(guards);
+        self.expose(colors)
+    }
 
-        let reflective_light = if ks.scalar() > 0.0 {
-            let ray: Ray = intersection.reflective_ray();
-            match scene.intersects(&ray) {
-                Intersected(intersection) =>
-                    ks * RayTracer::shade_intersection(scene, &intersection, num_samples, depth - 1),
-                Missed => Color::new()
-            }
-        } else {
-            Color::new()
+    // Applies the exposure, tone mapping and gamma to the colors in place. They are clamped into
+    // range afterwards, by `to_color`.
+    fn tone_map(&self, colors: &mut [ColorBuf]) {
+        material::apply_exposure(colors, self.exposure);
+        match self.tone_mapping {
+            ToneMapping::Clamp => (),
+            ToneMapping::Reinhard => material::tonemap_reinhard(colors)
+        }
+        material::apply_gamma(colors, self.gamma);
+    }
+
+    // Applies the exposure, tone mapping and gamma to a color, and quantizes it
+    fn to_pixel(&self, color: ColorBuf) -> Pixel {
+        let mut colors = [color];
+        self.tone_map(&mut colors[..]);
+        colors[0].to_color().as_pixel()
+    }
+
+    // Applies the exposure, tone mapping and gamma to the colors of every pixel, and stores them
+    // in an image
+    fn expose(&self, colors: Vec<ColorBuf>) -> Image {
+        self.expose_sized(colors, self.width, self.height)
+    }
+
+    // Like `expose`, for the colors of an image of the given size rather than the full frame
+    fn expose_sized(&self, mut colors: Vec<ColorBuf>, width: u32, height: u32) -> Image {
+        let bad = match self.nan_debug {
+            true => RayTracer::quarantine_nans(&mut colors[..], width),
+            false => Vec::new()
         };
+        self.tone_map(&mut colors[..]);
+        let colors: Vec<Color> = colors.iter().map(|c| c.to_color()).collect();
+        let mut img = Image::new(width, height);
+        match self.error_diffusion {
+            true => {
+                let pixels = RayTracer::diffuse_errors(&colors[..], width, height);
+                for (i, pixel) in pixels.into_iter().enumerate() {
+                    img.set_pixel(i as u32 % width, i as u32 / width, pixel);
+                }
+            },
+            false => for (i, color) in colors.iter().enumerate() {
+                img.set_pixel(i as u32 % width, i as u32 / width, color.as_pixel());
+            }
+        }
+        for &i in bad.iter() {
+            img.set_pixel(i as u32 % width, i as u32 / width, NAN_PIXEL);
+        }
+        img
+    }
 
-        let refractive_light = if kt > 0.0 {
-            match intersection.refractive_ray() {
-                Some(ray) => match scene.intersects(&ray) {
-                    Intersected(intersection) => RayTracer::shade_intersection(scene, &intersection,
-                        num_samples, depth - 1).mult(kt),
-                    Missed => Color::new()
-                },
-                None => Color::new()
+    // Replaces every color that is NaN or infinite with black, so it doesn't spread to its
+    // neighbours through error diffusion, and writes its position to stderr. Returns the indices
+    // of the replaced colors, which are painted magenta once the image is exposed.
+    fn quarantine_nans(colors: &mut [ColorBuf], width: u32) -> Vec<usize> {
+        let mut bad = Vec::new();
+        for (i, color) in colors.iter_mut().enumerate() {
+            if !color.is_finite() {
+                let (x, y) = (i as u32 % width, i as u32 / width);
+                let _ = writeln!(&mut io::stderr(), "Bad pixel at ({}, {}): {:?}", x, y, *color);
+                *color = ColorBuf::new();
+                bad.push(i);
             }
-        } else {
-            Color::new()
+        }
+        bad
+    }
+
+    // The colors of every pixel before exposure, traced one tile at a time on this thread
+    fn trace_tiles(&self, seed: u32) -> Vec<ColorBuf> {
+        let tiles_x = (self.width + TILE_SIZE - 1) / TILE_SIZE;
+        let num_tiles = tiles_x * ((self.height + TILE_SIZE - 1) / TILE_SIZE);
+        let mut colors: Vec<ColorBuf> = (0 .. self.width * self.height).map(|_| ColorBuf::new())
+            .collect();
+        for tile in 0 .. num_tiles {
+            for (x, y, color) in self.trace_tile(tile, tiles_x, seed).into_iter() {
+                colors[(y * self.width + x) as usize] = color;
+            }
+        }
+        colors
+    }
+
+    // Traces the pixels from (x0, y0) up to, but not including, (x1, y1), where y goes from the top
+    // of the image down like in `trace_rays`. The tiles the region overlaps are traced whole, so
+    // its pixels are the same as those of a full render with the same seed.
+    pub fn trace_region(&self, x0: u32, y0: u32, x1: u32, y1: u32) -> Image {
+        if self.scene.is_none() {
+            panic!("RayTracer has not been assigned any Scene");
+        }
+        if x0 >= x1 || y0 >= y1 || x1 > self.width || y1 > self.height {
+            panic!("Region from ({}, {}) to ({}, {}) is not inside the {}x{} image", x0, y0, x1,
+                y1, self.width, self.height);
+        }
+
+        let (width, height) = (x1 - x0, y1 - y0);
+        let tiles_x = (self.width + TILE_SIZE - 1) / TILE_SIZE;
+        let mut colors: Vec<ColorBuf> = (0 .. width * height).map(|_| ColorBuf::new()).collect();
+        for tile_y in y0 / TILE_SIZE .. (y1 - 1) / TILE_SIZE + 1 {
+            for tile_x in x0 / TILE_SIZE .. (x1 - 1) / TILE_SIZE + 1 {
+                let tile = tile_y * tiles_x + tile_x;
+                for (x, y, color) in self.trace_tile(tile, tiles_x, self.seed).into_iter() {
+                    if x >= x0 && x < x1 && y >= y0 && y < y1 {
+                        colors[((y - y0) * width + x - x0) as usize] = color;
+                    }
+                }
+            }
+        }
+        self.expose_sized(colors, width, height)
+    }
+
+    // Renders the image on a single thread with `seed` in place of the seed of the RayTracer,
+    // giving the same image every time, which can be kept as a reference to compare renders to.
+    // Shadow samples split between threads don't change the image, see `sample_shadows`.
+    pub fn render_golden(&self, seed: u64) -> Image {
+        if self.scene.is_none() {
+            panic!("RayTracer has not been assigned any Scene");
+        }
+        let seed = (seed ^ (seed >> 32)) as u32;
+        self.expose(self.trace_tiles(seed))
+    }
+
+    // A hash of every setting that changes the colors of the pixels before exposure
+    fn cache_key(&self) -> u64 {
+        // The mantissa, exponent and sign of each float, which can be hashed
+        let bits = |v: f32| v.integer_decode();
+        let mut floats = vec!(self.vertical_fov, self.horizontal_fov, self.camera_aspect,
+            self.focal_dist, self.near, self.far, self.aperture, self.ao_distance);
+        match self.background {
+            Some(Background::Solid(c)) => floats.push_all(&[c.r_val(), c.g_val(), c.b_val()]),
+            Some(Background::Gradient { top, bottom }) => floats.push_all(&[top.r_val(),
+                top.g_val(), top.b_val(), bottom.r_val(), bottom.g_val(), bottom.b_val()]),
+            None => ()
+        }
+        for v in [self.camera_pos, self.center, self.parallel_up, self.parallel_right].iter() {
+            floats.push_all(&[v.x, v.y, v.z]);
+        }
+        let floats: Vec<(u64, i16, i8)> = floats.into_iter().map(bits).collect();
+        hash::<_, SipHasher>(&(self.width, self.height, self.num_samples, self.depth,
+            self.aa_samples, self.background_aa, self.aspect_fit, self.sample_combiner as usize,
+            self.ao_samples, self.seed, floats))
+    }
+
+    // Like `trace_rays`, but keeps the colors of the frame before exposure. As long as nothing
+    // but the exposure changes, the next frame is made from those colors without tracing any rays.
+    pub fn trace_rays_cached(&self) -> Image {
+        if self.scene.is_none() {
+            panic!("RayTracer has not been assigned any Scene");
+        }
+
+        let key = self.cache_key();
+        let mut cache = self.cache.lock().unwrap();
+        let colors = match *cache {
+            Some((cached_key, ref colors)) if cached_key == key => colors.clone(),
+            _ => self.trace_tiles(self.seed)
         };
+        *cache = Some((key, colors.clone()));
+        self.expose(colors)
+    }
 
-        direct_light + ambient_light + reflective_light + refractive_light
+    pub fn trace_rays(&self) -> Image {
+        self.trace_rays_with_progress(|_, _| ())
     }
 
-    pub fn trace_rays(&'a self) -> Image {
+    // Like `trace_rays`, but also returns the number of rays traced and of the bounding box and
+    // primitive tests done for them
+    pub fn trace_rays_with_stats(&self) -> (Image, RenderStats) {
+        let stats = match self.scene {
+            Some(ref scene) => scene.stats(),
+            None => panic!("RayTracer has not been assigned any Scene")
+        };
+        stats.reset();
+        stats.set_enabled(true);
+        let img = self.trace_rays();
+        stats.set_enabled(false);
+        (img, stats.snapshot())
+    }
+
+    // Traces the image one tile at a time, and calls `progress` with the number of finished
+    // tiles and the total number of tiles each time a tile is done
+    pub fn trace_rays_with_progress<F>(&self, mut progress: F) -> Image where F: FnMut(u32, u32) {
         match self.scene {
-            Some(ref scene) => {
+            Some(_) => {
                 let mut img = Image::new(self.width as u32, self.height as u32);
+                // Error diffusion and the NaN pass need every pixel before any of them is written
+                let buffered = self.error_diffusion || self.nan_debug;
+                let mut colors: Vec<ColorBuf> = match buffered {
+                    true => (0 .. self.width * self.height).map(|_| ColorBuf::new()).collect(),
+                    false => Vec::new()
+                };
+
+                let tiles_x = (self.width + TILE_SIZE - 1) / TILE_SIZE;
+                let tiles_y = (self.height + TILE_SIZE - 1) / TILE_SIZE;
+                let num_tiles = tiles_x * tiles_y;
 
-                for (x, y) in img.coordinates() {
-                    let ray = self.compute_ray(x as f32, (self.height - y - 1) as f32);
-                    match scene.intersects(&ray) {
-                        Intersected(intersection) => {
-                            let color = RayTracer::shade_intersection(scene, &intersection,
-                                self.num_samples, self.depth);
-                            img.set_pixel(x as u32, y as u32, color.as_pixel());
-                        },
-                        Missed => ()
+                for tile in 0 .. num_tiles {
+                    for (x, y, color) in self.trace_tile(tile, tiles_x, self.seed).into_iter() {
+                        match buffered {
+                            true => colors[(y * self.width + x) as usize] = color,
+                            false => img.set_pixel(x, y, self.to_pixel(color))
+                        }
                     }
+                    progress(tile + 1, num_tiles);
+                }
+
+                match buffered {
+                    true => self.expose(colors),
+                    false => img
                 }
-                img
             },
             None => panic!("RayTracer has not been assigned any Scene")
         }
@@ -279,24 +1275,90 @@ impl<'a> RayTracer<'a> {
 
 #[cfg(test)]
 mod tests {
+    extern crate test;
+    use self::test::{Bencher, black_box};
+
     use std::f32::consts;
     use std::num::Float;
-    use RayTracer;
+    use rand::{thread_rng, Rng, SeedableRng, XorShiftRng};
+    use bmp::Pixel;
+
+    use {RayTracer, SampleCombiner, Shader, ToneMapping};
     use vec::Vec3;
-    use scene::{Scene, Camera};
+    use mat::Mat4;
+    use ray::Ray;
+    use scene::{IntersectableScene, Scene, BvhScene, Light, PointLight, AreaLight,
+        DirectionalLight, create_sphere_field, create_test_scene};
+    use scene::SceneIntersection::{Intersected, Missed};
+    use scene::shapes::{sphere, Primitive};
+    use scene::shapes::poly::Poly;
+    use scene::shapes::plane::Plane;
+    use scene::material::{Color, ColorBuf, Material};
 
     fn get_raytraer<'a>() -> RayTracer<'a> {
-        let mut scene = Box::new(Scene::new());
-        scene.camera = Camera::new();
-        scene.camera.view_dir = Vec3::init(0.0, 0.0, -1.0);
-        scene.camera.ortho_up = Vec3::init(0.0, 1.0, 0.0);
-        let pi: f32 = consts::PI;
-        scene.camera.vertical_fov = pi / 2.0;
-        let mut rt = RayTracer::init(2, 2, 2, 1);
+        get_raytracer_of_size(2, 2)
+    }
+
+    fn get_raytracer_of_size<'a>(width: u32, height: u32) -> RayTracer<'a> {
+        get_tracer_for(Box::new(create_test_scene()), width, height)
+    }
+
+    // A tracer of the given size that follows rays two bounces deep and takes one light sample
+    fn get_tracer_for<'a>(scene: Box<Scene>, width: u32, height: u32) -> RayTracer<'a> {
+        let mut rt = RayTracer::init(width, height, 2, 1);
         rt.set_scene(scene);
         rt
     }
 
+    fn get_lit_sphere_raytracer<'a>() -> RayTracer<'a> {
+        get_lit_sphere_raytracer_with_far(Float::infinity())
+    }
+
+    fn get_lit_sphere_raytracer_with_far<'a>(far: f32) -> RayTracer<'a> {
+        get_tracer_for(get_lit_sphere_scene(far), 2, 2)
+    }
+
+    fn get_lit_sphere_scene(far: f32) -> Box<Scene> {
+        let mut scene = Box::new(create_test_scene());
+        scene.camera.far = far;
+
+        let mut sphere = sphere::Sphere::init(Vec3::init(0.0, 0.0, -5.0), 1.0);
+        sphere.materials[0] = Material::init(Color::init(1.0, 0.0, 0.0));
+        scene.primitives.push(Primitive::Sphere(sphere));
+        scene.lights.push(Light::Point(PointLight {
+            pos: Vec3::new(),
+            intensity: ColorBuf::init(1.0, 1.0, 1.0),
+            enabled: true
+        }));
+        scene
+    }
+
+    // A white wall filling the view, lit by a light at the camera
+    fn get_lit_wall_raytracer<'a>(intensity: f32) -> RayTracer<'a> {
+        let mut scene = Box::new(create_test_scene());
+
+        let corners = [
+            Vec3::init(-10.0, -10.0, -1.0),
+            Vec3::init(10.0, -10.0, -1.0),
+            Vec3::init(10.0, 10.0, -1.0),
+            Vec3::init(-10.0, 10.0, -1.0)
+        ];
+        for &(a, b, c) in [(0, 1, 2), (0, 2, 3)].iter() {
+            let mut poly = Poly::init();
+            poly.materials[0] = Material::init(Color::init(1.0, 1.0, 1.0));
+            poly.vertices[0].position = corners[a];
+            poly.vertices[1].position = corners[b];
+            poly.vertices[2].position = corners[c];
+            scene.primitives.push(Primitive::Poly(poly));
+        }
+        scene.lights.push(Light::Point(PointLight {
+            pos: Vec3::new(),
+            intensity: ColorBuf::init(intensity, intensity, intensity),
+            enabled: true
+        }));
+        get_tracer_for(scene, 4, 4)
+    }
+
     fn assert_approx_eq(a: f32, b: f32) {
         assert!((a - b).abs() < 1.0e-6, "{} is not approximately equal to {}", a, b);
     }
@@ -318,8 +1380,1019 @@ mod tests {
         assert_approx_eq(0.0, r.ori[1]);
         assert_approx_eq(0.0, r.ori[2]);
 
-        assert_approx_eq(-0.57735, r.dir[0]);
-        assert_approx_eq(-0.57735, r.dir[1]);
-        assert_approx_eq(-0.57735, r.dir[2]);
+        assert_approx_eq(-0.57735, r.dir()[0]);
+        assert_approx_eq(-0.57735, r.dir()[1]);
+        assert_approx_eq(-0.57735, r.dir()[2]);
+    }
+
+    #[test]
+    fn rays_without_aperture_are_not_focused() {
+        let pinhole = get_raytraer();
+        let mut scene = Box::new(create_test_scene());
+        scene.camera.focal_dist = 5.0;
+        let mut rt = get_tracer_for(scene, 2, 2);
+        rt.set_aperture(0.0);
+
+        for &(x, y) in [(0.0, 0.0), (0.5, 1.5), (1.0, 1.0)].iter() {
+            let (a, b) = (pinhole.compute_ray(x, y), rt.compute_ray(x, y));
+            assert_eq!(a.ori, b.ori);
+            assert_eq!(a.dir(), b.dir());
+        }
+
+        // With an aperture the rays start off the camera, but still meet at the focal point
+        rt.set_aperture(0.5);
+        let focus = pinhole.compute_ray(0.5, 1.5).at(5.0);
+        for _ in 0 .. 10 {
+            let ray = rt.compute_ray(0.5, 1.5);
+            assert!(ray.ori.length() <= 0.5);
+            let t = focus.distance(ray.ori);
+            assert!(ray.at(t).distance(focus) < 1.0e-4);
+        }
+    }
+
+    // Dim samples from a seeded generator, where the first one is a firefly
+    fn samples_with_firefly() -> Vec<ColorBuf> {
+        let mut rng: XorShiftRng = SeedableRng::from_seed([1, 2, 3, 4]);
+        let mut samples: Vec<ColorBuf> = (0 .. 9).map(|_| {
+            let v = 0.1 + 0.1 * rng.gen::<f32>();
+            ColorBuf::init(v, v, v)
+        }).collect();
+        samples[0] = ColorBuf::init(1.0, 1.0, 1.0);
+        samples
+    }
+
+    #[test]
+    fn trimmed_mean_rejects_fireflies() {
+        let samples = samples_with_firefly();
+        let mean = SampleCombiner::Mean.combine(&samples[..]);
+        let trimmed = SampleCombiner::TrimmedMean.combine(&samples[..]);
+        let median = SampleCombiner::Median.combine(&samples[..]);
+        assert!(trimmed.luminance() < mean.luminance());
+        assert!(trimmed.luminance() < 0.2 && median.luminance() < 0.2);
+        assert!(mean.luminance() - trimmed.luminance() > 0.05);
+    }
+
+    #[test]
+    fn combiners_agree_on_uniform_samples() {
+        let gray = ColorBuf::init(0.25, 0.5, 0.75);
+        let samples = vec!(gray, gray, gray, gray);
+        for combiner in [SampleCombiner::Mean, SampleCombiner::TrimmedMean,
+                SampleCombiner::Median].iter() {
+            assert_eq!(gray, combiner.combine(&samples[..]));
+        }
+    }
+
+    #[test]
+    fn supersampled_render_averages_blocks_of_the_large_render() {
+        let rt = get_lit_sphere_raytracer();
+        let mut large = get_lit_sphere_raytracer();
+        large.width = 4;
+        large.height = 4;
+        large.setup_camera();
+
+        let img = rt.render_supersampled(2);
+        for y in 0 .. 2 {
+            for x in 0 .. 2 {
+                let mut sum = ColorBuf::new();
+                for i in 0 .. 2 {
+                    for j in 0 .. 2 {
+                        let (color, _) = large.sample_pixel(x * 2 + j, y * 2 + i,
+                            &mut thread_rng());
+                        sum.add_assign(color);
+                    }
+                }
+                let expected = sum.mult(0.25).to_color().as_pixel();
+                assert_eq!(expected, img.get_pixel(x, y));
+            }
+        }
+        // Only one of the large pixels hits the sphere, and it is in the top right block
+        assert!(img.get_pixel(1, 0).r > 0 && img.get_pixel(1, 0).r < 255);
+    }
+
+    #[test]
+    fn supersampled_render_uses_the_sample_combiner() {
+        let mut rt = get_lit_sphere_raytracer();
+        rt.set_sample_combiner(SampleCombiner::Median);
+        // The one large pixel that hits the sphere is an outlier in its block
+        let img = rt.render_supersampled(2);
+        assert_eq!(Pixel { r: 0, g: 0, b: 0 }, img.get_pixel(1, 0));
+    }
+
+    #[test]
+    fn parallel_render_matches_serial_render() {
+        let mut rt = get_lit_sphere_raytracer();
+        rt.width = 7;
+        rt.height = 5;
+        rt.setup_camera();
+        let serial = rt.trace_rays();
+        for threads in [1, 2, 3, 8].iter() {
+            assert!(serial == rt.trace_rays_parallel(*threads));
+        }
+
+        rt.set_error_diffusion(true);
+        assert!(rt.trace_rays() == rt.trace_rays_parallel(2));
+    }
+
+    #[test]
+    fn rays_that_miss_the_scene_get_the_background_color() {
+        let mut rt = get_lit_sphere_raytracer();
+        rt.width = 8;
+        rt.height = 8;
+        rt.setup_camera();
+        let sky = Color::init(0.2, 0.4, 0.6);
+        rt.set_background(sky);
+
+        let img = rt.trace_rays();
+        for &(x, y) in [(0, 0), (7, 0), (0, 7), (7, 7)].iter() {
+            assert_eq!(sky.as_pixel(), img.get_pixel(x, y));
+        }
+        assert!(img.get_pixel(4, 4) != sky.as_pixel());
+    }
+
+    #[test]
+    fn scenes_without_primitives_are_all_background() {
+        let mut scene = Scene::new();
+        scene.lights.push(Light::Point(PointLight {
+            pos: Vec3::new(),
+            intensity: ColorBuf::init(1.0, 1.0, 1.0),
+            enabled: true
+        }));
+        let sky = Color::init(0.2, 0.4, 0.6);
+        let mut rt = RayTracer::init(8, 8, 2, 1);
+        rt.set_scene(Box::new(BvhScene::from_scene(scene)));
+        rt.set_background(sky);
+
+        let img = rt.trace_rays();
+        for y in 0 .. 8 {
+            for x in 0 .. 8 {
+                assert_eq!(sky.as_pixel(), img.get_pixel(x, y));
+            }
+        }
+    }
+
+    // A lit sphere across 3 by 2 tiles, traced with jittered samples
+    fn get_tiled_sphere_raytracer<'a>() -> RayTracer<'a> {
+        let mut rt = get_lit_sphere_raytracer();
+        rt.width = 40;
+        rt.height = 24;
+        rt.setup_camera();
+        rt.set_aa_samples(2);
+        rt.set_aperture(0.1);
+        rt.set_seed(3);
+        rt
+    }
+
+    #[test]
+    fn region_matches_the_same_pixels_of_a_full_render() {
+        let full = get_tiled_sphere_raytracer().trace_rays();
+        let region = get_tiled_sphere_raytracer().trace_region(10, 5, 30, 20);
+        assert_eq!(20, region.get_width());
+        assert_eq!(15, region.get_height());
+        for y in 0 .. 15 {
+            for x in 0 .. 20 {
+                assert_eq!(full.get_pixel(x + 10, y + 5), region.get_pixel(x, y));
+            }
+        }
+    }
+
+    #[test]
+    #[should_fail(expected = "is not inside the")]
+    fn region_outside_the_image_panics() {
+        get_tiled_sphere_raytracer().trace_region(10, 5, 50, 20);
+    }
+
+    #[test]
+    fn nan_debug_paints_bad_pixels_magenta() {
+        let mut rt = get_lit_sphere_raytracer();
+        rt.set_nan_debug(true);
+        let (nan, inf): (f32, f32) = (Float::nan(), Float::infinity());
+        let mut colors = vec!(ColorBuf::init(0.5, 0.5, 0.5), ColorBuf::init(nan, 0.0, 0.0),
+            ColorBuf::init(0.0, inf, 0.0), ColorBuf::new());
+
+        let magenta = Pixel { r: 255, g: 0, b: 255 };
+        // Neither the exposure, the tone mapping nor error diffusion change the color
+        rt.set_tone_mapping(ToneMapping::Reinhard);
+        rt.set_exposure(0.25);
+        for &diffuse in [false, true].iter() {
+            rt.set_error_diffusion(diffuse);
+            let img = rt.expose(colors.clone());
+            assert_eq!(magenta, img.get_pixel(1, 0));
+            assert_eq!(magenta, img.get_pixel(0, 1));
+            assert_eq!(Pixel { r: 0, g: 0, b: 0 }, img.get_pixel(1, 1));
+        }
+
+        assert_eq!(vec!(1, 2), RayTracer::quarantine_nans(&mut colors[..], 2));
+        assert!(colors.iter().all(|color| color.is_finite()));
+    }
+
+    #[test]
+    fn stats_count_one_primary_ray_per_pixel() {
+        let mut scene = Box::new(create_test_scene());
+        let sphere = sphere::Sphere::init(Vec3::init(0.0, 0.0, -5.0), 1.0);
+        scene.primitives.push(Primitive::Sphere(sphere));
+
+        let mut rt = get_tracer_for(scene, 5, 3);
+        let (_, stats) = rt.trace_rays_with_stats();
+        assert_eq!(5 * 3, stats.rays_primary);
+        assert_eq!(0, stats.rays_shadow);
+        assert_eq!(5 * 3, stats.prim_tests);
+
+        // Counting starts over for every render
+        let (_, again) = rt.trace_rays_with_stats();
+        assert_eq!(stats, again);
+
+        // Renders without stats count nothing
+        rt.trace_rays();
+        assert_eq!(stats, rt.scene.as_ref().unwrap().stats().snapshot());
+    }
+
+    #[test]
+    fn changing_the_scene_traces_a_new_frame() {
+        let lit = get_lit_sphere_raytracer();
+        let lit_colors = lit.trace_tiles(lit.seed);
+        let mut rt = get_lit_sphere_raytracer();
+        let key = rt.cache_key();
+        *rt.cache.lock().unwrap() = Some((key, lit_colors));
+
+        let mut scene = get_lit_sphere_scene(Float::infinity());
+        scene.lights[0].set_enabled(false);
+        rt.set_scene(scene);
+
+        let mut scene = get_lit_sphere_scene(Float::infinity());
+        scene.lights[0].set_enabled(false);
+        let unlit = get_tracer_for(scene, 2, 2);
+
+        let img = rt.trace_rays_cached();
+        assert!(img == unlit.trace_rays());
+        assert!(img != lit.trace_rays());
+    }
+
+    #[test]
+    fn changing_only_the_exposure_reuses_the_cached_frame() {
+        let mut rt = get_lit_sphere_raytracer();
+        assert!(rt.trace_rays_cached() == rt.trace_rays());
+
+        // Marks a cached color, which a traced frame would not have
+        let marked = ColorBuf::init(0.5, 0.5, 0.5);
+        match *rt.cache.lock().unwrap() {
+            Some((_, ref mut colors)) => colors[0] = marked,
+            None => panic!("The frame should have been cached")
+        }
+        rt.set_exposure(0.5);
+        let img = rt.trace_rays_cached();
+
+        let mut fresh = get_lit_sphere_raytracer();
+        fresh.set_exposure(0.5);
+        let expected = fresh.trace_rays();
+        assert_eq!(marked.mult(0.5).to_color().as_pixel(), img.get_pixel(0, 0));
+        for &(x, y) in [(1, 0), (0, 1), (1, 1)].iter() {
+            assert_eq!(expected.get_pixel(x, y), img.get_pixel(x, y));
+        }
+    }
+
+    // A sphere lit by an area light, seen through a lens, so that every pixel is sampled randomly
+    fn get_sampled_sphere_raytracer<'a>(seed: u32) -> RayTracer<'a> {
+        let mut scene = Box::new(create_test_scene());
+        scene.camera.vertical_fov = consts::PI / 4.0;
+        scene.camera.focal_dist = 5.0;
+
+        let mut sphere = sphere::Sphere::init(Vec3::init(0.0, 0.0, -5.0), 1.0);
+        sphere.materials[0] = Material::init(Color::init(1.0, 0.0, 0.0));
+        scene.primitives.push(Primitive::Sphere(sphere));
+        let mut light = AreaLight::new();
+        light.min = Vec3::init(-2.0, 1.0, -3.0);
+        light.max = Vec3::init(2.0, 2.0, -2.0);
+        light.intensity = ColorBuf::init(1.0, 1.0, 1.0);
+        scene.lights.push(Light::Area(light));
+
+        let mut rt = RayTracer::init(32, 32, 2, 4);
+        rt.set_scene(scene);
+        rt.set_aperture(0.2);
+        rt.set_seed(seed);
+        rt
+    }
+
+    #[test]
+    fn seeded_render_is_the_same_on_any_number_of_threads() {
+        // 32 by 32 pixels are 4 tiles
+        let rt = get_sampled_sphere_raytracer(7);
+        let single = rt.trace_rays_parallel(1);
+        assert!(single == rt.trace_rays_parallel(4));
+        assert!(single == rt.trace_rays());
+        assert!(single == get_sampled_sphere_raytracer(7).trace_rays_parallel(3));
+    }
+
+    // Without an aperture, only the samples of the area light differ between seeds
+    fn get_area_lit_raytracer<'a>(seed: u32) -> RayTracer<'a> {
+        let mut rt = get_sampled_sphere_raytracer(seed);
+        rt.set_aperture(0.0);
+        rt
+    }
+
+    #[test]
+    fn area_light_samples_follow_the_seed() {
+        let rt = get_area_lit_raytracer(7);
+        let image = rt.trace_rays();
+        assert!(image == get_area_lit_raytracer(7).trace_rays());
+        assert!(image != get_area_lit_raytracer(8).trace_rays());
+    }
+
+    #[test]
+    fn golden_renders_with_the_same_seed_are_identical() {
+        let rt = get_sampled_sphere_raytracer(0);
+        let golden = rt.render_golden(7);
+        assert!(golden == rt.render_golden(7));
+        assert!(golden == get_sampled_sphere_raytracer(7).trace_rays());
+        assert!(golden != rt.render_golden(8));
+    }
+
+    #[test]
+    fn every_kind_of_render_follows_the_seed() {
+        let rt = get_sampled_sphere_raytracer(7);
+        let same = get_sampled_sphere_raytracer(7);
+        assert!(rt.trace_rays() != get_sampled_sphere_raytracer(8).trace_rays());
+        assert!(rt.render_supersampled(2) == same.render_supersampled(2));
+        assert!(rt.trace_ao(4, 1.0) == same.trace_ao(4, 1.0));
+    }
+
+    #[test]
+    fn overriding_the_camera_renders_from_another_viewpoint() {
+        let front = get_lit_sphere_raytracer();
+        let mut side = get_lit_sphere_raytracer();
+        let mut camera = *side.scene.as_ref().unwrap().get_camera();
+        camera.pos = Vec3::init(3.0, 0.0, -5.0);
+        camera.view_dir = Vec3::init(-1.0, 0.0, 0.0);
+        side.set_camera(camera);
+
+        assert_eq!(Vec3::new(), side.scene.as_ref().unwrap().get_camera().pos);
+        assert_eq!(Vec3::init(3.0, 0.0, -5.0), side.primary_ray(1.0, 1.0).ori);
+        assert!(front.trace_rays() != side.trace_rays());
+    }
+
+    #[test]
+    fn camera_transform_moves_the_viewpoint() {
+        let mut moved = get_lit_sphere_raytracer();
+        let mut transformed = get_lit_sphere_raytracer();
+        let mut camera = *moved.scene.as_ref().unwrap().get_camera();
+        let offset = Vec3::init(0.5, 0.0, 2.0);
+        camera.pos = offset;
+        moved.set_camera(camera);
+        camera.pos = Vec3::new();
+        camera.transform = Mat4::translation(offset);
+        transformed.set_camera(camera);
+
+        assert_eq!(offset, transformed.primary_ray(1.0, 1.0).ori);
+        assert!(moved.trace_rays() == transformed.trace_rays());
+    }
+
+    #[test]
+    fn primary_ray_of_center_pixel_follows_view_direction() {
+        let rt = get_lit_sphere_raytracer();
+        let ray = rt.primary_ray(1.0, 0.0);
+        assert_eq!(Vec3::new(), ray.ori);
+        assert_approx_eq(0.0, ray.dir()[0]);
+        assert_approx_eq(0.0, ray.dir()[1]);
+        assert_approx_eq(-1.0, ray.dir()[2]);
+
+        // Rows are counted from the top of the image
+        let bottom_left = rt.primary_ray(0.0, 1.0);
+        assert!(bottom_left.dir()[0] < 0.0 && bottom_left.dir()[1] < 0.0);
+    }
+
+    #[test]
+    fn can_trace_single_pixel() {
+        let rt = get_lit_sphere_raytracer();
+        let color = rt.trace_pixel(1.0, 0.0);
+
+        assert!(color.r_val() > 0.5, "{:?} should be lit by the point light", color);
+        assert_eq!(0.0, color.g_val());
+        assert_eq!(0.0, color.b_val());
+
+        let corner = rt.trace_pixel(0.0, 0.0);
+        assert_eq!(Color::new(), corner);
+    }
+
+    #[test]
+    fn sphere_beyond_far_plane_renders_as_background() {
+        let rt = get_lit_sphere_raytracer_with_far(3.0);
+        assert_eq!(Color::new(), rt.trace_pixel(1.0, 0.0));
+
+        let rt = get_lit_sphere_raytracer_with_far(10.0);
+        assert!(rt.trace_pixel(1.0, 0.0).r_val() > 0.0);
+    }
+
+    #[test]
+    fn error_diffusion_keeps_the_mean_of_a_ramp() {
+        let (width, height) = (64, 8);
+        let mut colors = Vec::new();
+        for _ in 0 .. height {
+            for x in 0 .. width {
+                let v = 0.1 * x as f32 / width as f32;
+                colors.push(Color::init(v, v, v));
+            }
+        }
+        let mean = colors.iter().fold(0.0, |sum, color| sum + color.r_val()) / colors.len() as f32;
+
+        let pixels = RayTracer::diffuse_errors(&colors[..], width, height);
+        let diffused = pixels.iter().fold(0.0, |sum, pixel| sum + pixel.r as f32 / 255.0) /
+            pixels.len() as f32;
+        let truncated = colors.iter().fold(0.0, |sum, color|
+            sum + color.as_pixel().r as f32 / 255.0) / colors.len() as f32;
+
+        assert!((diffused - mean).abs() < 0.0005, "{} is not close to {}", diffused, mean);
+        assert!((diffused - mean).abs() < (truncated - mean).abs());
+    }
+
+    #[test]
+    fn background_pixels_can_skip_anti_aliasing() {
+        let mut rt = get_lit_sphere_raytracer();
+        rt.set_aa_samples(2);
+        assert_eq!(4, rt.sample_pixel(1, 0, &mut thread_rng()).1);
+        assert_eq!(4, rt.sample_pixel(0, 1, &mut thread_rng()).1);
+
+        let mut rt = get_lit_sphere_raytracer();
+        rt.set_aa_samples(2);
+        rt.set_background_aa(false);
+        assert_eq!(4, rt.sample_pixel(1, 0, &mut thread_rng()).1);
+        let (background, num_rays) = rt.sample_pixel(0, 1, &mut thread_rng());
+        assert_eq!(1, num_rays);
+        assert_eq!(ColorBuf::new(), background);
+    }
+
+    // The number of pixels that are neither black nor white in a render of a flat white sphere
+    fn count_edge_pixels(aa_samples: usize) -> usize {
+        let mut scene = Box::new(create_test_scene());
+
+        let white = Color::init(1.0, 1.0, 1.0);
+        let mut sphere = sphere::Sphere::init(Vec3::init(0.0, 0.0, -5.0), 2.0);
+        sphere.materials[0] = Material::init(white);
+        sphere.materials[0].ambient = white;
+        scene.primitives.push(Primitive::Sphere(sphere));
+
+        let mut rt = RayTracer::init(16, 16, 1, 1);
+        rt.set_scene(scene);
+        rt.set_aa_samples(aa_samples);
+        let img = rt.trace_rays();
+
+        let mut count = 0;
+        for y in 0 .. 16 {
+            for x in 0 .. 16 {
+                let r = img.get_pixel(x, y).r;
+                if r > 0 && r < 255 {
+                    count += 1;
+                }
+            }
+        }
+        count
+    }
+
+    #[test]
+    fn anti_aliasing_blends_the_edges_of_a_silhouette() {
+        assert_eq!(0, count_edge_pixels(1));
+        assert!(count_edge_pixels(4) > 0);
+    }
+
+    #[test]
+    fn area_light_with_zero_samples_renders_finite_colors() {
+        let mut scene = Box::new(create_test_scene());
+
+        let mut sphere = sphere::Sphere::init(Vec3::init(0.0, 0.0, -5.0), 1.0);
+        sphere.materials[0] = Material::init(Color::init(1.0, 0.0, 0.0));
+        scene.primitives.push(Primitive::Sphere(sphere));
+        let mut light = AreaLight::new();
+        light.min = Vec3::init(-0.5, -0.5, 0.0);
+        light.max = Vec3::init(0.5, 0.5, 0.1);
+        light.intensity = ColorBuf::init(1.0, 1.0, 1.0);
+        scene.lights.push(Light::Area(light));
+
+        let mut rt = RayTracer::init(2, 2, 2, 0);
+        rt.set_scene(scene);
+        let color = rt.trace_pixel(1.0, 0.0);
+        assert!(!color.r_val().is_nan() && !color.g_val().is_nan() && !color.b_val().is_nan());
+        assert!(color.r_val() > 0.0, "{:?} should be lit by the area light", color);
+    }
+
+    #[test]
+    fn emissive_sphere_glows_without_lights() {
+        let mut scene = Box::new(create_test_scene());
+        let mut material = Material::new();
+        material.emissive = Color::init(0.5, 0.25, 0.0);
+        let mut sphere = sphere::Sphere::init(Vec3::init(0.0, 0.0, -5.0), 1.0);
+        sphere.materials[0] = material;
+        scene.primitives.push(Primitive::Sphere(sphere));
+
+        let rt = get_tracer_for(scene, 2, 2);
+        assert_eq!(Color::init(0.5, 0.25, 0.0), rt.trace_pixel(1.0, 0.0));
+        assert_eq!(Color::new(), rt.trace_pixel(0.0, 0.0));
+    }
+
+    #[test]
+    fn single_pixels_count_rows_from_the_top() {
+        let mut scene = Box::new(create_test_scene());
+        let mut material = Material::new();
+        material.emissive = Color::init(0.5, 0.25, 0.0);
+        // Straight below the center of the view
+        let mut sphere = sphere::Sphere::init(Vec3::init(0.0, -5.0, -5.0), 1.0);
+        sphere.materials[0] = material;
+        scene.primitives.push(Primitive::Sphere(sphere));
+
+        let rt = get_tracer_for(scene, 2, 2);
+        assert_eq!(Color::init(0.5, 0.25, 0.0), rt.trace_pixel(1.0, 1.0));
+        assert_eq!(Color::new(), rt.trace_pixel(1.0, 0.0));
+    }
+
+    #[test]
+    fn disabled_light_leaves_only_ambient_light() {
+        let mut scene = Box::new(create_test_scene());
+
+        let mut sphere = sphere::Sphere::init(Vec3::init(0.0, 0.0, -5.0), 1.0);
+        sphere.materials[0] = Material::init(Color::init(1.0, 0.0, 0.0));
+        sphere.materials[0].ambient = Color::init(0.2, 0.2, 0.2);
+        scene.primitives.push(Primitive::Sphere(sphere));
+        let mut light = PointLight::new();
+        light.intensity = ColorBuf::init(1.0, 1.0, 1.0);
+        scene.lights.push(Light::Point(light));
+        scene.set_light_enabled(0, false);
+
+        let rt = get_tracer_for(scene, 2, 2);
+        assert_eq!(Color::init(0.2, 0.0, 0.0), rt.trace_pixel(1.0, 0.0));
+    }
+
+    #[test]
+    fn reports_progress_for_each_tile() {
+        let rt = get_raytracer_of_size(40, 20);
+        let mut calls = Vec::new();
+        rt.trace_rays_with_progress(|done, total| calls.push((done, total)));
+
+        assert_eq!(6, calls.len());
+        assert_eq!((1, 6), calls[0]);
+        assert_eq!((6, 6), calls[5]);
+    }
+
+    #[test]
+    fn reports_progress_for_each_tile_on_several_threads() {
+        let rt = get_raytracer_of_size(40, 20);
+        let mut calls = Vec::new();
+        let img = rt.trace_rays_parallel_with_progress(4, |done, total| calls.push((done, total)));
+
+        assert_eq!(vec!((1, 6), (2, 6), (3, 6), (4, 6), (5, 6), (6, 6)), calls);
+        assert!(img == get_raytracer_of_size(40, 20).trace_rays());
+    }
+
+    #[test]
+    fn large_ground_plane_does_not_shadow_itself() {
+        let scale = 1000.0;
+        let corners = [
+            Vec3::init(-2.0 * scale, 0.0, -2.0 * scale),
+            Vec3::init(2.0 * scale, 0.0, -2.0 * scale),
+            Vec3::init(2.0 * scale, 0.0, 2.0 * scale),
+            Vec3::init(-2.0 * scale, 0.0, 2.0 * scale)
+        ];
+
+        let mut scene = Scene::new();
+        for &(a, b, c) in [(0, 1, 2), (0, 2, 3)].iter() {
+            let mut poly = Poly::init();
+            poly.vertices[0].position = corners[a];
+            poly.vertices[1].position = corners[b];
+            poly.vertices[2].position = corners[c];
+            scene.primitives.push(Primitive::Poly(poly));
+        }
+
+        // A light grazing the ground at a low angle
+        let mut light = DirectionalLight::new();
+        light.dir = Vec3::init(1.0, -0.05, 0.0);
+        light.dir.normalize();
+        light.intensity = ColorBuf::init(1.0, 1.0, 1.0);
+        scene.lights.push(Light::Directional(light));
+        let scene: Box<IntersectableScene + Sync> = Box::new(scene);
+
+        let eye = Vec3::init(0.0, 0.05 * scale, 0.0);
+        for i in 0 .. 10 {
+            for j in 0 .. 10 {
+                let x = (i as f32 - 4.5) * 0.15 * scale;
+                let z = (j as f32 - 4.5) * 0.15 * scale;
+                let mut dir = Vec3::init(x, 0.0, z) - eye;
+                dir.normalize();
+
+                match scene.intersects(&Ray::init(eye, dir)) {
+                    Intersected(intersection) => {
+                        let light = scene.get_lights()[0];
+                        let shade = Shader::shadow_scalar(&scene, &light, &intersection,
+                            intersection.surface_normal(), 1, 2, 1, &mut thread_rng());
+                        assert_eq!(1.0, shade.r_val());
+                    },
+                    Missed => panic!("Ray should have hit the ground")
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn auto_exposure_brightens_dim_scenes() {
+        let mut rt = get_lit_wall_raytracer(0.02);
+        assert_eq!(1.0, rt.exposure());
+        rt.auto_exposure();
+        assert!(rt.exposure() > 1.0);
+    }
+
+    #[test]
+    fn auto_exposure_darkens_bright_scenes() {
+        let mut rt = get_lit_wall_raytracer(1.0);
+        rt.auto_exposure();
+        assert!(rt.exposure() < 1.0);
+    }
+
+    #[test]
+    fn anisotropic_highlight_is_stretched_along_the_tangent() {
+        let frame = (Vec3::init(1.0, 0.0, 0.0), Vec3::init(0.0, 1.0, 0.0),
+            Vec3::init(0.0, 0.0, 1.0));
+        let (white, v) = (Color::init(1.0, 1.0, 1.0), Vec3::init(0.0, 0.0, 1.0));
+        let angle: f32 = 0.4;
+        let along_tangent = Vec3::init(angle.sin(), 0.0, angle.cos());
+        let along_bitangent = Vec3::init(0.0, angle.sin(), angle.cos());
+
+        let highlight = |anisotropy: f32, dj: Vec3|
+            Shader::anisotropic_lightning(32.0, white, anisotropy, frame, dj, v).r_val();
+        assert_eq!(highlight(0.0, along_tangent), highlight(0.0, along_bitangent));
+        assert!(highlight(0.8, along_tangent) > 2.0 * highlight(0.8, along_bitangent));
+        assert!(highlight(0.8, along_tangent) > highlight(0.0, along_tangent));
+        assert_eq!(white, Shader::anisotropic_lightning(32.0, white, 0.8, frame, v, v));
+    }
+
+    #[test]
+    fn bright_lights_are_not_clamped_before_the_pixel_is_stored() {
+        let rt = get_lit_wall_raytracer(4.0);
+        let scene = rt.scene.as_ref().unwrap();
+        let light = scene.get_lights()[0];
+
+        let ray = Ray::init(Vec3::new(), Vec3::init(0.0, 0.0, -1.0));
+        match scene.intersects(&ray) {
+            Intersected(intersection) => {
+                let normal = intersection.surface_normal();
+                let light = Shader::direct_lightning(&light, &intersection, normal,
+                    Color::init(1.0, 1.0, 1.0), 1.0, 1, &mut thread_rng());
+                assert!(light.r > 1.0 && light.g > 1.0 && light.b > 1.0);
+                assert_eq!(Color::init(1.0, 1.0, 1.0), light.to_color());
+            },
+            Missed => panic!("Ray should have hit the wall")
+        }
+    }
+
+    #[test]
+    fn shader_shades_hits_like_the_raytracer() {
+        let rt = get_lit_wall_raytracer(0.5);
+        let scene = rt.scene.as_ref().unwrap();
+        let shader = Shader::init(2, 1);
+        assert_eq!(shader, rt.shader());
+
+        let ray = rt.primary_ray(1.0, 2.0);
+        match scene.intersects(&ray) {
+            Intersected(intersection) => assert_eq!(rt.trace_pixel(1.0, 2.0),
+                shader.shade(scene, &intersection, &mut thread_rng()).to_color()),
+            Missed => panic!("Ray should have hit the wall")
+        }
+    }
+
+    #[test]
+    fn reinhard_tone_mapping_keeps_bright_pixels_apart() {
+        // Every pixel of both walls is brighter than 1.0, so clamping stores them all as white
+        let clamped_dim = get_lit_wall_raytracer(4.0).trace_rays();
+        let clamped_bright = get_lit_wall_raytracer(8.0).trace_rays();
+        assert!(clamped_dim == clamped_bright);
+        assert_eq!(Pixel { r: 255, g: 255, b: 255 }, clamped_dim.get_pixel(0, 0));
+
+        let mut dim = get_lit_wall_raytracer(4.0);
+        let mut bright = get_lit_wall_raytracer(8.0);
+        dim.set_tone_mapping(ToneMapping::Reinhard);
+        bright.set_tone_mapping(ToneMapping::Reinhard);
+        let (dim, bright) = (dim.trace_rays().get_pixel(0, 0), bright.trace_rays().get_pixel(0, 0));
+        assert!(dim.r < bright.r && bright.r < 255);
+    }
+
+    #[test]
+    fn aspect_fit_letterboxes_wide_images() {
+        let square = get_lit_wall_raytracer(1.0).trace_rays();
+        let mut wide = get_lit_wall_raytracer(1.0);
+        wide.width = 8;
+        wide.set_aspect_fit(true);
+        let wide = wide.trace_rays();
+
+        let black = Pixel { r: 0, g: 0, b: 0 };
+        for y in 0 .. 4 {
+            for &x in [0, 1, 6, 7].iter() {
+                assert_eq!(black, wide.get_pixel(x, y));
+            }
+            for x in 0 .. 4 {
+                assert!(square.get_pixel(x, y) != black);
+                assert_eq!(square.get_pixel(x, y), wide.get_pixel(x + 2, y));
+            }
+        }
+    }
+
+    fn push_quad(scene: &mut Scene, corners: [Vec3; 4]) {
+        for &(a, b, c) in [(0, 1, 2), (0, 2, 3)].iter() {
+            let mut poly = Poly::init();
+            poly.vertices[0].position = corners[a];
+            poly.vertices[1].position = corners[b];
+            poly.vertices[2].position = corners[c];
+            scene.primitives.push(Primitive::Poly(poly));
+        }
+    }
+
+    #[test]
+    fn corners_are_darker_than_open_surfaces_in_ao_pass() {
+        // A floor meeting a wall at x = 1
+        let mut scene = Scene::new();
+        push_quad(&mut scene, [
+            Vec3::init(-10.0, -1.0, -10.0), Vec3::init(1.0, -1.0, -10.0),
+            Vec3::init(1.0, -1.0, 0.0), Vec3::init(-10.0, -1.0, 0.0)
+        ]);
+        push_quad(&mut scene, [
+            Vec3::init(1.0, -1.0, -10.0), Vec3::init(1.0, 10.0, -10.0),
+            Vec3::init(1.0, 10.0, 0.0), Vec3::init(1.0, -1.0, 0.0)
+        ]);
+        let scene: Box<IntersectableScene + Sync> = Box::new(scene);
+
+        let ao_at = |target: Vec3| {
+            let mut dir = target;
+            dir.normalize();
+            match scene.intersects(&Ray::init(Vec3::new(), dir)) {
+                Intersected(intersection) =>
+                    Shader::ambient_occlusion(&scene, &intersection, 200, 1.0,
+                        &mut thread_rng()),
+                Missed => panic!("Ray should have hit the floor")
+            }
+        };
+
+        let corner = ao_at(Vec3::init(0.95, -1.0, -5.0));
+        let open = ao_at(Vec3::init(-3.0, -1.0, -5.0));
+        assert_eq!(1.0, open);
+        assert!(corner < 0.9);
+    }
+
+    #[test]
+    fn ambient_light_is_darker_between_close_spheres() {
+        // Two spheres 0.1 apart, along the x axis
+        let mut scene = Scene::new();
+        for &x in [-1.05, 1.05].iter() {
+            let mut sphere = sphere::Sphere::init(Vec3::init(x, 0.0, -5.0), 1.0);
+            sphere.materials[0].ambient = Color::init(1.0, 1.0, 1.0);
+            sphere.materials[0].diffuse = Color::init(1.0, 1.0, 1.0);
+            scene.primitives.push(Primitive::Sphere(sphere));
+        }
+        let scene: Box<IntersectableScene + Sync> = Box::new(scene);
+        let mut shader = Shader::new();
+        shader.ao_samples = 200;
+
+        // Facing the camera, and facing the other sphere from the middle of the gap
+        let open = Ray::init(Vec3::init(-1.05, 0.0, 0.0), Vec3::init(0.0, 0.0, -1.0));
+        let wedged = Ray::init(Vec3::init(0.0, 0.0, -5.0), Vec3::init(-1.0, 0.0, 0.0));
+        let shade = |ray: &Ray, shader: &Shader| match scene.intersects(ray) {
+            Intersected(intersection) => {
+                let ao = Shader::ambient_occlusion(&scene, &intersection, 200, 1.0,
+                    &mut thread_rng());
+                (ao, shader.shade(&scene, &intersection, &mut thread_rng()).r)
+            },
+            Missed => panic!("Ray should have hit a sphere")
+        };
+
+        let (open_ao, open_light) = shade(&open, &shader);
+        let (wedged_ao, wedged_light) = shade(&wedged, &shader);
+        assert_eq!(1.0, open_ao);
+        assert_eq!(1.0, open_light);
+        assert!(wedged_ao < 0.5);
+        assert!(wedged_light < 0.5);
+        assert_eq!(1.0, shade(&wedged, &Shader::new()).1);
+    }
+
+    #[test]
+    fn rough_materials_spread_their_reflections() {
+        let reflections = |roughness: f32| {
+            let mut sphere = sphere::Sphere::init(Vec3::init(0.0, 0.0, -5.0), 1.0);
+            sphere.materials[0].specular = Color::init(1.0, 1.0, 1.0);
+            sphere.materials[0].roughness = roughness;
+            let mut scene = Scene::new();
+            scene.primitives.push(Primitive::Sphere(sphere));
+            let scene: Box<IntersectableScene + Sync> = Box::new(scene);
+
+            let mut rng: XorShiftRng = SeedableRng::from_seed([1, 2, 3, 4]);
+            let ray = Ray::init(Vec3::new(), Vec3::init(0.0, 0.0, -1.0));
+            let dirs: Vec<Vec3> = match scene.intersects(&ray) {
+                Intersected(intersection) => Shader::reflective_rays(&intersection,
+                    intersection.surface_normal(), 16, &mut rng).iter().map(|ray| ray.dir())
+                    .collect(),
+                Missed => panic!("Ray should have hit the sphere")
+            };
+            dirs
+        };
+
+        let mirror = Vec3::init(0.0, 0.0, 1.0);
+        assert_eq!(vec!(mirror), reflections(0.0));
+
+        let rough = reflections(0.5);
+        assert_eq!(16, rough.len());
+        for dir in rough.iter() {
+            assert!(dir.dot(mirror) >= consts::FRAC_PI_4.cos() - 1.0e-5);
+        }
+        assert!(rough.iter().any(|dir| dir.x > 0.0) && rough.iter().any(|dir| dir.x < 0.0));
+    }
+
+    #[test]
+    fn rough_reflections_only_split_at_the_first_hit() {
+        let mut scene = create_test_scene();
+        // Two rough mirrors facing each other, on both sides of the camera
+        for &z in [-5.0, 5.0].iter() {
+            let mut mirror = Plane::init(Vec3::init(0.0, 0.0, z), Vec3::init(0.0, 0.0, -z));
+            mirror.material.specular = Color::init(1.0, 1.0, 1.0);
+            mirror.material.roughness = 0.5;
+            scene.primitives.push(Primitive::Plane(mirror));
+        }
+
+        let mut rt = RayTracer::init(1, 1, 3, 4);
+        rt.set_scene(Box::new(scene));
+        let (_, stats) = rt.trace_rays_with_stats();
+        assert_eq!(4 + 4 + 4, stats.rays_reflect);
+    }
+
+    #[test]
+    fn ao_pass_renders_an_image_of_the_same_size() {
+        let rt = get_lit_sphere_raytracer();
+        let img = rt.trace_ao(4, 1.0);
+        assert_eq!(2, img.get_width());
+        assert_eq!(2, img.get_height());
+    }
+
+    // A sphere of radius 1 at (0, 0, -3), tessellated into polys with interpolated normals
+    fn get_smooth_mesh_raytracer<'a>(rings: usize) -> RayTracer<'a> {
+        let mut scene = Box::new(create_test_scene());
+
+        let center = Vec3::init(0.0, 0.0, -3.0);
+        let point = |ring: usize, segment: usize| {
+            let theta = consts::PI * ring as f32 / rings as f32;
+            let phi = 2.0 * consts::PI * segment as f32 / rings as f32;
+            Vec3::init(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin())
+        };
+
+        let mut material = Material::init(Color::init(0.8, 0.8, 0.8));
+        material.specular = Color::init(0.3, 0.3, 0.3);
+        material.transparency = 0.2;
+        for ring in 0 .. rings {
+            for segment in 0 .. rings {
+                let corners = [point(ring, segment), point(ring + 1, segment),
+                    point(ring + 1, segment + 1), point(ring, segment + 1)];
+                for &(a, b, c) in [(0, 1, 2), (0, 2, 3)].iter() {
+                    let mut poly = Poly::init();
+                    poly.materials[0] = material.clone();
+                    poly.vertex_normal = true;
+                    for (i, &corner) in [a, b, c].iter().enumerate() {
+                        poly.vertices[i].position = center + corners[corner];
+                        poly.vertices[i].normal = corners[corner];
+                        poly.vertices[i].has_normal = true;
+                    }
+                    if Vec3::get_area(poly.vertices[0].position, poly.vertices[1].position,
+                                      poly.vertices[2].position) > 0.0 {
+                        scene.primitives.push(Primitive::Poly(poly));
+                    }
+                }
+            }
+        }
+        scene.lights.push(Light::Point(PointLight {
+            pos: Vec3::init(1.0, 1.0, 0.0),
+            intensity: ColorBuf::init(1.0, 1.0, 1.0),
+            enabled: true
+        }));
+
+        let mut rt = RayTracer::init(8, 8, 3, 1);
+        rt.set_scene(scene);
+        rt
+    }
+
+    #[test]
+    fn rays_from_precomputed_normal_match_recomputed_rays() {
+        let rt = get_smooth_mesh_raytracer(8);
+        let scene = match rt.scene {
+            Some(ref scene) => scene,
+            None => panic!("RayTracer should have a scene")
+        };
+
+        // Every pixel that sees the lit mesh reflects and refracts along the same rays
+        let img = rt.trace_rays();
+        let (mut hits, mut lit) = (0, 0);
+        for y in 0 .. 8 {
+            for x in 0 .. 8 {
+                let ray = rt.primary_ray(x as f32, y as f32);
+                let intersection = match scene.intersects(&ray) {
+                    Intersected(intersection) => intersection,
+                    Missed => continue
+                };
+                hits += 1;
+                if img.get_pixel(x, y) != (Pixel { r: 0, g: 0, b: 0 }) {
+                    lit += 1;
+                }
+
+                let normal = intersection.surface_normal();
+                let (a, b) = (intersection.reflective_ray(),
+                    intersection.reflective_ray_from(normal));
+                assert_eq!(a.ori, b.ori);
+                assert_eq!(a.dir(), b.dir());
+
+                match (intersection.refractive_ray(), intersection.refractive_ray_from(normal)) {
+                    (Some(a), Some(b)) => {
+                        assert_eq!(a.ori, b.ori);
+                        assert_eq!(a.dir(), b.dir());
+                    },
+                    (None, None) => (),
+                    _ => panic!("Refractive rays should match")
+                }
+            }
+        }
+        assert!(hits > 0 && hits < 8 * 8, "{} pixels should see the mesh", hits);
+        assert!(lit > 0, "The light should reach the mesh");
+    }
+
+    // The angle between the incoming ray and the ray refracted into a sphere of the given index
+    fn refraction_angle(ior: f32) -> f32 {
+        let mut material = Material::new();
+        material.transparency = 1.0;
+        material.refractive_index = ior;
+        let mut sphere = sphere::Sphere::init(Vec3::init(0.0, 0.0, -3.0), 1.0);
+        sphere.materials[0] = material;
+        let mut scene = Scene::new();
+        scene.primitives.push(Primitive::Sphere(sphere));
+
+        let mut dir = Vec3::init(0.3, 0.0, -1.0);
+        dir.normalize();
+        let ray = Ray::init(Vec3::new(), dir);
+        match scene.intersects(&ray) {
+            Intersected(intersection) => match intersection.refractive_ray() {
+                Some(refracted) => {
+                    assert_eq!(ior, refracted.ior());
+                    refracted.dir().dot(dir).acos()
+                },
+                None => panic!("Ray entering the sphere should refract")
+            },
+            Missed => panic!("Ray should hit the sphere")
+        }
+    }
+
+    #[test]
+    fn denser_materials_bend_rays_more() {
+        let water = refraction_angle(1.33);
+        assert!(refraction_angle(2.0) > water);
+        assert!(water > refraction_angle(1.1));
+    }
+
+    #[test]
+    fn threaded_shadow_sampling_matches_single_threaded() {
+        let mut scene = Scene::new();
+        push_quad(&mut scene, [
+            Vec3::init(-5.0, 0.0, -5.0), Vec3::init(5.0, 0.0, -5.0),
+            Vec3::init(5.0, 0.0, 5.0), Vec3::init(-5.0, 0.0, 5.0)
+        ]);
+        let blocker = sphere::Sphere::init(Vec3::init(0.0, 1.0, 0.0), 0.5);
+        scene.primitives.push(Primitive::Sphere(blocker));
+        scene.lights.push(Light::Area(AreaLight {
+            min: Vec3::init(-1.0, 2.0, -1.0),
+            max: Vec3::init(1.0, 2.1, 1.0),
+            intensity: ColorBuf::init(1.0, 1.0, 1.0),
+            enabled: true
+        }));
+        let scene: Box<IntersectableScene + Sync> = Box::new(scene);
+        let light = scene.get_lights()[0];
+
+        // A point on the floor in the penumbra of the sphere
+        let ray = Ray::init(Vec3::init(0.8, 5.0, 0.0), Vec3::init(0.0, -1.0, 0.0));
+        match scene.intersects(&ray) {
+            Intersected(intersection) => {
+                let shade = |threads: usize| {
+                    let mut rng: XorShiftRng = SeedableRng::from_seed([1, 2, 3, 4]);
+                    Shader::shadow_scalar(&scene, &light, &intersection,
+                        intersection.surface_normal(), 64, 2, threads, &mut rng).r_val()
+                };
+                let single = shade(1);
+                assert_eq!(single, shade(4));
+                assert_eq!(single, shade(3));
+                assert!(single > 0.0 && single < 1.0, "{} should be partially shadowed", single);
+            },
+            Missed => panic!("Ray should have hit the floor")
+        }
+    }
+
+    #[bench]
+    fn trace_sphere_field(b: &mut Bencher) {
+        let mut scene = create_sphere_field(1000);
+        let mut light = PointLight::new();
+        light.intensity = ColorBuf::init(1.0, 1.0, 1.0);
+        scene.lights.push(Light::Point(light));
+
+        let mut rt = RayTracer::init(32, 32, 2, 1);
+        rt.set_scene(Box::new(BvhScene::from_scene(scene)));
+        b.iter(|| black_box(rt.trace_rays()))
+    }
+
+    #[bench]
+    fn shade_smooth_mesh(b: &mut Bencher) {
+        let rt = get_smooth_mesh_raytracer(32);
+        b.iter(|| rt.trace_rays())
     }
 }
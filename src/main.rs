@@ -1,16 +1,26 @@
-#![feature(collections)]
+#![feature(collections, core)]
 
 extern crate rstracer;
 extern crate getopts;
+extern crate time;
 
 use std::env;
+use std::io;
+use std::io::Write;
+use std::num::Float;
+use std::os;
 use std::str::FromStr;
 
 use getopts::{Matches, Options};
 
 use rstracer::scene::parser::SceneParser;
 use rstracer::scene::IntersectableScene;
-use rstracer::RayTracer;
+use rstracer::{RayTracer, ToneMapping};
+use rstracer::output;
+
+use config::RenderConfig;
+
+mod config;
 
 fn print_usage(program: &str, opts: Options) {
     let brief = format!("Usage: {} [options]", program);
@@ -36,6 +46,33 @@ fn get_scene(matches: &Matches, default: &str) -> String {
     "scenes/".to_string() + &name + ".ascii"
 }
 
+// Formats the finished fraction of the render along with the estimated time remaining
+fn format_eta(fraction: f64, elapsed: f64) -> String {
+    let percent = (fraction * 100.0).floor() as u32;
+    if fraction <= 0.0 {
+        return format!("{}% (ETA --)", percent);
+    }
+    let remaining = (elapsed * (1.0 - fraction) / fraction).round() as u64;
+    format!("{}% (ETA {}m{:02}s)", percent, remaining / 60, remaining % 60)
+}
+
+// Parses a region of the image given as "x0,y0,x1,y1"
+fn parse_region(value: &str) -> Option<(u32, u32, u32, u32)> {
+    let coords: Result<Vec<u32>, _> = value.split(',').map(|c| c.trim().parse()).collect();
+    match coords {
+        Ok(ref c) if c.len() == 4 => Some((c[0], c[1], c[2], c[3])),
+        _ => None
+    }
+}
+
+// Images are saved as BMP unless the name ends in .ppm
+fn output_path(name: String) -> String {
+    match name.ends_with(".ppm") || name.ends_with(".bmp") {
+        true => name,
+        false => name + ".bmp"
+    }
+}
+
 #[allow(dead_code)]
 fn main() {
     let args: Vec<String> = env::args().collect();
@@ -44,11 +81,23 @@ fn main() {
     let mut opts = Options::new();
     opts.optflag("h", "help", "Print this help menu");
     opts.optflag("b", "bvh", "Optimize scene intersection with BVH-tree");
+    opts.optflag("", "stats", "Render on one thread and print the number of rays and intersection tests");
     opts.optopt("s", "size", "The width and height of the image to be generated", "-s 500");
     opts.optopt("a", "arealight-samples", "The number of times to sample the area lights", "-a 1000");
     opts.optopt("d", "depth", "The depth of the recursion in the main loop", "-d 10");
     opts.optopt("i", "scene", "The name of a scene located in the ./scenes directory", "-i test01");
-    opts.optopt("o", "out", "The name of the image to be generated", "-o image.bmp");
+    opts.optopt("o", "out", "The name of the image to be generated, saved as PPM if it ends in .ppm and as BMP otherwise", "-o image.bmp");
+    opts.optopt("", "aa", "The number of primary rays along each side of a pixel", "--aa 4");
+    opts.optopt("", "aperture", "The radius of the camera lens, blurs what is not at the focal distance", "--aperture 0.1");
+    opts.optopt("", "gamma", "The gamma the image is encoded with, 1 keeps the colors linear, defaults to 2.2", "--gamma 2.2");
+    opts.optopt("", "tonemap", "How colors brighter than white are stored, either clamp or reinhard, defaults to clamp", "--tonemap reinhard");
+    opts.optopt("", "background", "The color of rays that miss the scene, as r,g,b between 0 and 1", "--background 0.5,0.7,1");
+    opts.optopt("", "ao", "The number of rays that darken the ambient light of hits near other surfaces, defaults to 0", "--ao 16");
+    opts.optopt("", "ao-distance", "How close a surface has to be to darken the ambient light, defaults to 1", "--ao-distance 0.5");
+    opts.optopt("", "seed", "The seed of the random samples, renders with the same seed are identical", "--seed 42");
+    opts.optopt("", "region", "Only render the pixels from x0,y0 up to x1,y1, counted from the top left", "--region 0,0,100,50");
+    opts.optopt("t", "threads", "The number of threads to render with, defaults to the number of CPUs", "-t 4");
+    opts.optopt("c", "config", "A file with render settings, given as 'key = value' lines", "-c render.cfg");
 
     let matches = match opts.parse(args.tail()) {
         Ok(m) => { m }
@@ -60,20 +109,121 @@ fn main() {
         return;
     }
 
-    let size = get_opt(&matches, "s", 100);
-    let area_samples = get_opt(&matches, "a", 10);
-    let depth = get_opt(&matches, "d", 10);
+    let config = match matches.opt_str("c") {
+        Some(path) => match RenderConfig::load(&path) {
+            Ok(config) => config,
+            Err(e) => panic!(e)
+        },
+        None => RenderConfig::new()
+    };
+
+    let size = get_opt(&matches, "s", config.size);
+    let area_samples = get_opt(&matches, "a", config.samples);
+    let depth = get_opt(&matches, "d", config.depth);
     let scene = get_scene(&matches, "test01");
-    let out = get_str(&matches, "o", "img") + ".bmp";
+    let out = output_path(get_str(&matches, "o", &config.output));
 
     let mut parser = SceneParser::new(scene);
-    let scene: Box<IntersectableScene> = if matches.opt_present("b") {
-        Box::new(parser.parse_bvh_scene())
+    let scene: Box<IntersectableScene + Sync> = if matches.opt_present("b") {
+        Box::new(match parser.parse_bvh_scene() {
+            Ok(scene) => scene,
+            Err(e) => panic!("{}", e)
+        })
     } else {
-        Box::new(parser.parse_scene())
+        Box::new(match parser.parse_scene() {
+            Ok(scene) => scene,
+            Err(e) => panic!("{}", e)
+        })
     };
     let mut tracer = RayTracer::init(size, size, depth, area_samples);
     tracer.set_scene(scene);
-    let img = tracer.trace_rays();
-    let _ = img.save(&out);
+    tracer.set_aa_samples(get_opt(&matches, "aa", config.aa));
+    tracer.set_aperture(get_opt(&matches, "aperture", 0.0));
+    tracer.set_seed(get_opt(&matches, "seed", config.seed));
+    tracer.set_ambient_occlusion(get_opt(&matches, "ao", 0),
+        get_opt(&matches, "ao-distance", 1.0));
+    let background = match matches.opt_str("background") {
+        Some(value) => match config::parse_color(&value) {
+            Ok(color) => Some(color),
+            Err(e) => panic!(e)
+        },
+        None => config.background
+    };
+    match background {
+        Some(color) => tracer.set_background(color),
+        None => ()
+    }
+    tracer.set_gamma(get_opt(&matches, "gamma", 2.2));
+    tracer.set_tone_mapping(match get_str(&matches, "tonemap", "clamp").as_slice() {
+        "reinhard" => ToneMapping::Reinhard,
+        "clamp" => ToneMapping::Clamp,
+        other => panic!("Unknown tone mapping '{}'", other)
+    });
+
+    // Renders on several threads are pixel-identical to single-threaded ones with the same seed
+    let threads = get_opt(&matches, "t", os::num_cpus());
+    let region = matches.opt_str("region").map(|value| match parse_region(&value) {
+        Some(region) => region,
+        None => panic!("Region should be given as x0,y0,x1,y1, not '{}'", value)
+    });
+    let img = if region.is_some() {
+        let (x0, y0, x1, y1) = region.unwrap();
+        tracer.trace_region(x0, y0, x1, y1)
+    } else if matches.opt_present("stats") {
+        let (img, stats) = tracer.trace_rays_with_stats();
+        let _ = writeln!(&mut io::stderr(),
+            "Rays: {} primary, {} shadow, {} reflected, {} refracted", stats.rays_primary,
+            stats.rays_shadow, stats.rays_reflect, stats.rays_refract);
+        let _ = writeln!(&mut io::stderr(), "Tests: {} bounding boxes, {} primitives",
+            stats.bbox_tests, stats.prim_tests);
+        img
+    } else {
+        let start = time::precise_time_s();
+        let progress = |done: u32, total: u32| {
+            let eta = format_eta(done as f64 / total as f64, time::precise_time_s() - start);
+            let _ = write!(&mut io::stderr(), "\rRendering: {}   ", eta);
+        };
+        let img = match threads > 1 {
+            true => tracer.trace_rays_parallel_with_progress(threads, progress),
+            false => tracer.trace_rays_with_progress(progress)
+        };
+        let _ = writeln!(&mut io::stderr(), "");
+        img
+    };
+    let _ = match out.ends_with(".ppm") {
+        true => output::write_ppm(&img, &out),
+        false => img.save(&out)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use {format_eta, output_path, parse_region};
+
+    #[test]
+    fn can_format_eta_before_any_progress() {
+        assert_eq!("0% (ETA --)", format_eta(0.0, 3.0).as_slice());
+    }
+
+    #[test]
+    fn can_format_eta() {
+        assert_eq!("50% (ETA 0m10s)", format_eta(0.5, 10.0).as_slice());
+        assert_eq!("25% (ETA 1m30s)", format_eta(0.25, 30.0).as_slice());
+        assert_eq!("100% (ETA 0m00s)", format_eta(1.0, 42.0).as_slice());
+    }
+
+    #[test]
+    fn can_parse_region() {
+        assert_eq!(Some((10, 20, 110, 70)), parse_region("10,20,110,70"));
+        assert_eq!(None, parse_region("10,20,110"));
+        assert_eq!(None, parse_region("10,20,-1,70"));
+        assert_eq!(None, parse_region("10,20,x,110,70"));
+    }
+
+    #[test]
+    fn output_format_follows_the_extension() {
+        assert_eq!("image.bmp", output_path("image".to_string()).as_slice());
+        assert_eq!("image.bmp", output_path("image.bmp".to_string()).as_slice());
+        assert_eq!("image.ppm", output_path("image.ppm".to_string()).as_slice());
+    }
 }
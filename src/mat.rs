@@ -0,0 +1,179 @@
+use std::num::Float;
+use std::ops::Mul;
+
+use vec::Vec3;
+
+// A 4x4 matrix stored as rows, used for affine transforms where the bottom row is 0 0 0 1
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Mat4 {
+    pub m: [[f32; 4]; 4]
+}
+
+impl Mul for Mat4 {
+    type Output = Mat4;
+
+    // The transform that applies `other` first and then `self`
+    fn mul(self, other: Mat4) -> Mat4 {
+        let mut m = [[0.0f32; 4]; 4];
+        for i in 0 .. 4 {
+            for j in 0 .. 4 {
+                for k in 0 .. 4 {
+                    m[i][j] += self.m[i][k] * other.m[k][j];
+                }
+            }
+        }
+        Mat4::init(m)
+    }
+}
+
+impl Mat4 {
+    // The identity
+    pub fn new() -> Mat4 {
+        Mat4::init([
+            [1.0, 0.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0]
+        ])
+    }
+
+    pub fn init(m: [[f32; 4]; 4]) -> Mat4 {
+        Mat4 {
+            m: m
+        }
+    }
+
+    pub fn translation(offset: Vec3) -> Mat4 {
+        let mut mat = Mat4::new();
+        mat.m[0][3] = offset.x;
+        mat.m[1][3] = offset.y;
+        mat.m[2][3] = offset.z;
+        mat
+    }
+
+    pub fn scaling(scale: Vec3) -> Mat4 {
+        let mut mat = Mat4::new();
+        mat.m[0][0] = scale.x;
+        mat.m[1][1] = scale.y;
+        mat.m[2][2] = scale.z;
+        mat
+    }
+
+    // Rotates counterclockwise by `angle` radians around `axis`, looking down the axis
+    pub fn rotation(axis: Vec3, angle: f32) -> Mat4 {
+        let mut axis = axis;
+        axis.normalize();
+        let (x, y, z) = (axis.x, axis.y, axis.z);
+        let (s, c) = angle.sin_cos();
+        let t = 1.0 - c;
+        Mat4::init([
+            [t * x * x + c, t * x * y - s * z, t * x * z + s * y, 0.0],
+            [t * x * y + s * z, t * y * y + c, t * y * z - s * x, 0.0],
+            [t * x * z - s * y, t * y * z + s * x, t * z * z + c, 0.0],
+            [0.0, 0.0, 0.0, 1.0]
+        ])
+    }
+
+    // Points are moved by the translation of the matrix, directions are not
+    pub fn mul_point(&self, point: Vec3) -> Vec3 {
+        self.transform(point, 1.0)
+    }
+
+    pub fn mul_dir(&self, dir: Vec3) -> Vec3 {
+        self.transform(dir, 0.0)
+    }
+
+    fn transform(&self, v: Vec3, w: f32) -> Vec3 {
+        let row = |r: &[f32; 4]| r[0] * v.x + r[1] * v.y + r[2] * v.z + r[3] * w;
+        Vec3::init(row(&self.m[0]), row(&self.m[1]), row(&self.m[2]))
+    }
+
+    pub fn transpose(&self) -> Mat4 {
+        let mut m = self.m;
+        for i in 0 .. 4 {
+            for j in 0 .. 4 {
+                m[i][j] = self.m[j][i];
+            }
+        }
+        Mat4::init(m)
+    }
+
+    // Gauss-Jordan elimination with partial pivoting. Panics if the matrix can't be inverted.
+    pub fn inverse(&self) -> Mat4 {
+        let mut a = self.m;
+        let mut inv = Mat4::new().m;
+        for col in 0 .. 4 {
+            let pivot = (col .. 4).fold(col, |best, row|
+                match a[row][col].abs() > a[best][col].abs() {
+                    true => row,
+                    false => best
+                });
+            if a[pivot][col] == 0.0 {
+                panic!("Can't invert the singular matrix {:?}", self.m);
+            }
+            a.swap(col, pivot);
+            inv.swap(col, pivot);
+
+            let scale = 1.0 / a[col][col];
+            for j in 0 .. 4 {
+                a[col][j] *= scale;
+                inv[col][j] *= scale;
+            }
+            for row in 0 .. 4 {
+                let factor = a[row][col];
+                if row == col || factor == 0.0 {
+                    continue;
+                }
+                for j in 0 .. 4 {
+                    a[row][j] -= factor * a[col][j];
+                    inv[row][j] -= factor * inv[col][j];
+                }
+            }
+        }
+        Mat4::init(inv)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts;
+    use std::num::Float;
+
+    use vec::Vec3;
+    use mat::Mat4;
+
+    #[test]
+    fn can_transform_points_and_directions() {
+        let mat = Mat4::translation(Vec3::init(1.0, 2.0, 3.0)) *
+            Mat4::scaling(Vec3::init(2.0, 2.0, 2.0));
+        assert_eq!(Vec3::init(3.0, 4.0, 5.0), mat.mul_point(Vec3::init(1.0, 1.0, 1.0)));
+        assert_eq!(Vec3::init(2.0, 2.0, 2.0), mat.mul_dir(Vec3::init(1.0, 1.0, 1.0)));
+    }
+
+    #[test]
+    fn can_rotate_around_axis() {
+        let mat = Mat4::rotation(Vec3::init(0.0, 1.0, 0.0), consts::FRAC_PI_2);
+        let rotated = mat.mul_dir(Vec3::init(1.0, 0.0, 0.0));
+        assert!(rotated.distance(Vec3::init(0.0, 0.0, -1.0)) < 1.0e-6);
+    }
+
+    #[test]
+    fn matrix_times_inverse_is_identity() {
+        let mat = Mat4::translation(Vec3::init(1.0, -2.0, 3.0)) *
+            Mat4::rotation(Vec3::init(1.0, 1.0, 0.0), 0.7) *
+            Mat4::scaling(Vec3::init(2.0, 0.5, 3.0));
+        let product = mat * mat.inverse();
+        let identity = Mat4::new();
+        for i in 0 .. 4 {
+            for j in 0 .. 4 {
+                assert!((product.m[i][j] - identity.m[i][j]).abs() < 1.0e-5);
+            }
+        }
+    }
+
+    #[test]
+    #[should_fail(expected = "Can't invert the singular matrix")]
+    fn inverting_singular_matrix_panics() {
+        Mat4::scaling(Vec3::init(1.0, 0.0, 1.0)).inverse();
+    }
+}
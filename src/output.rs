@@ -0,0 +1,56 @@
+use std::fs::File;
+use std::io::{self, Write};
+
+use bmp::Image;
+
+// Writes the image as a binary PPM (P6), which is a short text header followed by the rows of
+// the image from the top, with three bytes per pixel and no padding
+pub fn write_ppm(img: &Image, path: &str) -> io::Result<()> {
+    let mut file = try!(File::create(path));
+    file.write_all(&encode_ppm(img)[..])
+}
+
+pub fn encode_ppm(img: &Image) -> Vec<u8> {
+    let (width, height) = (img.get_width(), img.get_height());
+    let mut bytes = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    bytes.reserve((width * height * 3) as usize);
+    for y in 0 .. height {
+        for x in 0 .. width {
+            let pixel = img.get_pixel(x, y);
+            bytes.push_all(&[pixel.r, pixel.g, pixel.b]);
+        }
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::File;
+    use std::io::Read;
+
+    use bmp::{Image, Pixel};
+    use output::write_ppm;
+
+    #[test]
+    fn can_write_ppm() {
+        let mut img = Image::new(2, 2);
+        for y in 0 .. 2 {
+            for x in 0 .. 2 {
+                let v = (x * 2 + y) as u8 * 85;
+                img.set_pixel(x, y, Pixel { r: v, g: 255 - v, b: 0 });
+            }
+        }
+
+        let path = env::temp_dir().join("rstracer-gradient.ppm");
+        let path = path.to_str().unwrap();
+        write_ppm(&img, path).unwrap();
+
+        let mut bytes = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut bytes).unwrap();
+        let header = b"P6\n2 2\n255\n";
+        assert_eq!(&header[..], &bytes[.. header.len()]);
+        assert_eq!(vec!(0, 255, 0, 170, 85, 0, 85, 170, 0, 255, 0, 0),
+            bytes[header.len() ..].to_vec());
+    }
+}
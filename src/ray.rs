@@ -1,12 +1,21 @@
 use std::cell::Cell;
+use std::num::Float;
 
 use vec::Vec3;
 
+pub static VACUUM_IOR: f32 = 1.0;
+
 #[derive(Clone)]
 pub struct Ray {
     pub ori: Vec3,
-    pub dir: Vec3,
-    vacuum: Cell<bool>
+    dir: Vec3,
+    inv_dir: Vec3,
+    sign: [usize; 3],
+    // The refractive index of the medium the ray is inside of, or None in vacuum. Tracked apart
+    // from the index, so media with the same index as vacuum are still left again.
+    medium: Cell<Option<f32>>,
+    // The id of the primitive the ray skips, and for meshes the id of the poly within it
+    excluded: Option<(usize, Option<usize>)>
 }
 
 impl Ray {
@@ -14,31 +23,105 @@ impl Ray {
         Ray {
             ori: Vec3::new(),
             dir: Vec3::new(),
-            vacuum: Cell::new(true)
+            inv_dir: Vec3::init(Float::infinity(), Float::infinity(), Float::infinity()),
+            sign: [0; 3],
+            medium: Cell::new(None),
+            excluded: None
         }
     }
 
     pub fn init(ori: Vec3, dir: Vec3) -> Ray {
         let mut ray = Ray::new();
         ray.ori = ori;
-        ray.dir = dir;
+        ray.set_dir(dir);
         ray
     }
 
-    pub fn switch_medium(&self) {
-        match self.vacuum.get() {
-            true => self.vacuum.set(false),
-            false => self.vacuum.set(true)
+    pub fn dir(&self) -> Vec3 {
+        self.dir
+    }
+
+    // Keeps the inverse direction and the signs up to date with the direction
+    pub fn set_dir(&mut self, dir: Vec3) {
+        self.dir = dir;
+        self.inv_dir = Vec3::init(1.0 / dir.x, 1.0 / dir.y, 1.0 / dir.z);
+        for i in 0 .. 3 {
+            self.sign[i] = match self.inv_dir[i as u32] < 0.0 {
+                true => 1,
+                false => 0
+            };
+        }
+    }
+
+    // The reciprocal of each component of the direction, where zero components give infinities
+    // of the same sign, used by the bounding box tests to multiply rather than divide
+    pub fn inv_dir(&self) -> Vec3 {
+        self.inv_dir
+    }
+
+    // 1 for the axes the ray travels in negative direction along, 0 for the others
+    pub fn sign(&self) -> [usize; 3] {
+        self.sign
+    }
+
+    // The point at distance `t` along the ray
+    pub fn at(&self, t: f32) -> Vec3 {
+        self.ori + self.dir.mult(t)
+    }
+
+    // Makes the ray skip the primitive with the id `id`, or only its poly `part` if it is a mesh.
+    // Used for rays leaving a planar surface, as they could otherwise hit the surface they start
+    // on due to lost precision.
+    pub fn exclude(&mut self, id: usize, part: Option<usize>) {
+        self.excluded = Some((id, part));
+    }
+
+    pub fn excludes(&self, id: usize) -> bool {
+        self.excluded == Some((id, None))
+    }
+
+    // The ray as seen by the polys of the mesh with the id `id`, which are numbered on their own.
+    // It only skips the poly it leaves from within that mesh.
+    pub fn inside(&self, id: usize) -> Ray {
+        let mut ray = self.clone();
+        ray.excluded = match self.excluded {
+            Some((excluded, Some(part))) if excluded == id => Some((part, None)),
+            _ => None
+        };
+        ray
+    }
+
+    // The refractive index of the medium the ray travels through
+    pub fn ior(&self) -> f32 {
+        self.medium.get().unwrap_or(VACUUM_IOR)
+    }
+
+    pub fn medium(&self) -> Option<f32> {
+        self.medium.get()
+    }
+
+    pub fn set_medium(&self, medium: Option<f32>) {
+        self.medium.set(medium);
+    }
+
+    // Enters a medium with the refractive index `ior` when in vacuum, otherwise leaves the
+    // current medium for vacuum
+    pub fn switch_medium(&self, ior: f32) {
+        match self.in_vacuum() {
+            true => self.medium.set(Some(ior)),
+            false => self.medium.set(None)
         }
     }
 
     pub fn in_vacuum(&self) -> bool {
-        self.vacuum.get()
+        self.medium.get().is_none()
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::num::Float;
+
     use vec::Vec3;
     use ray::Ray;
 
@@ -46,6 +129,43 @@ mod tests {
     fn can_init_ray() {
         let r = Ray::init(Vec3::init(0.0, 1.0, 2.0), Vec3::init(2.0, 1.0, 0.0));
         assert_eq!(r.ori[2], 2.0);
-        assert_eq!(r.dir[2], 0.0);
+        assert_eq!(r.dir()[2], 0.0);
+    }
+
+    #[test]
+    fn can_get_point_along_ray() {
+        let r = Ray::init(Vec3::init(1.0, -1.0, 0.5), Vec3::init(0.5, 1.0, -2.0));
+        assert_eq!(r.ori, r.at(0.0));
+        assert_eq!(Vec3::init(2.0, 1.0, -3.5), r.at(2.0));
+        assert_eq!(Vec3::init(0.5, -2.0, 2.5), r.at(-1.0));
+    }
+
+    #[test]
+    fn can_switch_medium() {
+        let r = Ray::init(Vec3::new(), Vec3::init(0.0, 0.0, -1.0));
+        assert!(r.in_vacuum());
+        r.switch_medium(1.33);
+        assert!(!r.in_vacuum());
+        assert_eq!(1.33, r.ior());
+        r.switch_medium(1.33);
+        assert!(r.in_vacuum());
+        assert_eq!(1.0, r.ior());
+
+        // A medium with the index of vacuum is entered and left like any other
+        r.switch_medium(1.0);
+        assert!(!r.in_vacuum());
+        r.switch_medium(1.0);
+        assert!(r.in_vacuum());
+    }
+
+    #[test]
+    fn can_get_inverse_direction() {
+        let mut r = Ray::init(Vec3::new(), Vec3::init(2.0, -0.5, 0.0));
+        assert_eq!(Vec3::init(0.5, -2.0, Float::infinity()), r.inv_dir());
+        assert_eq!([0, 1, 0], r.sign());
+
+        r.set_dir(Vec3::init(-4.0, 1.0, -0.0));
+        assert_eq!(Vec3::init(-0.25, 1.0, Float::neg_infinity()), r.inv_dir());
+        assert_eq!([1, 0, 1], r.sign());
     }
 }
@@ -1,9 +1,16 @@
-use std::cmp::Ordering;
+use std::cmp::{self, Ordering};
+use std::mem;
+use std::num::Float;
 
+use vec::Vec3;
 use ray::Ray;
-use scene::shapes::{BoundingBox, Primitive, Shape, ShapeIntersection};
+use scene::shapes::{BoundingBox, Primitive, Shape};
+use scene::stats::{self, Stat, StatCounters};
 use self::NodeIntersection::{Hit, Missed};
 
+// Splits are weighed in the surface area heuristic against the cost of testing a single shape
+static SAH_TRAVERSAL_COST: f32 = 0.125;
+static SAH_BUCKETS: usize = 12;
 
 #[derive(PartialEq, Debug)]
 pub enum Node {
@@ -14,7 +21,8 @@ pub enum Node {
 
 #[derive(PartialEq, Debug)]
 pub enum NodeIntersection<'a> {
-    Hit(&'a Box<TreeNode>, f32),
+    // The node hit, along with the primitive that was hit within its shape
+    Hit(&'a Box<TreeNode>, &'a Primitive, f32),
     Missed
 }
 
@@ -23,6 +31,7 @@ pub struct TreeNode {
     left: Node,
     right: Node,
     shape: Option<Primitive>,
+    id: usize,
     bbox: BoundingBox
 }
 
@@ -32,6 +41,7 @@ impl<'a> TreeNode {
             left: Node::Empty,
             right: Node::Empty,
             shape: None,
+            id: 0,
             bbox: BoundingBox::new()
         }
     }
@@ -55,9 +65,10 @@ impl<'a> TreeNode {
         node
     }
 
-    fn add(&mut self, shape: Primitive) {
+    fn add(&mut self, id: usize, shape: Primitive) {
         self.bbox = shape.get_bbox();
         self.shape = Some(shape);
+        self.id = id;
     }
 
     pub fn get_shape(&'a self) -> &'a Primitive {
@@ -66,43 +77,128 @@ impl<'a> TreeNode {
             None => panic!("Node has not been assigned a shape")
         }
     }
+
+    // The id the shape was given when the tree was built
+    pub fn get_id(&self) -> usize {
+        self.id
+    }
 }
 
+#[derive(PartialEq, Debug)]
 pub struct Tree {
-    pub root: Node
+    pub root: Node,
+    // The id given to the next shape added with `insert`
+    next_id: usize
 }
 
 impl<'a> Tree {
     pub fn new() -> Tree {
         Tree {
-            root: Node::Empty
+            root: Node::Empty,
+            next_id: 0
         }
     }
 
-    pub fn init(&mut self, mut shapes: Vec<Primitive>) {
+    // Gives each shape its index in `shapes` as id
+    pub fn init(&mut self, shapes: Vec<Primitive>) {
+        self.init_with_ids(shapes.into_iter().enumerate().collect());
+    }
+
+    pub fn init_with_ids(&mut self, mut shapes: Vec<(usize, Primitive)>) {
+        self.next_id = shapes.iter().map(|&(id, _)| id + 1).max().unwrap_or(0);
         let depth = 0;
         let root = self.build(shapes.as_mut_slice(), depth);
         self.root = root;
     }
 
-    fn build(&mut self, shapes: &'a mut [Primitive], depth: usize) -> Node {
+    pub fn is_empty(&self) -> bool {
+        match self.root {
+            Node::Empty => true,
+            _ => false
+        }
+    }
+
+    // Empties the tree, returning its shapes along with their ids
+    pub fn take_shapes(&mut self) -> Vec<(usize, Primitive)> {
+        let mut shapes = Vec::new();
+        Tree::collect_shapes(mem::replace(&mut self.root, Node::Empty), &mut shapes);
+        self.next_id = 0;
+        shapes
+    }
+
+    // Adds a shape without rebuilding the tree, giving it the id after the largest id in the
+    // tree. The shape descends into the child whose box grows least by it, is paired with the
+    // leaf it ends up at, and the boxes along the way are refitted around it. Inserted shapes
+    // aren't placed as well as by `init`, so the tree should still be rebuilt now and then when
+    // many shapes are added.
+    pub fn insert(&mut self, shape: Primitive) {
+        let mut leaf = Box::new(TreeNode::new());
+        leaf.add(self.next_id, shape);
+        self.next_id += 1;
+        let root = mem::replace(&mut self.root, Node::Empty);
+        self.root = Tree::insert_node(root, leaf);
+    }
+
+    fn insert_node(node: Node, leaf: Box<TreeNode>) -> Node {
+        match node {
+            Node::Empty => Node::Leaf(leaf),
+            Node::Leaf(other) => Node::Member(Box::new(TreeNode::init(Node::Leaf(other),
+                Node::Leaf(leaf)))),
+            Node::Member(mut node) => {
+                let bbox = leaf.bbox;
+                let growth = |child: &Node| {
+                    let child_bbox = TreeNode::get_bbox(child);
+                    (child_bbox + bbox).surface_area() - child_bbox.surface_area()
+                };
+                match growth(&node.left) <= growth(&node.right) {
+                    true => {
+                        let left = mem::replace(&mut node.left, Node::Empty);
+                        node.left = Tree::insert_node(left, leaf);
+                    },
+                    false => {
+                        let right = mem::replace(&mut node.right, Node::Empty);
+                        node.right = Tree::insert_node(right, leaf);
+                    }
+                }
+                node.bbox = TreeNode::get_bbox(&node.left) + TreeNode::get_bbox(&node.right);
+                Node::Member(node)
+            }
+        }
+    }
+
+    fn collect_shapes(node: Node, shapes: &mut Vec<(usize, Primitive)>) {
+        match node {
+            Node::Member(node) => {
+                let node = *node;
+                Tree::collect_shapes(node.left, shapes);
+                Tree::collect_shapes(node.right, shapes);
+            },
+            Node::Leaf(node) => {
+                let node = *node;
+                if let Some(shape) = node.shape {
+                    shapes.push((node.id, shape));
+                }
+            },
+            Node::Empty => ()
+        }
+    }
+
+    fn build(&mut self, shapes: &'a mut [(usize, Primitive)], depth: usize) -> Node {
         match shapes.len() {
             0 => Node::Empty,
             1 => {
                 let mut node = Box::new(TreeNode::new());
-                node.add(shapes[0].clone());
+                node.add(shapes[0].0, shapes[0].1.clone());
                 Node::Leaf(node)
             },
             _ => {
-                let axis = depth as u32 % 3;
-                shapes.sort_by(|a, b| {
-                    match a.get_bbox().centroid()[axis] < b.get_bbox().centroid()[axis] {
-                        true => Ordering::Less,
-                        false => Ordering::Greater
-                    }
-                });
-                let half = shapes.len() / 2;
-                let (head, tail) = shapes.split_at_mut(half);
+                let median_axis = depth as u32 % 3;
+                let (axis, split) = match Tree::sah_split(shapes, median_axis) {
+                    Some(split) => split,
+                    None => (median_axis, shapes.len() / 2)
+                };
+                Tree::sort_on_axis(shapes, axis);
+                let (head, tail) = shapes.split_at_mut(split);
 
                 let left = self.build(head, depth + 1);
                 let right = self.build(tail, depth + 1);
@@ -112,41 +208,316 @@ impl<'a> Tree {
         }
     }
 
+    fn sort_on_axis(shapes: &mut [(usize, Primitive)], axis: u32) {
+        shapes.sort_by(|&(_, ref a), &(_, ref b)| {
+            match a.get_bbox().centroid()[axis] < b.get_bbox().centroid()[axis] {
+                true => Ordering::Less,
+                false => Ordering::Greater
+            }
+        });
+    }
+
+    // Picks the axis and position to split the shapes at by the surface area heuristic, where a
+    // split costs the chance of a ray through the node hitting each half times the number of
+    // shapes in that half. Splits are only tried between buckets of centroids along each axis.
+    // Returns None when no split is cheaper than the median split along `median_axis`.
+    fn sah_split(shapes: &mut [(usize, Primitive)], median_axis: u32) -> Option<(u32, usize)> {
+        let n = shapes.len();
+        let mut best: Option<(f32, u32, usize)> = None;
+        let mut median_cost = Float::infinity();
+
+        for axis in 0 .. 3 {
+            Tree::sort_on_axis(shapes, axis);
+            let boxes: Vec<BoundingBox> = shapes.iter().map(|&(_, ref shape)| shape.get_bbox())
+                .collect();
+
+            // prefix[k] bounds the first k + 1 shapes, suffix[k] the shapes from k onwards
+            let mut prefix = boxes.clone();
+            let mut suffix = boxes.clone();
+            for k in 1 .. n {
+                prefix[k] = prefix[k - 1] + boxes[k];
+            }
+            for k in (0 .. n - 1).rev() {
+                suffix[k] = suffix[k + 1] + boxes[k];
+            }
+
+            let total = prefix[n - 1].surface_area();
+            let cost = |k: usize| SAH_TRAVERSAL_COST + (prefix[k - 1].surface_area() * k as f32 +
+                suffix[k].surface_area() * (n - k) as f32) / total;
+            if axis == median_axis {
+                median_cost = cost(n / 2);
+            }
+
+            let (lo, hi) = (boxes[0].centroid()[axis], boxes[n - 1].centroid()[axis]);
+            if hi <= lo {
+                continue; // All centroids are in the same spot
+            }
+            let bucket = |bbox: &BoundingBox| cmp::min(SAH_BUCKETS - 1,
+                (SAH_BUCKETS as f32 * (bbox.centroid()[axis] - lo) / (hi - lo)) as usize);
+
+            for k in 1 .. n {
+                if bucket(&boxes[k - 1]) == bucket(&boxes[k]) {
+                    continue;
+                }
+                let c = cost(k);
+                match best {
+                    Some((best_cost, _, _)) if best_cost <= c => (),
+                    _ => best = Some((c, axis, k))
+                }
+            }
+        }
+
+        match best {
+            Some((c, axis, k)) if c < median_cost => Some((axis, k)),
+            _ => None
+        }
+    }
+
+    // Builds a linear BVH: the shapes are sorted by the Morton code of their centroid within the
+    // bounds of all centroids, and split where the highest differing bit of the codes changes.
+    // Faster to build than `init`, but the tree is usually a bit slower to traverse.
+    pub fn init_lbvh(&mut self, shapes: Vec<Primitive>) {
+        let centroids: Vec<Vec3> = shapes.iter().map(|shape| shape.get_bbox().centroid()).collect();
+        let (min, max) = match centroids.first() {
+            Some(&first) => centroids.iter().fold((first, first), |(min, max), &c|
+                (min.component_min(c), max.component_max(c))),
+            None => (Vec3::new(), Vec3::new())
+        };
+
+        let extent = max - min;
+        let unit = |v: f32, min: f32, extent: f32| match extent > 0.0 {
+            true => (v - min) / extent,
+            false => 0.0
+        };
+        let codes: Vec<(u32, usize)> = centroids.iter().enumerate().map(|(i, c)| {
+            (morton_code(unit(c.x, min.x, extent.x), unit(c.y, min.y, extent.y),
+                unit(c.z, min.z, extent.z)), i)
+        }).collect();
+        let codes = radix_sort(codes);
+
+        let mut shapes: Vec<Option<Primitive>> = shapes.into_iter().map(|shape| Some(shape))
+            .collect();
+        let sorted: Vec<(usize, Primitive)> = codes.iter().map(|&(_, i)| match shapes[i].take() {
+            Some(shape) => (i, shape),
+            None => panic!("Shape {} was sorted twice", i)
+        }).collect();
+        let codes: Vec<u32> = codes.iter().map(|&(code, _)| code).collect();
+
+        self.root = Tree::build_sorted(&sorted[..], &codes[..]);
+        self.next_id = sorted.len();
+    }
+
+    fn build_sorted(shapes: &[(usize, Primitive)], codes: &[u32]) -> Node {
+        match shapes.len() {
+            0 => Node::Empty,
+            1 => {
+                let mut node = Box::new(TreeNode::new());
+                node.add(shapes[0].0, shapes[0].1.clone());
+                Node::Leaf(node)
+            },
+            len => {
+                let (first, last) = (codes[0], codes[len - 1]);
+                let split = match first == last {
+                    true => len / 2, // Equal codes, any split will do
+                    false => {
+                        let mut bit = 1 << 31;
+                        while (first ^ last) & bit == 0 {
+                            bit >>= 1;
+                        }
+                        match codes.iter().position(|&code| code & bit != 0) {
+                            Some(split) => split,
+                            None => len / 2
+                        }
+                    }
+                };
+
+                let left = Tree::build_sorted(&shapes[.. split], &codes[.. split]);
+                let right = Tree::build_sorted(&shapes[split ..], &codes[split ..]);
+                Node::Member(Box::new(TreeNode::init(left, right)))
+            }
+        }
+    }
+
     pub fn intersects(&'a self, ray: &Ray) -> NodeIntersection<'a> {
-        Tree::intersects_node(&self.root, ray)
+        self.intersects_between(ray, 0.0, Float::infinity(), None)
+    }
+
+    // Counts the tests of bounding boxes and shapes in `stats`, if given
+    pub fn intersects_between(&'a self, ray: &Ray, near: f32, far: f32,
+                              stats: Option<&StatCounters>) -> NodeIntersection<'a> {
+        match Tree::entry(&self.root, ray, stats) {
+            Some(t) if t <= far => Tree::intersects_node(&self.root, ray, near, far, stats),
+            _ => Missed
+        }
+    }
+
+    // The distance at which the ray enters the bounding box of the node
+    fn entry(node: &Node, ray: &Ray, stats: Option<&StatCounters>) -> Option<f32> {
+        match node {
+            &Node::Leaf(ref node) | &Node::Member(ref node) => {
+                stats::count(stats, Stat::BboxTests);
+                node.bbox.intersects(ray)
+            },
+            &Node::Empty => None
+        }
+    }
+
+    // Whether any opaque shape is hit closer than `max_dist`, stopping at the first one found.
+    // With `cull`, shapes hit from behind are skipped.
+    pub fn occludes(&self, ray: &Ray, max_dist: f32, cull: bool,
+                    stats: Option<&StatCounters>) -> bool {
+        Tree::occludes_node(&self.root, ray, max_dist, cull, stats)
+    }
+
+    fn occludes_node(node: &Node, ray: &Ray, max_dist: f32, cull: bool,
+                     stats: Option<&StatCounters>) -> bool {
+        match node {
+            &Node::Empty => false,
+            &Node::Leaf(ref node) => match node.shape {
+                Some(_) if ray.excludes(node.id) => false,
+                Some(ref shape) => {
+                    stats::count(stats, Stat::PrimTests);
+                    shape.occludes(ray, node.id, max_dist, cull)
+                },
+                None => false
+            },
+            &Node::Member(ref node) => {
+                stats::count(stats, Stat::BboxTests);
+                match node.bbox.intersects(ray) {
+                    Some(t) if t < max_dist =>
+                        Tree::occludes_node(&node.left, ray, max_dist, cull, stats) ||
+                        Tree::occludes_node(&node.right, ray, max_dist, cull, stats),
+                    _ => false
+                }
+            }
+        }
+    }
+
+    // Either an opaque shape closer than `max_dist`, which blocks the light of a shadow ray, or
+    // the closest shape, which is transparent and lets the light through. The search stops at
+    // the first opaque shape it finds. With `cull`, opaque shapes hit from behind are skipped.
+    pub fn shadow_hit(&'a self, ray: &Ray, max_dist: f32, cull: bool,
+                      stats: Option<&StatCounters>) -> NodeIntersection<'a> {
+        Tree::shadow_node(&self.root, ray, max_dist, cull, stats)
+    }
+
+    fn shadow_node(node: &'a Node, ray: &Ray, max_dist: f32, cull: bool,
+                   stats: Option<&StatCounters>) -> NodeIntersection<'a> {
+        match node {
+            &Node::Empty => Missed,
+            &Node::Leaf(ref node) => match node.shape {
+                Some(_) if ray.excludes(node.id) => Missed,
+                Some(ref shape) => {
+                    stats::count(stats, Stat::PrimTests);
+                    match shape.shadow_hit(ray, node.id, max_dist, cull) {
+                        Some((prim, p)) => Hit(node, prim, p),
+                        None => Missed
+                    }
+                },
+                None => Missed
+            },
+            &Node::Member(ref node) => {
+                stats::count(stats, Stat::BboxTests);
+                match node.bbox.intersects(ray) {
+                    Some(t) if t < max_dist =>
+                        match Tree::shadow_node(&node.left, ray, max_dist, cull, stats) {
+                            Hit(hit, prim, p) if prim.get_material().transparency == 0.0 =>
+                                Hit(hit, prim, p),
+                            Hit(hit, prim, p) =>
+                                match Tree::shadow_node(&node.right, ray, p, cull, stats) {
+                                    Missed => Hit(hit, prim, p),
+                                    closer => closer
+                                },
+                            Missed => Tree::shadow_node(&node.right, ray, max_dist, cull, stats)
+                        },
+                    _ => Missed
+                }
+            }
+        }
     }
 
-    fn intersects_node(node: &'a Node, ray: &Ray) -> NodeIntersection<'a> {
+    fn intersects_node(node: &'a Node, ray: &Ray, near: f32, far: f32,
+                       stats: Option<&StatCounters>) -> NodeIntersection<'a> {
         match node {
             &Node::Empty => Missed,
             &Node::Leaf(ref node) => match node.shape {
-                Some(ref shape) => match shape.intersects(ray) {
-                    ShapeIntersection::Hit(p) => Hit(node, p),
-                    ShapeIntersection::Missed => Missed
+                Some(_) if ray.excludes(node.id) => Missed,
+                Some(ref shape) => {
+                    stats::count(stats, Stat::PrimTests);
+                    match shape.hit(ray, node.id) {
+                        Some((prim, p)) if p >= near && p <= far => Hit(node, prim, p),
+                        _ => Missed
+                    }
                 },
                 None => Missed
             },
-            &Node::Member(ref node) => if node.bbox.intersects(ray) {
-                let left = Tree::intersects_node(&node.left, ray);
-                let right = Tree::intersects_node(&node.right, ray);
-
-                match (left, right) {
-                    (Hit(n0, p0), Hit(n1, p1)) => if p0 < p1 { Hit(n0, p0) } else { Hit(n1, p1) },
-                    (Hit(node, p), _) => Hit(node, p),
-                    (_, Hit(node, p)) => Hit(node, p),
-                    (_, _) => Missed
+            // The box of the node itself has been tested by its parent. The child that is
+            // entered first is visited first, so the other one can be skipped if its box starts
+            // beyond the closest hit found so far.
+            &Node::Member(ref node) => {
+                let (left, right) = (Tree::entry(&node.left, ray, stats),
+                    Tree::entry(&node.right, ray, stats));
+                let children = match (left, right) {
+                    (Some(l), Some(r)) if r < l => [(&node.right, right), (&node.left, left)],
+                    (None, Some(_)) => [(&node.right, right), (&node.left, left)],
+                    _ => [(&node.left, left), (&node.right, right)]
+                };
+
+                let mut closest = Missed;
+                let mut far = far;
+                for &(child, entry) in children.iter() {
+                    match entry {
+                        Some(t) if t <= far =>
+                            match Tree::intersects_node(child, ray, near, far, stats) {
+                                Hit(node, prim, p) => {
+                                    closest = Hit(node, prim, p);
+                                    far = p;
+                                },
+                                Missed => ()
+                            },
+                        _ => ()
+                    }
                 }
-            } else {
-                Missed
+                closest
             }
         }
     }
 }
 
+// Spreads the lowest 10 bits of `v` out so there are two zero bits between each of them
+fn expand_bits(v: u32) -> u32 {
+    let mut v = v & 0x3ff;
+    v = (v | (v << 16)) & 0x030000ff;
+    v = (v | (v << 8)) & 0x0300f00f;
+    v = (v | (v << 4)) & 0x030c30c3;
+    v = (v | (v << 2)) & 0x09249249;
+    v
+}
+
+// Interleaves the bits of the coordinates, each in [0, 1], into a 30 bit code where points that
+// are close in space tend to be close in order
+fn morton_code(x: f32, y: f32, z: f32) -> u32 {
+    let quantize = |v: f32| (v * 1024.0).max(0.0).min(1023.0) as u32;
+    (expand_bits(quantize(x)) << 2) | (expand_bits(quantize(y)) << 1) | expand_bits(quantize(z))
+}
+
+// Sorts 30 bit codes in three passes of 10 bits each
+fn radix_sort(mut codes: Vec<(u32, usize)>) -> Vec<(u32, usize)> {
+    for pass in 0 .. 3 {
+        let shift = pass * 10;
+        let mut buckets: Vec<Vec<(u32, usize)>> = (0 .. 1024).map(|_| Vec::new()).collect();
+        for &(code, i) in codes.iter() {
+            buckets[((code >> shift) & 0x3ff) as usize].push((code, i));
+        }
+        codes = buckets.into_iter().flat_map(|bucket| bucket.into_iter()).collect();
+    }
+    codes
+}
+
 #[cfg(test)]
 mod tests {
     extern crate test;
-    use self::test::Bencher;
+    use self::test::{Bencher, black_box};
 
     use vec::Vec3;
     use ray::Ray;
@@ -181,7 +552,7 @@ mod tests {
         );
 
         match intersection {
-            bvh::NodeIntersection::Hit(_, p) => assert_eq!(p, 4.0),
+            bvh::NodeIntersection::Hit(_, _, p) => assert_eq!(p, 4.0),
             _ => panic!("Should have intersected with tree")
         }
     }
@@ -245,7 +616,7 @@ mod tests {
         tree.init(shapes);
 
         let intersect_tree = |ray, primitive: Primitive| match tree.intersects(&ray) {
-            bvh::NodeIntersection::Hit(node, _) => {
+            bvh::NodeIntersection::Hit(node, _, _) => {
                 match node.shape {
                     Some(ref prim) => assert_eq!(&primitive, prim),
                     _ => panic!("Node should have primitive")
@@ -268,6 +639,133 @@ mod tests {
         assert_eq!(intersection, bvh::NodeIntersection::Missed);
     }
 
+    #[test]
+    fn can_intersect_shapes_inserted_one_at_a_time() {
+        let positions = [Vec3::init(0.0, 0.0, -5.0), Vec3::init(4.0, 0.0, -5.0),
+            Vec3::init(0.0, 4.0, -5.0)];
+        let mut tree = bvh::Tree::new();
+        for &pos in positions.iter() {
+            tree.insert(create_shape(pos));
+        }
+
+        for (i, &pos) in positions.iter().enumerate() {
+            let ray = Ray::init(Vec3::init(pos.x, pos.y, 0.0), Vec3::init(0.0, 0.0, -1.0));
+            match tree.intersects(&ray) {
+                bvh::NodeIntersection::Hit(node, _, p) => {
+                    assert_eq!(i, node.get_id());
+                    assert_eq!(4.0, p);
+                },
+                _ => panic!("Ray should have intersected the inserted sphere {}", i)
+            }
+        }
+        let between = Ray::init(Vec3::init(2.0, 2.0, 0.0), Vec3::init(0.0, 0.0, -1.0));
+        assert_eq!(bvh::NodeIntersection::Missed, tree.intersects(&between));
+    }
+
+    #[test]
+    fn finds_closest_hit_from_both_ends_of_a_row() {
+        let shapes = (0 .. 9).map(|i| create_shape(Vec3::init(i as f32 * 3.0, 0.0, 0.0)))
+            .collect();
+        let mut tree = bvh::Tree::new();
+        tree.init(shapes);
+
+        let closest = |ray| match tree.intersects(&ray) {
+            bvh::NodeIntersection::Hit(node, _, p) => (node.get_id(), p),
+            _ => panic!("Ray should have intersected tree")
+        };
+        assert_eq!((0, 2.0), closest(Ray::init(Vec3::init(-3.0, 0.0, 0.0),
+            Vec3::init(1.0, 0.0, 0.0))));
+        assert_eq!((8, 2.0), closest(Ray::init(Vec3::init(27.0, 0.0, 0.0),
+            Vec3::init(-1.0, 0.0, 0.0))));
+        assert_eq!((3, 0.5), closest(Ray::init(Vec3::init(10.5, 0.0, 0.0),
+            Vec3::init(-1.0, 0.0, 0.0))));
+    }
+
+    #[test]
+    fn sah_splits_between_clusters() {
+        // Two rows of four spheres, 20 apart along z. With the bounds x in [-1, 10], y in
+        // [-1, 1] and z in [-1, 21], splitting the rows apart costs 0.125 + 2 * 4 * 96 / 616,
+        // about 1.37, while the median split along x costs 0.125 + 2 * 4 * 328 / 616, about 4.38.
+        let mut shapes: Vec<(usize, Primitive)> = (0 .. 8).map(|i| {
+            let (x, z) = ((i % 4) as f32 * 3.0, (i / 4) as f32 * 20.0);
+            (i, create_shape(Vec3::init(x, 0.0, z)))
+        }).collect();
+        assert_eq!(Some((2, 4)), bvh::Tree::sah_split(&mut shapes[..], 0));
+
+        let mut tree = bvh::Tree::new();
+        tree.init(shapes.into_iter().map(|(_, shape)| shape).collect());
+        match tree.root {
+            bvh::Node::Member(ref node) => assert_eq!(shapes::BoundingBox::init(
+                Vec3::init(-1.0, -1.0, -1.0), Vec3::init(10.0, 1.0, 1.0)),
+                bvh::TreeNode::get_bbox(&node.left)),
+            _ => panic!("Root should be a member")
+        }
+    }
+
+    #[test]
+    fn can_sort_morton_codes() {
+        assert_eq!(0, bvh::morton_code(0.0, 0.0, 0.0));
+        assert_eq!(0x3fffffff, bvh::morton_code(1.0, 1.0, 1.0));
+        assert_eq!(0b100, bvh::morton_code(1.0 / 1024.0, 0.0, 0.0));
+
+        let codes = vec!((0x3fffffff, 0), (5, 1), (1 << 20, 2), (5, 3), (0, 4));
+        assert_eq!(vec!((0, 4), (5, 1), (5, 3), (1 << 20, 2), (0x3fffffff, 0)),
+            bvh::radix_sort(codes));
+    }
+
+    #[test]
+    fn can_intersect_lbvh_of_size_4() {
+        let shapes = vec!(
+            create_shape(Vec3::init(0.0, 0.0, 0.0)),
+            create_shape(Vec3::init(-1.0, 2.0, 1.0)),
+            create_shape(Vec3::init(-2.0, -2.0, 2.0)),
+            create_shape(Vec3::init(2.0, 2.0, -1.0))
+        );
+
+        let mut tree = bvh::Tree::new();
+        tree.init_lbvh(shapes);
+
+        let intersect_tree = |ray, id: usize| match tree.intersects(&ray) {
+            bvh::NodeIntersection::Hit(node, _, _) => assert_eq!(id, node.get_id()),
+            _ => panic!("Ray should have intersected tree")
+        };
+
+        intersect_tree(Ray::init(Vec3::init(2.0, 2.0, 2.0), Vec3::init(0.0, 0.0, -1.0)), 3);
+        intersect_tree(Ray::init(Vec3::init(-1.0, -1.0, 1.0), Vec3::init(-1.0, -1.0, 1.0)), 2);
+        intersect_tree(Ray::init(Vec3::init(0.0, 0.0, 5.0), Vec3::init(0.0, 0.0, -1.0)), 0);
+        let intersection = tree.intersects(
+            &Ray::init(Vec3::init(-1.0, -1.0, 1.0), Vec3::init(0.0, 0.0, 1.0))
+        );
+        assert_eq!(intersection, bvh::NodeIntersection::Missed);
+    }
+
+    fn create_shapes(count: usize) -> Vec<Primitive> {
+        (0 .. count).map(|i| {
+            let (x, y, z) = (i % 25, (i / 25) % 20, i / 500);
+            create_shape(Vec3::init(x as f32 * 3.0, y as f32 * 3.0, z as f32 * -3.0))
+        }).collect()
+    }
+
+    #[bench]
+    fn build_tree_of_10000_spheres(b: &mut Bencher) {
+        let shapes = create_shapes(10000);
+        b.iter(|| {
+            let mut tree = bvh::Tree::new();
+            tree.init(shapes.clone());
+            tree
+        })
+    }
+
+    #[bench]
+    fn build_lbvh_of_10000_spheres(b: &mut Bencher) {
+        let shapes = create_shapes(10000);
+        b.iter(|| {
+            let mut tree = bvh::Tree::new();
+            tree.init_lbvh(shapes.clone());
+            tree
+        })
+    }
+
     #[bench]
     fn name(b: &mut Bencher) {
         let shapes = vec!(
@@ -283,4 +781,29 @@ mod tests {
         let ray = Ray::init(Vec3::init(2.0, 2.0, 2.0), Vec3::init(0.0, 0.0, -1.0));
         b.iter(|| tree.intersects(&ray))
     }
+
+    #[bench]
+    fn intersect_tree_of_size_4_with_oblique_rays(b: &mut Bencher) {
+        let shapes = vec!(
+            create_shape(Vec3::init(0.0, 0.0, 0.0)),
+            create_shape(Vec3::init(-1.0, 2.0, 1.0)),
+            create_shape(Vec3::init(-2.0, -2.0, 2.0)),
+            create_shape(Vec3::init(2.0, 2.0, -1.0))
+        );
+
+        let mut tree = bvh::Tree::new();
+        tree.init(shapes);
+
+        // Every box test has to go through all three slabs
+        let rays: Vec<Ray> = (0 .. 16).map(|i| {
+            let mut dir = Vec3::init(-1.0 + i as f32 * 0.1, -0.5, -1.0);
+            dir.normalize();
+            Ray::init(Vec3::init(2.0, 3.0, 4.0), dir)
+        }).collect();
+        b.iter(|| {
+            for ray in rays.iter() {
+                black_box(tree.intersects(ray));
+            }
+        })
+    }
 }
@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use vec::Vec3;
+use scene::Scene;
+use scene::material::{Material, Color};
+use scene::shapes::Primitive;
+use scene::shapes::mesh::Mesh;
+use scene::shapes::poly::Vertex;
+
+// Indices of the position, texture coordinate and normal of a face vertex
+pub type VTNIndex = (usize, Option<usize>, Option<usize>);
+
+// A named group of faces, started by an `o` line
+pub struct Object {
+    pub name: String,
+    pub shapes: Vec<Vec<VTNIndex>>
+}
+
+// The contents of an OBJ file. Indices are shared between all objects of the file.
+pub struct ObjSet {
+    pub positions: Vec<Vec3>,
+    pub tex_coords: Vec<(f32, f32)>,
+    pub normals: Vec<Vec3>,
+    pub objects: Vec<Object>
+}
+
+fn parse_num<T: FromStr>(word: Option<&str>) -> Result<T, String> {
+    match word {
+        Some(word) => match word.parse() {
+            Ok(num) => Ok(num),
+            Err(_) => Err(format!("Could not represent token as num: '{}'", word))
+        },
+        None => Err("Expected a number at the end of the line".to_string())
+    }
+}
+
+// Parses a face vertex, given as `v`, `v/vt`, `v//vn` or `v/vt/vn` with 1-based indices
+fn parse_vtn(word: &str) -> Result<VTNIndex, String> {
+    let parts: Vec<&str> = word.split('/').collect();
+    let index = |i: usize| -> Result<Option<usize>, String> {
+        match parts.get(i) {
+            Some(part) if part.len() > 0 => match part.parse::<usize>() {
+                Ok(index) if index > 0 => Ok(Some(index - 1)),
+                _ => Err(format!("Invalid index in face: '{}'", word))
+            },
+            _ => Ok(None)
+        }
+    };
+
+    match try!(index(0)) {
+        Some(v) => Ok((v, try!(index(1)), try!(index(2)))),
+        None => Err(format!("Face is missing a position index: '{}'", word))
+    }
+}
+
+pub fn parse_obj(contents: &str) -> Result<ObjSet, String> {
+    let mut set = ObjSet {
+        positions: Vec::new(),
+        tex_coords: Vec::new(),
+        normals: Vec::new(),
+        objects: Vec::new()
+    };
+
+    for line in contents.lines() {
+        let line = line.trim();
+        let mut words = line.split(|c: char| c.is_whitespace()).filter(|word| word.len() > 0);
+        match words.next() {
+            Some("v") => set.positions.push(Vec3::init(try!(parse_num(words.next())),
+                try!(parse_num(words.next())), try!(parse_num(words.next())))),
+            Some("vn") => set.normals.push(Vec3::init(try!(parse_num(words.next())),
+                try!(parse_num(words.next())), try!(parse_num(words.next())))),
+            Some("vt") => set.tex_coords.push((try!(parse_num(words.next())),
+                try!(parse_num(words.next())))),
+            Some("o") => set.objects.push(Object {
+                name: line[1 ..].trim().to_string(),
+                shapes: Vec::new()
+            }),
+            Some("f") => {
+                let mut shape = Vec::new();
+                for word in words {
+                    shape.push(try!(parse_vtn(word)));
+                }
+                if set.objects.len() == 0 { // Faces before the first object
+                    set.objects.push(Object { name: "default".to_string(), shapes: Vec::new() });
+                }
+                let last = set.objects.len() - 1;
+                set.objects[last].shapes.push(shape);
+            },
+            _ => () // Comments, groups, smoothing and materials are ignored
+        }
+    }
+
+    for object in set.objects.iter() {
+        for &(v, t, n) in object.shapes.iter().flat_map(|shape| shape.iter()) {
+            let t_valid = t.map_or(true, |t| t < set.tex_coords.len());
+            let n_valid = n.map_or(true, |n| n < set.normals.len());
+            if v >= set.positions.len() || !t_valid || !n_valid {
+                return Err(format!("Face index out of bounds in object '{}'", object.name));
+            }
+        }
+    }
+    Ok(set)
+}
+
+// Vertices without a normal in the file get their normal from `smooth`, when it is given
+fn convert_vtindex(set: &ObjSet, vt: VTNIndex, smooth: Option<&[Vec3]>) -> Vertex {
+    let mut vertex = Vertex::init(set.positions[vt.0]);
+    match vt.1 {
+        Some(t) => vertex.uv = set.tex_coords[t],
+        None => ()
+    }
+    match (vt.2, smooth) {
+        (Some(n), _) => {
+            vertex.normal = set.normals[n];
+            vertex.has_normal = true;
+        },
+        (None, Some(normals)) if normals[vt.0].length() > 0.0 => {
+            vertex.normal = normals[vt.0];
+            vertex.has_normal = true;
+        },
+        _ => ()
+    }
+    vertex
+}
+
+// The normal of each position, averaged from the faces around it. The faces are weighted by
+// their area, so small slivers don't tilt the normals of the large faces next to them.
+fn smooth_normals(set: &ObjSet) -> Vec<Vec3> {
+    let mut normals: Vec<Vec3> = set.positions.iter().map(|_| Vec3::new()).collect();
+    for object in set.objects.iter() {
+        for shape in object.shapes.iter() {
+            for triangle in convert_shape(&shape[..]).iter() {
+                let (a, b, c) = (set.positions[triangle[0].0], set.positions[triangle[1].0],
+                    set.positions[triangle[2].0]);
+                // The length of the cross product is twice the area of the triangle
+                let normal = (b - a).cross(c - a);
+                for vt in triangle.iter() {
+                    normals[vt.0].add_assign(normal);
+                }
+            }
+        }
+    }
+    for normal in normals.iter_mut() {
+        if normal.length() > 0.0 {
+            normal.normalize();
+        }
+    }
+    normals
+}
+
+// Splits a face into a fan of triangles around its first vertex, which keeps the winding of the
+// face. Quads and other convex faces are split correctly, faces with less than 3 vertices give no
+// triangles.
+fn convert_shape(shape: &[VTNIndex]) -> Vec<[VTNIndex; 3]> {
+    match shape.len() {
+        0 ... 2 => Vec::new(),
+        n => (1 .. n - 1).map(|i| [shape[0], shape[i], shape[i + 1]]).collect()
+    }
+}
+
+// Converts each object to its own mesh, where the faces share the vertices they have in common
+fn convert_objects(set: &ObjSet, material: Material, smooth: Option<&[Vec3]>) -> Vec<Mesh> {
+    set.objects.iter().map(|object| {
+        let mut vertices = Vec::new();
+        let mut polys = Vec::new();
+        let mut indices: HashMap<VTNIndex, usize> = HashMap::new();
+        let triangles = object.shapes.iter().flat_map(|shape| convert_shape(&shape[..]).into_iter());
+        for triangle in triangles {
+            let mut poly = [0; 3];
+            for (i, vt) in triangle.iter().enumerate() {
+                let found = indices.get(vt).map(|&index| index);
+                poly[i] = match found {
+                    Some(index) => index,
+                    None => {
+                        let index = vertices.len();
+                        vertices.push(convert_vtindex(set, *vt, smooth));
+                        indices.insert(*vt, index);
+                        index
+                    }
+                };
+            }
+            polys.push((poly, material.clone()));
+        }
+        Mesh::init(object.name.clone(), vertices, polys)
+    }).collect()
+}
+
+pub fn obj_to_scene(set: &ObjSet) -> Scene {
+    build_scene(set, None)
+}
+
+// Like `obj_to_scene`, but vertices without a normal get the average normal of the faces around
+// them, so meshes that are meant to be smooth are not shaded flat
+pub fn obj_to_scene_smooth(set: &ObjSet) -> Scene {
+    build_scene(set, Some(&smooth_normals(set)[..]))
+}
+
+fn build_scene(set: &ObjSet, smooth: Option<&[Vec3]>) -> Scene {
+    let mut scene = Scene::new();
+    let material = Material::init(Color::init(1.0, 1.0, 1.0));
+
+    for mesh in convert_objects(set, material, smooth).into_iter() {
+        let mesh = Arc::new(mesh);
+        scene.primitives.push(Primitive::Mesh(mesh.clone()));
+        scene.meshes.push(mesh);
+    }
+    scene
+}
+
+fn read_obj(path: &str) -> Result<ObjSet, String> {
+    let mut contents = String::new();
+    match File::open(path) {
+        Ok(mut f) => match f.read_to_string(&mut contents) {
+            Ok(_) => parse_obj(contents.as_slice()),
+            Err(e) => Err(format!("file error: {}, path: {}", e, path))
+        },
+        Err(e) => Err(format!("file error: {}, path: {}", e, path))
+    }
+}
+
+pub fn parse_obj_scene(path: &str) -> Result<Scene, String> {
+    Ok(obj_to_scene(&try!(read_obj(path))))
+}
+
+pub fn parse_obj_scene_smooth(path: &str) -> Result<Scene, String> {
+    Ok(obj_to_scene_smooth(&try!(read_obj(path))))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Float;
+
+    use vec::Vec3;
+    use ray::Ray;
+    use scene::{IntersectableScene, SceneIntersection};
+    use scene::shapes::Primitive;
+    use scene::from_obj::{parse_obj, parse_obj_scene, parse_obj_scene_smooth};
+
+    static TEST_PATH: &'static str = "src/scene/from_obj/testdata-";
+
+    #[test]
+    fn can_parse_face_indices() {
+        let set = parse_obj("
+            v 0 0 0
+            v 1 0 0
+            v 0 1 0
+            vt 0.5 0.5
+            vn 0 0 1
+            f 1/1/1 2//1 3
+        ").unwrap();
+
+        assert_eq!(3, set.positions.len());
+        assert_eq!(1, set.objects.len());
+        assert_eq!(vec!((0, Some(0), Some(0)), (1, None, Some(0)), (2, None, None)),
+            set.objects[0].shapes[0]);
+    }
+
+    #[test]
+    fn rejects_invalid_faces() {
+        assert!(parse_obj("v 0 0 0\nf 1 2 3").is_err());
+        assert!(parse_obj("v 0 0 0\nf 0 1 1").is_err());
+        assert!(parse_obj("v 0 zero 0").is_err());
+    }
+
+    #[test]
+    fn keeps_objects_as_separate_meshes() {
+        let scene = parse_obj_scene(&(TEST_PATH.to_string() + "two-objects.obj")).unwrap();
+
+        assert_eq!(2, scene.meshes.len());
+        assert_eq!(2, scene.primitives.len());
+        let floor = scene.mesh("floor").unwrap();
+        let marker = scene.mesh("marker").unwrap();
+        assert_eq!(2, floor.polys.len());
+        assert_eq!(4, floor.vertices.len());
+        assert_eq!(1, marker.polys.len());
+        assert!(scene.mesh("missing").is_none());
+
+        let ray = Ray::init(Vec3::init(0.5, 1.0, -0.25), Vec3::init(0.0, -1.0, 0.0));
+        match scene.intersects(&ray) {
+            SceneIntersection::Intersected(intersection) =>
+                assert_eq!(Vec3::init(0.5, 0.0, -0.25), intersection.point()),
+            SceneIntersection::Missed => panic!("Ray should hit the floor")
+        }
+    }
+
+    #[test]
+    fn splits_quads_into_two_triangles() {
+        let scene = parse_obj_scene(&(TEST_PATH.to_string() + "cube-quads.obj")).unwrap();
+
+        let cube = scene.mesh("cube").unwrap();
+        assert_eq!(6 * 2, cube.polys.len());
+        // Each corner has a normal for each of its 3 faces
+        assert_eq!(8 * 3, cube.vertices.len());
+
+        // The faces are wound counter-clockwise seen from outside, which the triangles keep
+        for poly in cube.polys.iter() {
+            let normal = match Primitive::MeshPoly(poly.clone()).geometric_normal() {
+                Some(normal) => normal,
+                None => panic!("The cube should only have polys")
+            };
+            let center = poly.vertices.iter().fold(Vec3::new(), |sum, v| sum + v.position)
+                .mult(1.0 / 3.0);
+            assert!(normal.dot(center) > 0.0);
+        }
+    }
+
+    #[test]
+    fn smooth_import_averages_the_normals_of_shared_vertices() {
+        let path = TEST_PATH.to_string() + "sphere-no-normals.obj";
+        let flat = parse_obj_scene(&path).unwrap();
+        assert!(flat.mesh("sphere").unwrap().vertices.iter().all(|vertex| !vertex.has_normal));
+
+        let scene = parse_obj_scene_smooth(&path).unwrap();
+        let sphere = scene.mesh("sphere").unwrap();
+        assert_eq!(18, sphere.vertices.len());
+        for vertex in sphere.vertices.iter() {
+            assert!(vertex.has_normal);
+            assert!((vertex.normal.length() - 1.0).abs() < 1.0e-5);
+            // On a sphere around origo, the normal points away from the center
+            assert!(vertex.normal.dot(vertex.position) > 0.9, "{:?} at {:?}", vertex.normal,
+                vertex.position);
+        }
+    }
+}
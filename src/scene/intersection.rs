@@ -1,30 +1,51 @@
 use std::num::Float;
-use ray::Ray;
+use ray::{Ray, VACUUM_IOR};
 use vec::Vec3;
 use scene::shapes::{Primitive, Shape};
+use scene::shapes::poly;
 use scene::material;
 
+// Offsets used to lift secondary rays off the surface they start on. The relative part grows
+// with the hit distance, as f32 precision drops the further away the hit is.
+static ABSOLUTE_BIAS: f32 = 0.0001;
+static RELATIVE_BIAS: f32 = 0.00001;
+
 pub struct Intersection<'a> {
     point: f32,
     ray: Ray,
-    prim: &'a Primitive
+    prim: &'a Primitive,
+    id: usize
 }
 
 impl<'a> Intersection<'a> {
-    pub fn new(point: f32, ray: Ray, prim: &'a Primitive) -> Intersection<'a> {
+    pub fn new(point: f32, ray: Ray, prim: &'a Primitive, id: usize) -> Intersection<'a> {
         Intersection {
             point: point,
             ray: ray,
-            prim: prim
+            prim: prim,
+            id: id
         }
     }
 
+    // The index of the hit primitive in the primitives of the Scene it was built from
+    pub fn primitive_id(&self) -> usize {
+        self.id
+    }
+
+    pub fn distance(&self) -> f32 {
+        self.point
+    }
+
+    pub fn bias(&self) -> f32 {
+        ABSOLUTE_BIAS.max(RELATIVE_BIAS * self.point)
+    }
+
     pub fn direction(&self) -> Vec3 {
-        self.ray.dir
+        self.ray.dir()
     }
 
     pub fn point(&self) -> Vec3 {
-        self.ray.ori + self.ray.dir.mult(self.point)
+        self.ray.at(self.point)
     }
 
     pub fn color(&self) -> material::Color {
@@ -36,26 +57,72 @@ impl<'a> Intersection<'a> {
     }
 
     pub fn surface_normal(&self) -> Vec3 {
-        self.prim.surface_normal(self.ray.dir, self.point())
+        self.prim.surface_normal(self.ray.dir(), self.point())
     }
 
-    pub fn reflective_ray(&self) -> Ray {
+    // An orthonormal basis of tangent, bitangent and normal at the hit point. The tangent of
+    // polys follows their texture coordinate u when it can, other shapes get an arbitrary one.
+    pub fn tangent_frame(&self) -> (Vec3, Vec3, Vec3) {
         let normal = self.surface_normal();
-        let d0 = self.ray.dir.invert();
-        let origin = self.point() + normal.mult(0.0001);
-        let direction = normal.mult(d0.dot(normal) * 2.0) - d0;
-        Ray::init(origin, direction)
+        let tangent = match self.prim {
+            &Primitive::Poly(ref p) => poly::uv_tangent(p[0].position, p[1].position,
+                p[2].position, p[0].uv, p[1].uv, p[2].uv),
+            &Primitive::MeshPoly(ref p) => poly::uv_tangent(p.vertices[0].position,
+                p.vertices[1].position, p.vertices[2].position, p.vertices[0].uv,
+                p.vertices[1].uv, p.vertices[2].uv),
+            _ => None
+        };
+
+        // Removes the part of the tangent along the normal, as vertex normals may be bent
+        let tangent = tangent.map(|t| t - normal.mult(normal.dot(t)));
+        match tangent {
+            Some(mut tangent) if tangent.length() > 1.0e-6 => {
+                tangent.normalize();
+                (tangent, normal.cross(tangent), normal)
+            },
+            _ => {
+                let (tangent, bitangent) = normal.orthonormal_basis();
+                (tangent, bitangent, normal)
+            }
+        }
+    }
+
+    // A ray leaving the hit surface, which skips the primitive it starts on if it is planar. For
+    // meshes only the hit poly is skipped.
+    pub fn surface_ray(&self, origin: Vec3, direction: Vec3) -> Ray {
+        let mut ray = Ray::init(origin, direction);
+        ray.set_medium(self.ray.medium());
+        match self.prim {
+            &Primitive::MeshPoly(ref p) => ray.exclude(self.id, Some(p.index)),
+            prim if prim.is_planar() => ray.exclude(self.id, None),
+            _ => ()
+        }
+        ray
+    }
+
+    pub fn reflective_ray(&self) -> Ray {
+        self.reflective_ray_from(self.surface_normal())
+    }
+
+    // Same as `reflective_ray`, for when the surface normal has already been computed
+    pub fn reflective_ray_from(&self, normal: Vec3) -> Ray {
+        let origin = self.point() + normal.mult(self.bias());
+        self.surface_ray(origin, self.ray.dir().reflect(normal))
     }
 
     pub fn refractive_ray(&self) -> Option<Ray> {
-        let in_dir = self.ray.dir;
-        let mut normal = self.surface_normal();
+        self.refractive_ray_from(self.surface_normal())
+    }
 
-        // Calculate medium index, only switching between air and glass for now
-        let n: f32 = if self.ray.in_vacuum() {
-            1.0 / 1.5
-        } else {
-            1.5 / 1.0
+    // Same as `refractive_ray`, for when the surface normal has already been computed
+    pub fn refractive_ray_from(&self, mut normal: Vec3) -> Option<Ray> {
+        let in_dir = self.ray.dir();
+
+        // Rays in vacuum enter the material, other rays leave their medium for vacuum
+        let ior = self.material().refractive_index;
+        let n: f32 = match self.ray.in_vacuum() {
+            true => VACUUM_IOR / ior,
+            false => self.ray.ior() / VACUUM_IOR
         };
 
         let cos_in = normal.dot(in_dir);
@@ -74,9 +141,120 @@ impl<'a> Intersection<'a> {
 
             let direction: Vec3 = term1 - normal.mult(cos_phi);
             let origin = self.point() - normal.mult(0.01);
-            let ray = Ray::init(origin, direction);
-            ray.switch_medium();
+            let ray = self.surface_ray(origin, direction);
+            ray.switch_medium(ior);
             Some(ray)
         }
     }
+
+    pub fn fresnel(&self) -> f32 {
+        self.fresnel_from(self.surface_normal())
+    }
+
+    // The part of the light hitting a transparent surface that is reflected rather than refracted,
+    // after Schlick's approximation. It grows toward 1.0 as the ray grazes the surface, and is 1.0
+    // when the ray is totally internally reflected.
+    pub fn fresnel_from(&self, normal: Vec3) -> f32 {
+        let ior = self.material().refractive_index;
+        let (n1, n2) = match self.ray.in_vacuum() {
+            true => (VACUUM_IOR, ior),
+            false => (self.ray.ior(), VACUUM_IOR)
+        };
+        let r0 = ((n1 - n2) / (n1 + n2)).powi(2);
+
+        let mut dir = self.ray.dir();
+        dir.normalize();
+        let mut cos = normal.dot(dir).abs();
+        if n1 > n2 { // Leaving the denser medium, the refracted angle decides
+            let sin_t2 = (n1 / n2).powi(2) * (1.0 - cos * cos);
+            if sin_t2 > 1.0 {
+                return 1.0;
+            }
+            cos = (1.0 - sin_t2).sqrt();
+        }
+        r0 + (1.0 - r0) * (1.0 - cos).powi(5)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Float;
+
+    use vec::Vec3;
+    use ray::Ray;
+    use scene::{IntersectableScene, Scene};
+    use scene::SceneIntersection::{Intersected, Missed};
+    use scene::shapes::Primitive;
+    use scene::shapes::poly::Poly;
+    use scene::shapes::sphere::Sphere;
+
+    fn assert_orthonormal(frame: (Vec3, Vec3, Vec3)) {
+        let (t, b, n) = frame;
+        for v in [t, b, n].iter() {
+            assert!((v.length() - 1.0).abs() < 1.0e-5);
+        }
+        assert!(t.dot(b).abs() < 1.0e-5);
+        assert!(t.dot(n).abs() < 1.0e-5);
+        assert!(b.dot(n).abs() < 1.0e-5);
+    }
+
+    fn frame_at(scene: &Scene, ray: Ray) -> (Vec3, Vec3, Vec3) {
+        match scene.intersects(&ray) {
+            Intersected(intersection) => intersection.tangent_frame(),
+            Missed => panic!("Ray should hit the scene")
+        }
+    }
+
+    #[test]
+    fn glass_reflects_more_at_grazing_angles() {
+        let mut sphere = Sphere::init(Vec3::init(0.0, 0.0, -3.0), 1.0);
+        sphere.materials[0].transparency = 1.0;
+        let mut scene = Scene::new();
+        scene.primitives.push(Primitive::Sphere(sphere));
+
+        let fresnel_at = |x: f32| {
+            let ray = Ray::init(Vec3::init(x, 0.0, 0.0), Vec3::init(0.0, 0.0, -1.0));
+            match scene.intersects(&ray) {
+                Intersected(intersection) => intersection.fresnel(),
+                Missed => panic!("Ray should hit the sphere")
+            }
+        };
+
+        // Head-on, glass of index 1.5 reflects 4% of the light
+        let head_on = fresnel_at(0.0);
+        assert!((head_on - 0.04).abs() < 1.0e-5);
+        let edge_on = fresnel_at(0.99);
+        assert!(edge_on > 0.3);
+        assert!(edge_on <= 1.0);
+    }
+
+    #[test]
+    fn tangent_frame_of_sphere_is_orthonormal() {
+        let mut scene = Scene::new();
+        scene.primitives.push(Primitive::Sphere(Sphere::init(Vec3::init(0.0, 0.0, -3.0), 1.0)));
+
+        let mut dir = Vec3::init(0.2, -0.3, -1.0);
+        dir.normalize();
+        assert_orthonormal(frame_at(&scene, Ray::init(Vec3::new(), dir)));
+    }
+
+    #[test]
+    fn tangent_frame_of_poly_follows_texture_coordinates() {
+        // The poly lies in the plane z = -2, with u growing along -y and v along x
+        let mut poly = Poly::init();
+        poly.vertices[0].position = Vec3::init(0.0, 0.0, -2.0);
+        poly.vertices[1].position = Vec3::init(0.0, -1.0, -2.0);
+        poly.vertices[2].position = Vec3::init(1.0, 0.0, -2.0);
+        poly.vertices[1].uv = (1.0, 0.0);
+        poly.vertices[2].uv = (0.0, 1.0);
+        let mut scene = Scene::new();
+        scene.primitives.push(Primitive::Poly(poly));
+
+        let frame = frame_at(&scene, Ray::init(Vec3::init(0.25, -0.25, 0.0),
+            Vec3::init(0.0, 0.0, -1.0)));
+        assert_orthonormal(frame);
+        let (tangent, _, normal) = frame;
+        assert!(tangent.distance(Vec3::init(0.0, -1.0, 0.0)) < 1.0e-5);
+        assert!(normal.distance(Vec3::init(0.0, 0.0, 1.0)) < 1.0e-5);
+    }
 }
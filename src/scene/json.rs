@@ -0,0 +1,796 @@
+use std::iter::Peekable;
+use std::num::Float;
+use std::str::Chars;
+use std::sync::Arc;
+
+use vec::Vec3;
+use mat::Mat4;
+use scene::{Scene, Camera, Light, PointLight, AreaLight, TriangleLight,
+    DirectionalLight, SpotLight};
+use scene::material::{Material, Checkerboard, Color, ColorBuf, ImageTexture, Texture};
+use scene::shapes::{sphere, poly, plane, cylinder, disk, torus, Primitive};
+use self::Json::{Null, Bool, Number, Text, Array, Object};
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f32),
+    Text(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>)
+}
+
+impl Json {
+    pub fn encode(&self) -> String {
+        let mut buf = String::new();
+        self.write(&mut buf);
+        buf
+    }
+
+    fn write(&self, buf: &mut String) {
+        match self {
+            &Null => buf.push_str("null"),
+            &Bool(b) => buf.push_str(if b { "true" } else { "false" }),
+            &Number(n) => buf.push_str(&format!("{}", n)),
+            &Text(ref s) => Json::write_str(s, buf),
+            &Array(ref values) => {
+                buf.push('[');
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        buf.push(',');
+                    }
+                    value.write(buf);
+                }
+                buf.push(']');
+            },
+            &Object(ref members) => {
+                buf.push('{');
+                for (i, &(ref key, ref value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        buf.push(',');
+                    }
+                    Json::write_str(key, buf);
+                    buf.push(':');
+                    value.write(buf);
+                }
+                buf.push('}');
+            }
+        }
+    }
+
+    fn write_str(s: &str, buf: &mut String) {
+        buf.push('"');
+        for c in s.chars() {
+            match c {
+                '"' => buf.push_str("\\\""),
+                '\\' => buf.push_str("\\\\"),
+                '\n' => buf.push_str("\\n"),
+                '\t' => buf.push_str("\\t"),
+                c => buf.push(c)
+            }
+        }
+        buf.push('"');
+    }
+
+    fn get(&self, key: &str) -> Result<&Json, String> {
+        match self {
+            &Object(ref members) => match members.iter().find(|&&(ref k, _)| k.as_slice() == key) {
+                Some(&(_, ref value)) => Ok(value),
+                None => Err(format!("Missing key: '{}'", key))
+            },
+            _ => Err(format!("Expected an object when looking up '{}'", key))
+        }
+    }
+
+    fn as_f32(&self) -> Result<f32, String> {
+        match self {
+            &Number(n) => Ok(n),
+            other => Err(format!("Expected a number, found: {:?}", other))
+        }
+    }
+
+    fn as_bool(&self) -> Result<bool, String> {
+        match self {
+            &Bool(b) => Ok(b),
+            other => Err(format!("Expected a bool, found: {:?}", other))
+        }
+    }
+
+    fn as_str(&self) -> Result<&str, String> {
+        match self {
+            &Text(ref s) => Ok(s.as_slice()),
+            other => Err(format!("Expected a string, found: {:?}", other))
+        }
+    }
+
+    fn as_array(&self) -> Result<&[Json], String> {
+        match self {
+            &Array(ref values) => Ok(values.as_slice()),
+            other => Err(format!("Expected an array, found: {:?}", other))
+        }
+    }
+
+    fn as_triple(&self) -> Result<(f32, f32, f32), String> {
+        let values = try!(self.as_array());
+        if values.len() != 3 {
+            return Err(format!("Expected an array of 3 numbers, found {}", values.len()));
+        }
+        Ok((try!(values[0].as_f32()), try!(values[1].as_f32()), try!(values[2].as_f32())))
+    }
+
+    fn as_vec3(&self) -> Result<Vec3, String> {
+        let (x, y, z) = try!(self.as_triple());
+        Ok(Vec3::init(x, y, z))
+    }
+
+    // Four rows of four numbers
+    fn as_mat4(&self) -> Result<Mat4, String> {
+        let rows = try!(self.as_array());
+        if rows.len() != 4 {
+            return Err(format!("Expected 4 rows of a matrix, found {}", rows.len()));
+        }
+        let mut mat = Mat4::new();
+        for (i, row) in rows.iter().enumerate() {
+            let values = try!(row.as_array());
+            if values.len() != 4 {
+                return Err(format!("Expected a matrix row of 4 numbers, found {}", values.len()));
+            }
+            for (j, value) in values.iter().enumerate() {
+                mat.m[i][j] = try!(value.as_f32());
+            }
+        }
+        Ok(mat)
+    }
+
+    fn as_color(&self) -> Result<Color, String> {
+        let (r, g, b) = try!(self.as_triple());
+        Ok(Color::init(r, g, b))
+    }
+
+    fn as_intensity(&self) -> Result<ColorBuf, String> {
+        let (r, g, b) = try!(self.as_triple());
+        Ok(ColorBuf::init(r, g, b))
+    }
+}
+
+struct JsonParser<'a> {
+    chars: Peekable<Chars<'a>>
+}
+
+impl<'a> JsonParser<'a> {
+    fn new(s: &'a str) -> JsonParser<'a> {
+        JsonParser {
+            chars: s.chars().peekable()
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().map(|&c| c)
+    }
+
+    fn skip_whitespace(&mut self) {
+        loop {
+            match self.peek() {
+                Some(c) if c.is_whitespace() => { self.chars.next(); },
+                _ => return
+            }
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        self.skip_whitespace();
+        match self.chars.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(format!("Expected '{}', found '{}'", expected, c)),
+            None => Err(format!("Expected '{}', found end of input", expected))
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('{') => self.parse_object(),
+            Some('[') => self.parse_array(),
+            Some('"') => Ok(Text(try!(self.parse_string()))),
+            Some('t') => self.parse_literal("true", Bool(true)),
+            Some('f') => self.parse_literal("false", Bool(false)),
+            Some('n') => self.parse_literal("null", Null),
+            Some(c) if c == '-' || c.is_digit(10) => self.parse_number(),
+            Some(c) => Err(format!("Unexpected character: '{}'", c)),
+            None => Err("Unexpected end of input".to_string())
+        }
+    }
+
+    fn parse_literal(&mut self, literal: &str, value: Json) -> Result<Json, String> {
+        for expected in literal.chars() {
+            match self.chars.next() {
+                Some(c) if c == expected => (),
+                _ => return Err(format!("Invalid literal, expected '{}'", literal))
+            }
+        }
+        Ok(value)
+    }
+
+    fn parse_number(&mut self) -> Result<Json, String> {
+        let mut buf = String::new();
+        loop {
+            match self.peek() {
+                Some(c) if c.is_digit(10) || c == '-' || c == '+' || c == '.' || c == 'e' || c == 'E' => {
+                    buf.push(c);
+                    self.chars.next();
+                },
+                _ => break
+            }
+        }
+        match buf.as_slice().parse() {
+            Ok(n) => Ok(Number(n)),
+            Err(_) => Err(format!("Could not represent token as num: '{}'", buf))
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String, String> {
+        try!(self.expect('"'));
+        let mut buf = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(buf),
+                Some('\\') => match self.chars.next() {
+                    Some('n') => buf.push('\n'),
+                    Some('t') => buf.push('\t'),
+                    Some(c) if c == '"' || c == '\\' || c == '/' => buf.push(c),
+                    _ => return Err("Unsupported escape sequence in string".to_string())
+                },
+                Some(c) => buf.push(c),
+                None => return Err("Unterminated string".to_string())
+            }
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<Json, String> {
+        try!(self.expect('['));
+        let mut values = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.chars.next();
+            return Ok(Array(values));
+        }
+        loop {
+            values.push(try!(self.parse_value()));
+            self.skip_whitespace();
+            match self.chars.next() {
+                Some(',') => (),
+                Some(']') => return Ok(Array(values)),
+                _ => return Err("Expected ',' or ']' in array".to_string())
+            }
+        }
+    }
+
+    fn parse_object(&mut self) -> Result<Json, String> {
+        try!(self.expect('{'));
+        let mut members = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.chars.next();
+            return Ok(Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = try!(self.parse_string());
+            try!(self.expect(':'));
+            members.push((key, try!(self.parse_value())));
+            self.skip_whitespace();
+            match self.chars.next() {
+                Some(',') => (),
+                Some('}') => return Ok(Object(members)),
+                _ => return Err("Expected ',' or '}' in object".to_string())
+            }
+        }
+    }
+}
+
+fn object(members: Vec<(&str, Json)>) -> Json {
+    Object(members.into_iter().map(|(key, value)| (key.to_string(), value)).collect())
+}
+
+fn encode_vec3(v: Vec3) -> Json {
+    Array(vec!(Number(v.x), Number(v.y), Number(v.z)))
+}
+
+fn encode_color(c: Color) -> Json {
+    Array(vec!(Number(c.r_val()), Number(c.g_val()), Number(c.b_val())))
+}
+
+fn encode_intensity(c: ColorBuf) -> Json {
+    Array(vec!(Number(c.r), Number(c.g), Number(c.b)))
+}
+
+fn encode_camera(camera: &Camera) -> Json {
+    object(vec!(
+        ("position", encode_vec3(camera.pos)),
+        ("view_direction", encode_vec3(camera.view_dir)),
+        ("focal_distance", Number(camera.focal_dist)),
+        ("ortho_up", encode_vec3(camera.ortho_up)),
+        ("vertical_fov", Number(camera.vertical_fov)),
+        ("aspect", Number(camera.aspect)),
+        ("near", Number(camera.near)),
+        ("far", if camera.far.is_finite() { Number(camera.far) } else { Null }),
+        ("transform", Array(camera.transform.m.iter().map(|row|
+            Array(row.iter().map(|&v| Number(v)).collect())).collect()))
+    ))
+}
+
+fn encode_light(light: &Light) -> Json {
+    match light {
+        &Light::Point(ref light) => object(vec!(
+            ("type", Text("point".to_string())),
+            ("position", encode_vec3(light.pos)),
+            ("color", encode_intensity(light.intensity)),
+            ("enabled", Bool(light.enabled))
+        )),
+        &Light::Area(ref light) => object(vec!(
+            ("type", Text("area".to_string())),
+            ("min", encode_vec3(light.min)),
+            ("max", encode_vec3(light.max)),
+            ("color", encode_intensity(light.intensity)),
+            ("enabled", Bool(light.enabled))
+        )),
+        &Light::Triangle(ref light) => object(vec!(
+            ("type", Text("triangle".to_string())),
+            ("v0", encode_vec3(light.v0)),
+            ("v1", encode_vec3(light.v1)),
+            ("v2", encode_vec3(light.v2)),
+            ("color", encode_intensity(light.intensity)),
+            ("enabled", Bool(light.enabled))
+        )),
+        &Light::Directional(ref light) => object(vec!(
+            ("type", Text("directional".to_string())),
+            ("direction", encode_vec3(light.dir)),
+            ("color", encode_intensity(light.intensity)),
+            ("toward_light", Bool(light.toward_light)),
+            ("enabled", Bool(light.enabled))
+        )),
+        &Light::Spot(ref light) => object(vec!(
+            ("type", Text("spot".to_string())),
+            ("position", encode_vec3(light.pos)),
+            ("direction", encode_vec3(light.dir)),
+            ("color", encode_intensity(light.intensity)),
+            ("inner_angle", Number(light.inner_angle)),
+            ("outer_angle", Number(light.outer_angle)),
+            ("enabled", Bool(light.enabled))
+        ))
+    }
+}
+
+fn encode_material(material: &Material) -> Json {
+    object(vec!(
+        ("diffuse", encode_color(material.diffuse)),
+        ("ambient", encode_color(material.ambient)),
+        ("specular", encode_color(material.specular)),
+        ("emissive", encode_color(material.emissive)),
+        ("shininess", Number(material.shininess)),
+        ("transparency", Number(material.transparency)),
+        ("refractive_index", Number(material.refractive_index)),
+        ("anisotropy", Number(material.anisotropy)),
+        ("tangent", encode_vec3(material.tangent)),
+        ("roughness", Number(material.roughness)),
+        ("checker", match material.texture {
+            Some(Texture::Checker(ref checker)) => object(vec!(
+                ("color_a", encode_color(checker.color_a)),
+                ("color_b", encode_color(checker.color_b)),
+                ("scale", Number(checker.scale))
+            )),
+            _ => Null
+        }),
+        ("image", match material.texture {
+            Some(Texture::Image(ref image)) => {
+                let (width, height) = image.size();
+                object(vec!(
+                    ("width", Number(width as f32)),
+                    ("height", Number(height as f32)),
+                    ("texels", Array(image.texels().iter().map(|&c| encode_color(c)).collect()))
+                ))
+            },
+            _ => Null
+        })
+    ))
+}
+
+fn encode_vertex(vertex: &poly::Vertex) -> Json {
+    let (u, v) = vertex.uv;
+    object(vec!(
+        ("position", encode_vec3(vertex.position)),
+        ("normal", encode_vec3(vertex.normal)),
+        ("has_normal", Bool(vertex.has_normal)),
+        ("material_index", Number(vertex.mat_index as f32)),
+        ("uv", Array(vec!(Number(u), Number(v))))
+    ))
+}
+
+// Only spheres and polys are exported, volumes and mesh polys are skipped
+// Meshes, instances and volumes have no JSON form, so scenes with them can't be exported
+fn encode_primitive(prim: &Primitive) -> Result<Json, String> {
+    match prim {
+        &Primitive::Sphere(ref sphere) => Ok(object(vec!(
+            ("type", Text("sphere".to_string())),
+            ("materials", Array(sphere.materials.iter().map(encode_material).collect())),
+            ("origin", encode_vec3(sphere.origin)),
+            ("radius", Number(sphere.radius))
+        ))),
+        &Primitive::Poly(ref poly) => Ok(object(vec!(
+            ("type", Text("poly".to_string())),
+            ("materials", Array(poly.materials.iter().map(encode_material).collect())),
+            ("vertices", Array(poly.vertices.iter().map(encode_vertex).collect())),
+            ("vertex_material", Bool(poly.vertex_material)),
+            ("vertex_normal", Bool(poly.vertex_normal))
+        ))),
+        &Primitive::Plane(ref plane) => Ok(object(vec!(
+            ("type", Text("plane".to_string())),
+            ("material", encode_material(&plane.material)),
+            ("point", encode_vec3(plane.point)),
+            ("normal", encode_vec3(plane.normal))
+        ))),
+        &Primitive::Cylinder(ref cylinder) => Ok(object(vec!(
+            ("type", Text("cylinder".to_string())),
+            ("material", encode_material(&cylinder.material)),
+            ("base", encode_vec3(cylinder.base)),
+            ("axis", encode_vec3(cylinder.axis)),
+            ("radius", Number(cylinder.radius)),
+            ("height", Number(cylinder.height))
+        ))),
+        &Primitive::Disk(ref disk) => Ok(object(vec!(
+            ("type", Text("disk".to_string())),
+            ("material", encode_material(&disk.material)),
+            ("center", encode_vec3(disk.center)),
+            ("normal", encode_vec3(disk.normal)),
+            ("radius", Number(disk.radius))
+        ))),
+        &Primitive::Torus(ref torus) => Ok(object(vec!(
+            ("type", Text("torus".to_string())),
+            ("material", encode_material(&torus.material)),
+            ("center", encode_vec3(torus.center)),
+            ("axis", encode_vec3(torus.axis)),
+            ("major_radius", Number(torus.major_radius)),
+            ("minor_radius", Number(torus.minor_radius))
+        ))),
+        &Primitive::Mesh(_) | &Primitive::MeshPoly(_) =>
+            Err("Meshes can't be exported to JSON".to_string()),
+        &Primitive::Instance(_) => Err("Instances can't be exported to JSON".to_string()),
+        &Primitive::Volume(_) => Err("Volumes can't be exported to JSON".to_string())
+    }
+}
+
+pub fn to_json(scene: &Scene) -> Result<String, String> {
+    let mut primitives = Vec::with_capacity(scene.primitives.len());
+    for prim in scene.primitives.iter() {
+        primitives.push(try!(encode_primitive(prim)));
+    }
+    Ok(object(vec!(
+        ("camera", encode_camera(&scene.camera)),
+        ("lights", Array(scene.lights.iter().map(encode_light).collect())),
+        ("primitives", Array(primitives))
+    )).encode())
+}
+
+fn decode_camera(json: &Json) -> Result<Camera, String> {
+    Ok(Camera {
+        pos: try!(try!(json.get("position")).as_vec3()),
+        view_dir: try!(try!(json.get("view_direction")).as_vec3()),
+        focal_dist: try!(try!(json.get("focal_distance")).as_f32()),
+        ortho_up: try!(try!(json.get("ortho_up")).as_vec3()),
+        vertical_fov: try!(try!(json.get("vertical_fov")).as_f32()),
+        aspect: match json.get("aspect") {
+            Ok(aspect) => try!(aspect.as_f32()),
+            Err(_) => 1.0
+        },
+        near: match json.get("near") {
+            Ok(near) => try!(near.as_f32()),
+            Err(_) => 0.0
+        },
+        far: match json.get("far") {
+            Ok(&Null) | Err(_) => Float::infinity(),
+            Ok(far) => try!(far.as_f32())
+        },
+        transform: match json.get("transform") {
+            Ok(transform) => try!(transform.as_mat4()),
+            Err(_) => Mat4::new()
+        }
+    })
+}
+
+fn decode_light(json: &Json) -> Result<Light, String> {
+    let intensity = try!(try!(json.get("color")).as_intensity());
+    let enabled = match json.get("enabled") {
+        Ok(enabled) => try!(enabled.as_bool()),
+        Err(_) => true
+    };
+    match try!(try!(json.get("type")).as_str()) {
+        "point" => Ok(Light::Point(PointLight {
+            pos: try!(try!(json.get("position")).as_vec3()),
+            intensity: intensity,
+            enabled: enabled
+        })),
+        "area" => Ok(Light::Area(AreaLight {
+            min: try!(try!(json.get("min")).as_vec3()),
+            max: try!(try!(json.get("max")).as_vec3()),
+            intensity: intensity,
+            enabled: enabled
+        })),
+        "triangle" => Ok(Light::Triangle(TriangleLight {
+            v0: try!(try!(json.get("v0")).as_vec3()),
+            v1: try!(try!(json.get("v1")).as_vec3()),
+            v2: try!(try!(json.get("v2")).as_vec3()),
+            intensity: intensity,
+            enabled: enabled
+        })),
+        "directional" => Ok(Light::Directional(DirectionalLight {
+            dir: try!(try!(json.get("direction")).as_vec3()),
+            intensity: intensity,
+            toward_light: match json.get("toward_light") {
+                Ok(toward_light) => try!(toward_light.as_bool()),
+                Err(_) => false
+            },
+            enabled: enabled
+        })),
+        "spot" => Ok(Light::Spot(SpotLight {
+            pos: try!(try!(json.get("position")).as_vec3()),
+            dir: try!(try!(json.get("direction")).as_vec3()),
+            intensity: intensity,
+            inner_angle: try!(try!(json.get("inner_angle")).as_f32()),
+            outer_angle: try!(try!(json.get("outer_angle")).as_f32()),
+            enabled: enabled
+        })),
+        other => Err(format!("LightType is not valid: {}", other))
+    }
+}
+
+fn decode_texture(json: &Json) -> Result<Option<Texture>, String> {
+    match json.get("checker") {
+        Ok(&Null) | Err(_) => (),
+        Ok(checker) => return Ok(Some(Texture::Checker(Checkerboard::init(
+            try!(try!(checker.get("color_a")).as_color()),
+            try!(try!(checker.get("color_b")).as_color()),
+            try!(try!(checker.get("scale")).as_f32())))))
+    }
+    match json.get("image") {
+        Ok(&Null) | Err(_) => Ok(None),
+        Ok(image) => {
+            let width = try!(try!(image.get("width")).as_f32()) as u32;
+            let height = try!(try!(image.get("height")).as_f32()) as u32;
+            let mut texels = Vec::new();
+            for texel in try!(try!(image.get("texels")).as_array()).iter() {
+                texels.push(try!(texel.as_color()));
+            }
+            if texels.len() != (width * height) as usize || texels.is_empty() {
+                return Err(format!("A {}x{} texture can't have {} texels",
+                    width, height, texels.len()));
+            }
+            Ok(Some(Texture::Image(Arc::new(ImageTexture::init(width, height, texels)))))
+        }
+    }
+}
+
+fn decode_material(json: &Json) -> Result<Material, String> {
+    let mut material = Material {
+        diffuse: try!(try!(json.get("diffuse")).as_color()),
+        ambient: try!(try!(json.get("ambient")).as_color()),
+        specular: try!(try!(json.get("specular")).as_color()),
+        emissive: try!(try!(json.get("emissive")).as_color()),
+        shininess: try!(try!(json.get("shininess")).as_f32()),
+        transparency: try!(try!(json.get("transparency")).as_f32()),
+        refractive_index: match json.get("refractive_index") {
+            Ok(ior) => try!(ior.as_f32()),
+            Err(_) => 1.5
+        },
+        anisotropy: match json.get("anisotropy") {
+            Ok(anisotropy) => try!(anisotropy.as_f32()),
+            Err(_) => 0.0
+        },
+        tangent: match json.get("tangent") {
+            Ok(tangent) => try!(tangent.as_vec3()),
+            Err(_) => Vec3::new()
+        },
+        roughness: match json.get("roughness") {
+            Ok(roughness) => try!(roughness.as_f32()),
+            Err(_) => 0.0
+        },
+        texture: try!(decode_texture(json))
+    };
+    material.validate();
+    Ok(material)
+}
+
+fn decode_materials(json: &Json) -> Result<Vec<Material>, String> {
+    let mut materials = Vec::new();
+    for material in try!(try!(json.get("materials")).as_array()).iter() {
+        materials.push(try!(decode_material(material)));
+    }
+    Ok(materials)
+}
+
+fn decode_vertex(json: &Json) -> Result<poly::Vertex, String> {
+    let uv = try!(try!(json.get("uv")).as_array());
+    if uv.len() != 2 {
+        return Err(format!("Expected an array of 2 texture coordinates, found {}", uv.len()));
+    }
+
+    let mut vertex = poly::Vertex::init(try!(try!(json.get("position")).as_vec3()));
+    vertex.normal = try!(try!(json.get("normal")).as_vec3());
+    vertex.has_normal = try!(try!(json.get("has_normal")).as_bool());
+    vertex.mat_index = try!(try!(json.get("material_index")).as_f32()) as u32;
+    vertex.uv = (try!(uv[0].as_f32()), try!(uv[1].as_f32()));
+    Ok(vertex)
+}
+
+fn decode_primitive(json: &Json) -> Result<Primitive, String> {
+    match try!(try!(json.get("type")).as_str()) {
+        "sphere" => {
+            let mut sphere = sphere::Sphere::new();
+            sphere.materials = try!(decode_materials(json));
+            sphere.origin = try!(try!(json.get("origin")).as_vec3());
+            sphere.radius = try!(try!(json.get("radius")).as_f32());
+            Ok(Primitive::Sphere(sphere))
+        },
+        "poly" => {
+            let vertices = try!(try!(json.get("vertices")).as_array());
+            if vertices.len() != 3 {
+                return Err(format!("Expected a poly with 3 vertices, found {}", vertices.len()));
+            }
+
+            let mut poly = poly::Poly::new();
+            poly.materials = try!(decode_materials(json));
+            poly.vertices = [
+                try!(decode_vertex(&vertices[0])),
+                try!(decode_vertex(&vertices[1])),
+                try!(decode_vertex(&vertices[2]))
+            ];
+            poly.vertex_material = try!(try!(json.get("vertex_material")).as_bool());
+            poly.vertex_normal = try!(try!(json.get("vertex_normal")).as_bool());
+            Ok(Primitive::Poly(poly))
+        },
+        "plane" => {
+            let mut plane = plane::Plane::new();
+            plane.material = try!(decode_material(try!(json.get("material"))));
+            plane.point = try!(try!(json.get("point")).as_vec3());
+            plane.normal = try!(try!(json.get("normal")).as_vec3());
+            Ok(Primitive::Plane(plane))
+        },
+        "cylinder" => {
+            let mut cylinder = cylinder::Cylinder::new();
+            cylinder.material = try!(decode_material(try!(json.get("material"))));
+            cylinder.base = try!(try!(json.get("base")).as_vec3());
+            cylinder.axis = try!(try!(json.get("axis")).as_vec3());
+            cylinder.radius = try!(try!(json.get("radius")).as_f32());
+            cylinder.height = try!(try!(json.get("height")).as_f32());
+            Ok(Primitive::Cylinder(cylinder))
+        },
+        "disk" => {
+            let mut disk = disk::Disk::new();
+            disk.material = try!(decode_material(try!(json.get("material"))));
+            disk.center = try!(try!(json.get("center")).as_vec3());
+            disk.normal = try!(try!(json.get("normal")).as_vec3());
+            disk.radius = try!(try!(json.get("radius")).as_f32());
+            Ok(Primitive::Disk(disk))
+        },
+        "torus" => {
+            let mut torus = torus::Torus::new();
+            torus.material = try!(decode_material(try!(json.get("material"))));
+            torus.center = try!(try!(json.get("center")).as_vec3());
+            torus.axis = try!(try!(json.get("axis")).as_vec3());
+            torus.major_radius = try!(try!(json.get("major_radius")).as_f32());
+            torus.minor_radius = try!(try!(json.get("minor_radius")).as_f32());
+            Ok(Primitive::Torus(torus))
+        },
+        other => Err(format!("PrimitiveType is not valid: {}", other))
+    }
+}
+
+pub fn from_json(s: &str) -> Result<Scene, String> {
+    let mut parser = JsonParser::new(s);
+    let json = try!(parser.parse_value());
+    parser.skip_whitespace();
+    if parser.peek().is_some() {
+        return Err("Unexpected trailing characters after scene".to_string());
+    }
+
+    let mut scene = Scene::new();
+    scene.camera = try!(decode_camera(try!(json.get("camera"))));
+    for light in try!(try!(json.get("lights")).as_array()).iter() {
+        scene.lights.push(try!(decode_light(light)));
+    }
+    for prim in try!(try!(json.get("primitives")).as_array()).iter() {
+        scene.primitives.push(try!(decode_primitive(prim)));
+    }
+    Ok(scene)
+}
+
+#[cfg(test)]
+mod tests {
+    use vec::Vec3;
+    use mat::Mat4;
+    use scene::json::{from_json, to_json};
+    use scene::parser::SceneParser;
+    use scene::{Light, Scene};
+    use scene::material::{Color, Material};
+    use scene::shapes::{BoundingBox, Primitive, Shape};
+    use scene::shapes::plane::Plane;
+    use scene::shapes::cylinder::Cylinder;
+    use scene::shapes::disk::Disk;
+    use scene::shapes::torus::Torus;
+    use scene::shapes::volume::FogVolume;
+
+    #[test]
+    fn can_round_trip_scene_through_json() {
+        let mut parser = SceneParser::new("src/scene/parser/test/testdata-scene.txt".to_string());
+        let mut scene = parser.parse_scene().unwrap();
+        scene.camera.transform = Mat4::translation(Vec3::init(1.0, 2.0, 3.0));
+
+        let json = match to_json(&scene) {
+            Ok(json) => json,
+            Err(e) => panic!("Could not export scene: {}", e)
+        };
+        let parsed = match from_json(json.as_slice()) {
+            Ok(scene) => scene,
+            Err(e) => panic!("Could not parse exported scene: {}", e)
+        };
+
+        assert_eq!(scene.lights.len(), parsed.lights.len());
+        assert_eq!(scene.primitives.len(), parsed.primitives.len());
+        assert_eq!(scene.camera.transform, parsed.camera.transform);
+        match parsed.lights[2] {
+            Light::Directional(_) => (),
+            _ => panic!("The third light should be directional")
+        }
+    }
+
+    #[test]
+    fn can_round_trip_image_texture_through_json() {
+        let path = "src/scene/parser/test/testdata-textured-sphere.txt".to_string();
+        let sphere = SceneParser::new(path).parse_sphere().unwrap();
+        let north = sphere.origin + Vec3::init(0.0, sphere.radius, 0.0);
+        let mut scene = Scene::new();
+        scene.primitives.push(Primitive::Sphere(sphere));
+
+        let parsed = match to_json(&scene).and_then(|json| from_json(json.as_slice())) {
+            Ok(scene) => scene,
+            Err(e) => panic!("Could not round trip scene: {}", e)
+        };
+        let color = parsed.primitives[0].diffuse_color(north);
+        assert!((color.r_val() - 0.5).abs() < 1.0e-4 && (color.g_val() - 0.5).abs() < 1.0e-4);
+    }
+
+    #[test]
+    fn can_round_trip_analytic_shapes_through_json() {
+        let mut scene = Scene::new();
+        let up = Vec3::init(0.0, 1.0, 0.0);
+        let mut torus = Torus::init(Vec3::init(0.0, 1.0, -4.0), up, 1.0, 0.25);
+        torus.material = Material::init(Color::init(0.0, 0.0, 1.0));
+        scene.primitives.push(Primitive::Plane(Plane::init(Vec3::new(), up)));
+        scene.primitives.push(Primitive::Cylinder(
+            Cylinder::init(Vec3::init(1.0, 0.0, -3.0), up, 0.5, 2.0)));
+        scene.primitives.push(Primitive::Disk(
+            Disk::init(Vec3::init(-1.0, 0.5, -3.0), Vec3::init(0.0, 0.0, 1.0), 0.75)));
+        scene.primitives.push(Primitive::Torus(torus));
+
+        let parsed = match to_json(&scene).and_then(|json| from_json(json.as_slice())) {
+            Ok(scene) => scene,
+            Err(e) => panic!("Could not round trip scene: {}", e)
+        };
+        assert_eq!(scene.primitives, parsed.primitives);
+    }
+
+    #[test]
+    fn refuses_to_export_primitives_without_a_json_form() {
+        let mut scene = Scene::new();
+        let volume = FogVolume::init(BoundingBox::init(Vec3::new(), Vec3::init(1.0, 1.0, 1.0)),
+            0.5, Color::init(1.0, 1.0, 1.0));
+        scene.primitives.push(Primitive::Volume(volume));
+        assert_eq!(Err("Volumes can't be exported to JSON".to_string()), to_json(&scene));
+    }
+
+    #[test]
+    fn rejects_malformed_json() {
+        assert!(from_json("{\"camera\": [1, 2").is_err());
+        assert!(from_json("{\"lights\": []}").is_err());
+    }
+}
@@ -1,6 +1,10 @@
+use std::cmp;
 use std::num::Float;
-use bmp::Pixel;
+use bmp::{self, Image, Pixel};
 use std::ops::{Add, Mul};
+use std::sync::Arc;
+
+use vec::Vec3;
 
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub struct Color {
@@ -54,6 +58,11 @@ impl Color {
         (self.r * self.r + self.g * self.g + self.b * self.b).sqrt()
     }
 
+    // Relative luminance, using the Rec. 709 weights
+    pub fn luminance(&self) -> f32 {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
+
     pub fn as_pixel(&self) -> Pixel {
         Pixel{
             r: (self.r * 255.0) as u8,
@@ -62,9 +71,34 @@ impl Color {
         }
     }
 
+    // Like `as_pixel`, but raises each channel to 1 / gamma first, which brightens the dark
+    // tones of linear colors the way screens expect
+    pub fn to_gamma_pixel(&self, gamma: f32) -> Pixel {
+        let inv = 1.0 / gamma;
+        Color::init(self.r.powf(inv), self.g.powf(inv), self.b.powf(inv)).as_pixel()
+    }
+
     pub fn mult(&self, num: f32) -> Color {
         Color::init(self.r * num, self.g * num, self.b * num)
     }
+
+    // The cell of a grid with cells of size `epsilon` that this color lies in, see
+    // `Vec3::quantized_key`
+    pub fn quantized_key(&self, epsilon: f32) -> (i64, i64, i64) {
+        let inv = 1.0 / epsilon;
+        ((self.r * inv).floor() as i64, (self.g * inv).floor() as i64,
+            (self.b * inv).floor() as i64)
+    }
+
+    // Screen blending brightens like adding the colors would, but never goes above 1.0. The
+    // opposite, multiply blending, is given by `Mul`.
+    pub fn screen(&self, other: Color) -> Color {
+        Color::init(
+            1.0 - (1.0 - self.r) * (1.0 - other.r),
+            1.0 - (1.0 - self.g) * (1.0 - other.g),
+            1.0 - (1.0 - self.b) * (1.0 - other.b)
+        )
+    }
 }
 
 impl Mul for Color {
@@ -83,14 +117,223 @@ impl Add for Color {
     }
 }
 
+// A color whose channels are not clamped, used for light that can be brighter than 1.0. It
+// becomes a `Color` when it is converted with `to_color`.
 #[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ColorBuf {
+    pub r: f32,
+    pub g: f32,
+    pub b: f32
+}
+
+impl ColorBuf {
+    pub fn new() -> ColorBuf {
+        ColorBuf{ r: 0.0, g: 0.0, b: 0.0 }
+    }
+
+    pub fn init(r: f32, g: f32, b: f32) -> ColorBuf {
+        ColorBuf{ r: r, g: g, b: b }
+    }
+
+    pub fn from_color(color: Color) -> ColorBuf {
+        ColorBuf::init(color.r, color.g, color.b)
+    }
+
+    pub fn to_color(&self) -> Color {
+        Color::init(self.r, self.g, self.b)
+    }
+
+    pub fn mult(&self, num: f32) -> ColorBuf {
+        ColorBuf::init(self.r * num, self.g * num, self.b * num)
+    }
+
+    // An in place version of `+`, like `Vec3::add_assign`
+    pub fn add_assign(&mut self, col: ColorBuf) {
+        self.r += col.r;
+        self.g += col.g;
+        self.b += col.b;
+    }
+
+    pub fn luminance(&self) -> f32 {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
+
+    // Whether no channel is NaN or infinite
+    pub fn is_finite(&self) -> bool {
+        self.r.is_finite() && self.g.is_finite() && self.b.is_finite()
+    }
+}
+
+impl Mul<Color> for ColorBuf {
+    type Output = ColorBuf;
+
+    fn mul(self, col: Color) -> ColorBuf {
+        ColorBuf::init(self.r * col.r, self.g * col.g, self.b * col.b)
+    }
+}
+
+impl Add for ColorBuf {
+    type Output = ColorBuf;
+
+    fn add(self, col: ColorBuf) -> ColorBuf {
+        ColorBuf::init(self.r + col.r, self.g + col.g, self.b + col.b)
+    }
+}
+
+// Batch operations for post-processing a whole image. They do plain arithmetic on the unclamped
+// channels so the loops can be vectorized, and the colors are only clamped once, when they are
+// converted with `to_color`.
+
+pub fn apply_exposure(buf: &mut [ColorBuf], e: f32) {
+    for c in buf.iter_mut() {
+        c.r *= e;
+        c.g *= e;
+        c.b *= e;
+    }
+}
+
+pub fn apply_gamma(buf: &mut [ColorBuf], g: f32) {
+    let inv = 1.0 / g;
+    for c in buf.iter_mut() {
+        // Negative channels would turn into NaN
+        c.r = c.r.max(0.0).powf(inv);
+        c.g = c.g.max(0.0).powf(inv);
+        c.b = c.b.max(0.0).powf(inv);
+    }
+}
+
+// Maps each channel `c` to `c / (1 + c)`
+pub fn tonemap_reinhard(buf: &mut [ColorBuf]) {
+    for c in buf.iter_mut() {
+        c.r = c.r / (1.0 + c.r);
+        c.g = c.g / (1.0 + c.g);
+        c.b = c.b / (1.0 + c.b);
+    }
+}
+
+// A procedural texture of squares with sides of length `scale`, alternating between the two
+// colors along x and z in world space
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Checkerboard {
+    pub color_a: Color,
+    pub color_b: Color,
+    pub scale: f32
+}
+
+impl Checkerboard {
+    pub fn init(color_a: Color, color_b: Color, scale: f32) -> Checkerboard {
+        Checkerboard {
+            color_a: color_a,
+            color_b: color_b,
+            scale: scale
+        }
+    }
+
+    pub fn color_at(&self, point: Vec3) -> Color {
+        let squares = (point.x / self.scale).floor() as i64 + (point.z / self.scale).floor() as i64;
+        match squares % 2 == 0 {
+            true => self.color_a,
+            false => self.color_b
+        }
+    }
+}
+
+// An image wrapped around a shape by the texture coordinates (u, v), where (0, 0) is the top left
+// corner of the image
+#[derive(Clone, PartialEq, Debug)]
+pub struct ImageTexture {
+    width: u32,
+    height: u32,
+    texels: Vec<Color>
+}
+
+impl ImageTexture {
+    // The texels are given row by row, starting with the top row
+    pub fn init(width: u32, height: u32, texels: Vec<Color>) -> ImageTexture {
+        if texels.len() != (width * height) as usize || texels.len() == 0 {
+            panic!("A {}x{} texture can't have {} texels", width, height, texels.len());
+        }
+        ImageTexture {
+            width: width,
+            height: height,
+            texels: texels
+        }
+    }
+
+    pub fn from_image(image: &Image) -> ImageTexture {
+        let (width, height) = (image.get_width(), image.get_height());
+        let mut texels = Vec::with_capacity((width * height) as usize);
+        for y in 0 .. height {
+            for x in 0 .. width {
+                let pixel = image.get_pixel(x, y);
+                texels.push(Color::init(pixel.r as f32 / 255.0, pixel.g as f32 / 255.0,
+                    pixel.b as f32 / 255.0));
+            }
+        }
+        ImageTexture::init(width, height, texels)
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    pub fn texels(&self) -> &[Color] {
+        &self.texels[..]
+    }
+
+    pub fn open(path: &str) -> Result<ImageTexture, String> {
+        match bmp::open(path) {
+            Ok(image) => Ok(ImageTexture::from_image(&image)),
+            Err(e) => Err(format!("Could not open the texture {}: {}", path, e))
+        }
+    }
+
+    // Blends the four texels around (u, v). The image repeats along u and is clamped along v,
+    // which suits textures wrapped around spheres.
+    pub fn color_at(&self, u: f32, v: f32) -> Color {
+        let (w, h) = (self.width as i64, self.height as i64);
+        let x = u * self.width as f32 - 0.5;
+        let y = v.max(0.0).min(1.0) * self.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        let texel = |x: i64, y: i64| {
+            let x = ((x % w) + w) % w;
+            let y = cmp::max(0, cmp::min(h - 1, y));
+            self.texels[(y * w + x) as usize]
+        };
+        let top = texel(x0, y0).mult(1.0 - fx) + texel(x0 + 1, y0).mult(fx);
+        let bottom = texel(x0, y0 + 1).mult(1.0 - fx) + texel(x0 + 1, y0 + 1).mult(fx);
+        top.mult(1.0 - fy) + bottom.mult(fy)
+    }
+}
+
+// The kinds of texture a material can take its diffuse color from
+#[derive(Clone, PartialEq, Debug)]
+pub enum Texture {
+    Checker(Checkerboard),
+    // Shared, so that copies of the material don't copy the texels
+    Image(Arc<ImageTexture>)
+}
+
+#[derive(Clone, PartialEq, Debug)]
 pub struct Material {
     pub diffuse: Color,
     pub ambient: Color,
     pub specular: Color,
     pub emissive: Color,
     pub shininess: f32,
-    pub transparency: f32
+    pub transparency: f32,
+    pub refractive_index: f32,
+    pub texture: Option<Texture>,
+    // How much the highlight is stretched along the tangent, from 0 for round highlights to 1.
+    // A zero tangent means the tangent of the surface is used.
+    pub anisotropy: f32,
+    pub tangent: Vec3,
+    // How blurry reflections are, from 0 for a perfect mirror to 1 for reflected rays spread over
+    // the whole hemisphere
+    pub roughness: f32
 }
 
 impl Material {
@@ -101,7 +344,12 @@ impl Material {
             specular: Color::new(),
             emissive: Color::new(),
             shininess: 0.0,
-            transparency: 0.0
+            transparency: 0.0,
+            refractive_index: 1.5,
+            texture: None,
+            anisotropy: 0.0,
+            tangent: Vec3::new(),
+            roughness: 0.0
         }
     }
 
@@ -111,6 +359,50 @@ impl Material {
         material
     }
 
+    // The diffuse color at the world space `point`, taken from the checker if there is one.
+    // Image textures need texture coordinates, see `diffuse_at_uv`.
+    pub fn diffuse_at(&self, point: Vec3) -> Color {
+        match self.texture {
+            Some(Texture::Checker(ref checker)) => checker.color_at(point),
+            _ => self.diffuse
+        }
+    }
+
+    // The diffuse color at `point`, with an image texture sampled at the texture coordinate (u, v)
+    pub fn diffuse_at_uv(&self, point: Vec3, u: f32, v: f32) -> Color {
+        match self.texture {
+            Some(Texture::Image(ref image)) => image.color_at(u, v),
+            _ => self.diffuse_at(point)
+        }
+    }
+
+    // Used in place of a missing material, so that authoring errors stand out in renders
+    pub fn debug() -> Material {
+        Material::init(Color::init(1.0, 0.0, 1.0))
+    }
+
+    // The magenta and black checker a debug material shows at the texture coordinate (u, v)
+    pub fn debug_color(u: f32, v: f32) -> Color {
+        let squares = (u * 8.0).floor() as i32 + (v * 8.0).floor() as i32;
+        match squares % 2 == 0 {
+            true => Material::debug().diffuse,
+            false => Color::new()
+        }
+    }
+
+    // Clamps the fields that would otherwise produce NaN or over-bright shading. The comparisons
+    // are negated, so NaN fields are reset as well.
+    pub fn validate(&mut self) {
+        if !(self.shininess >= 0.0) { self.shininess = 0.0; }
+        if !(self.transparency >= 0.0) { self.transparency = 0.0; }
+        if self.transparency > 1.0 { self.transparency = 1.0; }
+        if !(self.refractive_index > 0.0) { self.refractive_index = 1.0; }
+        if !(self.anisotropy >= 0.0) { self.anisotropy = 0.0; }
+        if self.anisotropy > 1.0 { self.anisotropy = 1.0; }
+        if !(self.roughness >= 0.0) { self.roughness = 0.0; }
+        if self.roughness > 1.0 { self.roughness = 1.0; }
+    }
+
     pub fn is_reflective(&self) -> bool {
         self.specular.scalar() > 0.0
     }
@@ -122,7 +414,19 @@ impl Material {
 
 #[cfg(test)]
 mod tests {
-    use scene::material::{Color, Material};
+    use std::num::Float;
+
+    use scene::material::{Color, ColorBuf, Material, ImageTexture, apply_exposure, apply_gamma,
+        tonemap_reinhard};
+
+    // Channels from 0.0 to 2.0, so that some are out of range
+    fn ramp() -> Vec<ColorBuf> {
+        (0 .. 16).map(|i| {
+            let v = i as f32 / 15.0;
+            ColorBuf::init(2.0 * v, 2.0 - 2.0 * v, v)
+        }).collect()
+    }
+
     #[test]
     fn color_is_0(){
         let c = Color::new();
@@ -132,6 +436,16 @@ mod tests {
         assert!(c.b == 0.0);
     }
 
+    #[test]
+    fn near_colors_share_quantized_key() {
+        let a = Color::init(0.2501, 0.5001, 0.7501);
+        let b = Color::init(0.2504, 0.5004, 0.7504);
+        let c = Color::init(0.2501, 0.5201, 0.7501);
+        assert_eq!((250, 500, 750), a.quantized_key(0.001));
+        assert_eq!(a.quantized_key(0.001), b.quantized_key(0.001));
+        assert!(a.quantized_key(0.001) != c.quantized_key(0.001));
+    }
+
     #[test]
     fn color_is_between_0_and_1(){
         let mut c = Color::new();
@@ -143,6 +457,31 @@ mod tests {
         assert!(c.b == 0.0);
     }
 
+    #[test]
+    fn can_get_luminance_of_color(){
+        assert_eq!(0.0, Color::new().luminance());
+        assert!((Color::init(1.0, 1.0, 1.0).luminance() - 1.0).abs() < 1.0e-6);
+        assert!(Color::init(0.0, 1.0, 0.0).luminance() > Color::init(1.0, 0.0, 0.0).luminance());
+    }
+
+    #[test]
+    fn can_blend_colors(){
+        let gray = Color::init(0.5, 0.5, 0.5);
+        assert_eq!(Color::init(0.75, 0.75, 0.75), gray.screen(gray));
+        assert_eq!(Color::init(0.25, 0.25, 0.25), gray * gray);
+    }
+
+    #[test]
+    fn gamma_brightens_mid_gray(){
+        let half: f32 = 0.5;
+        let gray = Color::init(half, half, half);
+        let pixel = gray.to_gamma_pixel(2.2);
+        assert_eq!((half.powf(1.0 / 2.2) * 255.0) as u8, pixel.r);
+        assert_eq!(186, pixel.r); // 0.7297 before quantization
+        assert!(pixel.g > gray.as_pixel().g);
+        assert_eq!(gray.as_pixel(), gray.to_gamma_pixel(1.0));
+    }
+
     #[test]
     fn material_is_reflective(){
         let mut mat = Material::new();
@@ -156,4 +495,75 @@ mod tests {
         mat.transparency = 0.5;
         assert!(mat.is_refractive());
     }
+
+    #[test]
+    fn material_can_be_validated(){
+        let mut mat = Material::new();
+        mat.shininess = -0.5;
+        mat.transparency = 1.5;
+        mat.validate();
+        assert_eq!(mat.shininess, 0.0);
+        assert_eq!(mat.transparency, 1.0);
+
+        let nan: f32 = Float::nan();
+        mat.shininess = nan;
+        mat.transparency = nan;
+        mat.refractive_index = nan;
+        mat.roughness = nan;
+        mat.validate();
+        assert_eq!(mat.shininess, 0.0);
+        assert_eq!(mat.transparency, 0.0);
+        assert_eq!(mat.refractive_index, 1.0);
+        assert_eq!(mat.roughness, 0.0);
+    }
+
+    #[test]
+    fn batch_exposure_matches_per_pixel_exposure(){
+        let mut buf = ramp();
+        apply_exposure(&mut buf[..], 1.7);
+        for (batch, color) in buf.iter().zip(ramp().iter()) {
+            assert_eq!(color.mult(1.7), *batch);
+        }
+    }
+
+    #[test]
+    fn batch_gamma_matches_per_pixel_gamma(){
+        let mut buf = ramp();
+        apply_gamma(&mut buf[..], 2.2);
+        for (batch, c) in buf.iter().zip(ramp().iter()) {
+            let g = 1.0 / 2.2;
+            assert_eq!(ColorBuf::init(c.r.powf(g), c.g.powf(g), c.b.powf(g)), *batch);
+        }
+        assert!(buf[4].r > ramp()[4].r);
+    }
+
+    #[test]
+    fn batch_tonemap_matches_per_pixel_tonemap(){
+        let mut buf = ramp();
+        tonemap_reinhard(&mut buf[..]);
+        for (batch, c) in buf.iter().zip(ramp().iter()) {
+            let expected = ColorBuf::init(c.r / (1.0 + c.r), c.g / (1.0 + c.g), c.b / (1.0 + c.b));
+            assert_eq!(expected, *batch);
+        }
+        assert_eq!(ColorBuf::init(2.0 / 3.0, 0.0, 0.5), buf[15]);
+    }
+
+    #[test]
+    fn exposed_colors_are_not_clamped_before_tone_mapping(){
+        let mut buf = vec!(ColorBuf::init(0.5, 1.0, 0.0));
+        apply_exposure(&mut buf[..], 4.0);
+        tonemap_reinhard(&mut buf[..]);
+        assert_eq!(Color::init(2.0 / 3.0, 0.8, 0.0), buf[0].to_color());
+    }
+
+    #[test]
+    fn image_texture_blends_texels_and_wraps_along_u() {
+        let (black, white) = (Color::new(), Color::init(1.0, 1.0, 1.0));
+        let texture = ImageTexture::init(2, 1, vec!(black, white));
+        let gray = Color::init(0.5, 0.5, 0.5);
+        assert_eq!(black, texture.color_at(0.25, 0.5));
+        assert_eq!(white, texture.color_at(0.75, 0.0));
+        assert_eq!(gray, texture.color_at(0.5, 1.0));
+        assert_eq!(gray, texture.color_at(0.0, 0.5));
+    }
 }
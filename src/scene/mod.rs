@@ -1,75 +1,141 @@
-use rand::{random, Open01};
+use rand::{Open01, Rng};
+use std::cmp;
+use std::mem;
 use std::num::Float;
+use std::sync::Arc;
 
 use vec::Vec3;
+use mat::Mat4;
 use ray::Ray;
 use scene::bvh::{NodeIntersection, Tree};
-use scene::material::Color;
-use scene::shapes::{Shape, ShapeIntersection};
+use scene::material::{Color, ColorBuf};
+use scene::shapes::{Primitive, Shape};
+use scene::shapes::mesh::Mesh;
+use scene::stats::{Stat, StatCounters};
 use scene::intersection::Intersection;
 use self::SceneIntersection::{Intersected, Missed};
-use self::Light::{Point, Area, Directional};
+use self::Light::{Point, Area, Triangle, Directional, Spot};
 
 pub mod parser;
 pub mod material;
 pub mod shapes;
 pub mod intersection;
 pub mod bvh;
+pub mod json;
+pub mod from_obj;
+pub mod stats;
 
 #[derive(Copy, PartialEq, Clone, Debug)]
 pub enum Light {
     Point(PointLight),
     Area(AreaLight),
-    Directional(DirectionalLight)
+    Triangle(TriangleLight),
+    Directional(DirectionalLight),
+    Spot(SpotLight)
 }
 
 impl Light {
-    pub fn intensity(&self) -> Color {
+    pub fn intensity(&self) -> ColorBuf {
         match self {
             &Point(ref light) => light.intensity,
             &Area(ref light) => light.intensity,
-            &Directional(ref light) => light.intensity
+            &Triangle(ref light) => light.intensity,
+            &Directional(ref light) => light.intensity,
+            &Spot(ref light) => light.intensity
         }
     }
 
-    pub fn position(&self) -> Vec3 {
+    // Disabled lights stay in the scene, but do not light it
+    pub fn is_enabled(&self) -> bool {
+        match self {
+            &Point(ref light) => light.enabled,
+            &Area(ref light) => light.enabled,
+            &Triangle(ref light) => light.enabled,
+            &Directional(ref light) => light.enabled,
+            &Spot(ref light) => light.enabled
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        match self {
+            &mut Point(ref mut light) => light.enabled = enabled,
+            &mut Area(ref mut light) => light.enabled = enabled,
+            &mut Triangle(ref mut light) => light.enabled = enabled,
+            &mut Directional(ref mut light) => light.enabled = enabled,
+            &mut Spot(ref mut light) => light.enabled = enabled
+        }
+    }
+
+    // Where the light is, drawing the samples of area lights from `rng`
+    pub fn position_with<R: Rng>(&self, rng: &mut R) -> Vec3 {
         match self {
             &Point(ref light) => light.pos,
-            &Area(ref light) => light.sample_point(),
-            &Directional(_) => Vec3::new()
+            &Area(ref light) => light.sample_point_with(rng),
+            &Triangle(ref light) => light.sample_point_with(rng),
+            &Directional(_) => Vec3::new(),
+            &Spot(ref light) => light.pos
         }
     }
 
-    pub fn get_dir(&self, point: Vec3) -> Vec3 {
+    // The direction from `point` toward the light, drawing the samples of area lights from `rng`
+    pub fn get_dir_with<R: Rng>(&self, point: Vec3, rng: &mut R) -> Vec3 {
         match self {
-            &Light::Directional(ref light) => {
-                light.dir.invert()
+            &Light::Directional(ref light) => match light.toward_light {
+                true => light.dir,
+                false => light.dir.invert()
             },
-            &Light::Point(ref light) => {
-                let mut dir = light.pos - point;
+            _ => {
+                let mut dir = self.position_with(rng) - point;
                 dir.normalize();
                 dir
-            },
-            &Light::Area(ref light) => {
-                let mut dir = light.sample_point() - point;
+            }
+        }
+    }
+
+    // Like `get_dir_with`, but area lights are sampled at the `i`th of `n` stratified points
+    pub fn get_dir_stratified_with<R: Rng>(&self, point: Vec3, i: usize, n: usize,
+                                           rng: &mut R) -> Vec3 {
+        match self {
+            &Light::Directional(_) => self.get_dir_with(point, rng),
+            _ => {
+                let mut dir = self.position_stratified_with(i, n, rng) - point;
                 dir.normalize();
                 dir
             }
         }
     }
+
+    // Like `position_with`, but area lights are sampled at the `i`th of `n` stratified points
+    pub fn position_stratified_with<R: Rng>(&self, i: usize, n: usize, rng: &mut R) -> Vec3 {
+        match self {
+            &Area(ref light) => light.sample_stratified_with(i, n, rng),
+            _ => self.position_with(rng)
+        }
+    }
+
+    // The part of the intensity that reaches `point`, which is less than 1.0 only outside of the
+    // inner cone of spot lights
+    pub fn falloff(&self, point: Vec3) -> f32 {
+        match self {
+            &Spot(ref light) => light.falloff(point),
+            _ => 1.0
+        }
+    }
 }
 
 #[derive(Copy, PartialEq, Clone, Debug)]
 pub struct PointLight {
     pub pos: Vec3,
-    pub intensity: Color
+    pub intensity: ColorBuf,
+    pub enabled: bool
 }
 
 impl PointLight {
     pub fn new() -> PointLight {
         PointLight {
             pos: Vec3::new(),
-            intensity: Color::new()
+            intensity: ColorBuf::new(),
+            enabled: true
         }
     }
 }
@@ -78,7 +144,8 @@ impl PointLight {
 pub struct AreaLight {
     pub min: Vec3,
     pub max: Vec3,
-    pub intensity: Color
+    pub intensity: ColorBuf,
+    pub enabled: bool
 }
 
 impl AreaLight {
@@ -86,46 +153,150 @@ impl AreaLight {
         AreaLight {
             min: Vec3::new(),
             max: Vec3::new(),
-            intensity: Color::new()
+            intensity: ColorBuf::new(),
+            enabled: true
+        }
+    }
+
+    // A uniformly distributed point in the box from `min` to `max`
+    pub fn sample_point_with<R: Rng>(&self, rng: &mut R) -> Vec3 {
+        let Open01(rx) = rng.gen::<Open01<f32>>();
+        let Open01(ry) = rng.gen::<Open01<f32>>();
+        let Open01(rz) = rng.gen::<Open01<f32>>();
+        let at = |axis: u32, r: f32| self.min[axis] + r * (self.max[axis] - self.min[axis]);
+        Vec3::init(at(0, rx), at(1, ry), at(2, rz))
+    }
+
+    // The `i`th of `n` samples spread over a grid on the two longest sides of the light, jittered
+    // within its cell. The grid has about sqrt(n) columns and only as many rows as the samples
+    // fill, so every part of the light is sampled. Samples with different `i` fall in different
+    // cells, which gives smoother soft shadows than independent samples.
+    pub fn sample_stratified_with<R: Rng>(&self, i: usize, n: usize, rng: &mut R) -> Vec3 {
+        let m = (n as f32).sqrt().ceil().max(1.0) as usize;
+        let rows = cmp::max((n + m - 1) / m, 1);
+        let Open01(ru) = rng.gen::<Open01<f32>>();
+        let Open01(rv) = rng.gen::<Open01<f32>>();
+        let Open01(rw) = rng.gen::<Open01<f32>>();
+
+        let size = |axis: u32| (self.max[axis] - self.min[axis]).abs();
+        let thin = match (size(0) <= size(1), size(0) <= size(2), size(1) <= size(2)) {
+            (true, true, _) => 0,
+            (_, _, true) => 1,
+            _ => 2
+        };
+        let mut t = [rw; 3];
+        t[(thin + 1) % 3] = ((i % m) as f32 + ru) / m as f32;
+        t[(thin + 2) % 3] = ((i / m % rows) as f32 + rv) / rows as f32;
+        let at = |axis: u32| self.min[axis] + t[axis as usize] * (self.max[axis] - self.min[axis]);
+        Vec3::init(at(0), at(1), at(2))
+    }
+}
+
+#[derive(Copy, PartialEq, Clone, Debug)]
+pub struct TriangleLight {
+    pub v0: Vec3,
+    pub v1: Vec3,
+    pub v2: Vec3,
+    pub intensity: ColorBuf,
+    pub enabled: bool
+}
+
+impl TriangleLight {
+    pub fn new() -> TriangleLight {
+        TriangleLight {
+            v0: Vec3::new(),
+            v1: Vec3::new(),
+            v2: Vec3::new(),
+            intensity: ColorBuf::new(),
+            enabled: true
         }
     }
 
-    pub fn sample_point(&self) -> Vec3 {
-        let Open01(rx) = random::<Open01<f32>>();
-        let Open01(ry) = random::<Open01<f32>>();
-        let Open01(rz) = random::<Open01<f32>>();
-        let mut dx = (self.max[0] - self.min[0]).abs() * 0.5;
-        let mut dy = (self.max[1] - self.min[1]).abs() * 0.5;
-        let mut dz = (self.max[2] - self.min[2]).abs() * 0.5;
-        dx = dx - rx * (dx * 2.0);
-        dy = dy - ry * (dy * 2.0);
-        dz = dz - rz * (dz * 2.0);
-        Vec3::init(self.max[0] + dx, self.max[1] + dy, self.max[2] + dz)
+    // Taking the square root of the first number avoids clustering the samples around v0
+    pub fn sample_point_with<R: Rng>(&self, rng: &mut R) -> Vec3 {
+        let Open01(r1) = rng.gen::<Open01<f32>>();
+        let Open01(r2) = rng.gen::<Open01<f32>>();
+        let s = r1.sqrt();
+        let (a, b, c) = (1.0 - s, s * (1.0 - r2), s * r2);
+        self.v0.mult(a) + self.v1.mult(b) + self.v2.mult(c)
     }
 }
 
+// By default `dir` is the direction the light travels in, if `toward_light` is set it is
+// instead the direction pointing toward the light
 #[derive(Copy, PartialEq, Clone, Debug)]
 pub struct DirectionalLight {
     pub dir: Vec3,
-    pub intensity: Color
+    pub intensity: ColorBuf,
+    pub toward_light: bool,
+    pub enabled: bool
 }
 
 impl DirectionalLight {
     pub fn new() -> DirectionalLight {
         DirectionalLight {
             dir: Vec3::new(),
-            intensity: Color::new()
+            intensity: ColorBuf::new(),
+            toward_light: false,
+            enabled: true
         }
     }
 }
 
+// A point light shining along `dir`. Points within `inner_angle` of `dir` get the full
+// intensity, which falls off with the cosine of the angle to zero at `outer_angle`.
+#[derive(Copy, PartialEq, Clone, Debug)]
+pub struct SpotLight {
+    pub pos: Vec3,
+    pub dir: Vec3,
+    pub intensity: ColorBuf,
+    pub inner_angle: f32,
+    pub outer_angle: f32,
+    pub enabled: bool
+}
+
+impl SpotLight {
+    pub fn new() -> SpotLight {
+        SpotLight {
+            pos: Vec3::new(),
+            dir: Vec3::new(),
+            intensity: ColorBuf::new(),
+            inner_angle: 0.0,
+            outer_angle: 0.0,
+            enabled: true
+        }
+    }
+
+    pub fn falloff(&self, point: Vec3) -> f32 {
+        let (mut dir, mut to_point) = (self.dir, point - self.pos);
+        dir.normalize();
+        to_point.normalize();
+        let cos = dir.dot(to_point);
+        let (cos_inner, cos_outer) = (self.inner_angle.cos(), self.outer_angle.cos());
+        if cos >= cos_inner {
+            1.0
+        } else if cos <= cos_outer {
+            0.0
+        } else {
+            (cos - cos_outer) / (cos_inner - cos_outer)
+        }
+    }
+}
+
+// Primary rays only see what lies between the `near` and `far` clipping planes. The position and
+// directions are moved by `transform` before the camera is used.
 #[derive(Copy)]
 pub struct Camera {
     pub pos: Vec3,
     pub view_dir: Vec3,
     pub focal_dist: f32,
     pub ortho_up: Vec3,
-    pub vertical_fov: f32
+    pub vertical_fov: f32,
+    // The width over the height of the images the camera is meant for
+    pub aspect: f32,
+    pub near: f32,
+    pub far: f32,
+    pub transform: Mat4
 }
 
 impl Camera {
@@ -135,28 +306,139 @@ impl Camera {
             view_dir: Vec3::new(),
             focal_dist: 0.0,
             ortho_up: Vec3::new(),
-            vertical_fov: 0.0
+            vertical_fov: 0.0,
+            aspect: 1.0,
+            near: 0.0,
+            far: Float::infinity(),
+            transform: Mat4::new()
+        }
+    }
+
+    // The camera with its transform applied to the position and directions, and reset
+    pub fn transformed(&self) -> Camera {
+        let mut camera = *self;
+        camera.pos = self.transform.mul_point(self.pos);
+        camera.view_dir = self.transform.mul_dir(self.view_dir);
+        camera.view_dir.normalize();
+        camera.ortho_up = self.transform.mul_dir(self.ortho_up);
+        camera.ortho_up.normalize();
+        camera.transform = Mat4::new();
+        camera
+    }
+
+    // Blends between the cameras after their transforms are applied, as blending the matrices
+    // themselves doesn't give rotations in between
+    pub fn lerp(&self, other: &Camera, t: f32) -> Camera {
+        let identity = Mat4::new();
+        if self.transform != identity || other.transform != identity {
+            return self.transformed().lerp(&other.transformed(), t);
+        }
+        // Cameras facing opposite ways turn around their up direction
+        let view_dir = self.view_dir.slerp(other.view_dir, t, self.ortho_up);
+        let ortho_up = self.ortho_up.slerp(other.ortho_up, t, self.view_dir);
+
+        Camera {
+            pos: self.pos.lerp(other.pos, t),
+            view_dir: view_dir,
+            focal_dist: self.focal_dist * (1.0 - t) + other.focal_dist * t,
+            ortho_up: ortho_up,
+            vertical_fov: self.vertical_fov * (1.0 - t) + other.vertical_fov * t,
+            aspect: self.aspect * (1.0 - t) + other.aspect * t,
+            near: lerp_plane(self.near, other.near, t),
+            far: lerp_plane(self.far, other.far, t),
+            transform: Mat4::new()
         }
     }
 }
 
+// What rays that miss every primitive of a scene see
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Background {
+    Solid(Color),
+    // Blends from `bottom` for rays pointing straight down to `top` for rays pointing straight up
+    Gradient { top: Color, bottom: Color }
+}
+
+impl Background {
+    // The color seen by a ray going in the direction `dir`
+    pub fn color(&self, dir: Vec3) -> Color {
+        match *self {
+            Background::Solid(color) => color,
+            Background::Gradient { top, bottom } => {
+                let mut dir = dir;
+                dir.normalize();
+                let t = 0.5 * (dir.y + 1.0);
+                bottom.mult(1.0 - t) + top.mult(t)
+            }
+        }
+    }
+}
+
+// The far plane is often infinite, so the end points are returned as is to avoid `inf * 0.0`
+fn lerp_plane(a: f32, b: f32, t: f32) -> f32 {
+    if t <= 0.0 {
+        a
+    } else if t >= 1.0 {
+        b
+    } else {
+        a * (1.0 - t) + b * t
+    }
+}
+
 pub enum SceneIntersection<'a> {
     Intersected(Intersection<'a>),
     Missed
 }
 
-pub trait IntersectableScene<'a> {
+pub trait IntersectableScene {
     fn get_camera(&self) -> &Camera;
 
     fn get_lights(&self) -> &[Light];
 
-    fn intersects(&'a self, ray: &Ray) -> SceneIntersection<'a>;
+    fn get_background(&self) -> &Background;
+
+    fn intersects<'a>(&'a self, ray: &Ray) -> SceneIntersection<'a> {
+        self.intersects_between(ray, 0.0, Float::infinity())
+    }
+
+    // Like `intersects`, but ignores hits closer than `near` or farther away than `far`
+    fn intersects_between<'a>(&'a self, ray: &Ray, near: f32,
+                              far: f32) -> SceneIntersection<'a>;
+
+    // Whether an opaque primitive lies along `ray` closer than `max_dist`. This is cheaper than
+    // `intersects`, as it can stop at the first such primitive.
+    fn occludes(&self, ray: &Ray, max_dist: f32) -> bool;
+
+    // Either an opaque primitive closer than `max_dist`, which blocks the light of a shadow ray,
+    // or the closest primitive, which is transparent and lets the light through. Like `occludes`,
+    // the search stops at the first opaque primitive it finds.
+    fn shadow_hit<'a>(&'a self, ray: &Ray, max_dist: f32) -> SceneIntersection<'a>;
+
+    fn is_occluded(&self, ori: Vec3, target: Vec3) -> bool {
+        let mut dir = target - ori;
+        let dist = dir.length();
+        dir.normalize();
+        self.occludes(&Ray::init(ori, dir), dist)
+    }
+
+    // Blends `color`, seen at distance `dist` along the ray, with the volumes the ray passes through
+    fn attenuate(&self, ray: &Ray, dist: f32, color: ColorBuf) -> ColorBuf;
+
+    // Counts the rays traced through the scene, and the tests done to find what they hit
+    fn stats(&self) -> &StatCounters;
 }
 
+// Imported meshes are kept by name in `meshes`, and are shared with `primitives`.
+// With `cull_shadow_back_faces` set, polys only cast shadows from their front, which saves
+// shadow ray tests on closed meshes without changing their shadows.
 pub struct Scene {
     pub camera: Camera,
     pub lights: Vec<Light>,
-    pub primitives: Vec<shapes::Primitive>
+    pub primitives: Vec<Primitive>,
+    pub meshes: Vec<Arc<Mesh>>,
+    pub cull_shadow_back_faces: bool,
+    pub background: Background,
+    pub stats: StatCounters
 }
 
 impl<'a> Scene {
@@ -164,12 +446,63 @@ impl<'a> Scene {
         Scene {
             camera: Camera::new(),
             lights: Vec::new(),
-            primitives: Vec::new()
+            primitives: Vec::new(),
+            meshes: Vec::new(),
+            cull_shadow_back_faces: false,
+            background: Background::Solid(Color::new()),
+            stats: StatCounters::new()
+        }
+    }
+
+    pub fn mesh(&self, name: &str) -> Option<&Mesh> {
+        self.meshes.iter().find(|mesh| mesh.name.as_slice() == name).map(|mesh| &**mesh)
+    }
+
+    pub fn set_light_enabled(&mut self, index: usize, enabled: bool) {
+        self.lights[index].set_enabled(enabled);
+    }
+
+    // For edits to every primitive at once, like scaling or tinting the whole scene
+    pub fn primitives_mut(&mut self) -> &mut [Primitive] {
+        self.primitives.as_mut_slice()
+    }
+
+    // Checks that the vertices of every poly with per vertex materials refer to one of the
+    // materials of the poly, and that each of those materials is used. Polys without any
+    // materials are skipped, as they are shaded with the debug material.
+    pub fn validate_materials(&self) -> Result<(), String> {
+        let mut errors = Vec::new();
+        for (id, prim) in self.primitives.iter().enumerate() {
+            let poly = match prim {
+                &Primitive::Poly(ref poly) if poly.vertex_material => poly,
+                _ => continue
+            };
+            let count = poly.materials.len();
+            if count == 0 {
+                continue;
+            }
+
+            for (i, vertex) in poly.vertices.iter().enumerate() {
+                if vertex.mat_index as usize >= count {
+                    errors.push(format!("primitive {}: vertex {} has material index {} of {}",
+                        id, i, vertex.mat_index, count));
+                }
+            }
+            for m in 0 .. count {
+                if !poly.vertices.iter().any(|vertex| vertex.mat_index as usize == m) {
+                    errors.push(format!("primitive {}: material {} is not used", id, m));
+                }
+            }
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors.connect("\n"))
         }
     }
 }
 
-impl<'a> IntersectableScene<'a> for Scene {
+impl IntersectableScene for Scene {
     fn get_camera(&self) -> &Camera {
         &self.camera
     }
@@ -178,33 +511,88 @@ impl<'a> IntersectableScene<'a> for Scene {
         &self.lights
     }
 
-    fn intersects(&'a self, ray: &Ray) -> SceneIntersection<'a> {
+    fn get_background(&self) -> &Background {
+        &self.background
+    }
+
+    fn intersects_between<'a>(&'a self, ray: &Ray, near: f32,
+                              far: f32) -> SceneIntersection<'a> {
         let mut intersection = Missed;
         let mut point: f32 = 0.0;
 
         let mut has_intersected = false;
-        for prim in self.primitives.iter() {
-            match prim.intersects(ray) {
-                ShapeIntersection::Hit(new_point) if !has_intersected => {
+        let solids = self.primitives.iter().enumerate()
+            .filter(|&(id, prim)| prim.is_solid() && !ray.excludes(id));
+        for (id, prim) in solids {
+            self.stats.count(Stat::PrimTests);
+            match prim.hit(ray, id) {
+                Some((_, new_point)) if new_point < near || new_point > far => (),
+                Some((hit, new_point)) if !has_intersected => {
                     has_intersected = true;
                     point = new_point;
-                    intersection = Intersected(Intersection::new(point, ray.clone(), prim));
+                    intersection = Intersected(Intersection::new(point, ray.clone(), hit, id));
                 },
-                ShapeIntersection::Hit(new_point) if has_intersected && new_point < point => {
+                Some((hit, new_point)) if has_intersected && new_point < point => {
                     point = new_point;
-                    intersection = Intersected(Intersection::new(point, ray.clone(), prim));
+                    intersection = Intersected(Intersection::new(point, ray.clone(), hit, id));
                 },
                 _ => ()
             }
         }
         intersection
     }
+
+    fn occludes(&self, ray: &Ray, max_dist: f32) -> bool {
+        self.primitives.iter().enumerate()
+            .filter(|&(id, prim)| prim.is_solid() && !ray.excludes(id))
+            .any(|(id, prim)| {
+                self.stats.count(Stat::PrimTests);
+                prim.occludes(ray, id, max_dist, self.cull_shadow_back_faces)
+            })
+    }
+
+    fn shadow_hit<'a>(&'a self, ray: &Ray, max_dist: f32) -> SceneIntersection<'a> {
+        let mut closest = Missed;
+        let mut max_dist = max_dist;
+        let solids = self.primitives.iter().enumerate()
+            .filter(|&(id, prim)| prim.is_solid() && !ray.excludes(id));
+        for (id, prim) in solids {
+            self.stats.count(Stat::PrimTests);
+            match prim.shadow_hit(ray, id, max_dist, self.cull_shadow_back_faces) {
+                Some((hit, point)) => {
+                    closest = Intersected(Intersection::new(point, ray.clone(), hit, id));
+                    if hit.get_material().transparency == 0.0 {
+                        return closest;
+                    }
+                    max_dist = point;
+                },
+                None => ()
+            }
+        }
+        closest
+    }
+
+    fn attenuate(&self, ray: &Ray, dist: f32, color: ColorBuf) -> ColorBuf {
+        self.primitives.iter().fold(color, |color, prim| match prim {
+            &Primitive::Volume(ref volume) => volume.attenuate(ray, dist, color),
+            _ => color
+        })
+    }
+
+    fn stats(&self) -> &StatCounters {
+        &self.stats
+    }
 }
 
 pub struct BvhScene {
     pub camera: Camera,
     pub lights: Vec<Light>,
-    pub tree: Tree
+    pub volumes: Vec<Primitive>,
+    pub meshes: Vec<Arc<Mesh>>,
+    pub tree: Tree,
+    pub cull_shadow_back_faces: bool,
+    pub background: Background,
+    pub stats: StatCounters
 }
 
 impl<'a> BvhScene {
@@ -212,7 +600,12 @@ impl<'a> BvhScene {
         BvhScene {
             camera: Camera::new(),
             lights: Vec::new(),
-            tree: Tree::new()
+            volumes: Vec::new(),
+            meshes: Vec::new(),
+            tree: Tree::new(),
+            cull_shadow_back_faces: false,
+            background: Background::Solid(Color::new()),
+            stats: StatCounters::new()
         }
     }
 
@@ -220,12 +613,38 @@ impl<'a> BvhScene {
         let mut bvh_scene = BvhScene::new();
         bvh_scene.camera = scene.camera;
         bvh_scene.lights = scene.lights;
-        bvh_scene.tree.init(scene.primitives);
+        bvh_scene.meshes = scene.meshes;
+        bvh_scene.cull_shadow_back_faces = scene.cull_shadow_back_faces;
+        bvh_scene.background = scene.background;
+
+        // Volumes don't block rays, so they are kept out of the tree. The solids keep their index
+        // in the scene as id.
+        let (volumes, solids): (Vec<(usize, Primitive)>, Vec<(usize, Primitive)>) =
+            scene.primitives.into_iter().enumerate().partition(|&(_, ref prim)| !prim.is_solid());
+        bvh_scene.volumes = volumes.into_iter().map(|(_, prim)| prim).collect();
+        bvh_scene.tree.init_with_ids(solids);
         bvh_scene
     }
+
+    // Calls `edit` with the primitives of the scene, the solids in the order of the scene they
+    // came from followed by the volumes, and rebuilds the tree around the edited primitives
+    pub fn edit_primitives<F>(&mut self, edit: F) where F: FnOnce(&mut [Primitive]) {
+        let mut solids = self.tree.take_shapes();
+        solids.sort_by(|a, b| a.0.cmp(&b.0));
+        let (ids, mut primitives): (Vec<usize>, Vec<Primitive>) = solids.into_iter().unzip();
+        let num_solids = ids.len();
+        primitives.extend(mem::replace(&mut self.volumes, Vec::new()).into_iter());
+
+        edit(primitives.as_mut_slice());
+
+        let (solids, volumes): (Vec<(usize, Primitive)>, Vec<(usize, Primitive)>) =
+            primitives.into_iter().enumerate().partition(|&(i, _)| i < num_solids);
+        self.volumes = volumes.into_iter().map(|(_, prim)| prim).collect();
+        self.tree.init_with_ids(solids.into_iter().map(|(i, prim)| (ids[i], prim)).collect());
+    }
 }
 
-impl<'a> IntersectableScene<'a> for BvhScene {
+impl IntersectableScene for BvhScene {
     fn get_camera(&self) -> &Camera {
         &self.camera
     }
@@ -234,23 +653,108 @@ impl<'a> IntersectableScene<'a> for BvhScene {
         self.lights.as_slice()
     }
 
-    fn intersects(&'a self, ray: &Ray) -> SceneIntersection<'a> {
-        let intersection = self.tree.intersects(ray);
+    fn get_background(&self) -> &Background {
+        &self.background
+    }
+
+    // A scene without solids misses every ray, even when it has lights or volumes
+    fn intersects_between<'a>(&'a self, ray: &Ray, near: f32,
+                              far: f32) -> SceneIntersection<'a> {
+        if self.tree.is_empty() {
+            return Missed;
+        }
+        let intersection = self.tree.intersects_between(ray, near, far, self.stats.when_enabled());
         match intersection {
-            NodeIntersection::Hit(node, point) =>
-                Intersected(Intersection::new(point, ray.clone(), node.get_shape())),
+            NodeIntersection::Hit(node, prim, point) =>
+                Intersected(Intersection::new(point, ray.clone(), prim, node.get_id())),
+            NodeIntersection::Missed => Missed
+        }
+    }
+
+    fn occludes(&self, ray: &Ray, max_dist: f32) -> bool {
+        if self.tree.is_empty() {
+            return false;
+        }
+        self.tree.occludes(ray, max_dist, self.cull_shadow_back_faces,
+            self.stats.when_enabled())
+    }
+
+    fn shadow_hit<'a>(&'a self, ray: &Ray, max_dist: f32) -> SceneIntersection<'a> {
+        if self.tree.is_empty() {
+            return Missed;
+        }
+        let hit = self.tree.shadow_hit(ray, max_dist, self.cull_shadow_back_faces,
+            self.stats.when_enabled());
+        match hit {
+            NodeIntersection::Hit(node, prim, point) =>
+                Intersected(Intersection::new(point, ray.clone(), prim, node.get_id())),
             NodeIntersection::Missed => Missed
         }
     }
+
+    fn attenuate(&self, ray: &Ray, dist: f32, color: ColorBuf) -> ColorBuf {
+        self.volumes.iter().fold(color, |color, prim| match prim {
+            &Primitive::Volume(ref volume) => volume.attenuate(ray, dist, color),
+            _ => color
+        })
+    }
+
+    fn stats(&self) -> &StatCounters {
+        &self.stats
+    }
+}
+
+// An empty scene with the camera most tests use, at the origin looking down the negative z-axis
+// with a vertical field of view of 90 degrees
+#[cfg(test)]
+pub fn create_test_scene() -> Scene {
+    let mut scene = Scene::new();
+    scene.camera.view_dir = Vec3::init(0.0, 0.0, -1.0);
+    scene.camera.ortho_up = Vec3::init(0.0, 1.0, 0.0);
+    scene.camera.vertical_fov = ::std::f32::consts::PI / 2.0;
+    scene
+}
+
+// A scene of `count` unit spheres spread out in front of the test camera, always placed the
+// same way so benchmarks are comparable between runs
+#[cfg(test)]
+pub fn create_sphere_field(count: usize) -> Scene {
+    use rand::{SeedableRng, XorShiftRng};
+    use scene::shapes::sphere::Sphere;
+    use scene::material::Material;
+
+    let mut rng: XorShiftRng = SeedableRng::from_seed([1, 2, 3, 4]);
+    let mut scene = create_test_scene();
+    for _ in 0 .. count {
+        let Open01(x) = rng.gen::<Open01<f32>>();
+        let Open01(y) = rng.gen::<Open01<f32>>();
+        let Open01(z) = rng.gen::<Open01<f32>>();
+        let mut sphere = Sphere::init(Vec3::init(100.0 * x - 50.0, 100.0 * y - 50.0,
+            -10.0 - 100.0 * z), 1.0);
+        sphere.materials[0] = Material::init(Color::init(x, y, z));
+        scene.primitives.push(Primitive::Sphere(sphere));
+    }
+    scene
 }
 
 #[cfg(test)]
 mod tests {
+    extern crate test;
+    use self::test::{Bencher, black_box};
+
     use vec::Vec3;
+    use mat::Mat4;
     use ray::Ray;
-    use scene::{IntersectableScene, Scene, SceneIntersection};
-    use scene::shapes::{sphere, Primitive};
-    use scene::material::{Color, Material};
+    use std::f32::consts;
+    use std::num::Float;
+    use std::sync::Arc;
+    use rand::{SeedableRng, XorShiftRng};
+    use scene::bvh::Node;
+    use scene::shapes::mesh::Mesh;
+    use scene::{IntersectableScene, BvhScene, Scene, SceneIntersection, Camera, Light,
+        PointLight, AreaLight, TriangleLight, SpotLight, Background, create_sphere_field};
+    use scene::shapes::{sphere, poly, Primitive, Shape};
+    use scene::material::{Color, ColorBuf, Material};
 
     fn create_scene<'a>() -> Scene {
         let mut sphere = sphere::Sphere::init(Vec3::init(0.0, 0.0, -5.0), 1.0);
@@ -267,6 +771,20 @@ mod tests {
         assert!(scene.primitives.len() == 0);
     }
 
+    #[test]
+    fn gradient_sky_is_top_color_above_and_bottom_color_below() {
+        let (top, bottom) = (Color::init(0.0, 0.5, 1.0), Color::init(1.0, 1.0, 1.0));
+        let mut scene = create_scene();
+        scene.background = Background::Gradient { top: top, bottom: bottom };
+        let scene: Box<IntersectableScene> = Box::new(BvhScene::from_scene(scene));
+
+        let (up, down) = (Vec3::init(0.0, 3.0, 0.0), Vec3::init(0.0, -1.0, 0.0));
+        assert_eq!(top, scene.get_background().color(up));
+        assert_eq!(bottom, scene.get_background().color(down));
+        let horizon = scene.get_background().color(Vec3::init(0.0, 0.0, -1.0));
+        assert_eq!(Color::init(0.5, 0.75, 1.0), horizon);
+    }
+
     #[test]
     fn can_intersect_scene() {
         let scene = create_scene();
@@ -279,4 +797,469 @@ mod tests {
             _ => panic!("Ray did not intersect scene")
         }
     }
+
+    #[test]
+    fn can_check_occlusion() {
+        let mut scene = create_scene();
+        let point = Vec3::init(0.0, 0.0, 0.0);
+        assert!(scene.is_occluded(point, Vec3::init(0.0, 0.0, -10.0)));
+        assert!(!scene.is_occluded(point, Vec3::init(0.0, 0.0, -3.0)));
+        assert!(BvhScene::from_scene(create_scene()).is_occluded(point, Vec3::init(0.0, 0.0, -10.0)));
+
+        scene.primitives.clear();
+        assert!(!scene.is_occluded(point, Vec3::init(0.0, 0.0, -10.0)));
+    }
+
+    // Spheres of radius 1 in front of the origin, at z = -3 and z = -6
+    fn create_shadow_scene(front_transparency: f32, back_transparency: f32) -> Scene {
+        let mut scene = Scene::new();
+        for &(z, transparency) in [(-3.0, front_transparency), (-6.0, back_transparency)].iter() {
+            let mut sphere = sphere::Sphere::init(Vec3::init(0.0, 0.0, z), 1.0);
+            sphere.materials[0].transparency = transparency;
+            scene.primitives.push(Primitive::Sphere(sphere));
+        }
+        scene
+    }
+
+    // The distance to the shadow hit along -z and its transparency
+    fn shadow_hit(scene: &IntersectableScene, ori: Vec3,
+                  max_dist: f32) -> Option<(f32, f32)> {
+        let ray = Ray::init(ori, Vec3::init(0.0, 0.0, -1.0));
+        match scene.shadow_hit(&ray, max_dist) {
+            SceneIntersection::Intersected(intersection) =>
+                Some((intersection.distance(), intersection.material().transparency)),
+            SceneIntersection::Missed => None
+        }
+    }
+
+    #[test]
+    fn shadow_hits_stop_at_opaque_primitives() {
+        let origin = Vec3::new();
+        let scenes: Vec<Box<IntersectableScene>> = vec!(
+            Box::new(create_shadow_scene(0.5, 0.0)),
+            Box::new(BvhScene::from_scene(create_shadow_scene(0.5, 0.0))));
+        for scene in scenes.iter() {
+            assert_eq!(None, shadow_hit(&**scene, origin, 1.5));
+            assert_eq!(Some((2.0, 0.5)), shadow_hit(&**scene, origin, 4.5));
+            assert_eq!(Some((0.5, 0.0)), shadow_hit(&**scene, Vec3::init(0.0, 0.0, -4.5), 20.0));
+        }
+
+        let scenes: Vec<Box<IntersectableScene>> = vec!(
+            Box::new(create_shadow_scene(0.0, 0.5)),
+            Box::new(BvhScene::from_scene(create_shadow_scene(0.0, 0.5))));
+        for scene in scenes.iter() {
+            assert_eq!(Some((2.0, 0.0)), shadow_hit(&**scene, origin, 20.0));
+        }
+    }
+
+    #[test]
+    fn empty_scenes_miss_every_ray() {
+        let create_empty_scene = || {
+            let mut scene = Scene::new();
+            scene.lights.push(Light::Point(PointLight::new()));
+            scene
+        };
+        let scenes: Vec<Box<IntersectableScene>> = vec!(
+            Box::new(create_empty_scene()),
+            Box::new(BvhScene::from_scene(create_empty_scene())));
+
+        let ori = Vec3::init(0.0, 1.0, 0.0);
+        for scene in scenes.iter() {
+            for dir in [Vec3::init(0.0, 0.0, -1.0), Vec3::init(1.0, -1.0, 0.0)].iter() {
+                match scene.intersects(&Ray::init(ori, *dir)) {
+                    SceneIntersection::Intersected(_) => panic!("An empty scene can't be hit"),
+                    SceneIntersection::Missed => ()
+                }
+            }
+            assert!(!scene.is_occluded(ori, Vec3::init(0.0, -10.0, 0.0)));
+        }
+    }
+
+    // A closed unit cube floating above the origin, with all faces wound to point outward
+    fn create_cube_scene(cull_shadow_back_faces: bool) -> Scene {
+        let corner = |i: usize| Vec3::init((i & 1) as f32 - 0.5, ((i >> 1) & 1) as f32 + 0.5,
+            ((i >> 2) & 1) as f32 - 0.5);
+        let faces = [[0, 4, 6, 2], [1, 3, 7, 5], [0, 1, 5, 4], [2, 6, 7, 3], [0, 2, 3, 1],
+            [4, 5, 7, 6]];
+
+        let mut scene = Scene::new();
+        scene.cull_shadow_back_faces = cull_shadow_back_faces;
+        for face in faces.iter() {
+            for &(a, b, c) in [(0, 1, 2), (0, 2, 3)].iter() {
+                let mut triangle = poly::Poly::init();
+                triangle.vertices[0].position = corner(face[a]);
+                triangle.vertices[1].position = corner(face[b]);
+                triangle.vertices[2].position = corner(face[c]);
+                scene.primitives.push(Primitive::Poly(triangle));
+            }
+        }
+        scene
+    }
+
+    #[test]
+    fn culling_back_faces_keeps_shadows_of_closed_meshes() {
+        let scenes: Vec<Box<IntersectableScene>> = vec!(
+            Box::new(create_cube_scene(false)),
+            Box::new(create_cube_scene(true)),
+            Box::new(BvhScene::from_scene(create_cube_scene(false))),
+            Box::new(BvhScene::from_scene(create_cube_scene(true))));
+        let light = Vec3::init(0.3, 4.0, 0.2);
+
+        let mut shadowed = 0;
+        for x in 0 .. 16 {
+            for z in 0 .. 16 {
+                let point = Vec3::init(x as f32 * 0.25 - 2.0, 0.0, z as f32 * 0.25 - 2.0);
+                let occluded = scenes[0].is_occluded(point, light);
+                for scene in scenes.iter() {
+                    assert_eq!(occluded, scene.is_occluded(point, light));
+                }
+                if occluded {
+                    shadowed += 1;
+                }
+            }
+        }
+        assert!(shadowed > 0 && shadowed < 16 * 16);
+
+        // From inside the cube only back faces are in the way
+        let inside = Vec3::init(0.0, 1.0, 0.0);
+        assert!(scenes[0].is_occluded(inside, light) && scenes[2].is_occluded(inside, light));
+        assert!(!scenes[1].is_occluded(inside, light) && !scenes[3].is_occluded(inside, light));
+    }
+
+    #[test]
+    fn can_interpolate_camera() {
+        let mut a = Camera::new();
+        a.view_dir = Vec3::init(1.0, 0.0, 0.0);
+        a.ortho_up = Vec3::init(0.0, 1.0, 0.0);
+        a.vertical_fov = 0.5;
+        let mut b = Camera::new();
+        b.pos = Vec3::init(2.0, 0.0, 0.0);
+        b.view_dir = Vec3::init(0.0, 0.0, -1.0);
+        b.ortho_up = Vec3::init(0.0, 1.0, 0.0);
+        b.vertical_fov = 1.0;
+
+        let camera = a.lerp(&b, 0.5);
+        assert_eq!(Vec3::init(1.0, 0.0, 0.0), camera.pos);
+        assert_eq!(0.75, camera.vertical_fov);
+        assert!((camera.view_dir.length() - 1.0).abs() < 1.0e-6);
+        assert!((camera.view_dir.x + camera.view_dir.z).abs() < 1.0e-6);
+
+        b.view_dir = Vec3::init(-1.0, 0.0, 0.0);
+        let camera = a.lerp(&b, 0.5);
+        assert!(camera.view_dir.distance(Vec3::init(0.0, 0.0, -1.0)) < 1.0e-6);
+        assert_eq!(Vec3::init(0.0, 1.0, 0.0), camera.ortho_up);
+    }
+
+    #[test]
+    fn can_transform_camera() {
+        let mut camera = Camera::new();
+        camera.view_dir = Vec3::init(0.0, 0.0, -1.0);
+        camera.ortho_up = Vec3::init(0.0, 1.0, 0.0);
+        camera.transform = Mat4::translation(Vec3::init(1.0, 2.0, 3.0)) *
+            Mat4::rotation(Vec3::init(0.0, 1.0, 0.0), consts::FRAC_PI_2);
+
+        let camera = camera.transformed();
+        assert_eq!(Vec3::init(1.0, 2.0, 3.0), camera.pos);
+        assert!(camera.view_dir.distance(Vec3::init(-1.0, 0.0, 0.0)) < 1.0e-6);
+        assert!(camera.ortho_up.distance(Vec3::init(0.0, 1.0, 0.0)) < 1.0e-6);
+        assert_eq!(Mat4::new(), camera.transform);
+    }
+
+    #[test]
+    fn triangle_light_samples_lie_inside_triangle() {
+        let mut light = TriangleLight::new();
+        light.v0 = Vec3::init(1.0, 0.0, -2.0);
+        light.v1 = Vec3::init(3.0, 1.0, -2.0);
+        light.v2 = Vec3::init(2.0, 4.0, -1.0);
+
+        let normal = (light.v1 - light.v0).cross(light.v2 - light.v0);
+        let area = normal.dot(normal);
+
+        let mut rng: XorShiftRng = SeedableRng::from_seed([1, 2, 3, 4]);
+        for _ in 0 .. 1000 {
+            let p = light.sample_point_with(&mut rng);
+            let a = (light.v1 - p).cross(light.v2 - p).dot(normal) / area;
+            let b = (light.v2 - p).cross(light.v0 - p).dot(normal) / area;
+            let c = (light.v0 - p).cross(light.v1 - p).dot(normal) / area;
+            for &bary in [a, b, c].iter() {
+                assert!(bary >= -1.0e-5 && bary <= 1.0 + 1.0e-5);
+            }
+            assert!((a + b + c - 1.0).abs() < 1.0e-4);
+        }
+    }
+
+    #[test]
+    fn area_light_samples_lie_inside_its_box() {
+        let mut light = AreaLight::new();
+        light.min = Vec3::init(1.0, 2.0, -7.0);
+        light.max = Vec3::init(4.0, 2.5, -3.0);
+
+        let mut rng: XorShiftRng = SeedableRng::from_seed([1, 2, 3, 4]);
+        for _ in 0 .. 1000 {
+            let p = light.sample_point_with(&mut rng);
+            for axis in 0 .. 3 {
+                assert!(p[axis] >= light.min[axis] && p[axis] <= light.max[axis],
+                    "{:?} is outside of the light", p);
+            }
+        }
+    }
+
+    #[test]
+    fn stratified_area_light_samples_fall_in_different_cells() {
+        let mut light = AreaLight::new();
+        light.min = Vec3::init(-2.0, 3.0, -6.0);
+        light.max = Vec3::init(2.0, 3.0, -2.0);
+
+        let mut rng: XorShiftRng = SeedableRng::from_seed([1, 2, 3, 4]);
+        let mut cells = Vec::new();
+        for i in 0 .. 16 {
+            let p = light.sample_stratified_with(i, 16, &mut rng);
+            assert_eq!(3.0, p.y);
+            let cell = ((p.x + 2.0).floor() as i32, (p.z + 6.0).floor() as i32);
+            assert!(cell.0 >= 0 && cell.0 < 4 && cell.1 >= 0 && cell.1 < 4);
+            assert!(!cells.contains(&cell), "Two samples fell in the cell {:?}", cell);
+            cells.push(cell);
+        }
+    }
+
+    #[test]
+    fn stratified_area_light_samples_cover_the_light() {
+        let mut light = AreaLight::new();
+        light.min = Vec3::init(-2.0, 3.0, -6.0);
+        light.max = Vec3::init(2.0, 3.0, -2.0);
+
+        let mut rng: XorShiftRng = SeedableRng::from_seed([1, 2, 3, 4]);
+        for &n in [2, 5].iter() {
+            let (mut min, mut max) = (light.max, light.min);
+            for _ in 0 .. 100 {
+                for i in 0 .. n {
+                    let p = light.sample_stratified_with(i, n, &mut rng);
+                    min = Vec3::init(min.x.min(p.x), min.y, min.z.min(p.z));
+                    max = Vec3::init(max.x.max(p.x), max.y, max.z.max(p.z));
+                }
+            }
+            assert!(min.x < -1.8 && max.x > 1.8, "{} samples cover x {} to {}", n, min.x, max.x);
+            assert!(min.z < -5.8 && max.z > -2.2, "{} samples cover z {} to {}", n, min.z, max.z);
+        }
+    }
+
+    fn assert_reflection_skips_source(scene: &IntersectableScene) {
+        let ray = Ray::init(Vec3::init(0.0, 1.0, 0.0), Vec3::init(0.0, -1.0, 0.0));
+        match scene.intersects(&ray) {
+            SceneIntersection::Intersected(intersection) => {
+                // Start the reflection just below the triangle, as if the bias was too small
+                let mut reflection = intersection.reflective_ray();
+                reflection.ori = intersection.point() - intersection.surface_normal().mult(0.001);
+                match scene.intersects(&reflection) {
+                    SceneIntersection::Missed => (),
+                    _ => panic!("Reflected ray hit its source triangle")
+                }
+            },
+            _ => panic!("Ray did not intersect scene")
+        }
+    }
+
+    #[test]
+    fn reflected_ray_does_not_hit_source_triangle() {
+        let mut triangle = poly::Poly::init();
+        triangle.vertices[0].position = Vec3::init(-1.0, 0.0, -1.0);
+        triangle.vertices[1].position = Vec3::init(1.0, 0.0, -1.0);
+        triangle.vertices[2].position = Vec3::init(0.0, 0.0, 1.0);
+        let mut scene = Scene::new();
+        scene.primitives.push(Primitive::Poly(triangle));
+
+        assert_reflection_skips_source(&scene);
+        assert_reflection_skips_source(&BvhScene::from_scene(scene));
+    }
+
+    fn assert_reflection_reaches_other_poly(scene: &IntersectableScene) {
+        let ray = Ray::init(Vec3::init(0.0, 1.0, 0.0), Vec3::init(0.0, -1.0, 0.0));
+        match scene.intersects(&ray) {
+            SceneIntersection::Intersected(intersection) => {
+                assert_eq!(1, intersection.primitive_id());
+                let mut reflection = intersection.reflective_ray();
+                reflection.ori = intersection.point() - intersection.surface_normal().mult(0.001);
+                match scene.intersects(&reflection) {
+                    SceneIntersection::Intersected(ceiling) =>
+                        assert!((ceiling.point().y - 2.0).abs() < 1.0e-4),
+                    _ => panic!("Reflected ray should hit the ceiling of the mesh")
+                }
+            },
+            _ => panic!("Ray did not intersect scene")
+        }
+    }
+
+    #[test]
+    fn reflected_ray_only_skips_its_source_poly_within_a_mesh() {
+        let vertices = [(-1.0, 0.0, -1.0), (1.0, 0.0, -1.0), (0.0, 0.0, 1.0),
+            (-1.0, 2.0, -1.0), (1.0, 2.0, -1.0), (0.0, 2.0, 1.0)].iter()
+            .map(|&(x, y, z)| poly::Vertex::init(Vec3::init(x, y, z))).collect();
+        let mesh = Mesh::init("room".to_string(), vertices,
+            vec!(([0, 1, 2], Material::new()), ([3, 4, 5], Material::new())));
+        // The ids of the primitives and of the polys within the mesh overlap
+        let mut far = poly::Poly::init();
+        far.vertices[0].position = Vec3::init(9.0, 0.0, -1.0);
+        far.vertices[1].position = Vec3::init(11.0, 0.0, -1.0);
+        far.vertices[2].position = Vec3::init(10.0, 0.0, 1.0);
+        let mut scene = Scene::new();
+        scene.primitives.push(Primitive::Poly(far));
+        scene.primitives.push(Primitive::Mesh(Arc::new(mesh)));
+
+        assert_reflection_reaches_other_poly(&scene);
+        assert_reflection_reaches_other_poly(&BvhScene::from_scene(scene));
+    }
+
+    #[test]
+    fn mesh_is_a_single_primitive_in_the_tree() {
+        let vertices = [(-1.0, -1.0), (1.0, -1.0), (1.0, -3.0), (-1.0, -3.0)].iter()
+            .map(|&(x, z)| poly::Vertex::init(Vec3::init(x, 0.0, z))).collect();
+        // The ray hits the second poly, which has a material of its own
+        let mesh = Mesh::init("quad".to_string(), vertices,
+            vec!(([0, 1, 2], Material::init(Color::init(1.0, 0.0, 0.0))),
+                ([0, 2, 3], Material::init(Color::init(0.0, 1.0, 0.0)))));
+        let mut scene = Scene::new();
+        scene.primitives.push(Primitive::Mesh(Arc::new(mesh)));
+
+        let scene = BvhScene::from_scene(scene);
+        match scene.tree.root {
+            Node::Leaf(_) => (),
+            _ => panic!("The mesh should be a single leaf in the tree")
+        }
+
+        let ray = Ray::init(Vec3::init(-0.5, 1.0, -2.5), Vec3::init(0.0, -1.0, 0.0));
+        match scene.intersects(&ray) {
+            SceneIntersection::Intersected(intersection) => {
+                assert_eq!(Vec3::init(-0.5, 0.0, -2.5), intersection.point());
+                assert_eq!(Vec3::init(0.0, 1.0, 0.0), intersection.surface_normal());
+                assert_eq!(Color::init(0.0, 1.0, 0.0), intersection.color());
+            },
+            _ => panic!("Ray did not intersect the mesh")
+        }
+    }
+
+    #[test]
+    fn intersection_knows_the_id_of_the_hit_primitive() {
+        let mut scene = Scene::new();
+        for &x in [-3.0, 0.0, 3.0].iter() {
+            scene.primitives.push(Primitive::Sphere(sphere::Sphere::init(Vec3::init(x, 0.0, -5.0),
+                1.0)));
+        }
+        let ray = Ray::init(Vec3::init(3.0, 0.0, 0.0), Vec3::init(0.0, 0.0, -1.0));
+
+        match scene.intersects(&ray) {
+            SceneIntersection::Intersected(intersection) =>
+                assert_eq!(2, intersection.primitive_id()),
+            _ => panic!("Ray did not intersect scene")
+        }
+        match BvhScene::from_scene(scene).intersects(&ray) {
+            SceneIntersection::Intersected(intersection) =>
+                assert_eq!(2, intersection.primitive_id()),
+            _ => panic!("Ray did not intersect scene")
+        }
+    }
+
+    #[test]
+    fn sphere_field_is_the_same_for_scene_and_bvh_scene() {
+        let scene = create_sphere_field(200);
+        let bvh_scene = BvhScene::from_scene(create_sphere_field(200));
+        for ray in field_rays().iter() {
+            match (scene.intersects(ray), bvh_scene.intersects(ray)) {
+                (SceneIntersection::Intersected(a), SceneIntersection::Intersected(b)) =>
+                    assert_eq!(a.point(), b.point()),
+                (SceneIntersection::Missed, SceneIntersection::Missed) => (),
+                _ => panic!("Scene and BvhScene should agree on whether {:?} hits", ray.dir())
+            }
+        }
+    }
+
+    fn scale_by_2(prim: &mut Primitive) {
+        match prim {
+            &mut Primitive::Sphere(ref mut sphere) => {
+                sphere.origin = sphere.origin.mult(2.0);
+                sphere.radius *= 2.0;
+            },
+            &mut Primitive::Poly(ref mut poly) => for vertex in poly.vertices.iter_mut() {
+                vertex.position = vertex.position.mult(2.0);
+            },
+            _ => panic!("Only spheres and polys are scaled")
+        }
+    }
+
+    #[test]
+    fn scaling_every_primitive_by_2_doubles_their_bounding_boxes() {
+        let mut scene = create_scene();
+        let mut poly = poly::Poly::init();
+        poly.vertices[0].position = Vec3::init(1.0, 0.0, -3.0);
+        poly.vertices[1].position = Vec3::init(2.0, 0.0, -3.0);
+        poly.vertices[2].position = Vec3::init(1.0, 1.0, -4.0);
+        scene.primitives.push(Primitive::Poly(poly));
+        let before: Vec<_> = scene.primitives.iter().map(|prim| prim.get_bbox()).collect();
+
+        for prim in scene.primitives_mut().iter_mut() {
+            scale_by_2(prim);
+        }
+        for (prim, bbox) in scene.primitives.iter().zip(before.iter()) {
+            assert_eq!(bbox.centroid().mult(2.0), prim.get_bbox().centroid());
+            assert_eq!(bbox.surface_area() * 4.0, prim.get_bbox().surface_area());
+        }
+
+        // The tree of a BvhScene is rebuilt around the scaled primitives
+        let mut bvh_scene = BvhScene::from_scene(create_scene());
+        bvh_scene.edit_primitives(|prims| for prim in prims.iter_mut() {
+            scale_by_2(prim);
+        });
+        let ray = Ray::init(Vec3::new(), Vec3::init(0.0, 0.0, -1.0));
+        match bvh_scene.intersects(&ray) {
+            SceneIntersection::Intersected(intersection) =>
+                assert_eq!(Vec3::init(0.0, 0.0, -8.0), intersection.point()),
+            SceneIntersection::Missed => panic!("Ray should hit the scaled sphere")
+        }
+    }
+
+    #[test]
+    fn spot_light_falls_off_between_its_cone_angles() {
+        let mut spot = SpotLight::new();
+        spot.pos = Vec3::init(0.0, 4.0, 0.0);
+        spot.dir = Vec3::init(0.0, -1.0, 0.0);
+        spot.intensity = ColorBuf::init(1.0, 1.0, 1.0);
+        spot.inner_angle = 0.2;
+        spot.outer_angle = 0.4;
+        let light = Light::Spot(spot);
+
+        assert_eq!(1.0, light.falloff(Vec3::new()));
+        assert_eq!(1.0, light.falloff(Vec3::init(0.2, 0.0, 0.3)));
+        assert_eq!(0.0, light.falloff(Vec3::init(4.0, 0.0, 0.0)));
+        let between = light.falloff(Vec3::init(1.2, 0.0, 0.0));
+        assert!(between > 0.0 && between < 1.0);
+    }
+
+    // Rays from the camera spread over the sphere field
+    fn field_rays() -> Vec<Ray> {
+        let mut rays = Vec::new();
+        for y in 0 .. 4 {
+            for x in 0 .. 4 {
+                let dir = Vec3::init(x as f32 * 0.2 - 0.3, y as f32 * 0.2 - 0.3, -1.0);
+                rays.push(Ray::init(Vec3::new(), dir));
+            }
+        }
+        rays
+    }
+
+    #[bench]
+    fn intersect_scene_of_1000_spheres(b: &mut Bencher) {
+        let scene = create_sphere_field(1000);
+        let rays = field_rays();
+        b.iter(|| for ray in rays.iter() {
+            black_box(scene.intersects(ray));
+        })
+    }
+
+    #[bench]
+    fn intersect_bvh_scene_of_1000_spheres(b: &mut Bencher) {
+        let scene = BvhScene::from_scene(create_sphere_field(1000));
+        let rays = field_rays();
+        b.iter(|| for ray in rays.iter() {
+            black_box(scene.intersects(ray));
+        })
+    }
 }
@@ -1,56 +1,93 @@
-use std::io::Read;
+use std::fmt;
+use std::io::{self, Read};
 use std::io::Bytes;
 use std::fs::File;
 use std::str::FromStr;
+use std::sync::Arc;
 
 use vec::Vec3;
-use scene::{BvhScene, Scene, Camera, Light, PointLight, AreaLight, DirectionalLight};
-use scene::material::{Material, Color};
-use scene::shapes::{sphere, poly};
-use scene::shapes::Primitive::{Sphere, Poly};
+use mat::Mat4;
+use scene::{BvhScene, Scene, Camera, Background, Light, PointLight, AreaLight, TriangleLight,
+    DirectionalLight, SpotLight};
+use scene::material::{Material, Checkerboard, Color, ColorBuf, ImageTexture, Texture};
+use scene::shapes::{sphere, poly, plane, cylinder, disk, torus, Primitive};
+use scene::shapes::Primitive::{Sphere, Poly, Plane, Cylinder, Disk, Torus};
+
+// An error in a scene file, at the line and column of the token where it was found
+#[derive(Clone, PartialEq, Debug)]
+pub struct ParseError {
+    pub line: usize,
+    pub col: usize,
+    pub msg: String
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at line {}, col {}", self.msg, self.line, self.col)
+    }
+}
+
+pub type ParseResult<T> = Result<T, ParseError>;
 
 pub struct SceneParser {
+    path: String,
+    // The files that include this one, used to detect recursive includes
+    includes: Vec<String>,
+    // The materials of the `materials` block. A `materialRef` copies its entry into the primitive,
+    // so the palette saves repeating blocks in scene files, not memory.
+    palette: Vec<Material>,
     bytes: Bytes<File>,
     finished: bool,
     peaked: bool,
-    last_token: Option<String>
+    last_token: Option<String>,
+    // The line and column of the last byte read, and where the last token started
+    line: usize,
+    col: usize,
+    token_pos: (usize, usize)
 }
 
 impl SceneParser {
     pub fn new(scene: String) -> SceneParser {
-        SceneParser {
-            bytes: SceneParser::read_file(scene),
-            finished: false,
-            peaked: false,
-            last_token: None
+        match SceneParser::open(scene.clone()) {
+            Ok(parser) => parser,
+            Err(e) => panic!("file error: {}, path: {}", e, scene)
         }
     }
 
-    fn read_file(path: String) -> Bytes<File> {
-        match File::open(&path) {
-            Ok(f) => f.bytes(),
-            Err(e) => panic!("file error: {}, path: {}", e, path.clone())
-        }
+    fn open(scene: String) -> io::Result<SceneParser> {
+        let file = try!(File::open(&scene));
+        Ok(SceneParser {
+            path: scene,
+            includes: Vec::new(),
+            palette: Vec::new(),
+            bytes: file.bytes(),
+            finished: false,
+            peaked: false,
+            last_token: None,
+            line: 1,
+            col: 0,
+            token_pos: (1, 0)
+        })
     }
 
     fn has_next_token(&self) -> bool {
         !self.finished
     }
 
-    fn peak(&mut self) -> String {
+    fn peak(&mut self) -> ParseResult<String> {
         if self.peaked {
             match self.last_token {
-                Some(ref tkn) => { return tkn.clone(); },
+                Some(ref tkn) => { return Ok(tkn.clone()); },
                 None => panic!("The peaked word does not exist")
             }
         }
-        let tkn = self.next_token();
+        let tkn = try!(self.next_token());
         self.last_token = Some(tkn.clone());
         self.peaked = true;
-        return tkn;
+        Ok(tkn)
     }
 
-    fn next_token(&mut self) -> String {
+    fn next_token(&mut self) -> ParseResult<String> {
         if self.peaked {
             let tkn = match self.last_token {
                 Some(ref tkn) => tkn.clone(),
@@ -58,146 +95,347 @@ impl SceneParser {
             };
             self.last_token = None;
             self.peaked = false;
-            return tkn;
+            return Ok(tkn);
         }
 
         let mut buf = String::new();
+        let mut in_comment = false;
         loop {
             let c = match self.bytes.next() {
                 Some(res) => match res {
                     Ok(c) => c as char,
-                    Err(e) => panic!("{}", e)
+                    Err(e) => return self.error(format!("{}", e))
                 },
                 None => {
                     self.finished = true;
-                    return buf.to_string();
+                    if SceneParser::is_comment(buf.as_slice()) {
+                        buf.clear();
+                    }
+                    return Ok(buf.to_string());
                 }
             };
-            if !c.is_whitespace() {
+            match c == '\n' {
+                true => { self.line += 1; self.col = 0; },
+                false => self.col += 1
+            }
+            if in_comment {
+                in_comment = c != '\n';
+            } else if !c.is_whitespace() {
+                if buf.len() == 0 {
+                    self.token_pos = (self.line, self.col);
+                }
                 buf.push(c);
+            } else if SceneParser::is_comment(buf.as_slice()) {
+                buf.clear();
+                in_comment = c != '\n';
             } else if buf.len() > 0 {
-                return buf.to_string();
+                return Ok(buf.to_string());
             }
         }
     }
 
-    fn next_num<T:FromStr>(&mut self) -> T {
-        let tkn = self.next_token();
+    // Comments run from a `#` at the start of a token to the end of the line. The `#include`
+    // directive is the only token that starts with `#` without being a comment.
+    fn is_comment(tkn: &str) -> bool {
+        tkn.starts_with("#") && tkn != "#include"
+    }
+
+    // The line and column where the last token that was read or peaked at starts
+    fn position(&self) -> (usize, usize) {
+        self.token_pos
+    }
+
+    // An error at the position of the last token that was read or peaked at
+    fn error<T>(&self, msg: String) -> ParseResult<T> {
+        let (line, col) = self.position();
+        Err(ParseError { line: line, col: col, msg: msg })
+    }
+
+    fn unexpected<T>(&self, tkn: &str) -> ParseResult<T> {
+        self.error(format!("unexpected token '{}'", tkn))
+    }
+
+    fn next_num<T:FromStr>(&mut self) -> ParseResult<T> {
+        let tkn = try!(self.next_token());
         match tkn.as_slice().parse() {
-            Ok(f) => f,
-            Err(_) => panic!("Could not represent token as num: '{}'", tkn)
+            Ok(f) => Ok(f),
+            Err(_) => self.error(format!("Could not represent token as num: '{}'", tkn))
         }
     }
 
-    fn consume_next(&mut self) {
-        let _ = self.next_token();
+    fn consume_next(&mut self) -> ParseResult<()> {
+        try!(self.next_token());
+        Ok(())
     }
 
-    fn check_and_consume(&mut self, token: &str) {
-        // TODO: Give a nicer error message than this assert?
-        assert_eq!(self.next_token().as_slice(), token)
+    fn check_and_consume(&mut self, token: &str) -> ParseResult<()> {
+        let tkn = try!(self.next_token());
+        match tkn.as_slice() == token {
+            true => Ok(()),
+            false => self.error(format!("unexpected token '{}', expected '{}'", tkn, token))
+        }
     }
 
-    fn parse_f32(&mut self, name: &str) -> f32 {
-        self.check_and_consume(name);
+    fn parse_f32(&mut self, name: &str) -> ParseResult<f32> {
+        try!(self.check_and_consume(name));
         self.next_num()
     }
 
-    fn parse_vec3(&mut self, name: &str) -> Vec3 {
-        self.check_and_consume(name);
-        Vec3::init(self.next_num(), self.next_num(), self.next_num())
+    fn parse_vec3(&mut self, name: &str) -> ParseResult<Vec3> {
+        try!(self.check_and_consume(name));
+        Ok(Vec3::init(try!(self.next_num()), try!(self.next_num()), try!(self.next_num())))
     }
 
-    fn parse_color(&mut self, color: &str) -> Color {
-        self.check_and_consume(color);
-        Color::init(self.next_num(), self.next_num(), self.next_num())
+    fn parse_color(&mut self, color: &str) -> ParseResult<Color> {
+        try!(self.check_and_consume(color));
+        Ok(Color::init(try!(self.next_num()), try!(self.next_num()), try!(self.next_num())))
     }
 
-    fn parse_bool(&mut self, name: &str, flag: &str) -> bool {
-        self.check_and_consume(name);
-        match self.next_token() {
-            ref tkn if tkn.as_slice() == flag => true,
-            _ => false
-        }
+    // Like `parse_color`, but light can be brighter than 1.0
+    fn parse_intensity(&mut self, color: &str) -> ParseResult<ColorBuf> {
+        try!(self.check_and_consume(color));
+        Ok(ColorBuf::init(try!(self.next_num()), try!(self.next_num()), try!(self.next_num())))
+    }
+
+    fn parse_bool(&mut self, name: &str, flag: &str) -> ParseResult<bool> {
+        try!(self.check_and_consume(name));
+        Ok(try!(self.next_token()).as_slice() == flag)
     }
 
-    fn parse_light(&mut self) -> Light {
-        let keyword = self.next_token();
+    fn parse_light(&mut self) -> ParseResult<Light> {
+        let keyword = try!(self.next_token());
 
-        self.check_and_consume("{");
+        try!(self.check_and_consume("{"));
 
         let light = match keyword.as_slice() {
             "point_light" => Light::Point(PointLight {
-                pos: self.parse_vec3("position"),
-                intensity: self.parse_color("color")
+                pos: try!(self.parse_vec3("position")),
+                intensity: try!(self.parse_intensity("color")),
+                enabled: true
             }),
             "area_light" => Light::Area(AreaLight {
-                min: self.parse_vec3("position"),
-                max: self.parse_vec3("position"),
-                intensity: self.parse_color("color")
+                min: try!(self.parse_vec3("position")),
+                max: try!(self.parse_vec3("position")),
+                intensity: try!(self.parse_intensity("color")),
+                enabled: true
             }),
-            "directional_light" => Light::Directional(DirectionalLight {
-                dir: self.parse_vec3("direction"),
-                intensity: self.parse_color("color")
+            "triangle_light" => Light::Triangle(TriangleLight {
+                v0: try!(self.parse_vec3("position")),
+                v1: try!(self.parse_vec3("position")),
+                v2: try!(self.parse_vec3("position")),
+                intensity: try!(self.parse_intensity("color")),
+                enabled: true
             }),
-            _ => panic!("LightType is not valid: {}", keyword)
+            "directional_light" => {
+                let mut light = DirectionalLight::new();
+                light.dir = try!(self.parse_vec3("direction"));
+                light.intensity = try!(self.parse_intensity("color"));
+                if try!(self.peak()).as_slice() == "towardLight" {
+                    light.toward_light = try!(self.parse_bool("towardLight", "TRUE"));
+                }
+                Light::Directional(light)
+            },
+            "spot_light" => Light::Spot(SpotLight {
+                pos: try!(self.parse_vec3("position")),
+                dir: try!(self.parse_vec3("direction")),
+                intensity: try!(self.parse_intensity("color")),
+                inner_angle: try!(self.parse_f32("innerAngle")),
+                outer_angle: try!(self.parse_f32("outerAngle")),
+                enabled: true
+            }),
+            _ => return self.unexpected(keyword.as_slice())
         };
 
-        self.check_and_consume("}");
-        light
+        try!(self.check_and_consume("}"));
+        Ok(light)
     }
 
-    fn parse_material(&mut self) -> Material {
-        self.check_and_consume("material");
-        self.check_and_consume("{");
-
-        let material = Material {
-            diffuse: self.parse_color("diffColor"),
-            ambient: self.parse_color("ambColor"),
-            specular: self.parse_color("specColor"),
-            emissive: self.parse_color("emisColor"),
-            shininess: self.parse_f32("shininess"),
-            transparency: self.parse_f32("ktran")
+    // Parses either a `material` block, or a `materialRef` to a material of the palette
+    fn parse_material(&mut self) -> ParseResult<Material> {
+        if try!(self.peak()).as_slice() == "materialRef" {
+            return self.parse_material_ref();
+        }
+        try!(self.check_and_consume("material"));
+        try!(self.check_and_consume("{"));
+
+        let mut material = Material {
+            diffuse: try!(self.parse_color("diffColor")),
+            ambient: try!(self.parse_color("ambColor")),
+            specular: try!(self.parse_color("specColor")),
+            emissive: try!(self.parse_color("emisColor")),
+            shininess: try!(self.parse_f32("shininess")),
+            transparency: try!(self.parse_f32("ktran")),
+            refractive_index: 1.5,
+            texture: None,
+            anisotropy: 0.0,
+            tangent: Vec3::new(),
+            roughness: 0.0
+        };
+        if try!(self.peak()).as_slice() == "ior" {
+            material.refractive_index = try!(self.parse_f32("ior"));
+        }
+        if try!(self.peak()).as_slice() == "anisotropy" {
+            material.anisotropy = try!(self.parse_f32("anisotropy"));
+            material.tangent = try!(self.parse_vec3("tangent"));
+        }
+        if try!(self.peak()).as_slice() == "roughness" {
+            material.roughness = try!(self.parse_f32("roughness"));
+        }
+        material.texture = match try!(self.peak()).as_slice() {
+            "checker" => Some(Texture::Checker(try!(self.parse_checker()))),
+            "texture" => Some(Texture::Image(Arc::new(try!(self.parse_image_texture())))),
+            _ => None
         };
+        material.validate();
+
+        try!(self.check_and_consume("}"));
+        Ok(material)
+    }
+
+    fn parse_material_ref(&mut self) -> ParseResult<Material> {
+        try!(self.check_and_consume("materialRef"));
+        let index: usize = try!(self.next_num());
+        match self.palette.get(index) {
+            Some(material) => Ok(material.clone()),
+            None => self.error(format!("materialRef {} is not in the palette of {} materials",
+                index, self.palette.len()))
+        }
+    }
+
+    // Parses the palette of materials, which replaces any palette parsed before it
+    fn parse_palette(&mut self) -> ParseResult<()> {
+        try!(self.check_and_consume("materials"));
+        try!(self.check_and_consume("{"));
+        try!(self.check_and_consume("numMaterials"));
 
-        self.check_and_consume("}");
-        material
+        let num_materials: usize = try!(self.next_num());
+        let mut palette = Vec::with_capacity(num_materials);
+        for _ in 0 .. num_materials {
+            palette.push(try!(self.parse_material()));
+        }
+        self.palette = palette;
+        self.check_and_consume("}")
     }
 
-    fn parse_sphere(&mut self) -> sphere::Sphere {
-        self.check_and_consume("sphere");
-        self.check_and_consume("{");
-        self.check_and_consume("name");
-        self.consume_next();
-        self.check_and_consume("numMaterials");
+    fn parse_checker(&mut self) -> ParseResult<Checkerboard> {
+        try!(self.check_and_consume("checker"));
+        try!(self.check_and_consume("{"));
+        let checker = Checkerboard::init(try!(self.parse_color("colorA")),
+            try!(self.parse_color("colorB")), try!(self.parse_f32("scale")));
+        try!(self.check_and_consume("}"));
+        Ok(checker)
+    }
 
-        let mut num_materials: i32 = self.next_num();
+    // Parses the path of an image, relative to the scene file, and opens it
+    fn parse_image_texture(&mut self) -> ParseResult<ImageTexture> {
+        try!(self.check_and_consume("texture"));
+        let path = try!(self.parse_path());
+        match ImageTexture::open(path.as_slice()) {
+            Ok(texture) => Ok(texture),
+            Err(e) => self.error(e)
+        }
+    }
+
+    fn parse_sphere(&mut self) -> ParseResult<sphere::Sphere> {
+        try!(self.check_and_consume("sphere"));
+        try!(self.check_and_consume("{"));
+        try!(self.check_and_consume("name"));
+        try!(self.consume_next());
+        try!(self.check_and_consume("numMaterials"));
+
+        let mut num_materials: i32 = try!(self.next_num());
         let mut sphere = sphere::Sphere::new();
         while num_materials > 0 {
-            let material = self.parse_material();
+            let material = try!(self.parse_material());
             sphere.materials.push(material);
             num_materials -= 1;
         }
 
-        sphere.origin = self.parse_vec3("origin");
-        sphere.radius = self.parse_f32("radius");
-        sphere.xaxis = self.parse_vec3("xaxis");
-        sphere.xlength = self.parse_f32("xlength");
-        sphere.yaxis = self.parse_vec3("yaxis");
-        sphere.ylength = self.parse_f32("ylength");
-        sphere.zaxis = self.parse_vec3("zaxis");
-        sphere.zlength = self.parse_f32("zlength");
+        sphere.origin = try!(self.parse_vec3("origin"));
+        sphere.radius = try!(self.parse_f32("radius"));
+        sphere.xaxis = try!(self.parse_vec3("xaxis"));
+        sphere.xlength = try!(self.parse_f32("xlength"));
+        sphere.yaxis = try!(self.parse_vec3("yaxis"));
+        sphere.ylength = try!(self.parse_f32("ylength"));
+        sphere.zaxis = try!(self.parse_vec3("zaxis"));
+        sphere.zlength = try!(self.parse_f32("zlength"));
+
+        try!(self.check_and_consume("}"));
+        Ok(sphere)
+    }
 
-        self.check_and_consume("}");
-        sphere
+    fn parse_plane(&mut self) -> ParseResult<plane::Plane> {
+        try!(self.check_and_consume("plane"));
+        try!(self.check_and_consume("{"));
+        try!(self.check_and_consume("name"));
+        try!(self.consume_next());
+
+        let point = try!(self.parse_vec3("point"));
+        let normal = try!(self.parse_vec3("normal"));
+        let mut plane = plane::Plane::init(point, normal);
+        plane.material = try!(self.parse_material());
+
+        try!(self.check_and_consume("}"));
+        Ok(plane)
+    }
+
+    fn parse_cylinder(&mut self) -> ParseResult<cylinder::Cylinder> {
+        try!(self.check_and_consume("cylinder"));
+        try!(self.check_and_consume("{"));
+        try!(self.check_and_consume("name"));
+        try!(self.consume_next());
+
+        let base = try!(self.parse_vec3("base"));
+        let axis = try!(self.parse_vec3("axis"));
+        let radius = try!(self.parse_f32("radius"));
+        let height = try!(self.parse_f32("height"));
+        let mut cylinder = cylinder::Cylinder::init(base, axis, radius, height);
+        cylinder.material = try!(self.parse_material());
+
+        try!(self.check_and_consume("}"));
+        Ok(cylinder)
     }
 
-    fn parse_vertex(&mut self, has_normal: bool, has_material: bool) -> poly::Vertex {
-        let mut vertex = poly::Vertex::init(self.parse_vec3("pos"));
+    fn parse_disk(&mut self) -> ParseResult<disk::Disk> {
+        try!(self.check_and_consume("disk"));
+        try!(self.check_and_consume("{"));
+        try!(self.check_and_consume("name"));
+        try!(self.consume_next());
+
+        let center = try!(self.parse_vec3("center"));
+        let normal = try!(self.parse_vec3("normal"));
+        let radius = try!(self.parse_f32("radius"));
+        let mut disk = disk::Disk::init(center, normal, radius);
+        disk.material = try!(self.parse_material());
+
+        try!(self.check_and_consume("}"));
+        Ok(disk)
+    }
+
+    fn parse_torus(&mut self) -> ParseResult<torus::Torus> {
+        try!(self.check_and_consume("torus"));
+        try!(self.check_and_consume("{"));
+        try!(self.check_and_consume("name"));
+        try!(self.consume_next());
+
+        let center = try!(self.parse_vec3("center"));
+        let axis = try!(self.parse_vec3("axis"));
+        let major_radius = try!(self.parse_f32("majorRadius"));
+        let minor_radius = try!(self.parse_f32("minorRadius"));
+        let mut torus = torus::Torus::init(center, axis, major_radius, minor_radius);
+        torus.material = try!(self.parse_material());
+
+        try!(self.check_and_consume("}"));
+        Ok(torus)
+    }
+
+    fn parse_vertex(&mut self, has_normal: bool, has_material: bool, has_texture: bool)
+            -> ParseResult<poly::Vertex> {
+        let mut vertex = poly::Vertex::init(try!(self.parse_vec3("pos")));
 
         match has_normal {
             true => {
-                vertex.normal = self.parse_vec3("norm");
+                vertex.normal = try!(self.parse_vec3("norm"));
                 vertex.has_normal = true;
             },
             false => ()
@@ -205,84 +443,91 @@ impl SceneParser {
 
         match has_material {
             true => {
-                self.check_and_consume("materialIndex");
-                vertex.mat_index = self.next_num();
+                try!(self.check_and_consume("materialIndex"));
+                vertex.mat_index = try!(self.next_num());
             },
             false => ()
         }
-        vertex
+
+        match has_texture {
+            true => {
+                try!(self.check_and_consume("texCoord"));
+                vertex.uv = (try!(self.next_num()), try!(self.next_num()));
+            },
+            false => ()
+        }
+        Ok(vertex)
     }
 
-    fn parse_poly(&mut self, has_normal: bool, has_material: bool) -> poly::Poly {
-        self.check_and_consume("poly");
-        self.check_and_consume("{");
-        self.check_and_consume("numVertices");
-        self.consume_next(); // Always 3
+    fn parse_poly(&mut self, has_normal: bool, has_material: bool, has_texture: bool)
+            -> ParseResult<poly::Poly> {
+        try!(self.check_and_consume("poly"));
+        try!(self.check_and_consume("{"));
+        try!(self.check_and_consume("numVertices"));
+        try!(self.consume_next()); // Always 3
 
         let poly = poly::Poly {
             materials: Vec::new(),
             vertices: [
-                self.parse_vertex(has_normal, has_material),
-                self.parse_vertex(has_normal, has_material),
-                self.parse_vertex(has_normal, has_material)
+                try!(self.parse_vertex(has_normal, has_material, has_texture)),
+                try!(self.parse_vertex(has_normal, has_material, has_texture)),
+                try!(self.parse_vertex(has_normal, has_material, has_texture))
             ],
             vertex_material: has_material,
             vertex_normal: has_normal
         };
-        self.check_and_consume("}");
-        poly
+        try!(self.check_and_consume("}"));
+        Ok(poly)
     }
 
-    fn parse_polyset(&mut self) -> Vec<poly::Poly> {
-        self.check_and_consume("poly_set");
-        self.check_and_consume("{");
-        self.check_and_consume("name");
-        self.consume_next();
-        self.check_and_consume("numMaterials");
+    fn parse_polyset(&mut self) -> ParseResult<Vec<poly::Poly>> {
+        try!(self.check_and_consume("poly_set"));
+        try!(self.check_and_consume("{"));
+        try!(self.check_and_consume("name"));
+        try!(self.consume_next());
+        try!(self.check_and_consume("numMaterials"));
 
-        let mut num_materials: usize = self.next_num();
+        let mut num_materials: usize = try!(self.next_num());
         let mut materials = Vec::with_capacity(num_materials);
         while num_materials > 0 {
-            let material = self.parse_material();
+            let material = try!(self.parse_material());
             materials.push(material);
             num_materials -= 1;
         }
 
-        self.check_and_consume("type");
-        self.consume_next(); // TODO: Use this field later
-        let per_vertex_normal = self.parse_bool("normType", "PER_VERTEX_NORMAL");
-        let material_binding = self.parse_bool("materialBinding", "PER_VERTEX_MATERIAL");
-        self.check_and_consume("hasTextureCoords");
-        self.consume_next(); // TODO: This field is probably never used
-        self.check_and_consume("rowSize");
-        self.consume_next(); // TODO: This field is probably never used
-        self.check_and_consume("numPolys");
-
-        let mut num_polys: usize = self.next_num();
+        try!(self.check_and_consume("type"));
+        try!(self.consume_next()); // TODO: Use this field later
+        let per_vertex_normal = try!(self.parse_bool("normType", "PER_VERTEX_NORMAL"));
+        let material_binding = try!(self.parse_bool("materialBinding", "PER_VERTEX_MATERIAL"));
+        let has_texture_coords = try!(self.parse_bool("hasTextureCoords", "TRUE"));
+        try!(self.check_and_consume("rowSize"));
+        try!(self.consume_next()); // TODO: This field is probably never used
+        try!(self.check_and_consume("numPolys"));
+
+        let mut num_polys: usize = try!(self.next_num());
         let mut polyset = Vec::with_capacity(num_polys);
         while num_polys > 0 {
-            let mut poly = self.parse_poly(per_vertex_normal, material_binding);
+            let mut poly = try!(self.parse_poly(per_vertex_normal, material_binding,
+                has_texture_coords));
 
             match material_binding {
                 true => {
-                    let (i0, i1, i2) = (poly[0].mat_index, poly[1].mat_index, poly[2].mat_index);
-                    poly.materials.push(materials[i0 as usize].clone());
-                    poly.vertices[0].mat_index = poly.materials.len() as u32 - 1;
-
-                    if i1 != i0 {
-                        poly.materials.push(materials[i1 as usize].clone());
-                        poly.vertices[1].mat_index = poly.materials.len() as u32 - 1;
-                    } else {
-                        poly.vertices[1].mat_index = 0;
-                    }
-
-                    if i2 != i1 && i2 != i0 {
-                        poly.materials.push(materials[i2 as usize].clone());
-                        poly.vertices[2].mat_index = poly.materials.len() as u32 - 1;
-                    } else if i2 == i1 && i2 != i0 {
-                        poly.vertices[2].mat_index = 1;
-                    } else {
-                        poly.vertices[2].mat_index = 0;
+                    // Each poly only keeps the materials its vertices use, so the indices are
+                    // remapped to the position of the material in `poly.materials`
+                    let mut used: Vec<u32> = Vec::with_capacity(3);
+                    for vertex in poly.vertices.iter_mut() {
+                        let index = vertex.mat_index;
+                        if index as usize >= materials.len() {
+                            continue; // Left out of range, see `Scene::validate_materials`
+                        }
+                        vertex.mat_index = match used.iter().position(|&i| i == index) {
+                            Some(i) => i as u32,
+                            None => {
+                                used.push(index);
+                                poly.materials.push(materials[index as usize].clone());
+                                poly.materials.len() as u32 - 1
+                            }
+                        };
                     }
                 },
                 false => {
@@ -293,61 +538,171 @@ impl SceneParser {
             num_polys -= 1;
         }
 
-        self.check_and_consume("}");
-        polyset
+        try!(self.check_and_consume("}"));
+        Ok(polyset)
     }
 
-    fn parse_camera(&mut self) -> Camera {
-        self.check_and_consume("camera");
-        self.check_and_consume("{");
-        let camera = Camera {
-            pos: self.parse_vec3("position"),
-            view_dir: self.parse_vec3("viewDirection"),
-            focal_dist: self.parse_f32("focalDistance"),
-            ortho_up: self.parse_vec3("orthoUp"),
-            vertical_fov: self.parse_f32("verticalFOV")
-        };
-        self.check_and_consume("}");
-        camera
+    fn parse_primitives(&mut self) -> ParseResult<Vec<Primitive>> {
+        let tkn = try!(self.peak());
+        match tkn.as_slice() {
+            "sphere" => Ok(vec!(Sphere(try!(self.parse_sphere())))),
+            "plane" => Ok(vec!(Plane(try!(self.parse_plane())))),
+            "cylinder" => Ok(vec!(Cylinder(try!(self.parse_cylinder())))),
+            "disk" => Ok(vec!(Disk(try!(self.parse_disk())))),
+            "torus" => Ok(vec!(Torus(try!(self.parse_torus())))),
+            "poly_set" => {
+                let polyset = try!(self.parse_polyset());
+                Ok(polyset.into_iter().map(|poly| Poly(poly)).collect())
+            },
+            "grid" => self.parse_grid(),
+            other => self.unexpected(other)
+        }
     }
 
-    pub fn parse_scene<'a>(&mut self) -> Scene {
-        self.check_and_consume("Composer");
-        self.check_and_consume("format");
-        self.check_and_consume("2.1");
-        self.check_and_consume("ascii");
+    // Expands into copies of the primitives of the inner block, placed `spacing` apart on a grid
+    // of `count` copies along each axis
+    fn parse_grid(&mut self) -> ParseResult<Vec<Primitive>> {
+        try!(self.check_and_consume("grid"));
+        try!(self.check_and_consume("{"));
+        try!(self.check_and_consume("count"));
+        let (nx, ny, nz): (usize, usize, usize) = (try!(self.next_num()), try!(self.next_num()),
+            try!(self.next_num()));
+        let spacing = try!(self.parse_vec3("spacing"));
+        let prims = try!(self.parse_primitives());
+        try!(self.check_and_consume("}"));
+
+        let mut grid = Vec::with_capacity(nx * ny * nz * prims.len());
+        for x in 0 .. nx {
+            for y in 0 .. ny {
+                for z in 0 .. nz {
+                    let offset = Vec3::init(x as f32, y as f32, z as f32) * spacing;
+                    for prim in prims.iter() {
+                        grid.push(prim.translate(offset));
+                    }
+                }
+            }
+        }
+        Ok(grid)
+    }
 
-        let mut scene = Scene::new();
+    fn parse_camera(&mut self) -> ParseResult<Camera> {
+        try!(self.check_and_consume("camera"));
+        try!(self.check_and_consume("{"));
+        let mut camera = Camera::new();
+        camera.pos = try!(self.parse_vec3("position"));
+        camera.view_dir = try!(self.parse_vec3("viewDirection"));
+        camera.focal_dist = try!(self.parse_f32("focalDistance"));
+        camera.ortho_up = try!(self.parse_vec3("orthoUp"));
+        camera.vertical_fov = try!(self.parse_f32("verticalFOV"));
+        if try!(self.peak()).as_slice() == "transform" {
+            camera.transform = try!(self.parse_transform());
+        }
+        try!(self.check_and_consume("}"));
+        Ok(camera)
+    }
+
+    // A block of `translate x y z`, `rotate x y z angle` and `scale x y z` steps, which are
+    // applied in the order they are listed. Angles are in radians.
+    fn parse_transform(&mut self) -> ParseResult<Mat4> {
+        try!(self.check_and_consume("transform"));
+        try!(self.check_and_consume("{"));
+        let mut transform = Mat4::new();
+        loop {
+            let step = match try!(self.next_token()).as_slice() {
+                "translate" => Mat4::translation(Vec3::init(try!(self.next_num()),
+                    try!(self.next_num()), try!(self.next_num()))),
+                "rotate" => Mat4::rotation(Vec3::init(try!(self.next_num()),
+                    try!(self.next_num()), try!(self.next_num())), try!(self.next_num())),
+                "scale" => Mat4::scaling(Vec3::init(try!(self.next_num()), try!(self.next_num()),
+                    try!(self.next_num()))),
+                "}" => return Ok(transform),
+                tkn => return self.unexpected(tkn)
+            };
+            transform = step * transform;
+        }
+    }
+
+    // A sky that blends from the bottom color below the horizon to the top color above it
+    fn parse_sky(&mut self) -> ParseResult<Background> {
+        try!(self.check_and_consume("sky"));
+        try!(self.check_and_consume("{"));
+        let top = try!(self.parse_color("top"));
+        let bottom = try!(self.parse_color("bottom"));
+        try!(self.check_and_consume("}"));
+        Ok(Background::Gradient { top: top, bottom: bottom })
+    }
+
+    // A quoted path, taken to be relative to the directory of this file
+    fn parse_path(&mut self) -> ParseResult<String> {
+        let tkn = try!(self.next_token());
+        let name = tkn.as_slice().trim_matches('"');
+        Ok(match self.path.rfind('/') {
+            Some(i) => format!("{}{}", &self.path[.. i + 1], name),
+            None => name.to_string()
+        })
+    }
+
+    fn parse_header(&mut self) -> ParseResult<()> {
+        try!(self.check_and_consume("Composer"));
+        try!(self.check_and_consume("format"));
+        try!(self.check_and_consume("2.1"));
+        self.check_and_consume("ascii")
+    }
+
+    // Parses `#include "path"`, where the path is relative to the directory of this file, and
+    // adds what the included file contains to `scene`. The header of the included file is
+    // optional, so both scenes and bare lists of lights and primitives can be included.
+    fn parse_include(&mut self, scene: &mut Scene) -> ParseResult<()> {
+        try!(self.check_and_consume("#include"));
+        let path = try!(self.parse_path());
+        if path == self.path || self.includes.contains(&path) {
+            return self.error(format!("Recursive include of {} in {}", path, self.path));
+        }
+
+        let mut parser = match SceneParser::open(path.clone()) {
+            Ok(parser) => parser,
+            Err(e) => return self.error(format!("Could not include {}: {}", path, e))
+        };
+        parser.includes = self.includes.clone();
+        parser.includes.push(self.path.clone());
+        parser.palette = self.palette.clone();
+        if try!(parser.peak()).as_slice() == "Composer" {
+            try!(parser.parse_header());
+        }
+        parser.parse_entries(scene)
+    }
 
-        let mut tkn = self.peak();
+    fn parse_entries(&mut self, scene: &mut Scene) -> ParseResult<()> {
+        let mut tkn = try!(self.peak());
         while self.has_next_token() {
             match tkn.as_slice() {
-                "camera" => scene.camera = self.parse_camera(),
-                "sphere" => {
-                    let sphere = self.parse_sphere();
-                    scene.primitives.push(Sphere(sphere));
-                },
-                "poly_set" => {
-                    let mut polyset = self.parse_polyset();
-
-                    for _ in 0 .. polyset.len() {
-                        match polyset.pop() {
-                            Some(poly) => scene.primitives.push(Poly(poly)),
-                            None => panic!("Incorrect amount of polys in polyset")
-                        }
-                    }
-                },
-                token if token.ends_with("light") => scene.lights.push(self.parse_light()),
-                other => panic!("Unexpected token: {}", other)
+                "camera" => scene.camera = try!(self.parse_camera()),
+                "sky" => scene.background = try!(self.parse_sky()),
+                "materials" => try!(self.parse_palette()),
+                "#include" => try!(self.parse_include(scene)),
+                token if token.ends_with("light") => scene.lights.push(try!(self.parse_light())),
+                _ => scene.primitives.extend(try!(self.parse_primitives()).into_iter())
             }
-            tkn = self.peak();
+            tkn = try!(self.peak());
+        }
+        Ok(())
+    }
+
+    pub fn parse_scene(&mut self) -> ParseResult<Scene> {
+        try!(self.parse_header());
+
+        let mut scene = Scene::new();
+        try!(self.parse_entries(&mut scene));
+
+        match scene.validate_materials() {
+            Ok(_) => Ok(scene),
+            Err(e) => self.error(format!("Invalid materials in scene: {}", e))
         }
-        scene
     }
 
-    pub fn parse_bvh_scene<'a>(&mut self) -> BvhScene {
-        let scene = self.parse_scene();
-        BvhScene::from_scene(scene)
+    pub fn parse_bvh_scene(&mut self) -> ParseResult<BvhScene> {
+        let scene = try!(self.parse_scene());
+        Ok(BvhScene::from_scene(scene))
     }
 }
 
@@ -1,7 +1,13 @@
+use rand::thread_rng;
+
 use vec::Vec3;
-use scene::parser::SceneParser;
-use scene::material::Color;
-use scene::Light::{Point, Area, Directional};
+use mat::Mat4;
+use scene::parser::{ParseError, SceneParser};
+use scene::{Background, Scene};
+use scene::material::{Color, Material, Texture};
+use scene::Light::{Point, Area, Directional, Spot};
+use scene::shapes::Primitive::{Sphere, Poly};
+use scene::shapes::Shape;
 
 static TEST_PATH : &'static str   = "src/scene/parser/test/testdata-";
 
@@ -16,21 +22,21 @@ fn scene_parser(name: &str) -> SceneParser {
 fn can_parse_tokens() {
     let mut parser = scene_parser("light");
 
-    let fst = parser.next_token();
+    let fst = parser.next_token().unwrap();
     assert_eq!("point_light", fst.as_slice());
 
-    let snd = parser.next_token();
+    let snd = parser.next_token().unwrap();
     assert_eq!("{", snd.as_slice());
 
-    let thrd = parser.next_token();
+    let thrd = parser.next_token().unwrap();
     assert_eq!("position", thrd.as_slice());
 
-    let frth = parser.next_token();
+    let frth = parser.next_token().unwrap();
     assert_eq!("-1", frth.as_slice());
 
     let mut tkn = "".to_string();
     while parser.has_next_token() {
-        tkn = parser.next_token();
+        tkn = parser.next_token().unwrap();
     }
     assert_eq!("}", tkn.as_slice());
 }
@@ -39,36 +45,36 @@ fn can_parse_tokens() {
 fn can_peak_at_next_token() {
     let mut parser = scene_parser("light");
 
-    let tkn = parser.next_token();
+    let tkn = parser.next_token().unwrap();
     assert_eq!("point_light", tkn.as_slice());
 
-    let tkn = parser.peak();
+    let tkn = parser.peak().unwrap();
     assert_eq!("{", tkn.as_slice());
 
-    let tkn = parser.peak();
+    let tkn = parser.peak().unwrap();
     assert_eq!("{", tkn.as_slice());
 
-    let tkn = parser.next_token();
+    let tkn = parser.next_token().unwrap();
     assert_eq!("{", tkn.as_slice());
 
-    let tkn = parser.next_token();
+    let tkn = parser.next_token().unwrap();
     assert_eq!("position", tkn.as_slice());
 }
 
 #[test]
 fn can_parse_f32() {
     let mut parser = scene_parser("f32");
-    let fst: f32 = parser.next_num();
+    let fst: f32 = parser.next_num().unwrap();
     assert_eq!(1.5, fst);
 
-    let snd: f32 = parser.next_num();
+    let snd: f32 = parser.next_num().unwrap();
     assert_eq!(-0.5, snd);
 }
 
 #[test]
 fn can_parse_position() {
     let mut parser = scene_parser("position");
-    let pos: Vec3 = parser.parse_vec3("position");
+    let pos: Vec3 = parser.parse_vec3("position").unwrap();
     assert_eq!(-1.0, pos.x);
     assert_eq!(0.0, pos.y);
     assert_eq!(2.0, pos.z);
@@ -77,7 +83,7 @@ fn can_parse_position() {
 #[test]
 fn can_parse_color() {
     let mut parser = scene_parser("color");
-    let color: Color = parser.parse_color("color");
+    let color: Color = parser.parse_color("color").unwrap();
     assert_eq!(1.0, color.r_val());
     assert_eq!(0.0, color.g_val());
     assert_eq!(0.5, color.b_val());
@@ -87,55 +93,172 @@ fn can_parse_color() {
 fn can_parse_light() {
     let mut parser = scene_parser("light");
 
-    match parser.parse_light() {
+    match parser.parse_light().unwrap() {
         Point(ref p_light) => {
             assert_eq!(p_light.pos.x, -1.0);
-            assert_eq!(p_light.intensity.r_val(), 1.0);
+            assert_eq!(p_light.intensity.r, 1.0);
         },
         _ => ()
     }
 
-    match parser.parse_light() {
+    match parser.parse_light().unwrap() {
         Area(a_light) => {
             assert_eq!(a_light.min.x, 0.0);
             assert_eq!(a_light.max.x, 200.0);
-            assert_eq!(a_light.intensity.r_val(), 0.0);
+            assert_eq!(a_light.intensity.r, 0.0);
         },
         _ => ()
     }
 
-    match parser.parse_light() {
+    match parser.parse_light().unwrap() {
         Directional(ref d_light) => {
             assert_eq!(d_light.dir.x, 0.5);
-            assert_eq!(d_light.intensity.r_val(), 0.5);
+            assert_eq!(d_light.intensity.r, 0.5);
         },
         _ => ()
     }
 }
 
+#[test]
+fn can_parse_directional_light_conventions() {
+    let mut parser = scene_parser("directional-light");
+    let point = Vec3::init(1.0, 2.0, 3.0);
+    let mut rng = thread_rng();
+
+    let away = parser.parse_light().unwrap();
+    let toward = parser.parse_light().unwrap();
+    match (away, toward) {
+        (Directional(ref a), Directional(ref t)) => {
+            assert!(!a.toward_light);
+            assert!(t.toward_light);
+        },
+        _ => panic!("Both lights should be directional")
+    }
+
+    assert_eq!(Vec3::init(0.0, 1.0, 0.0), away.get_dir_with(point, &mut rng));
+    assert_eq!(Vec3::init(0.0, 1.0, 0.0), toward.get_dir_with(point, &mut rng));
+}
+
+#[test]
+fn can_parse_spot_light() {
+    let mut parser = scene_parser("spot-light");
+    match parser.parse_light().unwrap() {
+        Spot(ref s_light) => {
+            assert_eq!(Vec3::init(0.0, 4.0, -2.0), s_light.pos);
+            assert_eq!(Vec3::init(0.0, -1.0, 0.0), s_light.dir);
+            assert_eq!(s_light.intensity.g, 0.5);
+            assert_eq!(s_light.inner_angle, 0.3);
+            assert_eq!(s_light.outer_angle, 0.6);
+            assert!(s_light.enabled);
+        },
+        _ => panic!("Light should be a spot light")
+    }
+}
+
 #[test]
 fn can_parse_material() {
     let mut parser = scene_parser("material");
-    let material = parser.parse_material();
+    let material = parser.parse_material().unwrap();
     assert_eq!(material.diffuse.r_val(), 0.56);
     assert_eq!(material.ambient.r_val(), 0.2);
     assert_eq!(material.shininess, 0.2);
     assert_eq!(material.transparency, 0.5);
+    assert_eq!(material.refractive_index, 1.33);
+    assert_eq!(material.roughness, 0.3);
+}
+
+#[test]
+fn can_clamp_invalid_material() {
+    let mut parser = scene_parser("invalid-material");
+    let material = parser.parse_material().unwrap();
+    assert_eq!(material.shininess, 0.0);
+    assert_eq!(material.transparency, 1.0);
+    assert_eq!(material.refractive_index, 1.5);
 }
 
 #[test]
 fn can_parse_sphere() {
     let mut parser = scene_parser("sphere");
-    let sphere = parser.parse_sphere();
+    let sphere = parser.parse_sphere().unwrap();
     assert_eq!(sphere.materials.len(), 1);
     assert_eq!(sphere.origin.y, -0.5);
     assert_eq!(sphere.radius, 1.5);
 }
 
+#[test]
+fn can_parse_sphere_texture() {
+    let mut parser = scene_parser("textured-sphere");
+    let sphere = parser.parse_sphere().unwrap();
+    match sphere.materials[0].texture {
+        Some(Texture::Image(_)) => (),
+        _ => panic!("The material of the sphere should have an image texture")
+    }
+    // The top row of the texture is red and green, which blend at the north pole
+    let north = sphere.origin + Vec3::init(0.0, sphere.radius, 0.0);
+    assert_eq!(Color::init(0.5, 0.5, 0.0), sphere.diffuse_color(north));
+}
+
+#[test]
+fn reports_the_path_of_a_missing_texture() {
+    match scene_parser("missing-texture").parse_sphere() {
+        Ok(_) => panic!("The missing texture should be reported"),
+        Err(e) => assert!(e.msg.starts_with(
+            "Could not open the texture src/scene/parser/test/testdata-missing.bmp"))
+    }
+}
+
+#[test]
+fn can_parse_plane() {
+    let mut parser = scene_parser("plane");
+    let plane = parser.parse_plane().unwrap();
+    assert_eq!(plane.point, Vec3::init(0.0, -1.0, 0.0));
+    assert_eq!(plane.normal, Vec3::init(0.0, 1.0, 0.0));
+    assert_eq!(plane.material.diffuse, Color::init(0.5, 0.5, 0.5));
+    match plane.material.texture {
+        Some(Texture::Checker(checker)) => {
+            assert_eq!(checker.color_b, Color::init(0.0, 0.0, 0.0));
+            assert_eq!(checker.scale, 2.0);
+        },
+        _ => panic!("The material of the plane should have a checker")
+    }
+}
+
+#[test]
+fn can_parse_cylinder() {
+    let mut parser = scene_parser("cylinder");
+    let cylinder = parser.parse_cylinder().unwrap();
+    assert_eq!(cylinder.base, Vec3::init(0.0, -1.0, -4.0));
+    assert_eq!(cylinder.axis, Vec3::init(0.0, 1.0, 0.0));
+    assert_eq!(cylinder.radius, 0.5);
+    assert_eq!(cylinder.height, 2.0);
+    assert_eq!(cylinder.material.diffuse, Color::init(0.2, 0.4, 0.8));
+}
+
+#[test]
+fn can_parse_disk() {
+    let mut parser = scene_parser("disk");
+    let disk = parser.parse_disk().unwrap();
+    assert_eq!(disk.center, Vec3::init(0.0, -1.0, 0.0));
+    assert_eq!(disk.normal, Vec3::init(0.0, 1.0, 0.0));
+    assert_eq!(disk.radius, 3.0);
+    assert_eq!(disk.material.diffuse, Color::init(0.5, 0.5, 0.5));
+}
+
+#[test]
+fn can_parse_torus() {
+    let mut parser = scene_parser("torus");
+    let torus = parser.parse_torus().unwrap();
+    assert_eq!(torus.center, Vec3::init(0.0, 0.0, -5.0));
+    assert_eq!(torus.axis, Vec3::init(0.0, 0.0, 1.0));
+    assert_eq!(torus.major_radius, 2.0);
+    assert_eq!(torus.minor_radius, 0.5);
+    assert_eq!(torus.material.diffuse, Color::init(0.8, 0.6, 0.2));
+}
+
 #[test]
 fn can_parse_poly() {
     let mut parser = scene_parser("polygon");
-    let poly = parser.parse_poly(false, false);
+    let poly = parser.parse_poly(false, false, false).unwrap();
     assert_eq!(poly[0][0], 0.0);
     assert_eq!(poly[1][0], 0.5);
     assert_eq!(poly[2][0], 10.0);
@@ -144,7 +267,7 @@ fn can_parse_poly() {
 #[test]
 fn can_parse_polyset() {
     let mut parser = scene_parser("polyset");
-    let polyset = parser.parse_polyset();
+    let polyset = parser.parse_polyset().unwrap();
     assert_eq!(polyset.len(), 12);
 
     let ref poly0 = polyset[0];
@@ -156,7 +279,7 @@ fn can_parse_polyset() {
 #[test]
 fn can_parse_per_vertex_polyset() {
     let mut parser = scene_parser("per-vertex-polyset");
-    let polyset = parser.parse_polyset();
+    let polyset = parser.parse_polyset().unwrap();
     assert_eq!(polyset.len(), 3);
 
     let ref poly0 = polyset[0];
@@ -191,21 +314,178 @@ fn can_parse_per_vertex_polyset() {
     assert_eq!(poly2[2].mat_index, 0);
 }
 
+fn polyset_scene(name: &str) -> Scene {
+    let mut parser = scene_parser(name);
+    let mut scene = Scene::new();
+    for poly in parser.parse_polyset().unwrap().into_iter() {
+        scene.primitives.push(Poly(poly));
+    }
+    scene
+}
+
+#[test]
+fn per_vertex_polyset_has_valid_materials() {
+    assert_eq!(Ok(()), polyset_scene("per-vertex-polyset").validate_materials());
+}
+
+#[test]
+fn invalid_per_vertex_materials_are_reported() {
+    match polyset_scene("invalid-per-vertex-polyset").validate_materials() {
+        Ok(_) => panic!("Material index 3 of 2 materials should be reported"),
+        Err(e) => assert_eq!("primitive 0: vertex 2 has material index 3 of 1", e.as_slice())
+    }
+}
+
+#[test]
+fn can_parse_textured_polyset() {
+    let mut parser = scene_parser("textured-polyset");
+    let polyset = parser.parse_polyset().unwrap();
+    assert_eq!(polyset.len(), 2);
+
+    let ref poly0 = polyset[0];
+    assert_eq!(poly0[0].uv, (0.0, 0.0));
+    assert_eq!(poly0[1].uv, (1.0, 0.0));
+    assert_eq!(poly0[2].uv, (1.0, 1.0));
+
+    let ref poly1 = polyset[1];
+    assert_eq!(poly1[0].uv, (0.0, 0.0));
+    assert_eq!(poly1[1].uv, (1.0, 1.0));
+    assert_eq!(poly1[2].uv, (0.0, 0.5));
+}
+
+#[test]
+fn can_parse_grid() {
+    let mut parser = scene_parser("grid");
+    let grid = parser.parse_grid().unwrap();
+    assert_eq!(grid.len(), 27);
+
+    for x in 0 .. 3 {
+        for y in 0 .. 3 {
+            for z in 0 .. 3 {
+                match grid[x * 9 + y * 3 + z] {
+                    Sphere(ref sphere) => assert_eq!(sphere.origin,
+                        Vec3::init(1.0 + 2.0 * x as f32, 3.0 * y as f32, -4.0 * z as f32)),
+                    _ => panic!("Grid should only contain spheres")
+                }
+            }
+        }
+    }
+}
+
 #[test]
 fn can_parse_camera() {
     let mut parser = scene_parser("camera");
-    let camera = parser.parse_camera();
+    let camera = parser.parse_camera().unwrap();
     assert_eq!(camera.pos[0], 1.0);
     assert_eq!(camera.view_dir[0], -1.0);
     assert_eq!(camera.focal_dist, 12.0);
     assert_eq!(camera.ortho_up[0], 2.0);
     assert_eq!(camera.vertical_fov, 0.5);
+    assert_eq!(Mat4::new(), camera.transform);
+}
+
+#[test]
+fn can_parse_camera_transform() {
+    let mut parser = scene_parser("transformed-camera");
+    let camera = parser.parse_camera().unwrap().transformed();
+    // Turned a quarter to the left around the y-axis, and then moved up
+    assert!(camera.pos.distance(Vec3::init(3.0, 2.0, -1.0)) < 1.0e-5);
+    assert!(camera.view_dir.distance(Vec3::init(-1.0, 0.0, 0.0)) < 1.0e-5);
+    assert!(camera.ortho_up.distance(Vec3::init(0.0, 1.0, 0.0)) < 1.0e-5);
 }
 
 #[test]
 fn can_parse_scene() {
     let mut parser = scene_parser("scene");
-    let scene = parser.parse_scene();
+    let scene = parser.parse_scene().unwrap();
     assert_eq!(scene.lights.len(), 3);
     assert_eq!(scene.primitives.len(), 13);
 }
+
+#[test]
+fn can_parse_scene_with_comments() {
+    let mut parser = scene_parser("commented");
+    let scene = parser.parse_scene().unwrap();
+    assert_eq!(scene.lights.len(), 1);
+    assert_eq!(scene.primitives.len(), 2);
+    match scene.primitives[0] {
+        Sphere(ref sphere) => assert_eq!(sphere.radius, 1.5),
+        _ => panic!("The first primitive should be a sphere")
+    }
+}
+
+#[test]
+fn spheres_referring_to_the_same_palette_entry_get_equal_materials() {
+    let mut parser = scene_parser("palette");
+    let scene = parser.parse_scene().unwrap();
+    assert_eq!(scene.primitives.len(), 3);
+
+    let materials: Vec<Material> = scene.primitives.iter().map(|prim| match prim {
+        &Sphere(ref sphere) => sphere.materials[0].clone(),
+        _ => panic!("The scene should only have spheres")
+    }).collect();
+    assert_eq!(materials[0], materials[1]);
+    assert_eq!(Color::init(0.0, 0.0, 1.0), materials[0].diffuse);
+    // Inline materials still work next to the palette
+    assert_eq!(Color::init(0.0, 1.0, 0.0), materials[2].diffuse);
+}
+
+#[test]
+fn can_parse_sky() {
+    let mut parser = scene_parser("sky");
+    let scene = parser.parse_scene().unwrap();
+    let sky = Background::Gradient {
+        top: Color::init(0.3, 0.5, 0.9),
+        bottom: Color::init(1.0, 1.0, 1.0)
+    };
+    assert_eq!(sky, scene.background);
+}
+
+#[test]
+fn tracks_the_position_of_tokens() {
+    let mut parser = scene_parser("malformed");
+    parser.parse_header().unwrap();
+    assert_eq!((1, 21), parser.position());
+
+    while parser.peak().unwrap().as_slice() != "spnere" {
+        parser.consume_next().unwrap();
+    }
+    assert_eq!((13, 1), parser.position());
+    for _ in 0 .. 3 {
+        parser.consume_next().unwrap();
+    }
+    assert_eq!((14, 3), parser.position());
+}
+
+#[test]
+fn reports_the_line_of_an_unexpected_token() {
+    match scene_parser("malformed").parse_scene() {
+        Ok(_) => panic!("The misspelled sphere should be reported"),
+        Err(e) => {
+            let msg = "unexpected token 'spnere'".to_string();
+            assert_eq!(ParseError { line: 13, col: 1, msg: msg }, e);
+            assert_eq!("unexpected token 'spnere' at line 13, col 1", format!("{}", e).as_slice());
+        }
+    }
+}
+
+#[test]
+fn reports_the_token_that_was_expected() {
+    match scene_parser("plane").parse_sphere() {
+        Ok(_) => panic!("A plane should not parse as a sphere"),
+        Err(e) => assert_eq!(ParseError { line: 1, col: 1,
+            msg: "unexpected token 'plane', expected 'sphere'".to_string() }, e)
+    }
+}
+
+#[test]
+fn can_include_other_scene_files() {
+    let mut parser = scene_parser("include");
+    let scene = parser.parse_scene().unwrap();
+    assert_eq!(scene.lights.len(), 3 + 1);
+    assert_eq!(scene.primitives.len(), 13 + 1);
+    match scene.primitives[13] {
+        Sphere(ref sphere) => assert_eq!(sphere.radius, 1.5),
+        _ => panic!("The last primitive should be the included sphere")
+    }
+}
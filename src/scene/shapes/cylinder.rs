@@ -0,0 +1,180 @@
+use std::num::Float;
+
+use vec::Vec3;
+use ray::Ray;
+use scene::material::{Material, Color};
+use scene::shapes::{BoundingBox, Shape, ShapeIntersection};
+
+static EPSILON: f32 = 1.0e-4;
+
+// A closed cylinder standing on the disk at `base`, reaching `height` along `axis`
+#[derive(Clone, PartialEq, Debug)]
+pub struct Cylinder {
+    pub base: Vec3,
+    pub axis: Vec3,
+    pub radius: f32,
+    pub height: f32,
+    pub material: Material
+}
+
+impl Cylinder {
+    pub fn new() -> Cylinder {
+        Cylinder {
+            base: Vec3::new(),
+            axis: Vec3::init(0.0, 1.0, 0.0),
+            radius: 1.0,
+            height: 1.0,
+            material: Material::new()
+        }
+    }
+
+    pub fn init(base: Vec3, axis: Vec3, radius: f32, height: f32) -> Cylinder {
+        let mut cylinder = Cylinder::new();
+        cylinder.base = base;
+        cylinder.axis = axis;
+        cylinder.axis.normalize();
+        cylinder.radius = radius;
+        cylinder.height = height;
+        cylinder
+    }
+
+    // The parts of `v` along the axis and across it
+    fn split(&self, v: Vec3) -> (f32, Vec3) {
+        let along = v.dot(self.axis);
+        (along, v - self.axis.mult(along))
+    }
+}
+
+impl Shape for Cylinder {
+    // The boxes around both caps, where each cap reaches less far along the axes that the
+    // cylinder leans towards
+    fn get_bbox(&self) -> BoundingBox {
+        let extent = |a: f32| self.radius * (1.0 - a * a).max(0.0).sqrt();
+        let disk = Vec3::init(extent(self.axis.x), extent(self.axis.y), extent(self.axis.z));
+        let top = self.base + self.axis.mult(self.height);
+        BoundingBox::from_points(&[self.base - disk, self.base + disk, top - disk, top + disk])
+    }
+
+    fn bounding_sphere(&self) -> (Vec3, f32) {
+        let half = self.height * 0.5;
+        (self.base + self.axis.mult(half), (half * half + self.radius * self.radius).sqrt())
+    }
+
+    // The nearest hit of the side, where it is between the caps, and the caps, where they are
+    // within the radius
+    fn intersects(&self, ray: &Ray) -> ShapeIntersection {
+        let (ori_along, ori_across) = self.split(ray.ori - self.base);
+        let (dir_along, dir_across) = self.split(ray.dir());
+        let r2 = self.radius * self.radius;
+        let mut hits = Vec::new();
+
+        let a = dir_across.dot(dir_across);
+        let b = 2.0 * dir_across.dot(ori_across);
+        let c = ori_across.dot(ori_across) - r2;
+        let disc = b * b - 4.0 * a * c;
+        if a > 1.0e-7 && disc >= 0.0 {
+            let disc_sqrt = disc.sqrt();
+            for &t in [(-b - disc_sqrt) / (2.0 * a), (-b + disc_sqrt) / (2.0 * a)].iter() {
+                let h = ori_along + dir_along * t;
+                if h >= 0.0 && h <= self.height {
+                    hits.push(t);
+                }
+            }
+        }
+
+        if dir_along.abs() > 1.0e-7 {
+            for &h in [0.0, self.height].iter() {
+                let t = (h - ori_along) / dir_along;
+                let p = ori_across + dir_across.mult(t);
+                if p.dot(p) <= r2 {
+                    hits.push(t);
+                }
+            }
+        }
+
+        match hits.into_iter().filter(|&t| t > EPSILON).fold(None, |nearest, t| match nearest {
+            Some(n) if n <= t => Some(n),
+            _ => Some(t)
+        }) {
+            Some(t) => ShapeIntersection::Hit(t),
+            None => ShapeIntersection::Missed
+        }
+    }
+
+    fn get_material(&self) -> Material {
+        self.material.clone()
+    }
+
+    fn surface_normal(&self, _: Vec3, point: Vec3) -> Vec3 {
+        let (along, across) = self.split(point - self.base);
+        let mut normal = match (along < EPSILON, along > self.height - EPSILON) {
+            (true, _) => self.axis.invert(),
+            (_, true) => self.axis,
+            _ => across
+        };
+        normal.normalize();
+        normal
+    }
+
+    fn diffuse_color(&self, point: Vec3) -> Color {
+        self.material.diffuse_at(point)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vec::Vec3;
+    use ray::Ray;
+    use scene::shapes::{BoundingBox, Shape, ShapeIntersection};
+    use scene::shapes::cylinder::Cylinder;
+
+    #[test]
+    fn can_intersect_side_of_cylinder() {
+        let cylinder = Cylinder::init(Vec3::init(0.0, -1.0, -5.0), Vec3::init(0.0, 2.0, 0.0),
+            1.0, 2.0);
+        let ray = Ray::init(Vec3::new(), Vec3::init(0.0, 0.0, -1.0));
+        match cylinder.intersects(&ray) {
+            ShapeIntersection::Hit(t) => assert_eq!(4.0, t),
+            ShapeIntersection::Missed => panic!("Ray should hit the side of the cylinder")
+        }
+        assert_eq!(Vec3::init(0.0, 0.0, 1.0), cylinder.surface_normal(ray.dir(), ray.at(4.0)));
+    }
+
+    #[test]
+    fn can_intersect_cap_of_cylinder() {
+        let cylinder = Cylinder::init(Vec3::init(0.0, -1.0, -5.0), Vec3::init(0.0, 2.0, 0.0),
+            1.0, 2.0);
+        let ray = Ray::init(Vec3::init(0.5, 3.0, -5.0), Vec3::init(0.0, -1.0, 0.0));
+        match cylinder.intersects(&ray) {
+            ShapeIntersection::Hit(t) => assert_eq!(2.0, t),
+            ShapeIntersection::Missed => panic!("Ray should hit the top cap of the cylinder")
+        }
+        assert_eq!(Vec3::init(0.0, 1.0, 0.0), cylinder.surface_normal(ray.dir(), ray.at(2.0)));
+
+        let ray = Ray::init(Vec3::init(0.0, -4.0, -5.0), Vec3::init(0.0, 1.0, 0.0));
+        assert_eq!(Vec3::init(0.0, -1.0, 0.0), cylinder.surface_normal(ray.dir(), ray.at(3.0)));
+    }
+
+    #[test]
+    fn rays_passing_the_ends_miss_the_cylinder() {
+        let cylinder = Cylinder::init(Vec3::init(0.0, -1.0, -5.0), Vec3::init(0.0, 2.0, 0.0),
+            1.0, 2.0);
+        let above = Ray::init(Vec3::init(0.0, 1.5, 0.0), Vec3::init(0.0, 0.0, -1.0));
+        let below = Ray::init(Vec3::init(0.0, -1.5, 0.0), Vec3::init(0.0, 0.0, -1.0));
+        let beside = Ray::init(Vec3::init(1.5, 3.0, -5.0), Vec3::init(0.0, -1.0, 0.0));
+        for ray in [above, below, beside].iter() {
+            match cylinder.intersects(ray) {
+                ShapeIntersection::Hit(_) => panic!("Ray should miss the cylinder"),
+                ShapeIntersection::Missed => ()
+            }
+        }
+    }
+
+    #[test]
+    fn bbox_of_upright_cylinder_is_tight() {
+        let bbox = BoundingBox::init(Vec3::init(-1.0, -1.0, -6.0), Vec3::init(1.0, 1.0, -4.0));
+        let cylinder = Cylinder::init(Vec3::init(0.0, -1.0, -5.0), Vec3::init(0.0, 2.0, 0.0),
+            1.0, 2.0);
+        assert_eq!(bbox, cylinder.get_bbox());
+    }
+}
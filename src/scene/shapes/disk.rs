@@ -0,0 +1,137 @@
+use std::num::Float;
+
+use vec::Vec3;
+use ray::Ray;
+use scene::material::{Material, Color};
+use scene::shapes::{BoundingBox, Shape, ShapeIntersection};
+
+static EPSILON: f32 = 1.0e-4;
+
+// A flat disk around `center`, facing along `normal`
+#[derive(Clone, PartialEq, Debug)]
+pub struct Disk {
+    pub center: Vec3,
+    pub normal: Vec3,
+    pub radius: f32,
+    pub material: Material
+}
+
+impl Disk {
+    pub fn new() -> Disk {
+        Disk {
+            center: Vec3::new(),
+            normal: Vec3::init(0.0, 1.0, 0.0),
+            radius: 1.0,
+            material: Material::new()
+        }
+    }
+
+    pub fn init(center: Vec3, normal: Vec3, radius: f32) -> Disk {
+        let mut disk = Disk::new();
+        disk.center = center;
+        disk.normal = normal;
+        disk.normal.normalize();
+        disk.radius = radius;
+        disk
+    }
+}
+
+impl Shape for Disk {
+    // The disk reaches less far along the axes that its normal leans towards
+    fn get_bbox(&self) -> BoundingBox {
+        let extent = |a: f32| self.radius * (1.0 - a * a).max(0.0).sqrt();
+        let extent = Vec3::init(extent(self.normal.x), extent(self.normal.y),
+            extent(self.normal.z));
+        BoundingBox::init(self.center - extent, self.center + extent)
+    }
+
+    fn bounding_sphere(&self) -> (Vec3, f32) {
+        (self.center, self.radius)
+    }
+
+    fn intersects(&self, ray: &Ray) -> ShapeIntersection {
+        let denom = self.normal.dot(ray.dir());
+        if denom.abs() < 1.0e-7 { // The ray runs along the disk
+            return ShapeIntersection::Missed;
+        }
+
+        let t = (self.center - ray.ori).dot(self.normal) / denom;
+        let offset = ray.at(t) - self.center;
+        match t > EPSILON && offset.dot(offset) <= self.radius * self.radius {
+            true => ShapeIntersection::Hit(t),
+            false => ShapeIntersection::Missed
+        }
+    }
+
+    fn get_material(&self) -> Material {
+        self.material.clone()
+    }
+
+    fn surface_normal(&self, direction: Vec3, _: Vec3) -> Vec3 {
+        let mut normal = self.normal;
+        normal.normalize();
+        if normal.dot(direction) > 0.0 {
+            normal = normal.invert();
+        }
+        normal
+    }
+
+    fn diffuse_color(&self, point: Vec3) -> Color {
+        self.material.diffuse_at(point)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vec::Vec3;
+    use ray::Ray;
+    use scene::shapes::{BoundingBox, Shape, ShapeIntersection};
+    use scene::shapes::disk::Disk;
+
+    #[test]
+    fn can_intersect_disk_at_its_center() {
+        let disk = Disk::init(Vec3::init(0.0, -1.0, -4.0), Vec3::init(0.0, 0.0, 2.0), 2.0);
+        let ray = Ray::init(Vec3::init(0.0, -1.0, 0.0), Vec3::init(0.0, 0.0, -1.0));
+        match disk.intersects(&ray) {
+            ShapeIntersection::Hit(t) => assert_eq!(4.0, t),
+            ShapeIntersection::Missed => panic!("Ray should hit the center of the disk")
+        }
+        assert_eq!(Vec3::init(0.0, 0.0, 1.0), disk.surface_normal(ray.dir(), ray.at(4.0)));
+
+        let ray = Ray::init(Vec3::init(0.0, -1.0, -8.0), Vec3::init(0.0, 0.0, 1.0));
+        assert_eq!(Vec3::init(0.0, 0.0, -1.0), disk.surface_normal(ray.dir(), ray.at(4.0)));
+    }
+
+    #[test]
+    fn rays_hit_the_disk_up_to_its_edge() {
+        let disk = Disk::init(Vec3::init(0.0, -1.0, -4.0), Vec3::init(0.0, 0.0, 2.0), 2.0);
+        let edge = Ray::init(Vec3::init(1.9, -1.0, 0.0), Vec3::init(0.0, 0.0, -1.0));
+        match disk.intersects(&edge) {
+            ShapeIntersection::Hit(t) => assert_eq!(4.0, t),
+            ShapeIntersection::Missed => panic!("Ray should hit the disk inside its edge")
+        }
+
+        let beyond = Ray::init(Vec3::init(1.5, 0.5, 0.0), Vec3::init(0.0, 0.0, -1.0));
+        match disk.intersects(&beyond) {
+            ShapeIntersection::Hit(_) => panic!("Ray should miss the disk beyond its edge"),
+            ShapeIntersection::Missed => ()
+        }
+    }
+
+    #[test]
+    fn rays_in_the_plane_of_the_disk_miss_it() {
+        let disk = Disk::init(Vec3::init(0.0, -1.0, -4.0), Vec3::init(0.0, 0.0, 2.0), 2.0);
+        let ray = Ray::init(Vec3::init(-5.0, -1.0, -4.0), Vec3::init(1.0, 0.0, 0.0));
+        match disk.intersects(&ray) {
+            ShapeIntersection::Hit(_) => panic!("Ray should miss the disk"),
+            ShapeIntersection::Missed => ()
+        }
+    }
+
+    #[test]
+    fn bbox_of_disk_is_flat() {
+        let bbox = BoundingBox::init(Vec3::init(-2.0, -3.0, -4.0), Vec3::init(2.0, 1.0, -4.0));
+        let disk = Disk::init(Vec3::init(0.0, -1.0, -4.0), Vec3::init(0.0, 0.0, 2.0), 2.0);
+        assert_eq!(bbox, disk.get_bbox());
+    }
+}
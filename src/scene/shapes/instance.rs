@@ -0,0 +1,148 @@
+use std::sync::Arc;
+
+use vec::Vec3;
+use mat::Mat4;
+use ray::Ray;
+use scene::material::{Material, Color};
+use scene::shapes::{BoundingBox, Primitive, Shape, ShapeIntersection};
+
+// A shape placed in the scene by a transform, so that several instances can share the same
+// geometry. The shape itself is kept in object space, and rays are moved into it. It is shared
+// through an Arc rather than an Rc, as scenes are traced by several threads at once.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Instance {
+    pub shape: Arc<Primitive>,
+    transform: Mat4,
+    inverse: Mat4
+}
+
+impl Instance {
+    pub fn init(shape: Arc<Primitive>, transform: Mat4) -> Instance {
+        Instance {
+            shape: shape,
+            transform: transform,
+            inverse: transform.inverse()
+        }
+    }
+
+    pub fn transform(&self) -> Mat4 {
+        self.transform
+    }
+
+    // The same shape with `transform` applied after the current one
+    pub fn transformed(&self, transform: Mat4) -> Instance {
+        Instance::init(self.shape.clone(), transform * self.transform)
+    }
+
+    // The direction isn't normalized, so distances along the ray are the same in both spaces
+    fn to_object(&self, ray: &Ray) -> Ray {
+        Ray::init(self.inverse.mul_point(ray.ori), self.inverse.mul_dir(ray.dir()))
+    }
+}
+
+impl Shape for Instance {
+    fn get_bbox(&self) -> BoundingBox {
+        let bbox = self.shape.get_bbox();
+        let corners: Vec<Vec3> = (0 .. 8).map(|i: usize| {
+            let pick = |bit: usize, min: f32, max: f32| match i & bit == 0 {
+                true => min,
+                false => max
+            };
+            self.transform.mul_point(Vec3::init(pick(1, bbox.min.x, bbox.max.x),
+                pick(2, bbox.min.y, bbox.max.y), pick(4, bbox.min.z, bbox.max.z)))
+        }).collect();
+        BoundingBox::from_points(&corners[..])
+    }
+
+    fn bounding_sphere(&self) -> (Vec3, f32) {
+        self.get_bbox().bounding_sphere()
+    }
+
+    fn intersects(&self, ray: &Ray) -> ShapeIntersection {
+        self.shape.intersects(&self.to_object(ray))
+    }
+
+    fn get_material(&self) -> Material {
+        self.shape.get_material()
+    }
+
+    // Normals are moved back by the transposed inverse, which keeps them perpendicular to the
+    // surface under non-uniform scaling
+    fn surface_normal(&self, direction: Vec3, point: Vec3) -> Vec3 {
+        let normal = self.shape.surface_normal(self.inverse.mul_dir(direction),
+            self.inverse.mul_point(point));
+        let mut normal = self.inverse.transpose().mul_dir(normal);
+        normal.normalize();
+        normal
+    }
+
+    fn diffuse_color(&self, point: Vec3) -> Color {
+        self.shape.diffuse_color(self.inverse.mul_point(point))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use vec::Vec3;
+    use mat::Mat4;
+    use ray::Ray;
+    use scene::shapes::{BoundingBox, Primitive, Shape, ShapeIntersection};
+    use scene::shapes::sphere::Sphere;
+    use scene::shapes::instance::Instance;
+
+    #[test]
+    fn can_intersect_instanced_sphere() {
+        let sphere = Arc::new(Primitive::Sphere(Sphere::init(Vec3::new(), 1.0)));
+        let instance = Instance::init(sphere, Mat4::translation(Vec3::init(0.0, 0.0, -5.0)) *
+            Mat4::scaling(Vec3::init(2.0, 2.0, 2.0)));
+        let ray = Ray::init(Vec3::new(), Vec3::init(0.0, 0.0, -1.0));
+        match instance.intersects(&ray) {
+            ShapeIntersection::Hit(t) => assert_eq!(3.0, t),
+            ShapeIntersection::Missed => panic!("Ray should hit the instanced sphere")
+        }
+        assert_eq!(Vec3::init(0.0, 0.0, 1.0), instance.surface_normal(ray.dir(), ray.at(3.0)));
+    }
+
+    #[test]
+    fn rays_beside_the_instance_miss_it() {
+        let sphere = Arc::new(Primitive::Sphere(Sphere::init(Vec3::new(), 1.0)));
+        let instance = Instance::init(sphere, Mat4::translation(Vec3::init(0.0, 0.0, -5.0)) *
+            Mat4::scaling(Vec3::init(2.0, 2.0, 2.0)));
+        let inside = Ray::init(Vec3::init(1.9, 0.0, 0.0), Vec3::init(0.0, 0.0, -1.0));
+        let outside = Ray::init(Vec3::init(2.1, 0.0, 0.0), Vec3::init(0.0, 0.0, -1.0));
+        assert!(match instance.intersects(&inside) {
+            ShapeIntersection::Hit(_) => true,
+            ShapeIntersection::Missed => false
+        });
+        assert!(match instance.intersects(&outside) {
+            ShapeIntersection::Hit(_) => false,
+            ShapeIntersection::Missed => true
+        });
+    }
+
+    #[test]
+    fn instances_share_their_shape() {
+        let sphere = Arc::new(Primitive::Sphere(Sphere::init(Vec3::new(), 1.0)));
+        let instance = Instance::init(sphere, Mat4::translation(Vec3::init(0.0, 0.0, -5.0)) *
+            Mat4::scaling(Vec3::init(2.0, 2.0, 2.0)));
+        let moved = instance.transformed(Mat4::translation(Vec3::init(4.0, 0.0, 0.0)));
+        assert!(&*instance.shape as *const Primitive == &*moved.shape as *const Primitive);
+
+        let ray = Ray::init(Vec3::init(4.0, 0.0, 0.0), Vec3::init(0.0, 0.0, -1.0));
+        match moved.intersects(&ray) {
+            ShapeIntersection::Hit(t) => assert_eq!(3.0, t),
+            ShapeIntersection::Missed => panic!("Ray should hit the moved instance")
+        }
+    }
+
+    #[test]
+    fn bbox_of_instance_is_transformed() {
+        let bbox = BoundingBox::init(Vec3::init(-2.0, -2.0, -7.0), Vec3::init(2.0, 2.0, -3.0));
+        let sphere = Arc::new(Primitive::Sphere(Sphere::init(Vec3::new(), 1.0)));
+        let instance = Instance::init(sphere, Mat4::translation(Vec3::init(0.0, 0.0, -5.0)) *
+            Mat4::scaling(Vec3::init(2.0, 2.0, 2.0)));
+        assert_eq!(bbox, instance.get_bbox());
+    }
+}
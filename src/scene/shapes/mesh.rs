@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use vec::Vec3;
+use ray::Ray;
+use scene::bvh::{NodeIntersection, Tree};
+use scene::material::{Material, Color};
+use scene::shapes::{BoundingBox, Primitive, Shape, ShapeIntersection};
+use scene::shapes::poly::{self, Vertex};
+
+// A triangle whose vertices are shared with the other triangles of its mesh
+#[derive(Clone, PartialEq, Debug)]
+pub struct MeshPoly {
+    pub vertices: [Arc<Vertex>; 3],
+    pub material: Material,
+    // The index of the poly in the polys of its mesh
+    pub index: usize
+}
+
+impl MeshPoly {
+    fn has_normals(&self) -> bool {
+        self.vertices.iter().all(|vertex| vertex.has_normal)
+    }
+}
+
+impl Shape for MeshPoly {
+    fn get_bbox(&self) -> BoundingBox {
+        BoundingBox::from_points(&[self.vertices[0].position, self.vertices[1].position,
+            self.vertices[2].position])
+    }
+
+    fn bounding_sphere(&self) -> (Vec3, f32) {
+        self.get_bbox().bounding_sphere()
+    }
+
+    fn intersects(&self, ray: &Ray) -> ShapeIntersection {
+        poly::intersects_triangle(self.vertices[0].position, self.vertices[1].position,
+            self.vertices[2].position, ray)
+    }
+
+    fn get_material(&self) -> Material {
+        self.material.clone()
+    }
+
+    fn surface_normal(&self, direction: Vec3, point: Vec3) -> Vec3 {
+        let (a, b, c) = (self.vertices[0].position, self.vertices[1].position,
+            self.vertices[2].position);
+        let mut normal = match self.has_normals() {
+            true => {
+                let (w0, w1, w2) = poly::barycentric(a, b, c, point);
+                self.vertices[0].normal.mult(w0) + self.vertices[1].normal.mult(w1) +
+                    self.vertices[2].normal.mult(w2)
+            },
+            false => (b - a).cross(c - a)
+        };
+        normal.normalize();
+
+        if normal.dot(direction) > 0.0 {
+            normal = normal.invert();
+        }
+        normal
+    }
+
+    fn diffuse_color(&self, point: Vec3) -> Color {
+        self.material.diffuse_at(point)
+    }
+}
+
+// A named triangle mesh where the polys refer to a shared pool of vertices. It keeps its own
+// tree over the polys, so it can be used as a single primitive.
+#[derive(PartialEq, Debug)]
+pub struct Mesh {
+    pub name: String,
+    pub vertices: Vec<Arc<Vertex>>,
+    pub polys: Vec<MeshPoly>,
+    // The indices of the vertices of each poly in `vertices`
+    faces: Vec<[usize; 3]>,
+    tree: Tree
+}
+
+impl Mesh {
+    pub fn new() -> Mesh {
+        Mesh {
+            name: String::new(),
+            vertices: Vec::new(),
+            polys: Vec::new(),
+            faces: Vec::new(),
+            tree: Tree::new()
+        }
+    }
+
+    // A mesh of the polys given by the indices of their vertices in `vertices`
+    pub fn init(name: String, vertices: Vec<Vertex>, polys: Vec<([usize; 3], Material)>) -> Mesh {
+        let mut mesh = Mesh::new();
+        mesh.name = name;
+        for vertex in vertices.into_iter() {
+            mesh.add_vertex(vertex);
+        }
+        for (index, material) in polys.into_iter() {
+            mesh.add_poly(index[0], index[1], index[2], material);
+        }
+        mesh.build_tree();
+        mesh
+    }
+
+    fn add_vertex(&mut self, vertex: Vertex) -> usize {
+        self.vertices.push(Arc::new(vertex));
+        self.vertices.len() - 1
+    }
+
+    fn add_poly(&mut self, i0: usize, i1: usize, i2: usize, material: Material) {
+        self.polys.push(MeshPoly {
+            vertices: [
+                self.vertices[i0].clone(),
+                self.vertices[i1].clone(),
+                self.vertices[i2].clone()
+            ],
+            material: material,
+            index: self.polys.len()
+        });
+        self.faces.push([i0, i1, i2]);
+    }
+
+    // Merges vertices that lie within `epsilon` of each other, keeping the first of them, so
+    // the polys around a point share a single vertex. The welded vertices are bucketed by their
+    // cell in a grid with cells of size `epsilon`, so each vertex is only compared to those in
+    // its own and the neighbouring cells.
+    pub fn weld(&mut self, epsilon: f32) {
+        let mut cells: HashMap<(i64, i64, i64), Vec<usize>> = HashMap::new();
+        let mut welded: Vec<Vertex> = Vec::new();
+        // The index in `welded` of each vertex of the mesh
+        let mut remap: Vec<usize> = Vec::with_capacity(self.vertices.len());
+
+        for vertex in self.vertices.iter() {
+            let (x, y, z) = vertex.position.quantized_key(epsilon);
+            let mut found: Option<usize> = None;
+            for dx in -1i64 .. 2 {
+                for dy in -1i64 .. 2 {
+                    for dz in -1i64 .. 2 {
+                        let cell = match cells.get(&(x + dx, y + dy, z + dz)) {
+                            Some(cell) => cell,
+                            None => continue
+                        };
+                        for &i in cell.iter() {
+                            let near = welded[i].position.distance(vertex.position) <= epsilon;
+                            if near && found.map_or(true, |first| i < first) {
+                                found = Some(i);
+                            }
+                        }
+                    }
+                }
+            }
+            remap.push(match found {
+                Some(i) => i,
+                None => {
+                    let i = welded.len();
+                    welded.push(**vertex);
+                    match cells.contains_key(&(x, y, z)) {
+                        true => cells.get_mut(&(x, y, z)).unwrap().push(i),
+                        false => { cells.insert((x, y, z), vec!(i)); }
+                    }
+                    i
+                }
+            });
+        }
+
+        let polys = self.faces.iter().zip(self.polys.iter()).map(|(face, poly)|
+            ([remap[face[0]], remap[face[1]], remap[face[2]]], poly.material.clone())).collect();
+        *self = Mesh::init(self.name.clone(), welded, polys);
+    }
+
+    // A copy of the mesh moved by `offset`, where the polys share vertices like in this mesh
+    pub fn translate(&self, offset: Vec3) -> Mesh {
+        let vertices = self.vertices.iter().map(|vertex| {
+            let mut moved = **vertex;
+            moved.position.add_assign(offset);
+            moved
+        }).collect();
+        let polys = self.faces.iter().zip(self.polys.iter()).map(|(&face, poly)|
+            (face, poly.material.clone())).collect();
+        Mesh::init(self.name.clone(), vertices, polys)
+    }
+
+    // The poly hit by the ray and the distance to it. Scenes shade the hit poly itself, which
+    // has its own normal and material.
+    pub fn hit(&self, ray: &Ray) -> Option<(&Primitive, f32)> {
+        match self.tree.intersects(ray) {
+            NodeIntersection::Hit(_, poly, t) => Some((poly, t)),
+            NodeIntersection::Missed => None
+        }
+    }
+
+    pub fn occludes(&self, ray: &Ray, max_dist: f32, cull: bool) -> bool {
+        self.tree.occludes(ray, max_dist, cull, None)
+    }
+
+    pub fn shadow_hit(&self, ray: &Ray, max_dist: f32, cull: bool) -> Option<(&Primitive, f32)> {
+        match self.tree.shadow_hit(ray, max_dist, cull, None) {
+            NodeIntersection::Hit(_, poly, t) => Some((poly, t)),
+            NodeIntersection::Missed => None
+        }
+    }
+
+    // Rebuilds the tree used by `intersects` after the polys have changed
+    fn build_tree(&mut self) {
+        let polys = self.polys.iter().map(|poly| Primitive::MeshPoly(poly.clone())).collect();
+        self.tree = Tree::new();
+        self.tree.init(polys);
+    }
+}
+
+impl Shape for Mesh {
+    fn get_bbox(&self) -> BoundingBox {
+        match self.polys.first() {
+            Some(first) => self.polys.iter().fold(first.get_bbox(), |bbox, poly|
+                bbox + poly.get_bbox()),
+            None => BoundingBox::new()
+        }
+    }
+
+    fn bounding_sphere(&self) -> (Vec3, f32) {
+        self.get_bbox().bounding_sphere()
+    }
+
+    fn intersects(&self, ray: &Ray) -> ShapeIntersection {
+        match self.hit(ray) {
+            Some((_, t)) => ShapeIntersection::Hit(t),
+            None => ShapeIntersection::Missed
+        }
+    }
+
+    // Scenes take the normal and the material from the poly found by `hit`. These are only used
+    // when the mesh is shaded as a whole, like through an instance, where the normal is that of
+    // the poly found by tracing the tree again from just before `point`.
+    fn surface_normal(&self, direction: Vec3, point: Vec3) -> Vec3 {
+        let mut dir = direction;
+        dir.normalize();
+        match self.hit(&Ray::init(point - dir.mult(0.001), dir)) {
+            Some((poly, _)) => poly.surface_normal(direction, point),
+            None => direction.invert()
+        }
+    }
+
+    // The material of the first poly
+    fn get_material(&self) -> Material {
+        match self.polys.first() {
+            Some(poly) => poly.material.clone(),
+            None => Material::debug()
+        }
+    }
+
+    fn diffuse_color(&self, point: Vec3) -> Color {
+        self.get_material().diffuse_at(point)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Float;
+
+    use vec::Vec3;
+    use ray::Ray;
+    use scene::material::{Color, Material};
+    use scene::shapes::{Shape, ShapeIntersection};
+    use scene::shapes::poly::Vertex;
+    use scene::shapes::mesh::Mesh;
+
+    #[test]
+    fn can_weld_duplicate_vertices() {
+        let vertices = [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0), (1.000001, 0.0), (1.0, 1.0)].iter()
+            .map(|&(x, y)| Vertex::init(Vec3::init(x, y, 0.0))).collect();
+        let mut mesh = Mesh::init("quad".to_string(), vertices,
+            vec!(([0, 1, 2], Material::new()), ([3, 4, 2], Material::new())));
+        let shared = |mesh: &Mesh| &*mesh.polys[0].vertices[1] as *const Vertex ==
+            &*mesh.polys[1].vertices[0] as *const Vertex;
+        assert!(!shared(&mesh));
+
+        mesh.weld(0.0001);
+        assert_eq!(4, mesh.vertices.len());
+        assert_eq!(vec!([0, 1, 2], [1, 3, 2]), mesh.faces);
+        assert!(shared(&mesh));
+    }
+
+    #[test]
+    fn welding_compares_vertices_across_grid_cells() {
+        // Both vertices are within epsilon of each other, but on either side of a cell border
+        let vertices = [(0.0, 0.0), (0.99, 0.0), (1.01, 0.0), (0.0, 1.0)].iter()
+            .map(|&(x, y)| Vertex::init(Vec3::init(x, y, 0.0))).collect();
+        let mut mesh = Mesh::init("tri".to_string(), vertices,
+            vec!(([0, 1, 3], Material::new()), ([0, 2, 3], Material::new())));
+        mesh.weld(0.05);
+        assert_eq!(3, mesh.vertices.len());
+        assert_eq!(vec!([0, 1, 2], [0, 1, 2]), mesh.faces);
+        assert_eq!(0.99, mesh.vertices[1].position.x);
+    }
+
+    #[test]
+    fn can_intersect_mesh_through_its_tree() {
+        let vertices = [(-1.0, -1.0, -2.0), (1.0, -1.0, -2.0), (1.0, 1.0, -2.0), (-1.0, 1.0, -4.0)]
+            .iter().map(|&(x, y, z)| Vertex::init(Vec3::init(x, y, z))).collect();
+        let green = Material::init(Color::init(0.0, 1.0, 0.0));
+        let mesh = Mesh::init("bent".to_string(), vertices,
+            vec!(([0, 1, 2], Material::new()), ([0, 2, 3], green.clone())));
+
+        let ray = Ray::init(Vec3::init(-0.5, 0.5, 0.0), Vec3::init(0.0, 0.0, -1.0));
+        match mesh.intersects(&ray) {
+            ShapeIntersection::Hit(t) => assert!((t - 3.0).abs() < 1.0e-5),
+            ShapeIntersection::Missed => panic!("Ray should hit the second poly")
+        }
+        match mesh.hit(&ray) {
+            Some((poly, _)) => assert_eq!(green, poly.get_material()),
+            None => panic!("Ray should hit the second poly")
+        }
+        let normal = mesh.surface_normal(ray.dir(), Vec3::init(-0.5, 0.5, -3.0));
+        assert!(normal.x < 0.0 && normal.y > 0.0 && normal.z > 0.0);
+
+        let ray = Ray::init(Vec3::init(3.0, 0.0, 0.0), Vec3::init(0.0, 0.0, -1.0));
+        match mesh.intersects(&ray) {
+            ShapeIntersection::Hit(_) => panic!("Ray should miss the mesh"),
+            ShapeIntersection::Missed => ()
+        }
+    }
+}
@@ -1,22 +1,31 @@
-use std::mem::swap;
 use std::cmp::Ordering;
 use std::num::Float;
 use std::ops::Add;
+use std::sync::Arc;
 
 use vec::Vec3;
+use mat::Mat4;
 use ray::Ray;
 use scene::material::{Material, Color};
-use self::Primitive::{Sphere, Poly};
+use self::Primitive::{Sphere, Poly, Volume, MeshPoly, Mesh, Plane, Cylinder, Disk,
+    Torus, Instance};
 
 pub mod sphere;
 pub mod poly;
+pub mod volume;
+pub mod mesh;
+pub mod plane;
+pub mod cylinder;
+pub mod disk;
+pub mod torus;
+pub mod instance;
 
 pub enum ShapeIntersection {
     Hit(f32),
     Missed
 }
 
-#[derive(Copy, PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub struct BoundingBox {
     min: Vec3,
     max: Vec3
@@ -37,49 +46,82 @@ impl BoundingBox {
         }
     }
 
+    // The smallest box around every point, or an empty box at the origin if there are none
+    pub fn from_points(points: &[Vec3]) -> BoundingBox {
+        match points.first() {
+            Some(&first) => points.iter().fold(BoundingBox::init(first, first), |bbox, &point|
+                bbox + BoundingBox::init(point, point)),
+            None => BoundingBox::new()
+        }
+    }
+
+    // The smallest box around the boxes of every primitive
+    pub fn from_primitives(prims: &[Primitive]) -> BoundingBox {
+        match prims.first() {
+            Some(first) => prims.iter().fold(first.get_bbox(), |bbox, prim|
+                bbox + prim.get_bbox()),
+            None => BoundingBox::new()
+        }
+    }
+
     pub fn centroid(&self) -> Vec3 {
         self.min.mult(0.5) + self.max.mult(0.5)
     }
 
-    pub fn intersects(&self, ray: &Ray) -> bool {
-        let ori = ray.ori;
-        let dir = ray.dir;
+    pub fn surface_area(&self) -> f32 {
+        let d = self.max - self.min;
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
 
-        let mut tmin = (self.min[0] - ori[0]) / dir[0];
-        let mut tmax = (self.max[0] - ori[0]) / dir[0];
-        if tmin > tmax {
-            swap(&mut tmin, &mut tmax);
-        }
+    // The sphere through the corners of the box
+    pub fn bounding_sphere(&self) -> (Vec3, f32) {
+        let center = self.centroid();
+        (center, center.distance(self.max))
+    }
 
-        let mut tymin = (self.min[1] - ori[1]) / dir[1];
-        let mut tymax = (self.max[1] - ori[1]) / dir[1];
-        if tymin > tymax {
-            swap(&mut tymin, &mut tymax);
-        }
+    // The distances at which the ray enters and leaves the slabs of the box, which may be
+    // negative when the box is behind the ray. The slabs are picked by the sign of the ray
+    // direction, so axis-parallel rays give infinities rather than being culled.
+    fn slabs(&self, ray: &Ray) -> Option<(f32, f32)> {
+        let bounds = [self.min, self.max];
+        let (ori, inv_dir, sign) = (ray.ori, ray.inv_dir(), ray.sign());
 
-        if (tmin > tymax) || (tymin > tmax) {
-            return false;
-        }
+        let mut tmin = (bounds[sign[0]].x - ori.x) * inv_dir.x;
+        let mut tmax = (bounds[1 - sign[0]].x - ori.x) * inv_dir.x;
+        let tymin = (bounds[sign[1]].y - ori.y) * inv_dir.y;
+        let tymax = (bounds[1 - sign[1]].y - ori.y) * inv_dir.y;
 
-        if tymin > tmin {
-            tmin = tymin;
+        if (tmin > tymax) || (tymin > tmax) {
+            return None;
         }
 
-        if tymax < tmax {
-            tmax = tymax;
-        }
+        tmin = tmin.max(tymin);
+        tmax = tmax.min(tymax);
 
-        let mut tzmin = (self.min[2] - ori[2]) / dir[2];
-        let mut tzmax = (self.max[2] - ori[2]) / dir[2];
-        if tzmin > tzmax {
-            swap(&mut tzmin, &mut tzmax);
-        }
+        let tzmin = (bounds[sign[2]].z - ori.z) * inv_dir.z;
+        let tzmax = (bounds[1 - sign[2]].z - ori.z) * inv_dir.z;
 
         if (tmin > tzmax) || (tzmin > tmax) {
-            return false;
+            return None;
         }
 
-        true
+        Some((tmin.max(tzmin), tmax.min(tzmax)))
+    }
+
+    // Returns the distance at which the ray enters the box, which is negative when the ray
+    // starts inside of it, or lies entirely behind it
+    pub fn intersects(&self, ray: &Ray) -> Option<f32> {
+        self.slabs(ray).map(|(tmin, _)| tmin)
+    }
+
+    // Returns the distances at which the ray enters and exits the box, clamped so that a ray
+    // starting inside the box enters at 0
+    pub fn intersects_t(&self, ray: &Ray) -> Option<(f32, f32)> {
+        match self.slabs(ray) {
+            Some((_, tmax)) if tmax < 0.0 => None, // The box is behind the ray
+            Some((tmin, tmax)) => Some((tmin.max(0.0), tmax)),
+            None => None
+        }
     }
 }
 
@@ -87,19 +129,7 @@ impl Add for BoundingBox {
     type Output = BoundingBox;
 
     fn add(self, other: BoundingBox) -> BoundingBox {
-        let min = Vec3::init(
-            self.min[0].min(other.min[0]),
-            self.min[1].min(other.min[1]),
-            self.min[2].min(other.min[2])
-        );
-
-        let max = Vec3::init(
-            self.max[0].max(other.max[0]),
-            self.max[1].max(other.max[1]),
-            self.max[2].max(other.max[2])
-        );
-
-        BoundingBox::init(min, max)
+        BoundingBox::init(self.min.component_min(other.min), self.max.component_max(other.max))
     }
 }
 
@@ -119,6 +149,9 @@ impl PartialOrd for BoundingBox {
 pub trait Shape {
     fn get_bbox(&self) -> BoundingBox;
 
+    // Returns the center and radius of a sphere enclosing the shape
+    fn bounding_sphere(&self) -> (Vec3, f32);
+
     fn intersects(&self, ray: &Ray) -> ShapeIntersection;
 
     fn surface_normal(&self, direction: Vec3, point: Vec3) -> Vec3;
@@ -131,7 +164,153 @@ pub trait Shape {
 #[derive(Clone, PartialEq, Debug)]
 pub enum Primitive {
     Poly(poly::Poly),
-    Sphere(sphere::Sphere)
+    Sphere(sphere::Sphere),
+    Volume(volume::FogVolume),
+    MeshPoly(mesh::MeshPoly),
+    Mesh(Arc<mesh::Mesh>),
+    Plane(plane::Plane),
+    Cylinder(cylinder::Cylinder),
+    Disk(disk::Disk),
+    Torus(torus::Torus),
+    Instance(instance::Instance)
+}
+
+impl Primitive {
+    // Volumes are not solid, rays pass through them rather than stopping at their surface
+    pub fn is_solid(&self) -> bool {
+        match self {
+            &Volume(_) => false,
+            _ => true
+        }
+    }
+
+    // A copy of the primitive moved by `offset`
+    pub fn translate(&self, offset: Vec3) -> Primitive {
+        match self {
+            &Poly(ref poly) => {
+                let mut poly = poly.clone();
+                for vertex in poly.vertices.iter_mut() {
+                    vertex.position.add_assign(offset);
+                }
+                Poly(poly)
+            },
+            &Sphere(ref sphere) => {
+                let mut sphere = sphere.clone();
+                sphere.origin.add_assign(offset);
+                Sphere(sphere)
+            },
+            &Volume(ref volume) => {
+                let mut volume = volume.clone();
+                volume.bounds = BoundingBox::init(volume.bounds.min + offset,
+                    volume.bounds.max + offset);
+                Volume(volume)
+            },
+            &MeshPoly(ref poly) => {
+                let mut poly = poly.clone();
+                for vertex in poly.vertices.iter_mut() {
+                    let mut moved = **vertex;
+                    moved.position.add_assign(offset);
+                    *vertex = Arc::new(moved);
+                }
+                MeshPoly(poly)
+            },
+            &Mesh(ref mesh) => Mesh(Arc::new(mesh.translate(offset))),
+            &Plane(ref plane) => {
+                let mut plane = plane.clone();
+                plane.point.add_assign(offset);
+                Plane(plane)
+            },
+            &Cylinder(ref cylinder) => {
+                let mut cylinder = cylinder.clone();
+                cylinder.base.add_assign(offset);
+                Cylinder(cylinder)
+            },
+            &Disk(ref disk) => {
+                let mut disk = disk.clone();
+                disk.center.add_assign(offset);
+                Disk(disk)
+            },
+            &Torus(ref torus) => {
+                let mut torus = torus.clone();
+                torus.center.add_assign(offset);
+                Torus(torus)
+            },
+            &Instance(ref instance) => Instance(instance.transformed(Mat4::translation(offset)))
+        }
+    }
+
+    // A ray leaving the surface of a planar primitive can never hit that primitive again
+    pub fn is_planar(&self) -> bool {
+        match self {
+            &Poly(_) | &MeshPoly(_) | &Plane(_) | &Disk(_) => true,
+            _ => false
+        }
+    }
+
+    // The unnormalized normal of a planar primitive. For polys it is given by their winding,
+    // which makes it point out of closed meshes.
+    pub fn geometric_normal(&self) -> Option<Vec3> {
+        let (a, b, c) = match self {
+            &Poly(ref poly) => (poly.vertices[0].position, poly.vertices[1].position,
+                poly.vertices[2].position),
+            &MeshPoly(ref poly) => (poly.vertices[0].position, poly.vertices[1].position,
+                poly.vertices[2].position),
+            &Plane(ref plane) => return Some(plane.normal),
+            &Disk(ref disk) => return Some(disk.normal),
+            _ => return None
+        };
+        Some((b - a).cross(c - a))
+    }
+
+    // Whether the ray would hit the back of the primitive. Only planar primitives have a back.
+    pub fn faces_away(&self, ray: &Ray) -> bool {
+        match self.geometric_normal() {
+            Some(normal) => normal.dot(ray.dir()) >= 0.0,
+            None => false
+        }
+    }
+
+    // The primitive that is hit and the distance to it. For meshes it's the poly within them.
+    // `id` is the id of the primitive in its scene, which meshes use to skip the poly a ray
+    // leaves from.
+    pub fn hit<'a>(&'a self, ray: &Ray, id: usize) -> Option<(&'a Primitive, f32)> {
+        match self {
+            &Mesh(ref mesh) => mesh.hit(&ray.inside(id)),
+            _ => match self.intersects(ray) {
+                ShapeIntersection::Hit(t) => Some((self, t)),
+                ShapeIntersection::Missed => None
+            }
+        }
+    }
+
+    // Whether an opaque part of the primitive is hit closer than `max_dist`. With `cull`, parts
+    // hit from behind are skipped.
+    pub fn occludes(&self, ray: &Ray, id: usize, max_dist: f32, cull: bool) -> bool {
+        match self {
+            &Mesh(ref mesh) => mesh.occludes(&ray.inside(id), max_dist, cull),
+            _ if cull && self.faces_away(ray) => false,
+            _ if self.get_material().transparency == 0.0 => match self.intersects(ray) {
+                ShapeIntersection::Hit(t) => t < max_dist,
+                ShapeIntersection::Missed => false
+            },
+            _ => false
+        }
+    }
+
+    // Like `hit`, but only for hits closer than `max_dist`, and with `cull` opaque parts hit
+    // from behind are skipped. Within meshes an opaque poly is preferred over a closer
+    // transparent one, as it blocks the light anyway.
+    pub fn shadow_hit<'a>(&'a self, ray: &Ray, id: usize, max_dist: f32,
+                          cull: bool) -> Option<(&'a Primitive, f32)> {
+        match self {
+            &Mesh(ref mesh) => mesh.shadow_hit(&ray.inside(id), max_dist, cull),
+            _ if cull && self.get_material().transparency == 0.0 && self.faces_away(ray) => None,
+            _ => match self.intersects(ray) {
+                ShapeIntersection::Hit(t) if t < max_dist => Some((self, t)),
+                _ => None
+            }
+        }
+    }
 }
 
 impl Shape for Primitive {
@@ -139,6 +318,29 @@ impl Shape for Primitive {
         match self {
             &Poly(ref poly) => poly.get_bbox(),
             &Sphere(ref sphere) => sphere.get_bbox(),
+            &Volume(ref volume) => volume.get_bbox(),
+            &MeshPoly(ref poly) => poly.get_bbox(),
+            &Mesh(ref mesh) => mesh.get_bbox(),
+            &Plane(ref plane) => plane.get_bbox(),
+            &Cylinder(ref cylinder) => cylinder.get_bbox(),
+            &Disk(ref disk) => disk.get_bbox(),
+            &Torus(ref torus) => torus.get_bbox(),
+            &Instance(ref instance) => instance.get_bbox(),
+        }
+    }
+
+    fn bounding_sphere(&self) -> (Vec3, f32) {
+        match self {
+            &Poly(ref poly) => poly.bounding_sphere(),
+            &Sphere(ref sphere) => sphere.bounding_sphere(),
+            &Volume(ref volume) => volume.bounding_sphere(),
+            &MeshPoly(ref poly) => poly.bounding_sphere(),
+            &Mesh(ref mesh) => mesh.bounding_sphere(),
+            &Plane(ref plane) => plane.bounding_sphere(),
+            &Cylinder(ref cylinder) => cylinder.bounding_sphere(),
+            &Disk(ref disk) => disk.bounding_sphere(),
+            &Torus(ref torus) => torus.bounding_sphere(),
+            &Instance(ref instance) => instance.bounding_sphere(),
         }
     }
 
@@ -146,6 +348,14 @@ impl Shape for Primitive {
         match self {
             &Poly(ref poly) => poly.intersects(ray),
             &Sphere(ref sphere) => sphere.intersects(ray),
+            &Volume(ref volume) => volume.intersects(ray),
+            &MeshPoly(ref poly) => poly.intersects(ray),
+            &Mesh(ref mesh) => mesh.intersects(ray),
+            &Plane(ref plane) => plane.intersects(ray),
+            &Cylinder(ref cylinder) => cylinder.intersects(ray),
+            &Disk(ref disk) => disk.intersects(ray),
+            &Torus(ref torus) => torus.intersects(ray),
+            &Instance(ref instance) => instance.intersects(ray),
         }
     }
 
@@ -153,6 +363,14 @@ impl Shape for Primitive {
         match self {
             &Poly(ref poly) => poly.surface_normal(direction, point),
             &Sphere(ref sphere) => sphere.surface_normal(direction, point),
+            &Volume(ref volume) => volume.surface_normal(direction, point),
+            &MeshPoly(ref poly) => poly.surface_normal(direction, point),
+            &Mesh(ref mesh) => mesh.surface_normal(direction, point),
+            &Plane(ref plane) => plane.surface_normal(direction, point),
+            &Cylinder(ref cylinder) => cylinder.surface_normal(direction, point),
+            &Disk(ref disk) => disk.surface_normal(direction, point),
+            &Torus(ref torus) => torus.surface_normal(direction, point),
+            &Instance(ref instance) => instance.surface_normal(direction, point),
         }
     }
 
@@ -160,13 +378,29 @@ impl Shape for Primitive {
         match self {
             &Poly(ref poly) => poly.get_material(),
             &Sphere(ref sphere) => sphere.get_material(),
+            &Volume(ref volume) => volume.get_material(),
+            &MeshPoly(ref poly) => poly.get_material(),
+            &Mesh(ref mesh) => mesh.get_material(),
+            &Plane(ref plane) => plane.get_material(),
+            &Cylinder(ref cylinder) => cylinder.get_material(),
+            &Disk(ref disk) => disk.get_material(),
+            &Torus(ref torus) => torus.get_material(),
+            &Instance(ref instance) => instance.get_material(),
         }
     }
 
     fn diffuse_color(&self, point: Vec3) -> Color {
         match self {
             &Poly(ref poly) => poly.diffuse_color(point),
-            &Sphere(_) => self.get_material().diffuse,
+            &Sphere(ref sphere) => sphere.diffuse_color(point),
+            &Volume(ref volume) => volume.diffuse_color(point),
+            &MeshPoly(ref poly) => poly.diffuse_color(point),
+            &Mesh(ref mesh) => mesh.diffuse_color(point),
+            &Plane(ref plane) => plane.diffuse_color(point),
+            &Cylinder(ref cylinder) => cylinder.diffuse_color(point),
+            &Disk(ref disk) => disk.diffuse_color(point),
+            &Torus(ref torus) => torus.diffuse_color(point),
+            &Instance(ref instance) => instance.diffuse_color(point),
         }
     }
 }
@@ -175,8 +409,9 @@ impl Shape for Primitive {
 mod tests {
     use vec::Vec3;
     use ray::Ray;
-    use scene::shapes::Shape;
+    use scene::shapes::{Primitive, Shape};
     use scene::shapes::sphere::Sphere;
+    use scene::shapes::poly::Poly;
     use scene::shapes::BoundingBox;
 
     #[test]
@@ -187,13 +422,72 @@ mod tests {
         assert_eq!(Vec3::init(3.0, 3.0, 3.0), bbox.max);
     }
 
+    #[test]
+    fn can_create_boundingbox_from_points() {
+        let points = [Vec3::init(1.0, -2.0, 0.5), Vec3::init(-3.0, 4.0, 0.0),
+            Vec3::init(0.0, 0.0, 2.0), Vec3::init(2.0, 1.0, -1.0)];
+        let bbox = BoundingBox::from_points(&points);
+        assert_eq!(Vec3::init(-3.0, -2.0, -1.0), bbox.min);
+        assert_eq!(Vec3::init(2.0, 4.0, 2.0), bbox.max);
+        assert_eq!(BoundingBox::new(), BoundingBox::from_points(&[]));
+    }
+
+    #[test]
+    fn can_create_boundingbox_from_primitives() {
+        let prims = [Primitive::Sphere(Sphere::init(Vec3::init(1.0, 1.0, 1.0), 2.0)),
+            Primitive::Sphere(Sphere::init(Vec3::init(-4.0, 0.0, 0.0), 1.0))];
+        let bbox = BoundingBox::from_primitives(&prims);
+        assert_eq!(Vec3::init(-5.0, -1.0, -1.0), bbox.min);
+        assert_eq!(Vec3::init(3.0, 3.0, 3.0), bbox.max);
+    }
+
     #[test]
     fn can_intersect_bbox() {
         let s = Sphere::init(Vec3::init(1.0, 1.0, 1.0), 2.0);
         let bbox = s.get_bbox();
         let ray = Ray::init(Vec3::init(0.0, 0.0, -2.0), Vec3::init(0.0, 0.0, -1.0));
 
-        assert!(bbox.intersects(&ray));
+        assert!(bbox.intersects(&ray).is_some());
+    }
+
+    #[test]
+    fn axis_parallel_rays_are_not_culled_by_bbox() {
+        let bbox = BoundingBox::init(Vec3::init(-1.0, -1.0, -1.0), Vec3::init(1.0, 1.0, 1.0));
+
+        let ray = Ray::init(Vec3::init(0.5, -0.5, 5.0), Vec3::init(0.0, -0.0, -1.0));
+        assert_eq!(Some((4.0, 6.0)), bbox.intersects_t(&ray));
+
+        let ray = Ray::init(Vec3::init(-5.0, 0.0, 0.0), Vec3::init(1.0, 0.0, 0.0));
+        assert_eq!(Some((4.0, 6.0)), bbox.intersects_t(&ray));
+
+        // Parallel to a slab but outside of it
+        let ray = Ray::init(Vec3::init(2.0, 0.0, 5.0), Vec3::init(-0.0, 0.0, -1.0));
+        assert!(bbox.intersects(&ray).is_none());
+    }
+
+    #[test]
+    fn can_get_bounding_sphere_of_sphere() {
+        let s = Primitive::Sphere(Sphere::init(Vec3::init(1.0, 2.0, 3.0), 1.0));
+        assert_eq!((Vec3::init(1.0, 2.0, 3.0), 1.0), s.bounding_sphere());
+    }
+
+    #[test]
+    fn can_get_bounding_sphere_of_poly() {
+        let mut poly = Poly::init();
+        poly.vertices[0].position = Vec3::init(2.0, 0.0, -3.0);
+        poly.vertices[1].position = Vec3::init(-2.0, 0.0, -3.0);
+        poly.vertices[2].position = Vec3::init(0.0, 2.0, -1.0);
+
+        let (center, radius) = poly.bounding_sphere();
+        for vertex in poly.vertices.iter() {
+            assert!(center.distance(vertex.position) <= radius + 1.0e-5);
+        }
+    }
+
+    #[test]
+    fn can_get_surface_area_of_bbox() {
+        let bbox = BoundingBox::init(Vec3::init(-1.0, 0.0, 2.0), Vec3::init(1.0, 3.0, 6.0));
+        assert_eq!(2.0 * (2.0 * 3.0 + 3.0 * 4.0 + 4.0 * 2.0), bbox.surface_area());
     }
 
     #[test]
@@ -203,4 +497,18 @@ mod tests {
 
         assert!(b0 < b1);
     }
+
+    #[test]
+    fn can_get_entry_and_exit_distance_of_bbox() {
+        let bbox = BoundingBox::init(Vec3::init(0.0, 0.0, 0.0), Vec3::init(1.0, 1.0, 1.0));
+
+        let ray = Ray::init(Vec3::init(0.5, 0.5, -1.0), Vec3::init(0.0, 0.0, 1.0));
+        assert_eq!(Some((1.0, 2.0)), bbox.intersects_t(&ray));
+
+        let ray = Ray::init(Vec3::init(0.5, 0.5, 0.5), Vec3::init(0.0, 0.0, 1.0));
+        assert_eq!(Some((0.0, 0.5)), bbox.intersects_t(&ray));
+
+        let ray = Ray::init(Vec3::init(0.5, 0.5, 2.0), Vec3::init(0.0, 0.0, 1.0));
+        assert_eq!(None, bbox.intersects_t(&ray));
+    }
 }
@@ -0,0 +1,132 @@
+use std::num::Float;
+
+use vec::Vec3;
+use ray::Ray;
+use scene::material::{Material, Color};
+use scene::shapes::{BoundingBox, Shape, ShapeIntersection};
+
+// Planes are infinite, but the tree needs a finite bounding box to sort them by
+static EXTENT: f32 = 1.0e6;
+static EPSILON: f32 = 1.0e-4;
+
+// An infinite plane through `point`, facing along `normal`
+#[derive(Clone, PartialEq, Debug)]
+pub struct Plane {
+    pub point: Vec3,
+    pub normal: Vec3,
+    pub material: Material
+}
+
+impl Plane {
+    pub fn new() -> Plane {
+        Plane {
+            point: Vec3::new(),
+            normal: Vec3::init(0.0, 1.0, 0.0),
+            material: Material::new()
+        }
+    }
+
+    pub fn init(point: Vec3, normal: Vec3) -> Plane {
+        let mut plane = Plane::new();
+        plane.point = point;
+        plane.normal = normal;
+        plane.normal.normalize();
+        plane
+    }
+}
+
+impl Shape for Plane {
+    fn get_bbox(&self) -> BoundingBox {
+        let extent = Vec3::init(EXTENT, EXTENT, EXTENT);
+        BoundingBox::init(self.point - extent, self.point + extent)
+    }
+
+    fn bounding_sphere(&self) -> (Vec3, f32) {
+        self.get_bbox().bounding_sphere()
+    }
+
+    fn intersects(&self, ray: &Ray) -> ShapeIntersection {
+        let denom = self.normal.dot(ray.dir());
+        if denom.abs() < 1.0e-7 { // The ray runs along the plane
+            return ShapeIntersection::Missed;
+        }
+
+        let t = (self.point - ray.ori).dot(self.normal) / denom;
+        match t > EPSILON {
+            true => ShapeIntersection::Hit(t),
+            false => ShapeIntersection::Missed
+        }
+    }
+
+    fn get_material(&self) -> Material {
+        self.material.clone()
+    }
+
+    fn surface_normal(&self, direction: Vec3, _: Vec3) -> Vec3 {
+        let mut normal = self.normal;
+        normal.normalize();
+        if normal.dot(direction) > 0.0 {
+            normal = normal.invert();
+        }
+        normal
+    }
+
+    fn diffuse_color(&self, point: Vec3) -> Color {
+        self.material.diffuse_at(point)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vec::Vec3;
+    use ray::Ray;
+    use scene::material::{Checkerboard, Color, Texture};
+    use scene::shapes::{Shape, ShapeIntersection};
+    use scene::shapes::plane::Plane;
+
+    #[test]
+    fn can_intersect_plane_from_both_sides() {
+        let plane = Plane::init(Vec3::init(0.0, -1.0, 0.0), Vec3::init(0.0, 1.0, 0.0));
+
+        let ray = Ray::init(Vec3::init(3.0, 1.0, -2.0), Vec3::init(0.0, -1.0, 0.0));
+        match plane.intersects(&ray) {
+            ShapeIntersection::Hit(t) => assert_eq!(2.0, t),
+            ShapeIntersection::Missed => panic!("Ray should hit the plane from above")
+        }
+        assert_eq!(Vec3::init(0.0, 1.0, 0.0), plane.surface_normal(ray.dir(), ray.at(2.0)));
+
+        let ray = Ray::init(Vec3::init(0.0, -4.0, 0.0), Vec3::init(0.0, 1.0, 0.0));
+        match plane.intersects(&ray) {
+            ShapeIntersection::Hit(t) => assert_eq!(3.0, t),
+            ShapeIntersection::Missed => panic!("Ray should hit the plane from below")
+        }
+        assert_eq!(Vec3::init(0.0, -1.0, 0.0), plane.surface_normal(ray.dir(), ray.at(3.0)));
+    }
+
+    #[test]
+    fn rays_grazing_or_leaving_the_plane_miss_it() {
+        let plane = Plane::init(Vec3::new(), Vec3::init(0.0, 1.0, 0.0));
+
+        let grazing = Ray::init(Vec3::init(0.0, 1.0, 0.0), Vec3::init(1.0, 0.0, 0.0));
+        let along = Ray::init(Vec3::new(), Vec3::init(0.0, 0.0, -1.0));
+        let leaving = Ray::init(Vec3::init(0.0, 1.0, 0.0), Vec3::init(0.0, 1.0, 0.0));
+        for ray in [grazing, along, leaving].iter() {
+            match plane.intersects(ray) {
+                ShapeIntersection::Hit(_) => panic!("Ray should miss the plane"),
+                ShapeIntersection::Missed => ()
+            }
+        }
+    }
+
+    #[test]
+    fn checkered_plane_alternates_colors_every_scale() {
+        let (white, red) = (Color::init(1.0, 1.0, 1.0), Color::init(1.0, 0.0, 0.0));
+        let mut plane = Plane::init(Vec3::new(), Vec3::init(0.0, 1.0, 0.0));
+        plane.material.texture = Some(Texture::Checker(Checkerboard::init(white, red, 0.5)));
+
+        let colors: Vec<Color> = (0 .. 4).map(|i|
+            plane.diffuse_color(Vec3::init(-0.9 + i as f32 * 0.5, 0.0, 0.2))).collect();
+        assert_eq!(vec!(white, red, white, red), colors);
+        assert_eq!(red, plane.diffuse_color(Vec3::init(0.2, 0.0, 0.7)));
+    }
+}
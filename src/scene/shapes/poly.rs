@@ -1,5 +1,5 @@
-use std::ops::Index;
 use std::num::Float;
+use std::ops::Index;
 
 use vec::Vec3;
 use ray::Ray;
@@ -11,7 +11,8 @@ pub struct Vertex {
     pub mat_index: u32,
     pub has_normal: bool,
     pub position: Vec3,
-    pub normal: Vec3
+    pub normal: Vec3,
+    pub uv: (f32, f32)
 }
 
 impl Vertex {
@@ -20,7 +21,8 @@ impl Vertex {
             mat_index: 0,
             has_normal: false,
             position: Vec3::new(),
-            normal: Vec3::new()
+            normal: Vec3::new(),
+            uv: (0.0, 0.0)
         }
     }
 
@@ -29,7 +31,8 @@ impl Vertex {
             mat_index: 0,
             has_normal: false,
             position: position,
-            normal: Vec3::new()
+            normal: Vec3::new(),
+            uv: (0.0, 0.0)
         }
     }
 }
@@ -75,22 +78,25 @@ impl Poly {
         poly
     }
 
+    // The weights of the vertices at `point`, in reverse order: the first is the weight of the
+    // third vertex
     fn weighted_areas(&self, point: Vec3) -> (f32, f32, f32) {
-        let area = Vec3::get_area(self[0].position, self[1].position, self[2].position);
-        let area0 = Vec3::get_area(self[0].position, self[1].position, point) / area;
-        let area1 = Vec3::get_area(self[2].position, self[0].position, point) / area;
-        let area2 = Vec3::get_area(self[1].position, self[2].position, point) / area;
-
-        if area0 > 1.0 || area1 > 1.0 || area2 > 1.0 {
-            panic!("Cannot get area, as point is outside of poly")
-        }
-
-        (area0, area1, area2)
+        let (w0, w1, w2) = barycentric(self[0].position, self[1].position, self[2].position,
+            point);
+        (w2, w1, w0)
     }
 
     fn interpolated_color(&self, point: Vec3) -> Color {
         let (area0, area1, area2) = self.weighted_areas(point);
-        self.materials[0].diffuse.mult(area2) + self.materials[1].diffuse.mult(area1) + self.materials[2].diffuse.mult(area0)
+        let diffuse = |i: u32| self.materials[self[i].mat_index as usize].diffuse_at(point);
+        diffuse(0).mult(area2) + diffuse(1).mult(area1) + diffuse(2).mult(area0)
+    }
+
+    fn interpolated_uv(&self, point: Vec3) -> (f32, f32) {
+        let (area0, area1, area2) = self.weighted_areas(point);
+        let u = self[0].uv.0 * area2 + self[1].uv.0 * area1 + self[2].uv.0 * area0;
+        let v = self[0].uv.1 * area2 + self[1].uv.1 * area1 + self[2].uv.1 * area0;
+        (u, v)
     }
 
     fn static_normal(&self) -> Vec3 {
@@ -120,66 +126,35 @@ impl Index<u32> for Poly {
 
 impl Shape for Poly {
     fn get_bbox(&self) -> BoundingBox {
-        let min = Vec3::init(
-            self[0][0].min(self[1][0].min(self[2][0])),
-            self[0][1].min(self[1][1].min(self[2][1])),
-            self[0][2].min(self[1][2].min(self[2][2]))
-        );
-
-        let max = Vec3::init(
-            self[0][0].max(self[1][0].max(self[2][0])),
-            self[0][1].max(self[1][1].max(self[2][1])),
-            self[0][2].max(self[1][2].max(self[2][2]))
-        );
-
-        BoundingBox::init(min, max)
+        BoundingBox::from_points(&[self[0].position, self[1].position, self[2].position])
     }
 
-    fn intersects(&self, ray: &Ray) -> ShapeIntersection {
-        let p: Vec3 = ray.ori;
-        let d: Vec3 = ray.dir;
-        let v0: Vec3 = self[0].position;
-        let v1: Vec3 = self[1].position;
-        let v2: Vec3 = self[2].position;
-
-        let e1: Vec3 = v1 - v0;
-        let e2: Vec3 = v2 - v0;
+    // The circumscribed sphere of the triangle
+    fn bounding_sphere(&self) -> (Vec3, f32) {
+        let a = self[0].position;
+        let ab = self[1].position - a;
+        let ac = self[2].position - a;
+        let n = ab.cross(ac);
+        let n_len2 = n.dot(n);
 
-        let h: Vec3 = d.cross(e2);
-        let a0: f32 = e1.dot(h);
-
-        if a0 > -0.0000001 && a0 < 0.0000001 {
-            return ShapeIntersection::Missed;
+        if n_len2 == 0.0 {
+            return self.get_bbox().bounding_sphere(); // Degenerate triangle
         }
 
-        let f: f32 = 1.0 / a0;
-        let s: Vec3 = p - v0;
-        let u: f32 = f * s.dot(h);
-
-        if u < 0.0 || u > 1.0 {
-            return ShapeIntersection::Missed;
-        }
-
-        let q: Vec3 = s.cross(e1);
-        let v: f32 = f * d.dot(q);
-
-        if v < 0.0 || u + v > 1.0 {
-            return ShapeIntersection::Missed;
-        }
-
-        // at this stage we can compute t to find out where
-        // the intersection point is on the line
-        let t: f32 = f * e2.dot(q);
+        let offset = (n.cross(ab).mult(ac.dot(ac)) + ac.cross(n).mult(ab.dot(ab)))
+            .mult(1.0 / (2.0 * n_len2));
+        (a + offset, offset.length())
+    }
 
-        match t > 0.0000001 {
-            true => ShapeIntersection::Hit(t), // ray intersection
-            false => ShapeIntersection::Missed // this means that there is
-            // a line intersection but not a ray intersection
-        }
+    fn intersects(&self, ray: &Ray) -> ShapeIntersection {
+        intersects_triangle(self[0].position, self[1].position, self[2].position, ray)
     }
 
     fn get_material(&self) -> Material {
-        self.materials[0]
+        match self.materials.get(0) {
+            Some(material) => material.clone(),
+            None => Material::debug()
+        }
     }
 
     fn surface_normal(&self, direction: Vec3, point: Vec3) -> Vec3 {
@@ -196,21 +171,113 @@ impl Shape for Poly {
     }
 
     fn diffuse_color(&self, point: Vec3) -> Color {
+        if self.materials.is_empty() {
+            let (u, v) = self.interpolated_uv(point);
+            return Material::debug_color(u, v);
+        }
+
         match self.vertex_material {
             true => self.interpolated_color(point),
-            false => self.materials[0].diffuse
+            false => self.materials[0].diffuse_at(point)
+        }
+    }
+}
+
+// The weights of v0, v1 and v2 that give `point` on the triangle. On very thin triangles the
+// weights can drift out of [0, 1], so they are clamped and renormalized to sum to one. A triangle
+// without any area weighs its vertices equally.
+pub fn barycentric(v0: Vec3, v1: Vec3, v2: Vec3, point: Vec3) -> (f32, f32, f32) {
+    let area = Vec3::get_area(v0, v1, v2);
+    let weight = |a: Vec3, b: Vec3| (Vec3::get_area(a, b, point) / area).max(0.0).min(1.0);
+    let (w0, w1, w2) = (weight(v1, v2), weight(v2, v0), weight(v0, v1));
+    let sum = w0 + w1 + w2;
+    match sum > 0.0 {
+        true => (w0 / sum, w1 / sum, w2 / sum),
+        false => {
+            let third: f32 = 1.0 / 3.0;
+            (third, third, third)
         }
     }
 }
 
+// The direction in which the texture coordinate u grows across the triangle, or None when the
+// texture coordinates don't span the triangle
+pub fn uv_tangent(p0: Vec3, p1: Vec3, p2: Vec3, uv0: (f32, f32), uv1: (f32, f32),
+                  uv2: (f32, f32)) -> Option<Vec3> {
+    let (e1, e2) = (p1 - p0, p2 - p0);
+    let (du1, dv1) = (uv1.0 - uv0.0, uv1.1 - uv0.1);
+    let (du2, dv2) = (uv2.0 - uv0.0, uv2.1 - uv0.1);
+    let det = du1 * dv2 - du2 * dv1;
+    match det == 0.0 {
+        true => None,
+        false => Some((e1.mult(dv2) - e2.mult(dv1)).mult(1.0 / det))
+    }
+}
+
+// Watertight intersection of a ray and the triangle (v0, v1, v2), after Woop, Benthin and Wald.
+// The vertices are moved into a space where the ray starts in origo and runs along the z axis,
+// so neighbouring triangles compute the same edge functions for the edge they share, and a ray
+// can't slip between them.
+pub fn intersects_triangle(v0: Vec3, v1: Vec3, v2: Vec3, ray: &Ray) -> ShapeIntersection {
+    let d: Vec3 = ray.dir();
+    let (dx, dy, dz) = (d.x.abs(), d.y.abs(), d.z.abs());
+    let kz: u32 = if dx > dy && dx > dz { 0 } else if dy > dz { 1 } else { 2 };
+    // Swapping the other two axes keeps the winding of the triangle
+    let (kx, ky) = match d[kz] < 0.0 {
+        true => ((kz + 2) % 3, (kz + 1) % 3),
+        false => ((kz + 1) % 3, (kz + 2) % 3)
+    };
+    let (sx, sy, sz) = (d[kx] / d[kz], d[ky] / d[kz], 1.0 / d[kz]);
+
+    let project = |v: Vec3| {
+        let p = v - ray.ori;
+        (p[kx] - sx * p[kz], p[ky] - sy * p[kz], sz * p[kz])
+    };
+    let (a, b, c) = (project(v0), project(v1), project(v2));
+
+    // The edge functions are computed with f64, where the products of f32 are exact
+    let edge = |p: (f32, f32, f32), q: (f32, f32, f32)|
+        q.0 as f64 * p.1 as f64 - q.1 as f64 * p.0 as f64;
+    let (u, v, w) = (edge(b, c), edge(c, a), edge(a, b));
+
+    if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+        return ShapeIntersection::Missed;
+    }
+
+    let det = u + v + w;
+    if det == 0.0 { // The ray runs along the plane of the triangle
+        return ShapeIntersection::Missed;
+    }
+
+    // A ray through an edge hits only one of the two triangles sharing it
+    if (u == 0.0 && !owns_edge(b, c, det)) || (v == 0.0 && !owns_edge(c, a, det)) ||
+        (w == 0.0 && !owns_edge(a, b, det)) {
+        return ShapeIntersection::Missed;
+    }
+
+    let t = (u * a.2 as f64 + v * b.2 as f64 + w * c.2 as f64) / det;
+    match t > 0.0000001 {
+        true => ShapeIntersection::Hit(t as f32),
+        false => ShapeIntersection::Missed
+    }
+}
+
+// Whether the triangle with the determinant `det` owns its edge from p to q. The triangles on
+// either side of an edge have their insides on opposite sides of it, so exactly one of them does.
+fn owns_edge(p: (f32, f32, f32), q: (f32, f32, f32), det: f64) -> bool {
+    let forward = (p.0, p.1) < (q.0, q.1);
+    (det > 0.0) == forward
+}
+
 #[cfg(test)]
 mod tests {
     use std::num::Float;
 
     use ray::Ray;
     use vec::Vec3;
+    use scene::material::{Material, Color};
     use scene::shapes::{Shape, ShapeIntersection};
-    use scene::shapes::poly::{Poly, Vertex};
+    use scene::shapes::poly::{Poly, Vertex, intersects_triangle};
 
     fn assert_approx_eq(a: f32, b: f32) {
         assert!((a - b).abs() < 1.0e-6, "{} is not approximately equal to {}", a, b);
@@ -242,4 +309,75 @@ mod tests {
             _ => panic!("Ray should have intersected at {}", 2.292893 as f32)
         }
     }
+
+    #[test]
+    fn rays_through_a_shared_edge_hit_exactly_one_triangle() {
+        let corners = [
+            Vec3::init(-1.0, -1.0, -2.0),
+            Vec3::init(1.0, -1.0, -2.0),
+            Vec3::init(1.0, 1.0, -2.0),
+            Vec3::init(-1.0, 1.0, -2.0)
+        ];
+        // The second pair is wound against the first, as in meshes with inconsistent winding
+        let pairs = [
+            [(corners[0], corners[1], corners[2]), (corners[0], corners[2], corners[3])],
+            [(corners[0], corners[1], corners[2]), (corners[2], corners[0], corners[3])]
+        ];
+
+        for pair in pairs.iter() {
+            for i in 1 .. 8 {
+                // Along the diagonal from corners[0] to corners[2], which both triangles share
+                let k = i as f32 * 0.25 - 1.0;
+                let ray = Ray::init(Vec3::init(0.1, -0.3, 0.0), Vec3::init(k - 0.1, k + 0.3, -2.0));
+                let hits = pair.iter().filter(|&&(v0, v1, v2)|
+                    match intersects_triangle(v0, v1, v2, &ray) {
+                        ShapeIntersection::Hit(_) => true,
+                        ShapeIntersection::Missed => false
+                    }).count();
+                assert_eq!(1, hits);
+            }
+        }
+    }
+
+    #[test]
+    fn poly_without_material_shows_debug_checker() {
+        let mut poly = Poly::new();
+        poly.vertices[0].position = Vec3::init(0.0, 0.0, 0.0);
+        poly.vertices[1].position = Vec3::init(1.0, 0.0, 0.0);
+        poly.vertices[1].uv = (1.0, 0.0);
+        poly.vertices[2].position = Vec3::init(0.0, 1.0, 0.0);
+        poly.vertices[2].uv = (0.0, 1.0);
+
+        let magenta = Material::debug().diffuse;
+        assert_eq!(magenta, poly.get_material().diffuse);
+        assert_eq!(magenta, poly.diffuse_color(Vec3::init(0.05, 0.05, 0.0)));
+        assert_eq!(Color::new(), poly.diffuse_color(Vec3::init(0.2, 0.05, 0.0)));
+        assert_eq!(magenta, poly.diffuse_color(Vec3::init(0.2, 0.2, 0.0)));
+    }
+
+    #[test]
+    fn can_interpolate_color_on_sliver_poly() {
+        let mut poly = Poly::new();
+        poly.vertex_material = true;
+        poly.materials = vec!(Material::init(Color::init(1.0, 0.0, 0.0)),
+            Material::init(Color::init(0.0, 1.0, 0.0)), Material::init(Color::init(0.0, 0.0, 1.0)));
+        poly.vertices[1].position = Vec3::init(1.0, 0.0, 0.0);
+        poly.vertices[1].mat_index = 1;
+        poly.vertices[2].position = Vec3::init(0.5, 1.0e-6, 0.0);
+        poly.vertices[2].mat_index = 2;
+
+        // Rounding puts the hits on a triangle this thin a bit off it, where the areas of the
+        // sub-triangles are much larger than the area of the triangle itself
+        let color = poly.diffuse_color(Vec3::init(0.25, 1.0e-3, 0.0));
+        let weights = [color.r_val(), color.g_val(), color.b_val()];
+        for &w in weights.iter() {
+            assert!(w >= 0.0 && w <= 1.0, "{} is not a valid weight", w);
+        }
+        assert_approx_eq(1.0, weights.iter().fold(0.0, |sum, &w| sum + w));
+
+        let degenerate = Vec3::init(0.5, 0.0, 0.0);
+        poly.vertices[2].position = degenerate;
+        let color = poly.diffuse_color(degenerate);
+        assert_approx_eq(1.0 / 3.0, color.r_val());
+    }
 }
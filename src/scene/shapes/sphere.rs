@@ -1,3 +1,4 @@
+use std::f32::consts;
 use std::num::Float;
 use vec::Vec3;
 use ray::Ray;
@@ -39,6 +40,22 @@ impl Sphere {
         sphere.radius = radius;
         sphere
     }
+
+    // The texture coordinates of a point on the sphere, where u goes around the y-axis and v
+    // goes from 0 at the north pole to 1 at the south pole. When the axes of the sphere are set,
+    // they orient the texture, so the north pole lies along `yaxis`.
+    pub fn uv(&self, point: Vec3) -> (f32, f32) {
+        let mut dir = point - self.origin;
+        let axes = [self.xaxis, self.yaxis, self.zaxis];
+        if axes.iter().all(|axis| axis.length() > 0.0) {
+            let along = |axis: Vec3| dir.dot(axis) / axis.length();
+            dir = Vec3::init(along(axes[0]), along(axes[1]), along(axes[2]));
+        }
+        dir.normalize();
+        let u = 0.5 + dir.z.atan2(dir.x) / (2.0 * consts::PI);
+        let v = 0.5 - dir.y.max(-1.0).min(1.0).asin() / consts::PI;
+        (u, v)
+    }
 }
 
 impl Shape for Sphere {
@@ -51,12 +68,16 @@ impl Shape for Sphere {
         )
     }
 
+    fn bounding_sphere(&self) -> (Vec3, f32) {
+        (self.origin, self.radius)
+    }
+
     fn intersects(&self, ray: &Ray) -> ShapeIntersection {
         // Transforming ray to object space
         let transformed_origin = ray.ori - self.origin;
 
         //Compute A, B and C coefficients
-        let dest = ray.dir;
+        let dest = ray.dir();
         let orig = transformed_origin;
 
         let a: f32 = dest.dot(dest);
@@ -103,7 +124,10 @@ impl Shape for Sphere {
     }
 
     fn get_material(&self) -> Material {
-        self.materials[0]
+        match self.materials.get(0) {
+            Some(material) => material.clone(),
+            None => Material::debug()
+        }
     }
 
     fn surface_normal(&self, _: Vec3, point: Vec3) -> Vec3 {
@@ -112,15 +136,28 @@ impl Shape for Sphere {
         normal
     }
 
-    fn diffuse_color(&self, _: Vec3) -> Color {
-        self.get_material().diffuse
+    fn diffuse_color(&self, point: Vec3) -> Color {
+        match self.materials.get(0) {
+            Some(material) => {
+                let (u, v) = self.uv(point);
+                material.diffuse_at_uv(point, u, v)
+            },
+            None => {
+                let (u, v) = self.uv(point);
+                Material::debug_color(u, v)
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::num::Float;
+    use std::sync::Arc;
+
     use vec::Vec3;
     use ray::Ray;
+    use scene::material::{Color, ImageTexture, Material, Texture};
     use scene::shapes::sphere::Sphere;
     use scene::shapes::{ShapeIntersection, Shape};
 
@@ -141,4 +178,50 @@ mod tests {
             _ => panic!("Ray did not intersect sphere")
         }
     }
+
+    #[test]
+    fn poles_and_equator_of_sphere_map_to_v() {
+        let s = Sphere::init(Vec3::init(1.0, 2.0, -5.0), 2.0);
+        let (_, north) = s.uv(Vec3::init(1.0, 4.0, -5.0));
+        let (_, south) = s.uv(Vec3::init(1.0, 0.0, -5.0));
+        let (u, equator) = s.uv(Vec3::init(3.0, 2.0, -5.0));
+        assert!(north.abs() < 1.0e-6);
+        assert!((south - 1.0).abs() < 1.0e-6);
+        assert!((equator - 0.5).abs() < 1.0e-6);
+        assert!((u - 0.5).abs() < 1.0e-6);
+    }
+
+    #[test]
+    fn axes_of_sphere_orient_its_texture() {
+        let mut s = Sphere::init(Vec3::new(), 1.0);
+        s.xaxis = Vec3::init(0.0, -1.0, 0.0);
+        s.yaxis = Vec3::init(2.0, 0.0, 0.0);
+        s.zaxis = Vec3::init(0.0, 0.0, 1.0);
+        let (_, north) = s.uv(Vec3::init(1.0, 0.0, 0.0));
+        let (_, equator) = s.uv(Vec3::init(0.0, 1.0, 0.0));
+        assert!(north.abs() < 1.0e-6);
+        assert!((equator - 0.5).abs() < 1.0e-6);
+    }
+
+    #[test]
+    fn textured_sphere_takes_its_color_from_the_texture() {
+        let mut s = Sphere::init(Vec3::new(), 1.0);
+        let (red, blue) = (Color::init(1.0, 0.0, 0.0), Color::init(0.0, 0.0, 1.0));
+        let texture = ImageTexture::init(1, 2, vec!(red, blue));
+        s.materials[0].texture = Some(Texture::Image(Arc::new(texture)));
+        assert_eq!(red, s.diffuse_color(Vec3::init(0.0, 1.0, 0.0)));
+        assert_eq!(blue, s.diffuse_color(Vec3::init(0.0, -1.0, 0.0)));
+    }
+
+    #[test]
+    fn sphere_without_material_shows_debug_checker() {
+        let mut s = Sphere::init(Vec3::new(), 1.0);
+        s.materials.clear();
+
+        let magenta = Material::debug().diffuse;
+        assert_eq!(magenta, s.get_material().diffuse);
+        assert_eq!(magenta, s.diffuse_color(Vec3::init(0.0, 1.0, 0.0)));
+        assert_eq!(Color::new(), s.diffuse_color(Vec3::init(0.6, 0.8, 0.0)));
+        assert_eq!(magenta, s.diffuse_color(Vec3::init(1.0, 0.0, 0.0)));
+    }
 }
@@ -0,0 +1,253 @@
+use std::num::Float;
+
+use vec::Vec3;
+use ray::Ray;
+use scene::material::{Material, Color};
+use scene::shapes::{BoundingBox, Shape, ShapeIntersection};
+
+static EPSILON: f32 = 1.0e-4;
+// How close to zero a polynomial has to come at a turning point for it to count as a root
+static TOUCH_TOLERANCE: f64 = 1.0e-9;
+static BISECTIONS: usize = 60;
+
+// A ring around `center`, where the tube of radius `minor_radius` follows the circle of radius
+// `major_radius` in the plane perpendicular to `axis`
+#[derive(Clone, PartialEq, Debug)]
+pub struct Torus {
+    pub center: Vec3,
+    pub axis: Vec3,
+    pub major_radius: f32,
+    pub minor_radius: f32,
+    pub material: Material
+}
+
+impl Torus {
+    pub fn new() -> Torus {
+        Torus {
+            center: Vec3::new(),
+            axis: Vec3::init(0.0, 1.0, 0.0),
+            major_radius: 1.0,
+            minor_radius: 0.25,
+            material: Material::new()
+        }
+    }
+
+    pub fn init(center: Vec3, axis: Vec3, major_radius: f32, minor_radius: f32) -> Torus {
+        let mut torus = Torus::new();
+        torus.center = center;
+        torus.axis = axis;
+        torus.axis.normalize();
+        torus.major_radius = major_radius;
+        torus.minor_radius = minor_radius;
+        torus
+    }
+
+    // The distances at which the ray enters and leaves the sphere around the torus. The sphere
+    // is made a bit larger, so that hits where the torus touches it lie inside of it.
+    fn sphere_hits(&self, ray: &Ray) -> Option<(f32, f32)> {
+        let radius = (self.major_radius + self.minor_radius) * 1.01;
+        let o = ray.ori - self.center;
+        let a = ray.dir().dot(ray.dir());
+        let b = 2.0 * ray.dir().dot(o);
+        let c = o.dot(o) - radius * radius;
+        let disc = b * b - 4.0 * a * c;
+        match disc >= 0.0 && a > 0.0 {
+            true => {
+                let disc_sqrt = disc.sqrt();
+                Some(((-b - disc_sqrt) / (2.0 * a), (-b + disc_sqrt) / (2.0 * a)))
+            },
+            false => None
+        }
+    }
+
+    // The coefficients of (|x|² + R² - r²)² - 4R²(|x|² - (x·axis)²) along the ray, highest
+    // degree first, where x is the point on the ray relative to the center
+    fn quartic(&self, ray: &Ray) -> [f64; 5] {
+        let (o, d, a) = (ray.ori - self.center, ray.dir(), self.axis);
+        let dd = d.dot(d) as f64;
+        let od = o.dot(d) as f64;
+        let oo = o.dot(o) as f64;
+        let da = d.dot(a) as f64;
+        let oa = o.dot(a) as f64;
+        let r2 = (self.major_radius as f64) * (self.major_radius as f64);
+        let k = oo + r2 - (self.minor_radius as f64) * (self.minor_radius as f64);
+        [
+            dd * dd,
+            4.0 * dd * od,
+            4.0 * od * od + 2.0 * dd * k - 4.0 * r2 * (dd - da * da),
+            4.0 * od * k - 8.0 * r2 * (od - da * oa),
+            k * k - 4.0 * r2 * (oo - oa * oa)
+        ]
+    }
+}
+
+// The value of the polynomial with the coefficients `c`, highest degree first
+fn eval(c: &[f64], t: f64) -> f64 {
+    c.iter().fold(0.0, |sum, &a| sum * t + a)
+}
+
+// The sum of the magnitudes of the terms of the polynomial, which bounds its rounding error
+fn eval_magnitude(c: &[f64], t: f64) -> f64 {
+    c.iter().fold(0.0, |sum, &a| sum * t.abs() + a.abs())
+}
+
+fn derivative(c: &[f64]) -> Vec<f64> {
+    let n = c.len() - 1;
+    c[.. n].iter().enumerate().map(|(i, &a)| a * (n - i) as f64).collect()
+}
+
+// The real roots of the polynomial between `lo` and `hi`, in increasing order. The polynomial
+// is monotone between the roots of its derivative, so each piece in between holds at most one
+// root, which is found by bisection. The turning points themselves are roots when the
+// polynomial touches zero there, which is how rays grazing a surface are found.
+fn roots_between(c: &[f64], lo: f64, hi: f64) -> Vec<f64> {
+    if c.len() == 2 {
+        let root = -c[1] / c[0];
+        return match c[0] != 0.0 && root >= lo && root <= hi {
+            true => vec!(root),
+            false => Vec::new()
+        };
+    }
+
+    let turns = roots_between(derivative(c).as_slice(), lo, hi);
+    let mut roots = Vec::new();
+    let mut start = lo;
+    for i in 0 .. turns.len() + 1 {
+        let end = match turns.get(i) {
+            Some(&turn) => turn,
+            None => hi
+        };
+        let (mut a, mut b) = (start, end);
+        let below = eval(c, a) < 0.0;
+        if below != (eval(c, b) < 0.0) {
+            for _ in 0 .. BISECTIONS {
+                let mid = 0.5 * (a + b);
+                match (eval(c, mid) < 0.0) == below {
+                    true => a = mid,
+                    false => b = mid
+                }
+            }
+            roots.push(0.5 * (a + b));
+        } else if i < turns.len() {
+            let touches = eval(c, end).abs() <= TOUCH_TOLERANCE * eval_magnitude(c, end);
+            if touches {
+                roots.push(end);
+            }
+        }
+        start = end;
+    }
+    roots
+}
+
+impl Shape for Torus {
+    // The tube reaches less far along the axes that the torus leans towards
+    fn get_bbox(&self) -> BoundingBox {
+        let extent = |a: f32| self.major_radius * (1.0 - a * a).max(0.0).sqrt() +
+            self.minor_radius;
+        let extent = Vec3::init(extent(self.axis.x), extent(self.axis.y), extent(self.axis.z));
+        BoundingBox::init(self.center - extent, self.center + extent)
+    }
+
+    fn bounding_sphere(&self) -> (Vec3, f32) {
+        (self.center, self.major_radius + self.minor_radius)
+    }
+
+    // Only the part of the ray within the sphere around the torus is searched for roots
+    fn intersects(&self, ray: &Ray) -> ShapeIntersection {
+        let (near, far) = match self.sphere_hits(ray) {
+            Some((_, far)) if far < EPSILON => return ShapeIntersection::Missed,
+            Some((near, far)) => (near.max(EPSILON), far),
+            None => return ShapeIntersection::Missed
+        };
+
+        let quartic = self.quartic(ray);
+        match roots_between(&quartic, near as f64, far as f64).first() {
+            Some(&t) => ShapeIntersection::Hit(t as f32),
+            None => ShapeIntersection::Missed
+        }
+    }
+
+    fn get_material(&self) -> Material {
+        self.material.clone()
+    }
+
+    // Points away from the nearest point on the circle the tube follows
+    fn surface_normal(&self, _: Vec3, point: Vec3) -> Vec3 {
+        let x = point - self.center;
+        let mut ring = x - self.axis.mult(x.dot(self.axis));
+        ring.normalize();
+        let mut normal = x - ring.mult(self.major_radius);
+        normal.normalize();
+        normal
+    }
+
+    fn diffuse_color(&self, point: Vec3) -> Color {
+        self.material.diffuse_at(point)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vec::Vec3;
+    use ray::Ray;
+    use scene::shapes::{BoundingBox, Shape, ShapeIntersection};
+    use scene::shapes::torus::{Torus, roots_between};
+
+    #[test]
+    fn can_find_roots_of_quartic() {
+        // (t - 1)(t - 2)(t - 3)(t - 4)
+        let roots = roots_between(&[1.0, -10.0, 35.0, -50.0, 24.0], 0.0, 10.0);
+        assert_eq!(4, roots.len());
+        for (i, &root) in roots.iter().enumerate() {
+            assert!((root - (i + 1) as f64).abs() < 1.0e-9);
+        }
+        assert_eq!(2, roots_between(&[1.0, -10.0, 35.0, -50.0, 24.0], 1.5, 3.5).len());
+    }
+
+    #[test]
+    fn rays_through_the_hole_miss_the_torus() {
+        let torus = Torus::init(Vec3::init(0.0, 0.0, -5.0), Vec3::init(0.0, 3.0, 0.0), 2.0, 0.5);
+        let ray = Ray::init(Vec3::init(0.0, 5.0, -5.0), Vec3::init(0.0, -1.0, 0.0));
+        match torus.intersects(&ray) {
+            ShapeIntersection::Hit(_) => panic!("Ray should pass through the hole of the torus"),
+            ShapeIntersection::Missed => ()
+        }
+    }
+
+    #[test]
+    fn can_intersect_near_side_of_torus() {
+        let torus = Torus::init(Vec3::init(0.0, 0.0, -5.0), Vec3::init(0.0, 3.0, 0.0), 2.0, 0.5);
+        let ray = Ray::init(Vec3::new(), Vec3::init(0.0, 0.0, -1.0));
+        match torus.intersects(&ray) {
+            ShapeIntersection::Hit(t) => {
+                assert!((t - 2.5).abs() < 1.0e-4);
+                assert_eq!(Vec3::init(0.0, 0.0, 1.0), torus.surface_normal(ray.dir(), ray.at(t)));
+            },
+            ShapeIntersection::Missed => panic!("Ray should hit the near side of the torus")
+        }
+    }
+
+    #[test]
+    fn rays_tangent_to_the_tube_touch_it() {
+        let torus = Torus::init(Vec3::init(0.0, 0.0, -5.0), Vec3::init(0.0, 3.0, 0.0), 2.0, 0.5);
+        // Grazes the top of the tube on both sides of the hole
+        let tangent = Ray::init(Vec3::init(0.0, 0.5, 0.0), Vec3::init(0.0, 0.0, -1.0));
+        match torus.intersects(&tangent) {
+            ShapeIntersection::Hit(t) => assert!((t - 3.0).abs() < 1.0e-3),
+            ShapeIntersection::Missed => panic!("Ray should graze the top of the tube")
+        }
+
+        let above = Ray::init(Vec3::init(0.0, 0.6, 0.0), Vec3::init(0.0, 0.0, -1.0));
+        match torus.intersects(&above) {
+            ShapeIntersection::Hit(_) => panic!("Ray should pass above the tube"),
+            ShapeIntersection::Missed => ()
+        }
+    }
+
+    #[test]
+    fn bbox_of_flat_torus_is_tight() {
+        let bbox = BoundingBox::init(Vec3::init(-2.5, -0.5, -7.5), Vec3::init(2.5, 0.5, -2.5));
+        let torus = Torus::init(Vec3::init(0.0, 0.0, -5.0), Vec3::init(0.0, 3.0, 0.0), 2.0, 0.5);
+        assert_eq!(bbox, torus.get_bbox());
+    }
+}
@@ -0,0 +1,106 @@
+use std::num::Float;
+
+use vec::Vec3;
+use ray::Ray;
+use scene::material::{Material, Color, ColorBuf};
+use scene::shapes::{BoundingBox, Shape, ShapeIntersection};
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct FogVolume {
+    pub bounds: BoundingBox,
+    pub density: f32,
+    pub color: Color
+}
+
+impl FogVolume {
+    pub fn new() -> FogVolume {
+        FogVolume {
+            bounds: BoundingBox::new(),
+            density: 0.0,
+            color: Color::new()
+        }
+    }
+
+    pub fn init(bounds: BoundingBox, density: f32, color: Color) -> FogVolume {
+        FogVolume {
+            bounds: bounds,
+            density: density,
+            color: color
+        }
+    }
+
+    // The distance the ray travels inside the volume before reaching `dist`
+    pub fn path_length(&self, ray: &Ray, dist: f32) -> f32 {
+        match self.bounds.intersects_t(ray) {
+            Some((tmin, tmax)) => (tmax.min(dist) - tmin).max(0.0),
+            None => 0.0
+        }
+    }
+
+    pub fn attenuate(&self, ray: &Ray, dist: f32, color: ColorBuf) -> ColorBuf {
+        let transmittance = (-self.density * self.path_length(ray, dist)).exp();
+        color.mult(transmittance) + ColorBuf::from_color(self.color.mult(1.0 - transmittance))
+    }
+}
+
+impl Shape for FogVolume {
+    fn get_bbox(&self) -> BoundingBox {
+        self.bounds
+    }
+
+    fn bounding_sphere(&self) -> (Vec3, f32) {
+        self.bounds.bounding_sphere()
+    }
+
+    fn intersects(&self, ray: &Ray) -> ShapeIntersection {
+        match self.bounds.intersects_t(ray) {
+            Some((tmin, _)) => ShapeIntersection::Hit(tmin),
+            None => ShapeIntersection::Missed
+        }
+    }
+
+    fn surface_normal(&self, direction: Vec3, _: Vec3) -> Vec3 {
+        direction.invert()
+    }
+
+    fn get_material(&self) -> Material {
+        Material::init(self.color)
+    }
+
+    fn diffuse_color(&self, _: Vec3) -> Color {
+        self.color
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vec::Vec3;
+    use ray::Ray;
+    use scene::material::{Color, ColorBuf};
+    use scene::shapes::BoundingBox;
+    use scene::shapes::volume::FogVolume;
+
+    fn create_volume(density: f32) -> FogVolume {
+        let bounds = BoundingBox::init(Vec3::init(-1.0, -1.0, -3.0), Vec3::init(1.0, 1.0, -1.0));
+        FogVolume::init(bounds, density, Color::init(1.0, 1.0, 1.0))
+    }
+
+    #[test]
+    fn can_get_path_length_through_volume() {
+        let volume = create_volume(0.5);
+        let ray = Ray::init(Vec3::init(0.0, 0.0, 0.0), Vec3::init(0.0, 0.0, -1.0));
+        assert_eq!(2.0, volume.path_length(&ray, 10.0));
+        assert_eq!(1.0, volume.path_length(&ray, 2.0));
+        assert_eq!(0.0, volume.path_length(&ray, 0.5));
+    }
+
+    #[test]
+    fn denser_volume_tints_more() {
+        let ray = Ray::init(Vec3::init(0.0, 0.0, 0.0), Vec3::init(0.0, 0.0, -1.0));
+        let thin = create_volume(0.1).attenuate(&ray, 10.0, ColorBuf::new());
+        let dense = create_volume(1.0).attenuate(&ray, 10.0, ColorBuf::new());
+
+        assert!(thin.r > 0.0);
+        assert!(dense.r > thin.r);
+    }
+}
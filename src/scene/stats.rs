@@ -0,0 +1,104 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+// The work done by a render, see `RayTracer::trace_rays_with_stats`
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RenderStats {
+    pub rays_primary: usize,
+    pub rays_shadow: usize,
+    pub rays_reflect: usize,
+    pub rays_refract: usize,
+    pub bbox_tests: usize,
+    pub prim_tests: usize
+}
+
+impl RenderStats {
+    pub fn new() -> RenderStats {
+        RenderStats {
+            rays_primary: 0,
+            rays_shadow: 0,
+            rays_reflect: 0,
+            rays_refract: 0,
+            bbox_tests: 0,
+            prim_tests: 0
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Stat {
+    RaysPrimary,
+    RaysShadow,
+    RaysReflect,
+    RaysRefract,
+    BboxTests,
+    PrimTests
+}
+
+// The counters behind `RenderStats`. They are atomic, as scenes are shared between the threads of
+// a render. Nothing is counted until they are enabled, so renders without stats don't contend
+// for the counters on every test.
+pub struct StatCounters {
+    enabled: AtomicBool,
+    counters: [AtomicUsize; 6]
+}
+
+impl StatCounters {
+    pub fn new() -> StatCounters {
+        StatCounters {
+            enabled: AtomicBool::new(false),
+            counters: [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+                AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)]
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    // The counters, or None while they are disabled, for code taking optional counters
+    pub fn when_enabled(&self) -> Option<&StatCounters> {
+        match self.is_enabled() {
+            true => Some(self),
+            false => None
+        }
+    }
+
+    pub fn count(&self, stat: Stat) {
+        if self.is_enabled() {
+            self.counters[stat as usize].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn get(&self, stat: Stat) -> usize {
+        self.counters[stat as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn reset(&self) {
+        for counter in self.counters.iter() {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    pub fn snapshot(&self) -> RenderStats {
+        RenderStats {
+            rays_primary: self.get(Stat::RaysPrimary),
+            rays_shadow: self.get(Stat::RaysShadow),
+            rays_reflect: self.get(Stat::RaysReflect),
+            rays_refract: self.get(Stat::RaysRefract),
+            bbox_tests: self.get(Stat::BboxTests),
+            prim_tests: self.get(Stat::PrimTests)
+        }
+    }
+}
+
+// Counts `stat` when there are counters to count it in
+pub fn count(stats: Option<&StatCounters>, stat: Stat) {
+    match stats {
+        Some(stats) => stats.count(stat),
+        None => ()
+    }
+}
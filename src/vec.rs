@@ -1,6 +1,6 @@
 use std::cmp::Ordering;
 use std::num::Float;
-use std::ops::{Add, Sub, Mul, Index};
+use std::ops::{Add, Sub, Mul, Div, Neg, Index};
 
 #[derive(Clone, Copy, Debug)]
 pub struct Vec3 {
@@ -62,6 +62,23 @@ impl Mul for Vec3 {
     }
 }
 
+// Divides each component, so dividing by zero gives infinite or NaN components like f32 does
+impl Div<f32> for Vec3 {
+    type Output = Vec3;
+
+    fn div(self, num: f32) -> Vec3 {
+        Vec3::init(self.x / num, self.y / num, self.z / num)
+    }
+}
+
+impl Neg for Vec3 {
+    type Output = Vec3;
+
+    fn neg(self) -> Vec3 {
+        Vec3::init(-self.x, -self.y, -self.z)
+    }
+}
+
 impl Index<u32> for Vec3 {
     type Output = f32;
 
@@ -89,7 +106,19 @@ impl Vec3 {
     }
 
     pub fn length(&self) -> f32 {
-        (self.x * self.x + self.y * self.y + self.z * self.z).sqrt()
+        self.length_squared().sqrt()
+    }
+
+    // Cheaper than `length` when only comparing lengths
+    pub fn length_squared(&self) -> f32 {
+        self.x * self.x + self.y * self.y + self.z * self.z
+    }
+
+    // An in place version of `+`, for accumulating in loops
+    pub fn add_assign(&mut self, vec: Vec3) {
+        self.x += vec.x;
+        self.y += vec.y;
+        self.z += vec.z;
     }
 
     pub fn mult(&self, num: f32) -> Vec3 {
@@ -117,14 +146,102 @@ impl Vec3 {
     }
 
     pub fn invert(&self) -> Vec3 {
-        self.mult(-1.0)
+        -*self
     }
 
     pub fn distance(&self, other: Vec3) -> f32 {
-        let a = self.x - other.x;
-        let b = self.y - other.y;
-        let c = self.z - other.z;
-        (a * a + b * b + c * c).sqrt()
+        self.distance_squared(other).sqrt()
+    }
+
+    pub fn distance_squared(&self, other: Vec3) -> f32 {
+        (*self - other).length_squared()
+    }
+
+    pub fn lerp(&self, other: Vec3, t: f32) -> Vec3 {
+        self.mult(1.0 - t) + other.mult(t)
+    }
+
+    // Turns this direction toward `other` at a constant rate. Opposite directions have no single
+    // shortest turn between them, so they're turned around `axis`, or around any perpendicular
+    // axis if `axis` is parallel to them.
+    pub fn slerp(&self, other: Vec3, t: f32, axis: Vec3) -> Vec3 {
+        let (mut from, mut to) = (*self, other);
+        from.normalize();
+        to.normalize();
+        let cos = from.dot(to).max(-1.0).min(1.0);
+        // The direction perpendicular to `from` that it turns toward
+        let mut toward = to - from.mult(cos);
+        if toward.length() < 1.0e-4 {
+            toward = axis.cross(from);
+        }
+        if toward.length() < 1.0e-4 {
+            toward = from.orthonormal_basis().0;
+        }
+        toward.normalize();
+        let angle = cos.acos() * t;
+        from.mult(angle.cos()) + toward.mult(angle.sin())
+    }
+
+    pub fn component_min(&self, other: Vec3) -> Vec3 {
+        Vec3::init(self.x.min(other.x), self.y.min(other.y), self.z.min(other.z))
+    }
+
+    pub fn component_max(&self, other: Vec3) -> Vec3 {
+        Vec3::init(self.x.max(other.x), self.y.max(other.y), self.z.max(other.z))
+    }
+
+    pub fn abs(&self) -> Vec3 {
+        Vec3::init(self.x.abs(), self.y.abs(), self.z.abs())
+    }
+
+    // The sign of each component as 1.0 or -1.0. Like `f32::signum`, zero keeps its sign, so
+    // 0.0 gives 1.0 and -0.0 gives -1.0.
+    pub fn signum(&self) -> Vec3 {
+        Vec3::init(self.x.signum(), self.y.signum(), self.z.signum())
+    }
+
+    // Clamps each component to the range given by the same component of `lo` and `hi`
+    pub fn clamp(&self, lo: Vec3, hi: Vec3) -> Vec3 {
+        self.component_max(lo).component_min(hi)
+    }
+
+    // The cell of a grid with cells of size `epsilon` that this point lies in. Points closer
+    // than `epsilon` to each other mostly share a key, which makes it usable for hashing.
+    pub fn quantized_key(&self, epsilon: f32) -> (i64, i64, i64) {
+        let inv = 1.0 / epsilon;
+        ((self.x * inv).floor() as i64, (self.y * inv).floor() as i64,
+            (self.z * inv).floor() as i64)
+    }
+
+    // Mirrors this vector about the plane with the given normal, which must be normalized
+    pub fn reflect(&self, normal: Vec3) -> Vec3 {
+        *self - normal.mult(2.0 * self.dot(normal))
+    }
+
+    // Two unit vectors that are perpendicular to each other and to this (normalized) vector
+    pub fn orthonormal_basis(&self) -> (Vec3, Vec3) {
+        let helper = if self.x.abs() > 0.9 {
+            Vec3::init(0.0, 1.0, 0.0)
+        } else {
+            Vec3::init(1.0, 0.0, 0.0)
+        };
+        let mut tangent = self.cross(helper);
+        tangent.normalize();
+        let bitangent = self.cross(tangent);
+        (tangent, bitangent)
+    }
+
+    // The polar angle from the y-axis and the azimuth around it of this (normalized) vector
+    pub fn to_spherical(&self) -> (f32, f32) {
+        let theta = self.y.max(-1.0).min(1.0).acos();
+        let phi = self.z.atan2(self.x);
+        (theta, phi)
+    }
+
+    pub fn from_spherical(theta: f32, phi: f32) -> Vec3 {
+        let (sin_theta, cos_theta) = theta.sin_cos();
+        let (sin_phi, cos_phi) = phi.sin_cos();
+        Vec3::init(sin_theta * cos_phi, cos_theta, sin_theta * sin_phi)
     }
 
     pub fn get_area(a: Vec3, b: Vec3, c: Vec3) -> f32 {
@@ -136,6 +253,8 @@ impl Vec3 {
 
 #[cfg(test)]
 mod tests {
+    use std::num::Float;
+
     use vec::Vec3;
 
     #[test]
@@ -158,6 +277,15 @@ mod tests {
         assert_eq!(c.z, 0f32);
     }
 
+    #[test]
+    fn vec3_can_be_accumulated_in_place(){
+        let mut a = Vec3::new();
+        for i in 0 .. 4 {
+            a.add_assign(Vec3::init(1.0, i as f32, -0.5));
+        }
+        assert_eq!(Vec3::init(4.0, 6.0, -2.0), a);
+    }
+
     #[test]
     fn vec3_can_be_multiplied(){
         let a = Vec3{x: 0f32, y: 1f32, z: 2f32};
@@ -177,6 +305,24 @@ mod tests {
         assert_eq!(c.z, 4.0);
     }
 
+    #[test]
+    fn vec3_can_be_negated(){
+        let a = Vec3::init(1.0, -2.0, 0.5);
+        assert_eq!(Vec3::init(-1.0, 2.0, -0.5), -a);
+        assert_eq!(a.invert(), -a);
+    }
+
+    #[test]
+    fn vec3_can_be_divided_by_f32(){
+        let a = Vec3::init(1.0, -2.0, 0.5);
+        assert_eq!(a.mult(0.5), a / 2.0);
+
+        let b = Vec3::init(1.0, -1.0, 0.0) / 0.0;
+        assert!(b.x.is_infinite() && b.x > 0.0);
+        assert!(b.y.is_infinite() && b.y < 0.0);
+        assert!(b.z.is_nan());
+    }
+
     #[test]
     fn vec3_can_be_equal(){
         let a = Vec3{x: 1.2, y: 2.2, z: 3.2};
@@ -195,6 +341,14 @@ mod tests {
         assert!(x-4.06449 < 0.0);
     }
 
+    #[test]
+    fn vec3_has_squared_length(){
+        let a = Vec3::init(1.2, -2.2, 3.2);
+        assert!((a.length_squared() - a.length().powi(2)).abs() < 1.0e-5);
+        assert!((a.distance_squared(Vec3::new()) - a.distance(Vec3::new()).powi(2)).abs() < 1.0e-5);
+        assert_eq!(9.0, Vec3::init(1.0, 2.0, 2.0).distance_squared(Vec3::new()));
+    }
+
     #[test]
     fn vec3_can_be_normalized(){
         let mut v = Vec3::init(3.0, 4.0, 5.0);
@@ -221,4 +375,109 @@ mod tests {
         assert_eq!(x[1], 2.0);
         assert_eq!(x[2], 3.0);
     }
+
+    #[test]
+    fn vec3_can_be_interpolated(){
+        let a = Vec3::init(0.0, 2.0, -4.0);
+        let b = Vec3::init(2.0, 4.0, 4.0);
+        assert_eq!(a.lerp(b, 0.0), a);
+        assert_eq!(a.lerp(b, 1.0), b);
+        assert_eq!(a.lerp(b, 0.5), Vec3::init(1.0, 3.0, 0.0));
+    }
+
+    #[test]
+    fn vec3_can_be_spherically_interpolated(){
+        let (x, z) = (Vec3::init(1.0, 0.0, 0.0), Vec3::init(0.0, 0.0, -2.0));
+        let up = Vec3::init(0.0, 1.0, 0.0);
+        let half = x.slerp(z, 0.5, up);
+        assert!((half.length() - 1.0).abs() < 1.0e-6);
+        assert!((half.x + half.z).abs() < 1.0e-6);
+        assert!(x.slerp(z, 1.0, up).distance(Vec3::init(0.0, 0.0, -1.0)) < 1.0e-6);
+
+        // Opposite directions turn around the axis, or around any axis perpendicular to them
+        let back = x.slerp(x.invert(), 0.5, up);
+        assert!(back.distance(Vec3::init(0.0, 0.0, -1.0)) < 1.0e-6);
+        let along = x.slerp(x.invert(), 0.5, x);
+        assert!((along.length() - 1.0).abs() < 1.0e-6);
+        assert!(along.dot(x).abs() < 1.0e-6);
+    }
+
+    #[test]
+    fn vec3_has_orthonormal_basis(){
+        let mut normals = vec!(Vec3::init(1.0, 0.0, 0.0), Vec3::init(0.0, 0.0, -1.0),
+            Vec3::init(1.0, 2.0, 3.0));
+        for normal in normals.iter_mut() {
+            normal.normalize();
+            let (t, b) = normal.orthonormal_basis();
+            assert!((t.length() - 1.0).abs() < 1.0e-6);
+            assert!((b.length() - 1.0).abs() < 1.0e-6);
+            assert!(t.dot(b).abs() < 1.0e-6);
+            assert!(t.dot(*normal).abs() < 1.0e-6);
+            assert!(b.dot(*normal).abs() < 1.0e-6);
+        }
+    }
+
+    #[test]
+    fn vec3_can_be_converted_to_spherical_and_back(){
+        let mut dirs = vec!(Vec3::init(1.0, 0.0, 0.0), Vec3::init(0.0, 0.0, -1.0),
+            Vec3::init(-1.0, 2.0, 3.0), Vec3::init(0.5, -0.5, -2.0));
+        for dir in dirs.iter_mut() {
+            dir.normalize();
+            let (theta, phi) = dir.to_spherical();
+            assert!(Vec3::from_spherical(theta, phi).distance(*dir) < 1.0e-5);
+        }
+
+        // At the poles the azimuth is arbitrary, but the direction must survive
+        for &y in [1.0, -1.0].iter() {
+            let pole = Vec3::init(0.0, y, 0.0);
+            let (theta, phi) = pole.to_spherical();
+            assert!(!theta.is_nan() && !phi.is_nan());
+            assert!(Vec3::from_spherical(theta, phi).distance(pole) < 1.0e-5);
+        }
+    }
+
+    #[test]
+    fn vec3_can_be_reflected(){
+        let normal = Vec3::init(0.0, 1.0, 0.0);
+        let incoming = Vec3::init(1.0, -1.0, 0.0);
+        assert_eq!(Vec3::init(1.0, 1.0, 0.0), incoming.reflect(normal));
+
+        // A grazing ray barely changes direction
+        let mut grazing = Vec3::init(1.0, -0.001, 0.0);
+        grazing.normalize();
+        let reflected = grazing.reflect(normal);
+        assert!((reflected.x - grazing.x).abs() < 1.0e-6);
+        assert!((reflected.y + grazing.y).abs() < 1.0e-6);
+        assert!((reflected.length() - 1.0).abs() < 1.0e-6);
+    }
+
+    #[test]
+    fn near_vec3s_share_quantized_key(){
+        let a = Vec3::init(1.0001, -2.0003, 3.0002);
+        let b = Vec3::init(1.0004, -2.0001, 3.0006);
+        let c = Vec3::init(1.5, -2.0003, 3.0002);
+        assert_eq!((1000, -2001, 3000), a.quantized_key(0.001));
+        assert_eq!(a.quantized_key(0.001), b.quantized_key(0.001));
+        assert!(a.quantized_key(0.001) != c.quantized_key(0.001));
+    }
+
+    #[test]
+    fn vec3_has_component_min_max_and_clamp(){
+        let a = Vec3::init(-1.0, 2.0, -3.0);
+        let b = Vec3::init(1.0, -2.0, -4.0);
+        assert_eq!(Vec3::init(-1.0, -2.0, -4.0), a.component_min(b));
+        assert_eq!(Vec3::init(1.0, 2.0, -3.0), a.component_max(b));
+
+        let (lo, hi) = (Vec3::init(0.0, -1.0, -3.5), Vec3::init(0.5, 1.0, 3.5));
+        assert_eq!(Vec3::init(0.0, 1.0, -3.0), a.clamp(lo, hi));
+        assert_eq!(Vec3::init(0.5, -1.0, -3.5), b.clamp(lo, hi));
+    }
+
+    #[test]
+    fn vec3_has_component_abs_and_signum(){
+        let a = Vec3::init(-1.5, 2.0, -0.0);
+        assert_eq!(Vec3::init(1.5, 2.0, 0.0), a.abs());
+        assert_eq!(Vec3::init(-1.0, 1.0, -1.0), a.signum());
+        assert_eq!(a, a.abs() * a.signum());
+    }
 }